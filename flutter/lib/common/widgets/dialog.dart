@@ -256,9 +256,14 @@ void changeWhiteList({Function()? callback}) async {
                     r"^(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9][0-9]?|0)\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9][0-9]?|0)\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9][0-9]?|0)\.(25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9][0-9]?|0)(\/([1-9]|[1-2][0-9]|3[0-2])){0,1}$");
                 final ipv6Match = RegExp(
                     r"^(((?:[0-9A-Fa-f]{1,4}))*((?::[0-9A-Fa-f]{1,4}))*::((?:[0-9A-Fa-f]{1,4}))*((?::[0-9A-Fa-f]{1,4}))*|((?:[0-9A-Fa-f]{1,4}))((?::[0-9A-Fa-f]{1,4})){7})(\/([1-9]|[1-9][0-9]|1[0-1][0-9]|12[0-8])){0,1}$");
-                for (final ip in ips) {
-                  if (!ipMatch.hasMatch(ip) && !ipv6Match.hasMatch(ip)) {
-                    msg = "${translate("Invalid IP")} $ip";
+                // "!" denies, "@name" is a named list
+                final listMatch = RegExp(r"^@[\w-]+$");
+                for (final entry in ips) {
+                  final ip = entry.startsWith('!') ? entry.substring(1) : entry;
+                  if (!ipMatch.hasMatch(ip) &&
+                      !ipv6Match.hasMatch(ip) &&
+                      !listMatch.hasMatch(ip)) {
+                    msg = "${translate("Invalid IP")} $entry";
                     setState(() {
                       isInProgress = false;
                     });
@@ -2215,6 +2220,7 @@ void change2fa({Function()? callback}) async {
       if (await bind.mainVerify2Fa(code: controller.text.trim())) {
         callback?.call();
         close();
+        showRecoveryCodesDialog(await bind.mainTake2FaRecoveryCodes());
       } else {
         errorText = translate('wrong-2fa-code');
       }
@@ -2265,6 +2271,48 @@ void change2fa({Function()? callback}) async {
   });
 }
 
+void showRecoveryCodesDialog(String codes) {
+  final List<String> list =
+      codes.isEmpty ? [] : List<String>.from(jsonDecode(codes));
+  if (list.isEmpty) {
+    return;
+  }
+  gFFI.dialogManager.show((setState, close, context) {
+    return CustomAlertDialog(
+      title: Text(translate('Recovery codes')),
+      content: Column(
+        crossAxisAlignment: CrossAxisAlignment.start,
+        children: [
+          SelectableText(translate('recovery-codes-tip'),
+                  style: TextStyle(fontSize: 12))
+              .marginOnly(bottom: 12),
+          SelectableText(list.join('\n'),
+              style: TextStyle(fontFamily: 'monospace')),
+        ],
+      ),
+      actions: [
+        dialogButton('OK', onPressed: close),
+      ],
+      onSubmit: close,
+      onCancel: close,
+    );
+  });
+}
+
+// Replaces all recovery codes, the old ones stop working.
+void regenerateRecoveryCodes() {
+  CommonConfirmDialog(
+      gFFI.dialogManager, translate('regenerate-recovery-codes-confirm-tip'),
+      () async {
+    final codes = await bind.mainRegenerate2FaRecoveryCodes();
+    if (codes.isEmpty) {
+      showToast(translate('Failed'));
+    } else {
+      showRecoveryCodesDialog(codes);
+    }
+  });
+}
+
 void enter2FaDialog(
     SessionID sessionId, OverlayDialogManager dialogManager) async {
   final controller = TextEditingController();
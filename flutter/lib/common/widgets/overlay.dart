@@ -606,6 +606,10 @@ class QualityMonitor extends StatelessWidget {
                       _row(
                           "Codec", qualityMonitorModel.data.codecFormat ?? '-'),
                       _row("Chroma", qualityMonitorModel.data.chroma ?? '-'),
+                      _row("Connection",
+                          qualityMonitorModel.data.connection ?? '-'),
+                      _row("Reconnects",
+                          qualityMonitorModel.data.reconnects ?? '-'),
                     ],
                   ),
                 )
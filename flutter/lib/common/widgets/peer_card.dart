@@ -1,3 +1,5 @@
+import 'dart:convert';
+
 import 'package:bot_toast/bot_toast.dart';
 import 'package:flutter/material.dart';
 import 'package:flutter/services.dart';
@@ -675,6 +677,13 @@ abstract class BasePeerCard extends StatelessWidget {
       ),
       proc: () {
         bind.mainWol(id: id);
+        if (!isWeb) {
+          final relays =
+              List<String>.from(jsonDecode(bind.mainGetWolRelays(id: id)));
+          if (relays.isNotEmpty) {
+            showToast('${translate('wol-relay-tip')} ${relays.join(', ')}');
+          }
+        }
       },
       padding: menuPadding,
       dismissOnClicked: true,
@@ -253,6 +253,19 @@ List<TTextMenu> toolbarControls(BuildContext context, String id, FFI ffi) {
           onPressed: () => bind.sessionLockScreen(sessionId: sessionId)),
     );
   }
+  // wake-on-lan relay, for discovered peers on the subnet of this peer
+  if (isDefaultConn && !isWeb && ffi.ffiModel.keyboard) {
+    final targets = List<String>.from(
+        jsonDecode(bind.sessionGetWolTargets(sessionId: sessionId)));
+    for (final target in targets) {
+      v.add(
+        TTextMenu(
+            child: Text('${translate('Wake-on-LAN relay')}: $target'),
+            onPressed: () =>
+                bind.sessionWakePeer(sessionId: sessionId, id: target)),
+      );
+    }
+  }
   // blockUserInput
   if (isDefaultConn &&
       ffi.ffiModel.keyboard &&
@@ -395,6 +408,15 @@ Future<List<TRadioMenu<String>>> toolbarImageQuality(
     await bind.sessionSetImageQuality(sessionId: ffi.sessionId, value: value);
   }
 
+  var screenContent = false;
+  try {
+    final Map codecsJson = jsonDecode(
+        await bind.sessionAlternativeCodecs(sessionId: ffi.sessionId));
+    screenContent = codecsJson['screen_content'] ?? false;
+  } catch (e) {
+    debugPrint("Show screen content err=$e");
+  }
+
   return [
     TRadioMenu<String>(
         child: Text(translate('Good image quality')),
@@ -411,6 +433,12 @@ Future<List<TRadioMenu<String>>> toolbarImageQuality(
         value: kRemoteImageQualityLow,
         groupValue: groupValue,
         onChanged: onChanged),
+    if (screenContent || groupValue == kRemoteImageQualityScreenContent)
+      TRadioMenu<String>(
+          child: Text(translate('Screen content')),
+          value: kRemoteImageQualityScreenContent,
+          groupValue: groupValue,
+          onChanged: onChanged),
     TRadioMenu<String>(
       child: Text(translate('Custom')),
       value: kRemoteImageQualityCustom,
@@ -707,6 +707,13 @@ class _GeneralState extends State<_General> {
           _OptionCheckBox(context, 'Automatically record outgoing sessions',
               kOptionAllowAutoRecordOutgoing,
               isServer: false),
+        if (!bind.isOutgoingOnly())
+          _OptionCheckBox(context, 'Record audio of incoming sessions',
+              kOptionRecordAudio),
+        if (!bind.isIncomingOnly())
+          _OptionCheckBox(context, 'Record audio of outgoing sessions',
+              kOptionRecordAudio,
+              isServer: false),
         if (showRootDir && !bind.isOutgoingOnly())
           Row(
             children: [
@@ -964,8 +971,16 @@ class _SafetyState extends State<_Safety> with AutomaticKeepAliveClientMixin {
         ],
       ).marginOnly(left: 30);
 
+      final recovery = Row(
+        children: [
+          ElevatedButton(
+              onPressed: locked ? null : regenerateRecoveryCodes,
+              child: Text(translate('Regenerate recovery codes')))
+        ],
+      ).marginOnly(left: 30);
+
       return Column(
-        children: [tfa, bot, trust],
+        children: [tfa, bot, trust, recovery],
       );
     }
 
@@ -1052,6 +1067,13 @@ class _SafetyState extends State<_Safety> with AutomaticKeepAliveClientMixin {
             _OptionCheckBox(
                 context, 'Enable TCP tunneling', kOptionEnableTunnel,
                 enabled: enabled, fakeValue: fakeValue),
+            // Not granted by the access mode, it needs the explicit opt-in.
+            Tooltip(
+              message: translate('reverse-tunnel-tip'),
+              child: _OptionCheckBox(context, 'Enable reverse TCP tunneling',
+                  kOptionAllowReverseTunnel,
+                  enabled: enabled),
+            ),
             _OptionCheckBox(
                 context, 'Enable remote restart', kOptionEnableRemoteRestart,
                 enabled: enabled, fakeValue: fakeValue),
@@ -1062,6 +1084,9 @@ class _SafetyState extends State<_Safety> with AutomaticKeepAliveClientMixin {
               _OptionCheckBox(context, 'Enable blocking user input',
                   kOptionEnableBlockInput,
                   enabled: enabled, fakeValue: fakeValue),
+            _OptionCheckBox(
+                context, 'Enable Wake-on-LAN relay', kOptionAllowWolRelay,
+                enabled: enabled),
             _OptionCheckBox(context, 'Enable remote configuration modification',
                 kOptionAllowRemoteConfigModification,
                 enabled: enabled, fakeValue: fakeValue),
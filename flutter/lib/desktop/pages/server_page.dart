@@ -986,6 +986,14 @@ class _CmControlPanel extends StatelessWidget {
             textColor: Colors.white,
           ),
         ),
+        buildButton(context,
+            color: Colors.transparent,
+            border: Border.all(color: Colors.grey),
+            onClick: null,
+            onTapDown: (details) => handleTimeLimit(context, details),
+            icon: Icon(Icons.timer_outlined, size: 14),
+            text: 'Time limit',
+            textColor: null),
         Row(
           children: [
             Expanded(
@@ -1147,6 +1155,35 @@ class _CmControlPanel extends StatelessWidget {
     bind.cmCloseConnection(connId: client.id);
   }
 
+  // The peer is disconnected when the access granted to it expires.
+  void handleTimeLimit(BuildContext context, TapDownDetails details) {
+    final x = details.globalPosition.dx;
+    final y = details.globalPosition.dy;
+    final items = [
+      ['30 min', 30],
+      ['1 h', 60],
+      ['2 h', 120],
+      ['8 h', 480],
+    ]
+        .map((e) => PopupMenuItem<int>(
+            value: e[1] as int, height: 28, child: Text(e[0] as String)))
+        .toList();
+    items.add(PopupMenuItem<int>(
+        value: 0, height: 28, child: Text(translate('Revoke access'))));
+    showMenu<int>(
+      context: context,
+      position: RelativeRect.fromLTRB(x, y, x, y),
+      items: items,
+    ).then((minutes) {
+      if (minutes == null) return;
+      if (minutes > 0) {
+        bind.cmGrantAccess(connId: client.id, minutes: minutes);
+      } else {
+        bind.cmRevokeAccess(connId: client.id);
+      }
+    });
+  }
+
   void handleAccept(BuildContext context) {
     final model = Provider.of<ServerModel>(context, listen: false);
     model.sendLoginResponse(client, true);
@@ -357,7 +357,11 @@ class _SettingsState extends State<SettingsPage> with WidgetsBindingObserver {
               Navigator.push(context, MaterialPageRoute(builder: (context) {
                 return _ManageTrustedDevices();
               }));
-            })
+            }),
+      if (enable2fa)
+        SettingsTile(
+            title: Text(translate('Regenerate recovery codes')),
+            onPressed: (context) => regenerateRecoveryCodes()),
     ];
     final List<AbstractSettingsTile> shareScreenTiles = [
       SettingsTile.switchTile(
@@ -392,6 +392,8 @@ class FfiModel with ChangeNotifier {
         parent.target?.serverModel.onClientRemove(evt);
       } else if (name == 'update_quality_status') {
         parent.target?.qualityMonitorModel.updateQualityStatus(evt);
+      } else if (name == 'update_transport_stats') {
+        parent.target?.qualityMonitorModel.updateTransportStats(evt);
       } else if (name == 'update_block_input_state') {
         updateBlockInputState(evt, peerId);
       } else if (name == 'update_privacy_mode') {
@@ -3477,6 +3479,9 @@ class QualityMonitorData {
   String? targetBitrate;
   String? codecFormat;
   String? chroma;
+  String? connection;
+  String? reconnects;
+  Map<String, dynamic>? transportStats;
 }
 
 class QualityMonitorModel with ChangeNotifier {
@@ -3544,6 +3549,18 @@ class QualityMonitorModel with ChangeNotifier {
       //
     }
   }
+
+  updateTransportStats(Map<String, dynamic> evt) {
+    try {
+      final stats = jsonDecode(evt['stats']) as Map<String, dynamic>;
+      _data.transportStats = stats;
+      _data.connection = stats['kind']?.toString();
+      _data.reconnects = stats['reconnects']?.toString();
+      notifyListeners();
+    } catch (e) {
+      //
+    }
+  }
 }
 
 class RecordingModel with ChangeNotifier {
@@ -367,6 +367,11 @@ class RustdeskImpl {
     }
   }
 
+  Future<String?> sessionGetTransportStats(
+      {required UuidValue sessionId, dynamic hint}) {
+    return Future.value(null);
+  }
+
   bool sessionIsKeyboardModeSupported(
       {required UuidValue sessionId, required String mode, dynamic hint}) {
     if (mainGetInputSource(hint: hint) == 'Input source 1') {
@@ -1308,6 +1313,15 @@ class RustdeskImpl {
     return Future(() => js.context.callMethod('setByName', ['restart']));
   }
 
+  Future<void> sessionWakePeer(
+      {required UuidValue sessionId, required String id, dynamic hint}) {
+    throw UnimplementedError("sessionWakePeer");
+  }
+
+  String sessionGetWolTargets({required UuidValue sessionId, dynamic hint}) {
+    throw UnimplementedError("sessionGetWolTargets");
+  }
+
   String sessionGetAuditServerSync(
       {required UuidValue sessionId, required String typ, dynamic hint}) {
     return js.context.callMethod('getByName', ['audit_server', typ]);
@@ -1388,6 +1402,10 @@ class RustdeskImpl {
     throw UnimplementedError("mainWol");
   }
 
+  String mainGetWolRelays({required String id, dynamic hint}) {
+    throw UnimplementedError("mainGetWolRelays");
+  }
+
   Future<void> mainCreateShortcut({required String id, dynamic hint}) {
     throw UnimplementedError("mainCreateShortcut");
   }
@@ -1427,6 +1445,15 @@ class RustdeskImpl {
     throw UnimplementedError("cmSwitchPermission");
   }
 
+  Future<void> cmGrantAccess(
+      {required int connId, required int minutes, dynamic hint}) {
+    throw UnimplementedError("cmGrantAccess");
+  }
+
+  Future<void> cmRevokeAccess({required int connId, dynamic hint}) {
+    throw UnimplementedError("cmRevokeAccess");
+  }
+
   bool cmCanElevate({dynamic hint}) {
     throw UnimplementedError("cmCanElevate");
   }
@@ -1769,6 +1796,14 @@ class RustdeskImpl {
     throw UnimplementedError("mainVerify2Fa");
   }
 
+  Future<String> mainTake2FaRecoveryCodes({dynamic hint}) {
+    throw UnimplementedError("mainTake2FaRecoveryCodes");
+  }
+
+  Future<String> mainRegenerate2FaRecoveryCodes({dynamic hint}) {
+    throw UnimplementedError("mainRegenerate2FaRecoveryCodes");
+  }
+
   bool mainHasValid2FaSync({dynamic hint}) {
     throw UnimplementedError("mainHasValid2FaSync");
   }
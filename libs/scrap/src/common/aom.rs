@@ -47,6 +47,8 @@ pub struct AomEncoderConfig {
     pub height: u32,
     pub quality: f32,
     pub keyframe_interval: Option<usize>,
+    /// Palette and intra block copy, with a lower quantizer for the text
+    pub screen_content: bool,
 }
 
 pub struct AomEncoder {
@@ -55,6 +57,7 @@ pub struct AomEncoder {
     height: usize,
     i444: bool,
     yuvfmt: EncodeYuvFormat,
+    screen_content: bool,
 }
 
 // https://webrtc.googlesource.com/src/+/refs/heads/main/modules/video_coding/codecs/av1/libaom_av1_encoder.cc
@@ -110,7 +113,7 @@ mod webrtc {
         } else {
             c.kf_mode = aom_kf_mode::AOM_KF_DISABLED;
         }
-        let (q_min, q_max) = AomEncoder::calc_q_values(cfg.quality);
+        let (q_min, q_max) = AomEncoder::calc_q_values(cfg.quality, cfg.screen_content);
         c.rc_min_quantizer = q_min;
         c.rc_max_quantizer = q_max;
         c.rc_target_bitrate = AomEncoder::bitrate(cfg.width as _, cfg.height as _, cfg.quality);
@@ -132,7 +135,11 @@ mod webrtc {
         Ok(c)
     }
 
-    pub fn set_controls(ctx: *mut aom_codec_ctx_t, cfg: &aom_codec_enc_cfg) -> ResultType<()> {
+    pub fn set_controls(
+        ctx: *mut aom_codec_ctx_t,
+        cfg: &aom_codec_enc_cfg,
+        screen_content: bool,
+    ) -> ResultType<()> {
         use aom_tune_content::*;
         use aome_enc_control_id::*;
         macro_rules! call_ctl {
@@ -185,9 +192,10 @@ mod webrtc {
         call_ctl!(ctx, AV1E_SET_ENABLE_INTERINTRA_COMP, 0);
         call_ctl!(ctx, AV1E_SET_ENABLE_INTERINTRA_WEDGE, 0);
         call_ctl!(ctx, AV1E_SET_ENABLE_INTRA_EDGE_FILTER, 0);
-        call_ctl!(ctx, AV1E_SET_ENABLE_INTRABC, 0);
+        // Repeated glyphs are copied within the frame, paeth prediction fits the sharp edges.
+        call_ctl!(ctx, AV1E_SET_ENABLE_INTRABC, screen_content as i32);
         call_ctl!(ctx, AV1E_SET_ENABLE_MASKED_COMP, 0);
-        call_ctl!(ctx, AV1E_SET_ENABLE_PAETH_INTRA, 0);
+        call_ctl!(ctx, AV1E_SET_ENABLE_PAETH_INTRA, screen_content as i32);
         call_ctl!(ctx, AV1E_SET_ENABLE_QM, 0);
         call_ctl!(ctx, AV1E_SET_ENABLE_RECT_PARTITIONS, 0);
         call_ctl!(ctx, AV1E_SET_ENABLE_RESTORATION, 0);
@@ -219,13 +227,14 @@ impl EncoderApi for AomEncoder {
                     flags,
                     AOM_ENCODER_ABI_VERSION as _
                 ));
-                webrtc::set_controls(&mut ctx, &c)?;
+                webrtc::set_controls(&mut ctx, &c, config.screen_content)?;
                 Ok(Self {
                     ctx,
                     width: config.width as _,
                     height: config.height as _,
                     i444,
                     yuvfmt: Self::get_yuvfmt(config.width, config.height, i444),
+                    screen_content: config.screen_content,
                 })
             }
             _ => Err(anyhow!("encoder type mismatch")),
@@ -258,7 +267,7 @@ impl EncoderApi for AomEncoder {
 
     fn set_quality(&mut self, ratio: f32) -> ResultType<()> {
         let mut c = unsafe { *self.ctx.config.enc.to_owned() };
-        let (q_min, q_max) = Self::calc_q_values(ratio);
+        let (q_min, q_max) = Self::calc_q_values(ratio, self.screen_content);
         c.rc_min_quantizer = q_min;
         c.rc_max_quantizer = q_max;
         c.rc_target_bitrate = Self::bitrate(self.width as _, self.height as _, ratio);
@@ -350,13 +359,15 @@ impl AomEncoder {
     }
 
     #[inline]
-    fn calc_q_values(ratio: f32) -> (u32, u32) {
+    fn calc_q_values(ratio: f32, screen_content: bool) -> (u32, u32) {
         let b = (ratio * 100.0) as u32;
         let b = std::cmp::min(b, 200);
-        let q_min1 = 24;
-        let q_min2 = 5;
-        let q_max1 = 45;
-        let q_max2 = 25;
+        let (q_min1, q_min2, q_max1, q_max2) = if screen_content {
+            // Lower quantizers, for the small fonts
+            (12, 0, 36, 16)
+        } else {
+            (24, 5, 45, 25)
+        };
 
         let t = b as f32 / 200.0;
 
@@ -14,6 +14,7 @@ use crate::vram::*;
 use crate::{
     aom::{self, AomDecoder, AomEncoder, AomEncoderConfig},
     common::GoogleImage,
+    damage::DamageRect,
     vpxcodec::{self, VpxDecoder, VpxDecoderConfig, VpxEncoder, VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, EncodeInput, EncodeYuvFormat, ImageRgb, ImageTexture,
 };
@@ -47,6 +48,15 @@ lazy_static::lazy_static! {
 
 pub const ENCODE_NEED_SWITCH: &'static str = "ENCODE_NEED_SWITCH";
 
+/// The image quality option of the screen content mode, AV1 with its screen content tools,
+/// I444 and a lower quantizer, for the small fonts.
+pub const IMAGE_QUALITY_SCREEN_CONTENT: &str = "screen-content";
+/// `ability_av1` of a decoding which prefers the screen content mode, the older peers
+/// only check it is above 0.
+pub const AV1_ABILITY_SCREEN_CONTENT: i32 = 2;
+/// Key of the peer info platform additions, set if the peer has the screen content mode.
+pub const PEER_SUPPORT_SCREEN_CONTENT: &str = "support_screen_content";
+
 #[derive(Debug, Clone)]
 pub enum EncoderCfg {
     VPX(VpxEncoderConfig),
@@ -71,6 +81,10 @@ pub trait EncoderApi {
 
     fn set_quality(&mut self, ratio: f32) -> ResultType<()>;
 
+    /// Hints of the regions changed in the next frame, `None` for a full frame.
+    /// The encoders which can't use them encode the full frames.
+    fn set_damage(&mut self, _damage: Option<&[DamageRect]>) {}
+
     fn bitrate(&self) -> u32;
 
     fn support_changing_quality(&self) -> bool;
@@ -234,6 +248,10 @@ impl Encoder {
             _all_support_h264_decoding && (h264vram_encoding || h264hw_encoding.is_some());
         let h265_useable =
             _all_support_h265_decoding && (h265vram_encoding || h265hw_encoding.is_some());
+        let screen_content = av1_useable
+            && decodings
+                .iter()
+                .all(|(_, s)| s.ability_av1 == AV1_ABILITY_SCREEN_CONTENT);
         let mut format = ENCODE_CODEC_FORMAT.lock().unwrap();
         let preferences: Vec<_> = decodings
             .iter()
@@ -310,9 +328,13 @@ impl Encoder {
             }
             PreferCodec::Auto => auto_codec,
         };
+        // The screen content tools are of AV1 only.
+        if screen_content {
+            *format = CodecFormat::AV1;
+        }
         if decodings.len() > 0 {
             log::info!(
-                "usable: vp8={vp8_useable}, av1={av1_useable}, h264={h264_useable}, h265={h265_useable}",
+                "usable: vp8={vp8_useable}, av1={av1_useable}, h264={h264_useable}, h265={h265_useable}, screen_content={screen_content}",
             );
             log::info!(
                 "connection count: {}, used preference: {:?}, encoder: {:?}",
@@ -402,9 +424,11 @@ impl Encoder {
 
     pub fn use_i444(config: &EncoderCfg) -> bool {
         let decodings = PEER_DECODINGS.lock().unwrap().clone();
-        let prefer_i444 = decodings
-            .iter()
-            .all(|d| d.1.prefer_chroma == Chroma::I444.into());
+        // The colors of the small fonts are blurred by the chroma subsampling.
+        let prefer_i444 = decodings.iter().all(|d| {
+            d.1.prefer_chroma == Chroma::I444.into()
+                || d.1.ability_av1 == AV1_ABILITY_SCREEN_CONTENT
+        });
         let i444_useable = match config {
             EncoderCfg::VPX(vpx) => match vpx.codec {
                 VpxVideoCodecId::VP8 => false,
@@ -418,6 +442,21 @@ impl Encoder {
         };
         prefer_i444 && i444_useable && !decodings.is_empty()
     }
+
+    /// Whether all the peers prefer the screen content mode.
+    pub fn use_screen_content() -> bool {
+        let decodings = PEER_DECODINGS.lock().unwrap();
+        !decodings.is_empty()
+            && !disable_av1()
+            && decodings
+                .iter()
+                .all(|(_, d)| d.ability_av1 == AV1_ABILITY_SCREEN_CONTENT)
+    }
+
+    /// Whether the screen content mode can be used, see [`PEER_SUPPORT_SCREEN_CONTENT`].
+    pub fn screen_content_supported() -> bool {
+        !disable_av1()
+    }
 }
 
 impl Decoder {
@@ -427,13 +466,19 @@ impl Decoder {
         _luid: Option<i64>,
         mark_unsupported: &Vec<CodecFormat>,
     ) -> SupportedDecoding {
-        let (prefer, prefer_chroma) = Self::preference(id_for_perfer);
+        let (prefer, prefer_chroma, screen_content) = Self::preference(id_for_perfer);
 
         #[allow(unused_mut)]
         let mut decoding = SupportedDecoding {
             ability_vp8: 1,
             ability_vp9: 1,
-            ability_av1: if disable_av1() { 0 } else { 1 },
+            ability_av1: if disable_av1() {
+                0
+            } else if screen_content {
+                AV1_ABILITY_SCREEN_CONTENT
+            } else {
+                1
+            },
             i444: Some(CodecAbility {
                 vp9: true,
                 av1: true,
@@ -816,12 +861,14 @@ impl Decoder {
         return Ok(false);
     }
 
-    fn preference(id: Option<&str>) -> (PreferCodec, Chroma) {
+    fn preference(id: Option<&str>) -> (PreferCodec, Chroma, bool) {
         let id = id.unwrap_or_default();
         if id.is_empty() {
-            return (PreferCodec::Auto, Chroma::I420);
+            return (PreferCodec::Auto, Chroma::I420, false);
         }
-        let options = PeerConfig::load(id).options;
+        let config = PeerConfig::load(id);
+        let screen_content = config.image_quality == IMAGE_QUALITY_SCREEN_CONTENT;
+        let options = config.options;
         let codec = options
             .get("codec-preference")
             .map_or("".to_owned(), |c| c.to_owned());
@@ -843,7 +890,7 @@ impl Decoder {
         } else {
             Chroma::I420
         };
-        (codec, chroma)
+        (codec, chroma, screen_content)
     }
 }
 
@@ -1107,6 +1154,7 @@ pub fn test_av1() {
                     height,
                     quality,
                     keyframe_interval,
+                    screen_content: false,
                 }),
                 i444,
             ) else {
@@ -0,0 +1,168 @@
+// Damage regions, the parts of a frame which changed since the previous one.
+//
+// Capturers which know them, e.g. from XDamage or the pipewire damage metadata, attach them
+// to the frames. They are refined by comparing the regions only, to skip the unchanged frames,
+// and passed to the encoders as hints. A frame without damage is a full frame.
+
+/// Regions beyond it are merged, to bound the cost of refining and encoding hints.
+pub const MAX_DAMAGE_RECTS: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DamageRect {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+impl DamageRect {
+    pub fn new(x: usize, y: usize, w: usize, h: usize) -> Self {
+        Self { x, y, w, h }
+    }
+
+    /// From the screen coordinates, relative to the capture area at `origin` and clipped to `size`.
+    pub fn from_i32(
+        x: i32,
+        y: i32,
+        w: i32,
+        h: i32,
+        origin: (i32, i32),
+        size: (usize, usize),
+    ) -> Option<Self> {
+        let left = (x - origin.0).max(0) as usize;
+        let top = (y - origin.1).max(0) as usize;
+        let right = ((x + w - origin.0).max(0) as usize).min(size.0);
+        let bottom = ((y + h - origin.1).max(0) as usize).min(size.1);
+        if right > left && bottom > top {
+            Some(Self::new(left, top, right - left, bottom - top))
+        } else {
+            None
+        }
+    }
+
+    pub fn area(&self) -> usize {
+        self.w * self.h
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.w).max(other.x + other.w);
+        let bottom = (self.y + self.h).max(other.y + other.h);
+        Self::new(x, y, right - x, bottom - y)
+    }
+
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.x < other.x + other.w
+            && other.x < self.x + self.w
+            && self.y < other.y + other.h
+            && other.y < self.y + self.h
+    }
+}
+
+/// Merges the overlapping regions, then the closest ones while there are more than `max`.
+pub fn merge(mut rects: Vec<DamageRect>, max: usize) -> Vec<DamageRect> {
+    rects.retain(|r| r.area() > 0);
+    let mut merged = true;
+    while merged {
+        merged = false;
+        'outer: for i in 0..rects.len() {
+            for j in i + 1..rects.len() {
+                if rects[i].intersects(&rects[j]) {
+                    rects[i] = rects[i].union(&rects[j]);
+                    rects.swap_remove(j);
+                    merged = true;
+                    break 'outer;
+                }
+            }
+        }
+    }
+    while rects.len() > max.max(1) {
+        // The pair whose union adds the least area
+        let mut best = (0, 1, usize::MAX);
+        for i in 0..rects.len() {
+            for j in i + 1..rects.len() {
+                let cost = rects[i].union(&rects[j]).area() - rects[i].area() - rects[j].area();
+                if cost < best.2 {
+                    best = (i, j, cost);
+                }
+            }
+        }
+        rects[best.0] = rects[best.0].union(&rects[best.1]);
+        rects.swap_remove(best.1);
+    }
+    rects
+}
+
+/// The ratio of the frame covered by the regions, which do not overlap after `merge`.
+pub fn coverage(rects: &[DamageRect], width: usize, height: usize) -> f32 {
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    let area: usize = rects.iter().map(|r| r.area()).sum();
+    (area as f32 / (width * height) as f32).min(1.0)
+}
+
+/// Compares the regions of `data` with `saved`, and copies the changed ones into `saved`.
+/// Returns the changed regions.
+pub fn refine(
+    saved: &mut [u8],
+    data: &[u8],
+    stride: usize,
+    bytes_per_pixel: usize,
+    rects: &[DamageRect],
+) -> Vec<DamageRect> {
+    let mut changed = Vec::new();
+    for r in rects.iter() {
+        let rows = r.y..(r.y + r.h);
+        let range = |y: usize| {
+            let start = y * stride + r.x * bytes_per_pixel;
+            start..(start + r.w * bytes_per_pixel)
+        };
+        if rows
+            .clone()
+            .any(|y| range(y).end > data.len().min(saved.len()))
+        {
+            // Out of the frame, e.g. on resizing
+            continue;
+        }
+        if rows.clone().any(|y| data[range(y)] != saved[range(y)]) {
+            for y in rows {
+                saved[range(y)].copy_from_slice(&data[range(y)]);
+            }
+            changed.push(*r);
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_damage() {
+        let r = |x, y, w, h| DamageRect::new(x, y, w, h);
+        assert_eq!(
+            DamageRect::from_i32(90, -10, 30, 30, (100, 0), (50, 50)),
+            Some(r(0, 0, 20, 20))
+        );
+        assert_eq!(DamageRect::from_i32(0, 0, 10, 10, (100, 0), (50, 50)), None);
+
+        let rects = merge(vec![r(0, 0, 10, 10), r(5, 5, 10, 10), r(100, 100, 1, 1)], 8);
+        assert_eq!(rects, vec![r(0, 0, 15, 15), r(100, 100, 1, 1)]);
+        let rects = merge(vec![r(0, 0, 1, 1), r(2, 0, 1, 1), r(100, 100, 1, 1)], 2);
+        assert_eq!(rects, vec![r(0, 0, 3, 1), r(100, 100, 1, 1)]);
+        assert_eq!(coverage(&[r(0, 0, 5, 10)], 10, 10), 0.5);
+
+        // 4x4 pixels of 1 byte
+        let mut saved = vec![0u8; 16];
+        let mut data = saved.clone();
+        data[5] = 1;
+        let changed = refine(&mut saved, &data, 4, 1, &[r(0, 0, 2, 2), r(2, 2, 2, 2)]);
+        assert_eq!(changed, vec![r(0, 0, 2, 2)]);
+        assert_eq!(saved, data);
+        assert!(refine(&mut saved, &data, 4, 1, &[r(0, 0, 4, 4)]).is_empty());
+        assert!(refine(&mut saved, &data, 4, 1, &[r(3, 3, 2, 2)]).is_empty());
+    }
+}
@@ -0,0 +1,636 @@
+// A Matroska writer for the recordings with audio, which also need the chapters and the tags
+// that the webm muxer does not support.
+//
+// The clusters are written when they are closed, so that a recording is playable up to the last
+// closed cluster if the process is killed. The segment size, the duration and the seek head are
+// patched on finalizing, and the cues, chapters and tags are appended after the clusters.
+use hbb_common::log;
+use std::{
+    io::{self, Seek, SeekFrom, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const EBML: u32 = 0x1A45DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const VOID: u32 = 0xEC;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114D9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const INFO: u32 = 0x1549A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const DURATION: u32 = 0x4489;
+const DATE_UTC: u32 = 0x4461;
+const TITLE: u32 = 0x7BA9;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CLUSTER: u32 = 0x1F43B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const CUES: u32 = 0x1C53BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+const CHAPTERS: u32 = 0x1043A770;
+const EDITION_ENTRY: u32 = 0x45B9;
+const CHAPTER_ATOM: u32 = 0xB6;
+const CHAPTER_UID: u32 = 0x73C4;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+const TAGS: u32 = 0x1254C367;
+const TAG: u32 = 0x7373;
+const TARGETS: u32 = 0x63C0;
+const TARGET_TYPE_VALUE: u32 = 0x68CA;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+
+pub const VIDEO_TRACK: u8 = 1;
+pub const AUDIO_TRACK: u8 = 2;
+// The timestamps are in ms.
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+// Large enough for the seek head of the 5 top level elements below, see `seek_head`.
+const SEEK_HEAD_RESERVED: usize = 160;
+const MAX_CLUSTER_MS: i64 = 5_000;
+const MAX_CLUSTER_BYTES: usize = 8 * 1024 * 1024;
+// Seconds from the unix epoch to 2001-01-01, the origin of `DateUTC`
+const MKV_EPOCH_SECS: u64 = 978_307_200;
+
+fn put_id(buf: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    buf.extend_from_slice(&bytes[skip..]);
+}
+
+fn put_size(buf: &mut Vec<u8>, size: u64) {
+    // All ones are reserved for the unknown size.
+    let len = (1..=8).find(|n| size < (1u64 << (7 * n)) - 1).unwrap_or(8);
+    let marked = size | (1u64 << (7 * len));
+    buf.extend_from_slice(&marked.to_be_bytes()[8 - len..]);
+}
+
+// 8 bytes, so that it can be patched in place.
+fn put_size_fixed(buf: &mut Vec<u8>, size: u64) {
+    buf.push(0x01);
+    buf.extend_from_slice(&size.to_be_bytes()[1..]);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, id: u32, data: &[u8]) {
+    put_id(buf, id);
+    put_size(buf, data.len() as _);
+    buf.extend_from_slice(data);
+}
+
+fn put_uint(buf: &mut Vec<u8>, id: u32, v: u64) {
+    let bytes = v.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(7);
+    put_bytes(buf, id, &bytes[skip..]);
+}
+
+fn put_int(buf: &mut Vec<u8>, id: u32, v: i64) {
+    put_bytes(buf, id, &v.to_be_bytes());
+}
+
+fn put_float(buf: &mut Vec<u8>, id: u32, v: f64) {
+    put_bytes(buf, id, &v.to_be_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, id: u32, s: &str) {
+    put_bytes(buf, id, s.as_bytes());
+}
+
+fn put_master(buf: &mut Vec<u8>, id: u32, f: impl FnOnce(&mut Vec<u8>)) {
+    let mut body = Vec::new();
+    f(&mut body);
+    put_bytes(buf, id, &body);
+}
+
+fn put_void(buf: &mut Vec<u8>, len: usize) {
+    // id, 8 bytes size, zeros
+    debug_assert!(len >= 9);
+    put_id(buf, VOID);
+    put_size_fixed(buf, (len - 9) as _);
+    buf.resize(buf.len() + len - 9, 0);
+}
+
+#[derive(Debug, Clone)]
+pub struct VideoParams {
+    /// e.g. `V_VP9`
+    pub codec_id: &'static str,
+    pub codec_private: Option<Vec<u8>>,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioParams {
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+/// A `SimpleTag`, the children refine it, e.g. the operator of a peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tag {
+    pub name: String,
+    pub value: String,
+    pub children: Vec<Tag>,
+}
+
+impl Tag {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            children: vec![],
+        }
+    }
+
+    fn put(&self, buf: &mut Vec<u8>) {
+        put_master(buf, SIMPLE_TAG, |b| {
+            put_str(b, TAG_NAME, &self.name);
+            put_str(b, TAG_STRING, &self.value);
+            for child in self.children.iter() {
+                child.put(b);
+            }
+        });
+    }
+}
+
+struct Cluster {
+    pos: u64,
+    timestamp: i64,
+    key: bool,
+    blocks: Vec<u8>,
+}
+
+pub struct MkvWriter<W: Write + Seek> {
+    out: W,
+    // Absolute positions
+    segment_size_pos: u64,
+    segment_data_pos: u64,
+    duration_pos: u64,
+    // Relative to the segment data
+    info_pos: u64,
+    tracks_pos: u64,
+    audio: bool,
+    cluster: Option<Cluster>,
+    // The timestamp of the first video frame, the others are relative to it
+    origin: Option<i64>,
+    last_timestamp: i64,
+    cues: Vec<(i64, u64)>,
+    // Absolute timestamps, the origin may not be known yet when they are added
+    chapters: Vec<(Option<i64>, String)>,
+    dropped_audio: usize,
+}
+
+impl<W: Write + Seek> MkvWriter<W> {
+    pub fn new(
+        mut out: W,
+        title: &str,
+        video: &VideoParams,
+        audio: Option<&AudioParams>,
+    ) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        put_master(&mut buf, EBML, |b| {
+            put_uint(b, EBML_VERSION, 1);
+            put_uint(b, EBML_READ_VERSION, 1);
+            put_uint(b, EBML_MAX_ID_LENGTH, 4);
+            put_uint(b, EBML_MAX_SIZE_LENGTH, 8);
+            put_str(b, DOC_TYPE, "matroska");
+            put_uint(b, DOC_TYPE_VERSION, 4);
+            put_uint(b, DOC_TYPE_READ_VERSION, 2);
+        });
+        put_id(&mut buf, SEGMENT);
+        let segment_size_pos = buf.len() as u64;
+        // Unknown until finalized
+        buf.extend_from_slice(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let segment_data_pos = buf.len() as u64;
+        put_void(&mut buf, SEEK_HEAD_RESERVED);
+
+        let info_pos = buf.len() as u64 - segment_data_pos;
+        let mut info = Vec::new();
+        put_uint(&mut info, TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+        let duration_offset = info.len() as u64;
+        put_float(&mut info, DURATION, 0.0);
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i64 - (MKV_EPOCH_SECS * 1_000_000_000) as i64)
+            .unwrap_or_default();
+        put_int(&mut info, DATE_UTC, date);
+        put_str(&mut info, TITLE, title);
+        put_str(&mut info, MUXING_APP, "rustdesk");
+        put_str(&mut info, WRITING_APP, "rustdesk");
+        put_id(&mut buf, INFO);
+        put_size(&mut buf, info.len() as _);
+        // id and size of the duration element
+        let duration_pos = buf.len() as u64 + duration_offset + 3;
+        buf.extend_from_slice(&info);
+
+        let tracks_pos = buf.len() as u64 - segment_data_pos;
+        put_master(&mut buf, TRACKS, |b| {
+            put_master(b, TRACK_ENTRY, |b| {
+                put_uint(b, TRACK_NUMBER, VIDEO_TRACK as _);
+                put_uint(b, TRACK_UID, VIDEO_TRACK as _);
+                put_uint(b, TRACK_TYPE, 1);
+                put_uint(b, FLAG_LACING, 0);
+                put_str(b, CODEC_ID, video.codec_id);
+                if let Some(private) = &video.codec_private {
+                    put_bytes(b, CODEC_PRIVATE, private);
+                }
+                put_master(b, VIDEO, |b| {
+                    put_uint(b, PIXEL_WIDTH, video.width as _);
+                    put_uint(b, PIXEL_HEIGHT, video.height as _);
+                });
+            });
+            if let Some(audio) = audio {
+                put_master(b, TRACK_ENTRY, |b| {
+                    put_uint(b, TRACK_NUMBER, AUDIO_TRACK as _);
+                    put_uint(b, TRACK_UID, AUDIO_TRACK as _);
+                    put_uint(b, TRACK_TYPE, 2);
+                    put_uint(b, FLAG_LACING, 0);
+                    put_str(b, CODEC_ID, "A_OPUS");
+                    put_bytes(b, CODEC_PRIVATE, &opus_head(audio));
+                    put_uint(b, SEEK_PRE_ROLL, 80_000_000);
+                    put_master(b, AUDIO, |b| {
+                        // Opus is always decoded at 48k.
+                        put_float(b, SAMPLING_FREQUENCY, 48000.0);
+                        put_uint(b, CHANNELS, audio.channels as _);
+                    });
+                });
+            }
+        });
+        out.write_all(&buf)?;
+        Ok(Self {
+            out,
+            segment_size_pos,
+            segment_data_pos,
+            duration_pos,
+            info_pos,
+            tracks_pos,
+            audio: audio.is_some(),
+            cluster: None,
+            origin: None,
+            last_timestamp: 0,
+            cues: vec![],
+            chapters: vec![],
+            dropped_audio: 0,
+        })
+    }
+
+    /// The first frame must be a key frame, the timestamps are in ms.
+    pub fn write_video(&mut self, data: &[u8], timestamp: i64, key: bool) -> io::Result<()> {
+        let origin = match self.origin {
+            Some(origin) => origin,
+            None if key => *self.origin.insert(timestamp),
+            None => return Ok(()),
+        };
+        self.write_block(VIDEO_TRACK, data, timestamp - origin, key)
+    }
+
+    /// The audio before the first video frame is dropped.
+    pub fn write_audio(&mut self, data: &[u8], timestamp: i64) -> io::Result<()> {
+        if !self.audio {
+            return Ok(());
+        }
+        let Some(origin) = self.origin else {
+            return Ok(());
+        };
+        self.write_block(AUDIO_TRACK, data, timestamp - origin, true)
+    }
+
+    /// A chapter without a timestamp, or one before the first video frame, starts at 0.
+    pub fn add_chapter(&mut self, timestamp: Option<i64>, title: &str) {
+        self.chapters.push((timestamp, title.to_owned()));
+    }
+
+    fn write_block(&mut self, track: u8, data: &[u8], timestamp: i64, key: bool) -> io::Result<()> {
+        let new_cluster = match &self.cluster {
+            Some(c) => {
+                (track == VIDEO_TRACK && key)
+                    || timestamp - c.timestamp > MAX_CLUSTER_MS
+                    || c.blocks.len() > MAX_CLUSTER_BYTES
+            }
+            None => true,
+        };
+        if new_cluster {
+            self.close_cluster()?;
+            let pos = self.out.stream_position()?;
+            self.cluster = Some(Cluster {
+                pos,
+                timestamp: timestamp.max(0),
+                key: track == VIDEO_TRACK && key,
+                blocks: vec![],
+            });
+        }
+        let Some(cluster) = self.cluster.as_mut() else {
+            return Ok(());
+        };
+        let Ok(relative) = i16::try_from(timestamp - cluster.timestamp) else {
+            // Audio far behind the video
+            self.dropped_audio += 1;
+            if self.dropped_audio == 1 {
+                log::warn!(
+                    "Audio at {} ms dropped, out of the cluster at {} ms",
+                    timestamp,
+                    cluster.timestamp
+                );
+            }
+            return Ok(());
+        };
+        let blocks = &mut cluster.blocks;
+        put_id(blocks, SIMPLE_BLOCK);
+        put_size(blocks, (data.len() + 4) as _);
+        blocks.push(0x80 | track);
+        blocks.extend_from_slice(&relative.to_be_bytes());
+        blocks.push(if key { 0x80 } else { 0 });
+        blocks.extend_from_slice(data);
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+        Ok(())
+    }
+
+    fn close_cluster(&mut self) -> io::Result<()> {
+        let Some(cluster) = self.cluster.take() else {
+            return Ok(());
+        };
+        let mut body = Vec::new();
+        put_uint(&mut body, TIMESTAMP, cluster.timestamp as _);
+        body.extend_from_slice(&cluster.blocks);
+        let mut buf = Vec::new();
+        put_bytes(&mut buf, CLUSTER, &body);
+        self.out.write_all(&buf)?;
+        if cluster.key {
+            self.cues
+                .push((cluster.timestamp, cluster.pos - self.segment_data_pos));
+        }
+        Ok(())
+    }
+
+    pub fn finalize(mut self, tags: &[Tag]) -> io::Result<W> {
+        self.close_cluster()?;
+        if self.dropped_audio > 0 {
+            log::warn!("{} audio blocks dropped", self.dropped_audio);
+        }
+        let origin = self.origin.unwrap_or_default();
+        let mut seeks = vec![(INFO, self.info_pos), (TRACKS, self.tracks_pos)];
+        let mut buf = Vec::new();
+        let end = self.out.stream_position()?;
+        let relative = |buf: &Vec<u8>| end + buf.len() as u64 - self.segment_data_pos;
+        if !self.cues.is_empty() {
+            seeks.push((CUES, relative(&buf)));
+            put_master(&mut buf, CUES, |b| {
+                for (timestamp, pos) in self.cues.iter() {
+                    put_master(b, CUE_POINT, |b| {
+                        put_uint(b, CUE_TIME, (*timestamp).max(0) as _);
+                        put_master(b, CUE_TRACK_POSITIONS, |b| {
+                            put_uint(b, CUE_TRACK, VIDEO_TRACK as _);
+                            put_uint(b, CUE_CLUSTER_POSITION, *pos);
+                        });
+                    });
+                }
+            });
+        }
+        if !self.chapters.is_empty() {
+            seeks.push((CHAPTERS, relative(&buf)));
+            put_master(&mut buf, CHAPTERS, |b| {
+                put_master(b, EDITION_ENTRY, |b| {
+                    for (i, (timestamp, title)) in self.chapters.iter().enumerate() {
+                        let start = timestamp.map_or(0, |t| (t - origin).max(0));
+                        put_master(b, CHAPTER_ATOM, |b| {
+                            put_uint(b, CHAPTER_UID, i as u64 + 1);
+                            put_uint(b, CHAPTER_TIME_START, start as u64 * TIMESTAMP_SCALE_NS);
+                            put_master(b, CHAPTER_DISPLAY, |b| put_str(b, CHAP_STRING, title));
+                        });
+                    }
+                });
+            });
+        }
+        if !tags.is_empty() {
+            seeks.push((TAGS, relative(&buf)));
+            put_master(&mut buf, TAGS, |b| {
+                put_master(b, TAG, |b| {
+                    // The whole recording
+                    put_master(b, TARGETS, |b| put_uint(b, TARGET_TYPE_VALUE, 50));
+                    for tag in tags.iter() {
+                        tag.put(b);
+                    }
+                });
+            });
+        }
+        self.out.write_all(&buf)?;
+        let end = end + buf.len() as u64;
+
+        let mut size = Vec::new();
+        put_size_fixed(&mut size, end - self.segment_data_pos);
+        self.out.seek(SeekFrom::Start(self.segment_size_pos))?;
+        self.out.write_all(&size)?;
+        self.out.seek(SeekFrom::Start(self.duration_pos))?;
+        self.out
+            .write_all(&(self.last_timestamp.max(0) as f64).to_be_bytes())?;
+        self.out.seek(SeekFrom::Start(self.segment_data_pos))?;
+        self.out.write_all(&seek_head(&seeks))?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// The seek head padded with a void element to `SEEK_HEAD_RESERVED`.
+fn seek_head(seeks: &[(u32, u64)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_master(&mut buf, SEEK_HEAD, |b| {
+        for (id, pos) in seeks.iter() {
+            put_master(b, SEEK, |b| {
+                let mut id_bytes = Vec::new();
+                put_id(&mut id_bytes, *id);
+                put_bytes(b, SEEK_ID, &id_bytes);
+                put_uint(b, SEEK_POSITION, *pos);
+            });
+        }
+    });
+    let len = buf.len();
+    put_void(&mut buf, SEEK_HEAD_RESERVED - len);
+    buf
+}
+
+fn opus_head(audio: &AudioParams) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(audio.channels);
+    // pre-skip
+    head.extend_from_slice(&0u16.to_le_bytes());
+    head.extend_from_slice(&audio.sample_rate.to_le_bytes());
+    // gain
+    head.extend_from_slice(&0i16.to_le_bytes());
+    // mapping family, mono or stereo
+    head.push(0);
+    head
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // (id, data) of the elements in `data`
+    fn parse(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut res = vec![];
+        let mut i = 0;
+        let vint = |i: &mut usize, keep_marker: bool| {
+            let len = data[*i].leading_zeros() as usize + 1;
+            let mut v = data[*i] as u64;
+            if !keep_marker {
+                v &= (1u64 << (8 - len)) - 1;
+            }
+            for j in 1..len {
+                v = (v << 8) | data[*i + j] as u64;
+            }
+            *i += len;
+            v
+        };
+        while i < data.len() {
+            let id = vint(&mut i, true) as u32;
+            let size = vint(&mut i, false) as usize;
+            res.push((id, &data[i..i + size]));
+            i += size;
+        }
+        res
+    }
+
+    fn find<'a>(elements: &[(u32, &'a [u8])], id: u32) -> &'a [u8] {
+        elements.iter().find(|(x, _)| *x == id).unwrap().1
+    }
+
+    #[test]
+    fn test_mkv() {
+        let mut size = vec![];
+        put_size(&mut size, 126);
+        put_size(&mut size, 127);
+        assert_eq!(size, vec![0xFE, 0x40, 0x7F]);
+
+        let video = VideoParams {
+            codec_id: "V_VP9",
+            codec_private: None,
+            width: 1920,
+            height: 1080,
+        };
+        let audio = AudioParams {
+            sample_rate: 48000,
+            channels: 2,
+        };
+        let mut mkv = MkvWriter::new(Cursor::new(vec![]), "title", &video, Some(&audio)).unwrap();
+        mkv.write_audio(&[9], 990).unwrap();
+        mkv.add_chapter(None, "Display 0");
+        // before the first frame, it is placed once the origin is known
+        mkv.add_chapter(Some(1200), "Peer 1");
+        mkv.write_video(&[1, 2], 1000, true).unwrap();
+        mkv.write_audio(&[3], 1010).unwrap();
+        mkv.write_video(&[4], 1040, false).unwrap();
+        mkv.add_chapter(Some(1500), "Display 1");
+        mkv.write_video(&[5], 2000, true).unwrap();
+        // too far behind the cluster of the video
+        mkv.write_audio(&[6], 1000 - 40_000).unwrap();
+        assert_eq!(mkv.dropped_audio, 1);
+        let tags = vec![Tag {
+            children: vec![Tag::new("OPERATOR", "name")],
+            ..Tag::new("PEER_ID", "123")
+        }];
+        let data = mkv.finalize(&tags).unwrap().into_inner();
+
+        let top = parse(&data);
+        assert_eq!(top.len(), 2);
+        assert_eq!(find(&parse(find(&top, EBML)), DOC_TYPE), b"matroska");
+        let segment = parse(find(&top, SEGMENT));
+        let ids: Vec<_> = segment.iter().map(|(id, _)| *id).collect();
+        assert_eq!(
+            ids,
+            vec![SEEK_HEAD, VOID, INFO, TRACKS, CLUSTER, CLUSTER, CUES, CHAPTERS, TAGS]
+        );
+        let info = parse(find(&segment, INFO));
+        assert_eq!(find(&info, DURATION), 1000f64.to_be_bytes());
+        let tracks = parse(find(&segment, TRACKS));
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(
+            &find(&parse(tracks[1].1), CODEC_PRIVATE)[..9],
+            b"OpusHead\x01"
+        );
+
+        // The audio before the first video frame is dropped.
+        let clusters: Vec<_> = segment
+            .iter()
+            .filter(|(id, _)| *id == CLUSTER)
+            .map(|(_, data)| parse(data))
+            .collect();
+        assert_eq!(find(&clusters[0], TIMESTAMP), [0]);
+        let blocks: Vec<_> = clusters[0]
+            .iter()
+            .filter(|(id, _)| *id == SIMPLE_BLOCK)
+            .map(|(_, data)| data.to_vec())
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                vec![0x81, 0, 0, 0x80, 1, 2],
+                vec![0x82, 0, 10, 0x80, 3],
+                vec![0x81, 0, 40, 0, 4]
+            ]
+        );
+        assert_eq!(find(&clusters[1], TIMESTAMP), 1000u16.to_be_bytes());
+
+        // The seek positions are relative to the segment data.
+        let segment_data = find(&top, SEGMENT);
+        for (_, seek) in parse(find(&segment, SEEK_HEAD)) {
+            let seek = parse(seek);
+            let pos = find(&seek, SEEK_POSITION)
+                .iter()
+                .fold(0usize, |v, b| (v << 8) | *b as usize);
+            assert_eq!(&segment_data[pos..pos + 4], find(&seek, SEEK_ID));
+        }
+        let cues = parse(find(&segment, CUES));
+        assert_eq!(cues.len(), 2);
+
+        let chapters = parse(find(&parse(find(&segment, CHAPTERS)), EDITION_ENTRY));
+        assert_eq!(find(&parse(chapters[0].1), CHAPTER_TIME_START), [0]);
+        assert_eq!(
+            find(&parse(chapters[1].1), CHAPTER_TIME_START),
+            200_000_000u32.to_be_bytes()
+        );
+        let third = parse(chapters[2].1);
+        assert_eq!(
+            find(&third, CHAPTER_TIME_START),
+            500_000_000u32.to_be_bytes()
+        );
+        assert_eq!(
+            find(&parse(find(&third, CHAPTER_DISPLAY)), CHAP_STRING),
+            b"Display 1"
+        );
+        let tag = parse(find(&parse(find(&segment, TAGS)), TAG));
+        let peer = parse(find(&tag, SIMPLE_TAG));
+        assert_eq!(find(&peer, TAG_STRING), b"123");
+        assert_eq!(find(&parse(find(&peer, SIMPLE_TAG)), TAG_NAME), b"OPERATOR");
+    }
+}
@@ -37,6 +37,7 @@ cfg_if! {
 
 pub mod codec;
 pub mod convert;
+pub mod damage;
 #[cfg(feature = "hwcodec")]
 pub mod hwcodec;
 #[cfg(feature = "mediacodec")]
@@ -51,6 +52,7 @@ pub const HW_STRIDE_ALIGN: usize = 0; // recommended by av_frame_get_buffer
 pub mod aom;
 #[cfg(not(any(target_os = "ios")))]
 pub mod camera;
+pub mod mkv;
 pub mod record;
 mod vpx;
 
@@ -170,6 +172,11 @@ pub trait TraitPixelBuffer {
     fn stride(&self) -> Vec<usize>;
 
     fn pixfmt(&self) -> Pixfmt;
+
+    /// The regions changed since the previous frame, `None` if unknown.
+    fn damage(&self) -> Option<&[damage::DamageRect]> {
+        None
+    }
 }
 
 #[cfg(not(any(target_os = "ios")))]
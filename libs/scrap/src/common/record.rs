@@ -1,4 +1,7 @@
-use crate::CodecFormat;
+use crate::{
+    mkv::{self, MkvWriter, Tag},
+    CodecFormat,
+};
 #[cfg(feature = "hwcodec")]
 use hbb_common::anyhow::anyhow;
 use hbb_common::{
@@ -19,6 +22,21 @@ use std::{
 use webm::mux::{self, Segment, Track, VideoTrack, Writer};
 
 const MIN_SECS: u64 = 1;
+// [129, 8, 12, 0] in 3.6.0, but zero works
+const AV1_CODEC_PRIVATE: [u8; 4] = [0, 0, 0, 0];
+
+/// Record the VP8/VP9/AV1 into mkv with the audio, the chapters and the metadata, instead of webm.
+/// Off unless "Y", unlike the other options without the `allow-` prefix.
+pub const OPTION_RECORD_AUDIO: &str = "record-audio";
+
+/// Written into the metadata of the mkv recordings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordPeer {
+    pub id: String,
+    /// The operator
+    pub name: String,
+    pub permissions: Vec<String>,
+}
 
 #[derive(Debug, Clone)]
 pub struct RecorderContext {
@@ -27,6 +45,7 @@ pub struct RecorderContext {
     pub dir: String,
     pub display_idx: usize,
     pub camera: bool,
+    pub audio: bool,
     pub tx: Option<Sender<RecordState>>,
 }
 
@@ -57,7 +76,11 @@ impl RecorderContext2 {
                 || self.format == CodecFormat::VP8
                 || self.format == CodecFormat::AV1
             {
-                ".webm"
+                if ctx.audio {
+                    ".mkv"
+                } else {
+                    ".webm"
+                }
             } else {
                 ".mp4"
             };
@@ -77,6 +100,12 @@ pub trait RecorderApi {
     where
         Self: Sized;
     fn write_video(&mut self, frame: &EncodedVideoFrame) -> bool;
+    /// An Opus packet, placed on the video timeline by the time since the last frame.
+    fn write_audio(&mut self, _data: &[u8]) -> bool {
+        false
+    }
+    fn add_chapter(&mut self, _title: &str) {}
+    fn set_peers(&mut self, _peers: &[RecordPeer]) {}
 }
 
 #[derive(Debug)]
@@ -93,6 +122,7 @@ pub struct Recorder {
     ctx2: Option<RecorderContext2>,
     pts: Option<i64>,
     check_failed: bool,
+    peers: Vec<RecordPeer>,
 }
 
 impl Deref for Recorder {
@@ -117,6 +147,7 @@ impl Recorder {
             ctx2: None,
             pts: None,
             check_failed: false,
+            peers: vec![],
         })
     }
 
@@ -152,6 +183,9 @@ impl Recorder {
         };
         if self.inner.is_none() {
             self.inner = match format {
+                CodecFormat::VP8 | CodecFormat::VP9 | CodecFormat::AV1 if self.ctx.audio => Some(
+                    Box::new(MkvRecorder::new(self.ctx.clone(), (*ctx2).clone())?),
+                ),
                 CodecFormat::VP8 | CodecFormat::VP9 | CodecFormat::AV1 => Some(Box::new(
                     WebmRecorder::new(self.ctx.clone(), (*ctx2).clone())?,
                 )),
@@ -165,6 +199,11 @@ impl Recorder {
             };
             // pts is None when new inner is created
             self.pts = None;
+            if let Some(inner) = self.inner.as_mut() {
+                inner.set_peers(&self.peers);
+                let source = if self.ctx.camera { "Camera" } else { "Display" };
+                inner.add_chapter(&format!("{} {}", source, self.ctx.display_idx));
+            }
             self.send_state(RecordState::NewFile(ctx2.filename.clone()));
         }
         Ok(())
@@ -265,6 +304,34 @@ impl Recorder {
         Ok(())
     }
 
+    pub fn write_audio(&mut self, data: &[u8]) {
+        self.as_mut().map(|x| x.write_audio(data));
+    }
+
+    pub fn add_chapter(&mut self, title: &str) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.add_chapter(title);
+        }
+    }
+
+    /// The permissions granted at any time of the recording are kept.
+    pub fn add_peer(&mut self, peer: RecordPeer) {
+        match self.peers.iter_mut().find(|p| p.id == peer.id) {
+            Some(p) => {
+                p.name = peer.name;
+                for permission in peer.permissions {
+                    if !p.permissions.contains(&permission) {
+                        p.permissions.push(permission);
+                    }
+                }
+            }
+            None => self.peers.push(peer),
+        }
+        if let Some(inner) = self.inner.as_mut() {
+            inner.set_peers(&self.peers);
+        }
+    }
+
     fn send_state(&self, state: RecordState) {
         self.ctx.tx.as_ref().map(|tx| tx.send(state));
     }
@@ -280,18 +347,22 @@ struct WebmRecorder {
     start: Instant,
 }
 
+fn create_file(filename: &str) -> ResultType<File> {
+    match {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(filename)
+    } {
+        Ok(file) => Ok(file),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(File::create(filename)?),
+        Err(e) => Err(e.into()),
+    }
+}
+
 impl RecorderApi for WebmRecorder {
     fn new(ctx: RecorderContext, ctx2: RecorderContext2) -> ResultType<Self> {
-        let out = match {
-            OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&ctx2.filename)
-        } {
-            Ok(file) => file,
-            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => File::create(&ctx2.filename)?,
-            Err(e) => return Err(e.into()),
-        };
+        let out = create_file(&ctx2.filename)?;
         let mut webm = match mux::Segment::new(mux::Writer::new(out)) {
             Some(v) => v,
             None => bail!("Failed to create webm mux"),
@@ -309,9 +380,7 @@ impl RecorderApi for WebmRecorder {
             },
         );
         if ctx2.format == CodecFormat::AV1 {
-            // [129, 8, 12, 0] in 3.6.0, but zero works
-            let codec_private = vec![0, 0, 0, 0];
-            if !webm.set_codec_private(vt.track_number(), &codec_private) {
+            if !webm.set_codec_private(vt.track_number(), &AV1_CODEC_PRIVATE) {
                 bail!("Failed to set codec private");
             }
         }
@@ -356,6 +425,158 @@ impl Drop for WebmRecorder {
     }
 }
 
+struct MkvRecorder {
+    mkv: Option<MkvWriter<File>>,
+    ctx: RecorderContext,
+    ctx2: RecorderContext2,
+    key: bool,
+    written: bool,
+    start: Instant,
+    start_time: chrono::DateTime<chrono::Local>,
+    // The pts and the time of the last written frame, to place the audio and the chapters
+    // on the clock of the video pts
+    last_frame: Option<(i64, Instant)>,
+    last_audio: i64,
+    peers: Vec<RecordPeer>,
+}
+
+impl MkvRecorder {
+    fn timestamp(&self) -> Option<i64> {
+        self.last_frame
+            .map(|(pts, time)| pts + time.elapsed().as_millis() as i64)
+    }
+
+    fn tags(&self) -> Vec<Tag> {
+        let mut tags = vec![
+            Tag::new("ID", &self.ctx.id),
+            Tag::new(
+                "DIRECTION",
+                if self.ctx.server {
+                    "incoming"
+                } else {
+                    "outgoing"
+                },
+            ),
+            Tag::new("START_TIME", &self.start_time.to_rfc3339()),
+            Tag::new("END_TIME", &chrono::Local::now().to_rfc3339()),
+        ];
+        for peer in self.peers.iter() {
+            tags.push(Tag {
+                children: vec![
+                    Tag::new("OPERATOR", &peer.name),
+                    Tag::new("PERMISSIONS", &peer.permissions.join(",")),
+                ],
+                ..Tag::new("PEER_ID", &peer.id)
+            });
+        }
+        tags
+    }
+}
+
+impl RecorderApi for MkvRecorder {
+    fn new(ctx: RecorderContext, ctx2: RecorderContext2) -> ResultType<Self> {
+        let out = create_file(&ctx2.filename)?;
+        let video = mkv::VideoParams {
+            codec_id: match ctx2.format {
+                CodecFormat::VP8 => "V_VP8",
+                CodecFormat::VP9 => "V_VP9",
+                _ => "V_AV1",
+            },
+            codec_private: if ctx2.format == CodecFormat::AV1 {
+                Some(AV1_CODEC_PRIVATE.to_vec())
+            } else {
+                None
+            },
+            width: ctx2.width,
+            height: ctx2.height,
+        };
+        // The audio format may change during the recording, and an Opus decoder of 2 channels
+        // decodes the mono packets too.
+        let audio = mkv::AudioParams {
+            sample_rate: 48000,
+            channels: 2,
+        };
+        let title = format!(
+            "{} {}",
+            if ctx.server { "incoming" } else { "outgoing" },
+            ctx.id
+        );
+        let mkv = MkvWriter::new(out, &title, &video, Some(&audio))?;
+        Ok(MkvRecorder {
+            mkv: Some(mkv),
+            ctx,
+            ctx2,
+            key: false,
+            written: false,
+            start: Instant::now(),
+            start_time: chrono::Local::now(),
+            last_frame: None,
+            last_audio: i64::MIN,
+            peers: vec![],
+        })
+    }
+
+    fn write_video(&mut self, frame: &EncodedVideoFrame) -> bool {
+        if frame.key {
+            self.key = true;
+        }
+        if !self.key {
+            return false;
+        }
+        let ok = self
+            .mkv
+            .as_mut()
+            .map(|m| m.write_video(&frame.data, frame.pts, frame.key).is_ok())
+            .unwrap_or_default();
+        if ok {
+            self.written = true;
+            self.last_frame = Some((frame.pts, Instant::now()));
+        }
+        ok
+    }
+
+    fn write_audio(&mut self, data: &[u8]) -> bool {
+        let Some(timestamp) = self.timestamp() else {
+            return false;
+        };
+        // A frame late on its pts does not take the audio back.
+        let timestamp = timestamp.max(self.last_audio);
+        self.last_audio = timestamp;
+        self.mkv
+            .as_mut()
+            .map(|m| m.write_audio(data, timestamp).is_ok())
+            .unwrap_or_default()
+    }
+
+    fn add_chapter(&mut self, title: &str) {
+        let timestamp = self.timestamp();
+        if let Some(mkv) = self.mkv.as_mut() {
+            mkv.add_chapter(timestamp, title);
+        }
+    }
+
+    fn set_peers(&mut self, peers: &[RecordPeer]) {
+        self.peers = peers.to_vec();
+    }
+}
+
+impl Drop for MkvRecorder {
+    fn drop(&mut self) {
+        let tags = self.tags();
+        if let Some(mkv) = self.mkv.take() {
+            if let Err(e) = mkv.finalize(&tags) {
+                log::error!("Failed to finalize mkv: {}", e);
+            }
+        }
+        let mut state = RecordState::WriteTail;
+        if !self.written || self.start.elapsed().as_secs() < MIN_SECS {
+            std::fs::remove_file(&self.ctx2.filename).ok();
+            state = RecordState::RemoveFile;
+        }
+        self.ctx.tx.as_ref().map(|tx| tx.send(state));
+    }
+}
+
 #[cfg(feature = "hwcodec")]
 struct HwRecorder {
     muxer: Option<Muxer>,
@@ -17,6 +17,12 @@ impl Default for vpx_codec_ctx {
     }
 }
 
+impl Default for vpx_roi_map {
+    fn default() -> Self {
+        unsafe { std::mem::zeroed() }
+    }
+}
+
 impl Default for vpx_image_t {
     fn default() -> Self {
         unsafe { std::mem::zeroed() }
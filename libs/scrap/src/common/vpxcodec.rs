@@ -9,6 +9,7 @@ use hbb_common::message_proto::{Chroma, EncodedVideoFrame, EncodedVideoFrames, V
 use hbb_common::ResultType;
 
 use crate::codec::{base_bitrate, codec_thread_num, EncoderApi};
+use crate::damage::{self, DamageRect};
 use crate::{EncodeInput, EncodeYuvFormat, GoogleImage, Pixfmt, STRIDE_ALIGN};
 
 use super::vpx::{vp8e_enc_control_id::*, vpx_codec_err_t::*, *};
@@ -20,6 +21,11 @@ use std::{ptr, slice};
 generate_call_macro!(call_vpx, false);
 generate_call_ptr_macro!(call_vpx_ptr);
 
+// The quantizer delta of the changed regions, negative for a higher quality.
+const ROI_DELTA_Q: c_int = -12;
+// Beyond it the whole frame is changed more or less, no regions are worth a higher quality.
+const ROI_MAX_COVERAGE: f32 = 0.5;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum VpxVideoCodecId {
     VP8,
@@ -39,6 +45,9 @@ pub struct VpxEncoder {
     id: VpxVideoCodecId,
     i444: bool,
     yuvfmt: EncodeYuvFormat,
+    // The segment of each block, empty if no ROI map is set
+    roi_map: Vec<u8>,
+    roi_supported: bool,
 }
 
 pub struct VpxDecoder {
@@ -165,6 +174,8 @@ impl EncoderApi for VpxEncoder {
                     id: config.codec,
                     i444,
                     yuvfmt: Self::get_yuvfmt(config.width, config.height, i444),
+                    roi_map: Vec::new(),
+                    roi_supported: true,
                 })
             }
             _ => Err(anyhow!("encoder type mismatch")),
@@ -210,6 +221,33 @@ impl EncoderApi for VpxEncoder {
         Ok(())
     }
 
+    // The changed regions are encoded with a higher quality by a ROI map.
+    fn set_damage(&mut self, damage: Option<&[DamageRect]>) {
+        if !self.roi_supported {
+            return;
+        }
+        let damage =
+            damage.filter(|d| damage::coverage(d, self.width, self.height) < ROI_MAX_COVERAGE);
+        let Some(damage) = damage else {
+            if !self.roi_map.is_empty() {
+                self.roi_map.clear();
+                self.set_roi_map();
+            }
+            return;
+        };
+        let (block, rows, cols) = self.roi_grid();
+        self.roi_map.clear();
+        self.roi_map.resize(rows * cols, 0);
+        for r in damage {
+            for y in (r.y / block)..((r.y + r.h + block - 1) / block).min(rows) {
+                for x in (r.x / block)..((r.x + r.w + block - 1) / block).min(cols) {
+                    self.roi_map[y * cols + x] = 1;
+                }
+            }
+        }
+        self.set_roi_map();
+    }
+
     fn bitrate(&self) -> u32 {
         let c = unsafe { *self.ctx.config.enc.to_owned() };
         c.rc_target_bitrate
@@ -231,6 +269,43 @@ impl EncoderApi for VpxEncoder {
 }
 
 impl VpxEncoder {
+    // The block size, rows and columns of the ROI map, which are 16x16 macroblocks in VP8
+    // and 8x8 blocks in VP9.
+    fn roi_grid(&self) -> (usize, usize, usize) {
+        let block = match self.id {
+            VpxVideoCodecId::VP8 => 16,
+            VpxVideoCodecId::VP9 => 8,
+        };
+        let rows = (self.height + block - 1) / block;
+        let cols = (self.width + block - 1) / block;
+        (block, rows, cols)
+    }
+
+    // Segment 1 is the changed regions, an empty map clears the ROI.
+    fn set_roi_map(&mut self) {
+        let (_, rows, cols) = self.roi_grid();
+        let mut roi = vpx_roi_map::default();
+        roi.rows = rows as _;
+        roi.cols = cols as _;
+        // No reference frame is forced on any segment.
+        roi.ref_frame = [-1; 8];
+        if !self.roi_map.is_empty() {
+            roi.roi_map = self.roi_map.as_mut_ptr();
+            roi.delta_q[1] = ROI_DELTA_Q;
+        }
+        let id = match self.id {
+            VpxVideoCodecId::VP8 => VP8E_SET_ROI_MAP,
+            VpxVideoCodecId::VP9 => VP9E_SET_ROI_MAP,
+        };
+        let ret = unsafe { vpx_codec_control_(&mut self.ctx, id as _, &mut roi as *mut _) };
+        if ret != VPX_CODEC_OK {
+            // e.g. VP8 rejects it with the cyclic refresh of the error resilient mode
+            log::info!("ROI map is not supported by {:?}: {:?}", self.id, ret);
+            self.roi_supported = false;
+            self.roi_map.clear();
+        }
+    }
+
     pub fn encode<'a>(&'a mut self, pts: i64, data: &[u8], stride_align: usize) -> Result<EncodeFrames<'a>> {
         let bpp = if self.i444 { 24 } else { 12 };
         if data.len() < self.width * self.height * bpp / 8 {
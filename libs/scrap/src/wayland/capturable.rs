@@ -1,3 +1,4 @@
+use crate::damage::DamageRect;
 use std::boxed::Box;
 use std::error::Error;
 
@@ -25,6 +26,14 @@ impl<'a> PixelProvider<'a> {
 
 pub trait Recorder {
     fn capture(&mut self, timeout_ms: u64) -> Result<PixelProvider, Box<dyn Error>>;
+
+    /// The frame and the regions changed since the previous one, `None` if unknown.
+    fn capture_with_damage(
+        &mut self,
+        timeout_ms: u64,
+    ) -> Result<(PixelProvider, Option<Vec<DamageRect>>), Box<dyn Error>> {
+        self.capture(timeout_ms).map(|p| (p, None))
+    }
 }
 
 pub trait BoxCloneCapturable {
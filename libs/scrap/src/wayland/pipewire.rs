@@ -6,7 +6,7 @@ use std::sync::{
     atomic::{AtomicBool, AtomicU8, Ordering},
     Arc, Mutex,
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{debug, error, trace, warn};
 
 use dbus::{
@@ -31,6 +31,7 @@ use super::display::{clear_wayland_displays_cache, get_displays, Displays};
 use super::remote_desktop_portal::OrgFreedesktopPortalRemoteDesktop as remote_desktop_portal;
 use super::request_portal::OrgFreedesktopPortalRequestResponse;
 use super::screencast_portal::OrgFreedesktopPortalScreenCast as screencast_portal;
+use crate::damage::{self, DamageRect, MAX_DAMAGE_RECTS};
 
 lazy_static! {
     pub static ref RDP_SESSION_INFO: Mutex<Option<RdpSessionInfo>> = Mutex::new(None);
@@ -261,8 +262,13 @@ pub struct PipeWireRecorder {
     width: usize,
     height: usize,
     saved_raw_data: Vec<u8>, // for faster compare and copy
+    last_full_check: Instant,
 }
 
+// The full frames are still compared in this interval, for the damage of the buffers dropped by
+// the appsink.
+const FULL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 impl PipeWireRecorder {
     pub fn new(capturable: PipeWireCapturable) -> ResultType<Self> {
         let pipeline = gst::Pipeline::new(None);
@@ -341,12 +347,21 @@ impl PipeWireRecorder {
             buffer_cropped: vec![],
             is_cropped: false,
             saved_raw_data: Vec::new(),
+            last_full_check: Instant::now(),
         })
     }
 }
 
 impl Recorder for PipeWireRecorder {
     fn capture(&mut self, timeout_ms: u64) -> Result<PixelProvider, Box<dyn Error>> {
+        self.capture_with_damage(timeout_ms).map(|(p, _)| p)
+    }
+
+    fn capture_with_damage(
+        &mut self,
+        timeout_ms: u64,
+    ) -> Result<(PixelProvider, Option<Vec<DamageRect>>), Box<dyn Error>> {
+        let mut frame_damage = None;
         if let Some(sample) = self
             .appsink
             .try_pull_sample(gst::ClockTime::from_mseconds(timeout_ms))
@@ -375,11 +390,60 @@ impl Recorder for PipeWireRecorder {
             if Some((0, 0, w as u32, h as u32)) == crop {
                 crop = None;
             }
+            // pipewiresrc attaches the damage of the buffer as the region of interest metas.
+            let rects = if buf
+                .iter_meta::<gstreamer_video::VideoRegionOfInterestMeta>()
+                .next()
+                .is_some()
+            {
+                let rects = buf
+                    .iter_meta::<gstreamer_video::VideoRegionOfInterestMeta>()
+                    .filter_map(|m| {
+                        let (x, y, rw, rh) = m.get_rect();
+                        DamageRect::from_i32(x as _, y as _, rw as _, rh as _, (0, 0), (w, h))
+                    })
+                    .collect();
+                Some(damage::merge(rects, MAX_DAMAGE_RECTS))
+            } else {
+                None
+            };
             let buf = buf
                 .into_mapped_buffer_readable()
                 .map_err(|_| GStreamerError("Failed to map buffer.".into()))?;
-            if let Err(..) = crate::would_block_if_equal(&mut self.saved_raw_data, buf.as_slice()) {
-                return Ok(PixelProvider::NONE);
+            let full_check = self.saved_raw_data.len() != buf.get_size()
+                || self.last_full_check.elapsed() >= FULL_CHECK_INTERVAL;
+            match rects {
+                Some(rects) if !full_check => {
+                    let changed =
+                        damage::refine(&mut self.saved_raw_data, buf.as_slice(), w * 4, 4, &rects);
+                    // Relative to the cropped area
+                    let (x0, y0, cw, ch) = crop.unwrap_or((0, 0, w as _, h as _));
+                    let changed: Vec<_> = changed
+                        .iter()
+                        .filter_map(|r| {
+                            DamageRect::from_i32(
+                                r.x as _,
+                                r.y as _,
+                                r.w as _,
+                                r.h as _,
+                                (x0 as _, y0 as _),
+                                (cw as _, ch as _),
+                            )
+                        })
+                        .collect();
+                    if changed.is_empty() {
+                        return Ok((PixelProvider::NONE, None));
+                    }
+                    frame_damage = Some(changed);
+                }
+                _ => {
+                    self.last_full_check = Instant::now();
+                    if let Err(..) =
+                        crate::would_block_if_equal(&mut self.saved_raw_data, buf.as_slice())
+                    {
+                        return Ok((PixelProvider::NONE, None));
+                    }
+                }
             }
             let buf_size = buf.get_size();
             // BGRx is 4 bytes per pixel
@@ -420,7 +484,7 @@ impl Recorder for PipeWireRecorder {
                 self.buffer = Some(buf);
             }
         } else {
-            return Ok(PixelProvider::NONE);
+            return Ok((PixelProvider::NONE, None));
         }
         if self.buffer.is_none() {
             return Err(Box::new(GStreamerError("No buffer available!".into())));
@@ -434,8 +498,8 @@ impl Recorder for PipeWireRecorder {
                 .as_slice()
         };
         match self.pix_fmt.as_str() {
-            "BGRx" => Ok(PixelProvider::BGR0(self.width, self.height, buf)),
-            "RGBx" => Ok(PixelProvider::RGB0(self.width, self.height, buf)),
+            "BGRx" => Ok((PixelProvider::BGR0(self.width, self.height, buf), frame_damage)),
+            "RGBx" => Ok((PixelProvider::RGB0(self.width, self.height, buf), frame_damage)),
             _ => Err(Box::new(GStreamerError(format!(
                 "Unreachable! Unknown pix_fmt, {}",
                 &self.pix_fmt
@@ -1,7 +1,15 @@
+use super::damage::XDamage;
 use super::ffi::*;
 use super::Display;
+use crate::damage::{self, DamageRect, MAX_DAMAGE_RECTS};
 use hbb_common::libc;
-use std::{io, ptr, slice};
+use std::{
+    io, ptr, slice,
+    time::{Duration, Instant},
+};
+
+// The full frames are still compared in this interval, in case of any damage missed.
+const FULL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Capturer {
     display: Display,
@@ -11,6 +19,8 @@ pub struct Capturer {
 
     size: usize,
     saved_raw_data: Vec<u8>, // for faster compare and copy
+    damage: Option<XDamage>,
+    last_full_check: Instant,
 }
 
 impl Capturer {
@@ -57,6 +67,7 @@ impl Capturer {
             );
         }
 
+        let damage = XDamage::new(server, display.root());
         let c = Capturer {
             display,
             shmid,
@@ -64,6 +75,8 @@ impl Capturer {
             buffer,
             size,
             saved_raw_data: Vec::new(),
+            damage,
+            last_full_check: Instant::now(),
         };
         Ok(c)
     }
@@ -94,15 +107,72 @@ impl Capturer {
     }
 
     pub fn frame<'b>(&'b mut self) -> std::io::Result<&'b [u8]> {
+        self.frame_with_damage().map(|(data, _)| data)
+    }
+
+    /// The frame and the regions changed since the previous one, `None` if unknown.
+    pub fn frame_with_damage<'b>(
+        &'b mut self,
+    ) -> std::io::Result<(&'b [u8], Option<Vec<DamageRect>>)> {
+        // Taken before getting the image, so that nothing is missed in between.
+        let rects = self.damage.as_mut().and_then(|d| d.take());
+        let full_check = self.saved_raw_data.len() != self.size
+            || self.last_full_check.elapsed() >= FULL_CHECK_INTERVAL;
+        let rects = match rects {
+            Some(rects) if !full_check => {
+                let r = self.display.rect();
+                let rects = rects
+                    .iter()
+                    .filter_map(|x| {
+                        DamageRect::from_i32(
+                            x.x as _,
+                            x.y as _,
+                            x.width as _,
+                            x.height as _,
+                            (r.x as _, r.y as _),
+                            (r.w as _, r.h as _),
+                        )
+                    })
+                    .collect();
+                let rects = damage::merge(rects, MAX_DAMAGE_RECTS);
+                if rects.is_empty() {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Some(rects)
+            }
+            _ => None,
+        };
         self.get_image();
         let result = unsafe { slice::from_raw_parts(self.buffer, self.size) };
-        crate::would_block_if_equal(&mut self.saved_raw_data, result)?;
-        Ok(result)
+        match rects {
+            Some(rects) => {
+                let bytes_per_pixel = self.display.pixfmt().bytes_per_pixel();
+                let stride = self.display.rect().w as usize * bytes_per_pixel;
+                let changed = damage::refine(
+                    &mut self.saved_raw_data,
+                    result,
+                    stride,
+                    bytes_per_pixel,
+                    &rects,
+                );
+                if changed.is_empty() {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Ok((result, Some(changed)))
+            }
+            None => {
+                self.last_full_check = Instant::now();
+                crate::would_block_if_equal(&mut self.saved_raw_data, result)?;
+                Ok((result, None))
+            }
+        }
     }
 }
 
 impl Drop for Capturer {
     fn drop(&mut self) {
+        // Before the connection is closed with the display.
+        self.damage.take();
         unsafe {
             // Detach segment from XCB.
             xcb_shm_detach(self.display.server().raw(), self.xcbid);
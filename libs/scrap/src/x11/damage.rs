@@ -0,0 +1,193 @@
+// The regions damaged on the root window, by the XDamage extension.
+//
+// The damage is accumulated into an XFixes region and taken once per frame. libxcb-damage and
+// libxcb-xfixes are loaded at runtime, the capture falls back to comparing the full frames
+// without them.
+use super::ffi::*;
+use hbb_common::{dlopen::symbor::Library, lazy_static, libc, log};
+use std::{ptr, slice};
+
+// XCB_DAMAGE_REPORT_LEVEL_NON_EMPTY, one event until the damage is subtracted
+const REPORT_LEVEL_NON_EMPTY: u8 = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cookie {
+    sequence: u32,
+}
+
+type QueryVersion = unsafe extern "C" fn(*mut xcb_connection_t, u32, u32) -> Cookie;
+type Reply = unsafe extern "C" fn(
+    *mut xcb_connection_t,
+    Cookie,
+    *mut *mut xcb_generic_error_t,
+) -> *mut libc::c_void;
+type DamageCreate = unsafe extern "C" fn(*mut xcb_connection_t, u32, u32, u8) -> Cookie;
+type DamageSubtract = unsafe extern "C" fn(*mut xcb_connection_t, u32, u32, u32) -> Cookie;
+type CreateRegion =
+    unsafe extern "C" fn(*mut xcb_connection_t, u32, u32, *const xcb_rectangle_t) -> Cookie;
+type Destroy = unsafe extern "C" fn(*mut xcb_connection_t, u32) -> Cookie;
+type FetchRegion = unsafe extern "C" fn(*mut xcb_connection_t, u32) -> Cookie;
+type Rectangles = unsafe extern "C" fn(*const libc::c_void) -> *mut xcb_rectangle_t;
+type RectanglesLength = unsafe extern "C" fn(*const libc::c_void) -> libc::c_int;
+
+struct Lib {
+    _damage: Library,
+    _xfixes: Library,
+    damage_query_version: QueryVersion,
+    damage_query_version_reply: Reply,
+    damage_create: DamageCreate,
+    damage_subtract: DamageSubtract,
+    damage_destroy: Destroy,
+    xfixes_query_version: QueryVersion,
+    xfixes_query_version_reply: Reply,
+    xfixes_create_region: CreateRegion,
+    xfixes_destroy_region: Destroy,
+    xfixes_fetch_region: FetchRegion,
+    xfixes_fetch_region_reply: Reply,
+    xfixes_fetch_region_rectangles: Rectangles,
+    xfixes_fetch_region_rectangles_length: RectanglesLength,
+}
+
+macro_rules! symbol {
+    ($lib:expr, $name:literal, $tp:ty) => {
+        match unsafe { $lib.symbol::<$tp>($name) } {
+            Ok(f) => *f,
+            Err(e) => {
+                log::warn!("Failed to load func {}, {}", $name, e);
+                return None;
+            }
+        }
+    };
+}
+
+impl Lib {
+    fn load() -> Option<Self> {
+        let open = |name: &str| match Library::open(name) {
+            Ok(lib) => Some(lib),
+            Err(e) => {
+                log::info!("Failed to load library {}, {}", name, e);
+                None
+            }
+        };
+        let damage = open("libxcb-damage.so.0")?;
+        let xfixes = open("libxcb-xfixes.so.0")?;
+        Some(Self {
+            damage_query_version: symbol!(damage, "xcb_damage_query_version", QueryVersion),
+            damage_query_version_reply: symbol!(damage, "xcb_damage_query_version_reply", Reply),
+            damage_create: symbol!(damage, "xcb_damage_create", DamageCreate),
+            damage_subtract: symbol!(damage, "xcb_damage_subtract", DamageSubtract),
+            damage_destroy: symbol!(damage, "xcb_damage_destroy", Destroy),
+            xfixes_query_version: symbol!(xfixes, "xcb_xfixes_query_version", QueryVersion),
+            xfixes_query_version_reply: symbol!(xfixes, "xcb_xfixes_query_version_reply", Reply),
+            xfixes_create_region: symbol!(xfixes, "xcb_xfixes_create_region", CreateRegion),
+            xfixes_destroy_region: symbol!(xfixes, "xcb_xfixes_destroy_region", Destroy),
+            xfixes_fetch_region: symbol!(xfixes, "xcb_xfixes_fetch_region", FetchRegion),
+            xfixes_fetch_region_reply: symbol!(xfixes, "xcb_xfixes_fetch_region_reply", Reply),
+            xfixes_fetch_region_rectangles: symbol!(
+                xfixes,
+                "xcb_xfixes_fetch_region_rectangles",
+                Rectangles
+            ),
+            xfixes_fetch_region_rectangles_length: symbol!(
+                xfixes,
+                "xcb_xfixes_fetch_region_rectangles_length",
+                RectanglesLength
+            ),
+            _damage: damage,
+            _xfixes: xfixes,
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref LIB: Option<Lib> = Lib::load();
+}
+
+pub struct XDamage {
+    lib: &'static Lib,
+    conn: *mut xcb_connection_t,
+    damage: u32,
+    region: u32,
+}
+
+impl XDamage {
+    pub fn new(conn: *mut xcb_connection_t, root: xcb_window_t) -> Option<Self> {
+        let lib = LIB.as_ref()?;
+        unsafe {
+            // The versions must be negotiated before using the extensions.
+            let reply = (lib.damage_query_version_reply)(
+                conn,
+                (lib.damage_query_version)(conn, 1, 1),
+                ptr::null_mut(),
+            );
+            if reply.is_null() {
+                log::info!("XDamage is not supported");
+                return None;
+            }
+            libc::free(reply);
+            let reply = (lib.xfixes_query_version_reply)(
+                conn,
+                (lib.xfixes_query_version)(conn, 2, 0),
+                ptr::null_mut(),
+            );
+            if reply.is_null() {
+                log::info!("XFixes is not supported");
+                return None;
+            }
+            libc::free(reply);
+            let damage = xcb_generate_id(conn);
+            (lib.damage_create)(conn, damage, root, REPORT_LEVEL_NON_EMPTY);
+            let region = xcb_generate_id(conn);
+            (lib.xfixes_create_region)(conn, region, 0, ptr::null());
+            Some(Self {
+                lib,
+                conn,
+                damage,
+                region,
+            })
+        }
+    }
+
+    /// The rectangles damaged since the last call, in root coordinates.
+    pub fn take(&mut self) -> Option<Vec<xcb_rectangle_t>> {
+        let lib = self.lib;
+        unsafe {
+            (lib.damage_subtract)(self.conn, self.damage, 0, self.region);
+            let reply = (lib.xfixes_fetch_region_reply)(
+                self.conn,
+                (lib.xfixes_fetch_region)(self.conn, self.region),
+                ptr::null_mut(),
+            );
+            // The notify events are not used, they only must not pile up.
+            loop {
+                let event = xcb_poll_for_event(self.conn);
+                if event.is_null() {
+                    break;
+                }
+                libc::free(event);
+            }
+            if reply.is_null() {
+                return None;
+            }
+            let len = (lib.xfixes_fetch_region_rectangles_length)(reply).max(0) as usize;
+            let rects = (lib.xfixes_fetch_region_rectangles)(reply);
+            let res = if rects.is_null() {
+                vec![]
+            } else {
+                slice::from_raw_parts(rects, len).to_vec()
+            };
+            libc::free(reply);
+            Some(res)
+        }
+    }
+}
+
+impl Drop for XDamage {
+    fn drop(&mut self) {
+        unsafe {
+            (self.lib.damage_destroy)(self.conn, self.damage);
+            (self.lib.xfixes_destroy_region)(self.conn, self.region);
+        }
+    }
+}
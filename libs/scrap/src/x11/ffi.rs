@@ -100,12 +100,14 @@ extern "C" {
         e: *mut *mut xcb_generic_error_t,
     ) -> *mut xcb_get_geometry_reply_t;
 
+    pub fn xcb_poll_for_event(c: *mut xcb_connection_t) -> *mut xcb_generic_event_t;
 }
 
 pub const XCB_IMAGE_FORMAT_Z_PIXMAP: u8 = 2;
 
 pub type xcb_atom_t = u32;
 pub type xcb_connection_t = c_void;
+pub type xcb_generic_event_t = c_void;
 pub type xcb_window_t = u32;
 pub type xcb_keycode_t = u8;
 pub type xcb_visualid_t = u32;
@@ -213,6 +215,15 @@ pub struct xcb_get_geometry_cookie_t {
     pub sequence: u32,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct xcb_rectangle_t {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
 #[repr(C)]
 pub struct xcb_generic_error_t {
     pub response_type: u8,
@@ -4,6 +4,7 @@ pub use self::iter::*;
 pub use self::server::*;
 
 mod capturer;
+mod damage;
 mod display;
 mod ffi;
 mod iter;
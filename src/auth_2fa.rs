@@ -2,21 +2,48 @@ use hbb_common::{
     anyhow::anyhow,
     bail,
     config::Config,
-    get_time,
+    get_time, log,
     password_security::{decrypt_vec_or_original, encrypt_vec_or_original},
+    rand::{self, Rng},
+    sha2::{Digest, Sha256},
+    timeout,
+    tokio::{
+        self,
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+    },
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
-use std::sync::Mutex;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 use totp_rs::{Algorithm, Secret, TOTP};
 
 lazy_static::lazy_static! {
     static ref CURRENT_2FA: Mutex<Option<(TOTPInfo, TOTP)>> = Mutex::new(None);
+    // Generated with the secret, saved with it and shown once.
+    static ref PENDING_RECOVERY_CODES: Mutex<Option<(RecoveryCodes, Vec<String>)>> = Mutex::new(None);
+    // Serializes using and regenerating the codes in the process owning the config.
+    static ref RECOVERY_CODES_LOCK: Mutex<()> = Mutex::new(());
 }
 
 const ISSUER: &str = "RustDesk";
 const TAG_LOGIN: &str = "Connection";
 
+/// Which second factor the operator requires, one of `totp` (default), `email`, `webhook`
+/// and `recovery-codes`. The provider is only active once it is configured.
+pub const OPTION_2FA_PROVIDER: &str = "2fa-provider";
+// json of `EmailRelay`
+const OPTION_2FA_EMAIL: &str = "2fa-email";
+const OPTION_2FA_WEBHOOK: &str = "2fa-webhook";
+// json of `RecoveryCodes`
+const OPTION_2FA_RECOVERY_CODES: &str = "2fa-recovery-codes";
+const ONE_TIME_CODE_TIMEOUT: Duration = Duration::from_secs(300);
+const SMTP_TIMEOUT_MS: u64 = 10_000;
+const RECOVERY_CODE_COUNT: usize = 10;
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TOTPInfo {
     pub name: String,
@@ -82,6 +109,7 @@ pub fn generate2fa() -> String {
         if let Ok(totp) = info.new_totp() {
             let code = totp.get_url();
             *CURRENT_2FA.lock().unwrap() = Some((info, totp));
+            *PENDING_RECOVERY_CODES.lock().unwrap() = Some(RecoveryCodes::generate());
             return code;
         }
     }
@@ -93,10 +121,14 @@ pub fn verify2fa(code: String) -> bool {
         if let Ok(res) = totp.check_current(&code) {
             if res {
                 if let Ok(v) = info.into_string() {
-                    #[cfg(not(any(target_os = "android", target_os = "ios")))]
-                    crate::ipc::set_option("2fa", &v);
-                    #[cfg(any(target_os = "android", target_os = "ios"))]
-                    Config::set_option("2fa".to_owned(), v);
+                    let codes = PENDING_RECOVERY_CODES
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .and_then(|(codes, _)| serde_json::to_string(codes).ok())
+                        .unwrap_or_default();
+                    // Saved together, so there are never codes of another secret.
+                    save_2fa(v, codes);
                     return res;
                 }
             }
@@ -105,6 +137,26 @@ pub fn verify2fa(code: String) -> bool {
     false
 }
 
+/// Turn 2FA off, the recovery codes belong to the secret and are removed with it.
+pub fn disable2fa() {
+    save_2fa("".to_owned(), "".to_owned());
+}
+
+// Only the two keys are written, so options changed meanwhile are kept.
+fn save_2fa(secret: String, codes: String) {
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    {
+        crate::ipc::set_option(OPTION_2FA_RECOVERY_CODES, &codes);
+        crate::ipc::set_option("2fa", &secret);
+    }
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    {
+        let _lock = RECOVERY_CODES_LOCK.lock().unwrap();
+        Config::set_option(OPTION_2FA_RECOVERY_CODES.to_owned(), codes);
+        Config::set_option("2fa".to_owned(), secret);
+    }
+}
+
 pub fn get_2fa(raw: Option<String>) -> Option<TOTP> {
     TOTPInfo::from_str(&raw.unwrap_or(Config::get_option("2fa")))
         .map(|x| Some(x))
@@ -202,3 +254,493 @@ pub fn get_chatid_telegram(bot_token: &str) -> ResultType<Option<String>> {
 
     Ok(chat_id)
 }
+
+/// The connection waiting for its second factor.
+pub struct LoginContext {
+    pub peer_id: String,
+    pub peer_name: String,
+    pub ip: String,
+}
+
+impl LoginContext {
+    fn text(&self, code: &str) -> String {
+        format!(
+            "2FA code: {}\n\nA new connection has been established to your device with ID {}. The source IP address is {}.",
+            code,
+            Config::get_id(),
+            self.ip,
+        )
+    }
+}
+
+/// A second factor a connection must pass after the password.
+pub trait SecondFactor: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Called when a connection starts waiting for the code, e.g. to deliver it out of band.
+    fn challenge(&mut self, ctx: &LoginContext);
+    fn verify(&mut self, code: &str) -> bool;
+}
+
+/// The provider chosen by the operator, `None` if 2FA is not set up.
+pub fn get_second_factor() -> Option<Box<dyn SecondFactor>> {
+    let provider = Config::get_option(OPTION_2FA_PROVIDER);
+    let res: ResultType<Option<Box<dyn SecondFactor>>> = match provider.as_str() {
+        "" | "totp" => Ok(get_2fa(None).map(|totp| Box::new(TotpFactor { totp }) as _)),
+        "email" => EmailFactor::get().map(|x| x.map(|x| Box::new(x) as _)),
+        "webhook" => WebhookFactor::get().map(|x| x.map(|x| Box::new(x) as _)),
+        "recovery-codes" => {
+            RecoveryCodes::get().map(|x| x.map(|_| Box::new(RecoveryCodeFactor) as _))
+        }
+        _ => Err(anyhow!("Unknown 2fa provider {}", provider)),
+    };
+    match res {
+        Ok(factor) => factor,
+        Err(err) => {
+            // An explicitly chosen provider which is broken must not silently disable 2FA.
+            log::error!("Failed to load 2fa provider {}: {}", provider, err);
+            Some(Box::new(Unavailable))
+        }
+    }
+}
+
+struct Unavailable;
+
+impl SecondFactor for Unavailable {
+    fn name(&self) -> &'static str {
+        "unavailable"
+    }
+
+    fn challenge(&mut self, _ctx: &LoginContext) {}
+
+    fn verify(&mut self, _code: &str) -> bool {
+        false
+    }
+}
+
+struct TotpFactor {
+    totp: TOTP,
+}
+
+impl SecondFactor for TotpFactor {
+    fn name(&self) -> &'static str {
+        "totp"
+    }
+
+    // The current code is also sent to the telegram bot if there is one.
+    fn challenge(&mut self, ctx: &LoginContext) {
+        let bot = match TelegramBot::get() {
+            Ok(Some(bot)) => bot,
+            Err(err) => {
+                log::error!("Failed to get telegram bot: {}", err);
+                return;
+            }
+            _ => return,
+        };
+        if let Ok(code) = self.totp.generate_current() {
+            let text = ctx.text(&code);
+            tokio::spawn(async move {
+                if let Err(err) = send_2fa_code_to_telegram(&text, bot).await {
+                    log::error!("Failed to send 2fa code to telegram bot: {}", err);
+                }
+            });
+        }
+    }
+
+    fn verify(&mut self, code: &str) -> bool {
+        self.totp.check_current(code).unwrap_or(false)
+    }
+}
+
+// A random code per connection, for providers which deliver the code themselves.
+#[derive(Default)]
+struct OneTimeCode {
+    code: Option<(String, Instant)>,
+}
+
+impl OneTimeCode {
+    // Returns a new code, or `None` if the last one is still valid.
+    fn renew(&mut self) -> Option<String> {
+        if let Some((_, t)) = self.code.as_ref() {
+            if t.elapsed() < ONE_TIME_CODE_TIMEOUT {
+                return None;
+            }
+        }
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        self.code = Some((code.clone(), Instant::now()));
+        Some(code)
+    }
+
+    fn verify(&mut self, code: &str) -> bool {
+        match self.code.as_ref() {
+            Some((c, t)) if t.elapsed() < ONE_TIME_CODE_TIMEOUT && c == code.trim() => {
+                self.code.take();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A local SMTP relay, no authentication or TLS.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EmailRelay {
+    /// "host:port", port 25 if omitted
+    pub server: String,
+    pub from: String,
+    pub to: String,
+}
+
+struct EmailFactor {
+    relay: EmailRelay,
+    code: OneTimeCode,
+}
+
+impl EmailFactor {
+    fn get() -> ResultType<Option<Self>> {
+        let data = Config::get_option(OPTION_2FA_EMAIL);
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let mut relay = serde_json::from_str::<EmailRelay>(&data)?;
+        if relay.server.is_empty() || relay.to.is_empty() {
+            bail!("Email relay server and recipient are required");
+        }
+        if !relay.server.contains(':') {
+            relay.server = format!("{}:25", relay.server);
+        }
+        if relay.from.is_empty() {
+            relay.from = relay.to.clone();
+        }
+        Ok(Some(Self {
+            relay,
+            code: Default::default(),
+        }))
+    }
+}
+
+impl SecondFactor for EmailFactor {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn challenge(&mut self, ctx: &LoginContext) {
+        let Some(code) = self.code.renew() else {
+            return;
+        };
+        let relay = self.relay.clone();
+        let text = ctx.text(&code);
+        tokio::spawn(async move {
+            let subject = format!("{} 2FA code", ISSUER);
+            if let Err(err) = send_mail(&relay, &subject, &text).await {
+                log::error!("Failed to send 2fa code by email: {}", err);
+            }
+        });
+    }
+
+    fn verify(&mut self, code: &str) -> bool {
+        self.code.verify(code)
+    }
+}
+
+async fn smtp_reply(stream: &mut BufReader<TcpStream>) -> ResultType<u16> {
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            bail!("SMTP connection closed");
+        }
+        // "250-..." continues a multi-line reply, "250 ..." ends it.
+        if line.len() < 4 || line.as_bytes()[3] != b'-' {
+            return Ok(line.get(..3).unwrap_or_default().parse()?);
+        }
+    }
+}
+
+async fn smtp_command(
+    stream: &mut BufReader<TcpStream>,
+    cmd: &str,
+    expected: u16,
+) -> ResultType<()> {
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", cmd).as_bytes())
+        .await?;
+    let code = smtp_reply(stream).await?;
+    if code != expected {
+        bail!(
+            "SMTP {} failed with {}",
+            cmd.split(' ').next().unwrap_or_default(),
+            code
+        );
+    }
+    Ok(())
+}
+
+fn smtp_body(relay: &EmailRelay, subject: &str, text: &str) -> String {
+    let mut body = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n",
+        relay.from, relay.to, subject
+    );
+    for line in text.lines() {
+        // dot-stuffing, a single "." ends the data
+        if line.starts_with('.') {
+            body.push('.');
+        }
+        body.push_str(line);
+        body.push_str("\r\n");
+    }
+    body.push('.');
+    body
+}
+
+async fn send_mail(relay: &EmailRelay, subject: &str, text: &str) -> ResultType<()> {
+    let stream = timeout(SMTP_TIMEOUT_MS, TcpStream::connect(&relay.server)).await??;
+    let mut stream = BufReader::new(stream);
+    timeout(SMTP_TIMEOUT_MS, async {
+        if smtp_reply(&mut stream).await? != 220 {
+            bail!("SMTP server not ready");
+        }
+        smtp_command(&mut stream, "HELO rustdesk", 250).await?;
+        smtp_command(&mut stream, &format!("MAIL FROM:<{}>", relay.from), 250).await?;
+        smtp_command(&mut stream, &format!("RCPT TO:<{}>", relay.to), 250).await?;
+        smtp_command(&mut stream, "DATA", 354).await?;
+        smtp_command(&mut stream, &smtp_body(relay, subject, text), 250).await?;
+        smtp_command(&mut stream, "QUIT", 221).await
+    })
+    .await?
+}
+
+struct WebhookFactor {
+    url: String,
+    code: OneTimeCode,
+}
+
+impl WebhookFactor {
+    fn get() -> ResultType<Option<Self>> {
+        let url = Config::get_option(OPTION_2FA_WEBHOOK);
+        if url.is_empty() {
+            return Ok(None);
+        }
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            bail!("Invalid 2fa webhook url {}", url);
+        }
+        Ok(Some(Self {
+            url,
+            code: Default::default(),
+        }))
+    }
+}
+
+impl SecondFactor for WebhookFactor {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn challenge(&mut self, ctx: &LoginContext) {
+        let Some(code) = self.code.renew() else {
+            return;
+        };
+        let url = self.url.clone();
+        let body = serde_json::json!({
+            "id": Config::get_id(),
+            "code": code,
+            "peer_id": ctx.peer_id,
+            "peer_name": ctx.peer_name,
+            "ip": ctx.ip,
+            "text": ctx.text(&code),
+        });
+        tokio::spawn(async move {
+            if let Err(err) = crate::post_request(url, body.to_string(), "").await {
+                log::error!("Failed to post 2fa code to webhook: {}", err);
+            }
+        });
+    }
+
+    fn verify(&mut self, code: &str) -> bool {
+        self.code.verify(code)
+    }
+}
+
+/// Offline single-use codes, only their salted hashes are stored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RecoveryCodes {
+    salt: String,
+    hashes: Vec<String>,
+}
+
+impl RecoveryCodes {
+    fn get() -> ResultType<Option<Self>> {
+        let data = Config::get_option(OPTION_2FA_RECOVERY_CODES);
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let codes = serde_json::from_str::<Self>(&data)?;
+        if codes.hashes.is_empty() {
+            bail!("All recovery codes are used");
+        }
+        Ok(Some(codes))
+    }
+
+    fn hash(&self, code: &str) -> String {
+        let code: String = code
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update(b":");
+        hasher.update(code.as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    // Position of the hash of `code`, every stored hash is compared in full so that
+    // the time taken does not tell how much of a hash matched.
+    fn position(&self, code: &str) -> Option<usize> {
+        let hash = self.hash(code);
+        let mut found = None;
+        for (i, h) in self.hashes.iter().enumerate() {
+            if constant_time_eq(h.as_bytes(), hash.as_bytes()) && found.is_none() {
+                found = Some(i);
+            }
+        }
+        found
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl RecoveryCodes {
+    fn generate() -> (Self, Vec<String>) {
+        let mut rng = rand::thread_rng();
+        let plain: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let s = format!("{:010}", rng.gen_range(0..10_000_000_000u64));
+                format!("{}-{}", &s[..5], &s[5..])
+            })
+            .collect();
+        let mut codes = Self {
+            salt: hex::encode(rng.gen::<[u8; 16]>()),
+            hashes: vec![],
+        };
+        codes.hashes = plain.iter().map(|c| codes.hash(c)).collect();
+        (codes, plain)
+    }
+
+    fn save(&self) -> ResultType<()> {
+        Config::set_option(
+            OPTION_2FA_RECOVERY_CODES.to_owned(),
+            serde_json::to_string(self)?,
+        );
+        Ok(())
+    }
+}
+
+/// The plain recovery codes generated with the last secret, only returned once.
+pub fn take_pending_recovery_codes() -> Vec<String> {
+    PENDING_RECOVERY_CODES
+        .lock()
+        .unwrap()
+        .take()
+        .map(|(_, plain)| plain)
+        .unwrap_or_default()
+}
+
+/// Use up `code` if it is a recovery code, returns the number of codes left.
+/// This is the only place codes are used up: they are reloaded, checked and saved
+/// under one lock, so a code can not pass twice on concurrent logins and no login
+/// saves back codes another one has used.
+/// Must run in the process owning the config, i.e. the server.
+pub fn consume_recovery_code(code: &str) -> Option<usize> {
+    let _lock = RECOVERY_CODES_LOCK.lock().unwrap();
+    let Ok(Some(mut codes)) = RecoveryCodes::get() else {
+        return None;
+    };
+    let pos = codes.position(code)?;
+    codes.hashes.remove(pos);
+    if let Err(err) = codes.save() {
+        // Not accepted if it can not be used up.
+        log::error!("Failed to save recovery codes: {}", err);
+        return None;
+    }
+    Some(codes.hashes.len())
+}
+
+/// Replace the recovery codes, see `ipc::Data::RecoveryCodes`.
+pub fn regenerate_recovery_codes() -> ResultType<Vec<String>> {
+    let _lock = RECOVERY_CODES_LOCK.lock().unwrap();
+    let (codes, plain) = RecoveryCodes::generate();
+    codes.save()?;
+    log::info!("2fa recovery codes regenerated");
+    Ok(plain)
+}
+
+struct RecoveryCodeFactor;
+
+impl SecondFactor for RecoveryCodeFactor {
+    fn name(&self) -> &'static str {
+        "recovery-codes"
+    }
+
+    fn challenge(&mut self, _ctx: &LoginContext) {}
+
+    // Recovery codes pass with every provider and are used up by the connection with
+    // `consume_recovery_code`, this provider keeps no copy and accepts nothing else.
+    fn verify(&mut self, _code: &str) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_time_code() {
+        let mut code = OneTimeCode::default();
+        let c = code.renew().unwrap();
+        assert_eq!(c.len(), 6);
+        assert!(code.renew().is_none());
+        assert!(!code.verify("x"));
+        assert!(code.verify(&c));
+        // single use
+        assert!(!code.verify(&c));
+    }
+
+    #[test]
+    fn test_recovery_code_hash() {
+        let (codes, plain) = RecoveryCodes::generate();
+        assert_eq!(plain.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(codes.hashes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(plain[0].len(), 11);
+        // Separators and case do not matter when typing the code.
+        assert_eq!(codes.hash(&plain[0]), codes.hashes[0]);
+        assert_eq!(codes.hash(&plain[0].replace('-', " ")), codes.hashes[0]);
+        assert!(!codes.hashes.contains(&codes.hash("00000-00000")));
+        assert_eq!(codes.position(&plain[3]), Some(3));
+        assert_eq!(codes.position("00000-00000"), None);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_smtp_body_dot_stuffing() {
+        let relay = EmailRelay {
+            server: "127.0.0.1:25".to_owned(),
+            from: "a@example.com".to_owned(),
+            to: "b@example.com".to_owned(),
+        };
+        let body = smtp_body(&relay, "s", "line\n.hidden");
+        assert!(body.ends_with("line\r\n..hidden\r\n."));
+    }
+}
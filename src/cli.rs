@@ -1,17 +1,45 @@
 use crate::client::*;
 use async_trait::async_trait;
 use hbb_common::{
+    allow_err,
     config::PeerConfig,
     config::READ_TIMEOUT,
+    fs::{self, new_send_confirm},
     futures::{SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
-    tokio::{self, sync::mpsc},
+    tokio::{
+        self,
+        sync::mpsc,
+        time::{self, Duration, Instant},
+    },
     Stream,
 };
-use std::sync::{Arc, RwLock};
+use serde_json::{json, Value};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+/// Exit codes of the headless client subcommands, following `sysexits.h`.
+///
+/// The exit code of the remote command of `exec` is only in the JSON output,
+/// `EXIT_COMMAND_FAILED` tells it is not zero.
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_COMMAND_FAILED: i32 = 2;
+pub const EXIT_USAGE: i32 = 64;
+pub const EXIT_PEER_OFFLINE: i32 = 69;
+pub const EXIT_TRANSFER_FAILED: i32 = 74;
+pub const EXIT_AUTH_FAILED: i32 = 77;
+
+const HEADLESS_TERMINAL_ID: i32 = 0;
+const HEADLESS_SCREENSHOT_SID: &str = "cli";
 
 #[derive(Clone)]
 pub struct Session {
@@ -19,6 +47,9 @@ pub struct Session {
     lc: Arc<RwLock<LoginConfigHandler>>,
     sender: mpsc::UnboundedSender<Data>,
     password: String,
+    headless: bool,
+    // Set when the peer asks for a password, the headless login fails with it.
+    password_required: Arc<AtomicBool>,
 }
 
 impl Session {
@@ -32,6 +63,8 @@ impl Session {
             sender,
             password,
             lc: Default::default(),
+            headless: false,
+            password_required: Default::default(),
         };
         session.lc.write().unwrap().initialize(
             id.to_owned(),
@@ -40,6 +73,38 @@ impl Session {
             false,
             None,
             None,
+            None,
+        );
+        session
+    }
+
+    /// Create a session that never prompts.
+    ///
+    /// `conn_token` is the json returned by `session_get_conn_token`, it carries the
+    /// last login password of an existing session.
+    pub fn new_headless(
+        id: &str,
+        conn_type: ConnType,
+        password: String,
+        conn_token: Option<String>,
+        sender: mpsc::UnboundedSender<Data>,
+    ) -> Self {
+        let session = Self {
+            id: id.to_owned(),
+            sender,
+            password,
+            lc: Default::default(),
+            headless: true,
+            password_required: Default::default(),
+        };
+        session.lc.write().unwrap().initialize(
+            id.to_owned(),
+            conn_type,
+            None,
+            false,
+            None,
+            None,
+            conn_token,
         );
         session
     }
@@ -47,22 +112,37 @@ impl Session {
 
 #[async_trait]
 impl Interface for Session {
-    fn get_login_config_handler(&self) -> Arc<RwLock<LoginConfigHandler>> {
+    fn get_lch(&self) -> Arc<RwLock<LoginConfigHandler>> {
         return self.lc.clone();
     }
 
     fn msgbox(&self, msgtype: &str, title: &str, text: &str, link: &str) {
         match msgtype {
+            "input-password" | "terminal-admin-login-password" if self.headless => {
+                // `handle_hash` has already sent the preset password (or an empty one),
+                // the login result is checked by `login()`.
+                self.password_required.store(true, Ordering::SeqCst);
+            }
             "input-password" => {
                 self.sender
-                    .send(Data::Login((self.password.clone(), true)))
+                    .send(Data::Login((
+                        "".to_owned(),
+                        "".to_owned(),
+                        self.password.clone(),
+                        true,
+                    )))
                     .ok();
             }
+            "re-input-password" if self.headless => {
+                log::error!("{}: {}", title, text);
+                self.password_required.store(true, Ordering::SeqCst);
+            }
             "re-input-password" => {
                 log::error!("{}: {}", title, text);
                 match rpassword::prompt_password("Enter password: ") {
                     Ok(password) => {
-                        let login_data = Data::Login((password, true));
+                        let login_data =
+                            Data::Login(("".to_owned(), "".to_owned(), password, true));
                         self.sender.send(login_data).ok();
                     }
                     Err(e) => {
@@ -74,7 +154,7 @@ impl Interface for Session {
                 log::error!("{}: {}: {}", msgtype, title, text);
             }
             _ => {
-                log::info!("{}: {}: {}", msgtype, title, text);
+                log::info!("{}: {}: {}: {}", msgtype, title, text, link);
             }
         }
     }
@@ -87,11 +167,11 @@ impl Interface for Session {
         self.lc.write().unwrap().handle_peer_info(&pi);
     }
 
+    fn set_multiple_windows_session(&self, sessions: Vec<WindowsSession>) {
+        log::info!("multiple windows sessions: {:?}", sessions);
+    }
+
     async fn handle_hash(&self, pass: &str, hash: Hash, peer: &mut Stream) {
-        log::info!(
-            "password={}",
-            hbb_common::password_security::temporary_password()
-        );
         handle_hash(self.lc.clone(), &pass, hash, self, peer).await;
     }
 
@@ -123,15 +203,223 @@ impl Interface for Session {
     }
 }
 
+/// Failure of a headless subcommand, each kind maps to its own exit code.
+#[derive(Debug)]
+pub enum CliError {
+    Auth(String),
+    PeerOffline(String),
+    Transfer(String),
+    Other(String),
+}
+
+impl CliError {
+    fn from_connect_error(err: String) -> Self {
+        let lower = err.to_lowercase();
+        if lower.contains("offline") || lower.contains("not exist") {
+            Self::PeerOffline(err)
+        } else {
+            Self::Other(err)
+        }
+    }
+
+    fn from_login_error(err: String) -> Self {
+        if err == LOGIN_MSG_PASSWORD_EMPTY
+            || err == LOGIN_MSG_PASSWORD_WRONG
+            || err == LOGIN_MSG_2FA_WRONG
+            || err == REQUIRE_2FA
+            || err == LOGIN_MSG_NO_PASSWORD_ACCESS
+            || err == LOGIN_MSG_DESKTOP_SESSION_NOT_READY_PASSWORD_EMPTY
+            || err == LOGIN_MSG_DESKTOP_SESSION_NOT_READY_PASSWORD_WRONG
+        {
+            Self::Auth(err)
+        } else if err == LOGIN_MSG_OFFLINE {
+            Self::PeerOffline(err)
+        } else {
+            Self::Other(err)
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Auth(_) => EXIT_AUTH_FAILED,
+            Self::PeerOffline(_) => EXIT_PEER_OFFLINE,
+            Self::Transfer(_) => EXIT_TRANSFER_FAILED,
+            Self::Other(_) => EXIT_ERROR,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Auth(_) => "auth_failed",
+            Self::PeerOffline(_) => "peer_offline",
+            Self::Transfer(_) => "transfer_failed",
+            Self::Other(_) => "error",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Self::Auth(m) | Self::PeerOffline(m) | Self::Transfer(m) | Self::Other(m) => m,
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.message())
+    }
+}
+
+/// Options shared by all headless subcommands.
+#[derive(Debug, Clone, Default)]
+pub struct HeadlessOptions {
+    pub id: String,
+    pub password: String,
+    pub conn_token: Option<String>,
+    pub key: String,
+    pub token: String,
+    pub timeout: Option<Duration>,
+}
+
+struct LoggedIn {
+    stream: Stream,
+    peer_info: PeerInfo,
+    _keep_it: Option<mpsc::UnboundedSender<()>>,
+}
+
+fn print_result(command: &str, id: &str, res: &Result<Value, CliError>) -> i32 {
+    let (out, code) = match res {
+        Ok(v) => {
+            let mut out = json!({
+                "command": command,
+                "id": id,
+                "ok": true,
+            });
+            if let (Some(out), Some(v)) = (out.as_object_mut(), v.as_object()) {
+                out.extend(v.clone());
+            }
+            let code = match v.get("exit_code").and_then(|c| c.as_i64()) {
+                Some(c) if c != 0 => EXIT_COMMAND_FAILED,
+                _ => EXIT_OK,
+            };
+            (out, code)
+        }
+        Err(err) => (
+            json!({
+                "command": command,
+                "id": id,
+                "ok": false,
+                "error": err.kind(),
+                "message": err.message(),
+            }),
+            err.exit_code(),
+        ),
+    };
+    println!("{}", out);
+    code
+}
+
+/// `deadline` bounds the login, otherwise each read is bounded by `READ_TIMEOUT`.
+async fn login(
+    opts: &HeadlessOptions,
+    conn_type: ConnType,
+    deadline: Option<Instant>,
+) -> Result<(Session, LoggedIn), CliError> {
+    let (sender, _receiver) = mpsc::unbounded_channel::<Data>();
+    let session = Session::new_headless(
+        &opts.id,
+        conn_type,
+        opts.password.clone(),
+        opts.conn_token.clone(),
+        sender,
+    );
+    let id = session.get_id();
+    let ((mut stream, direct, _pk, _kcp, _stream_type), (feedback, rendezvous_server)) =
+        Client::start(&id, &opts.key, &opts.token, conn_type, session.clone())
+            .await
+            .map_err(|e| CliError::from_connect_error(e.to_string()))?;
+    session.update_direct(Some(direct));
+    let _keep_it = hc_connection(feedback, rendezvous_server, &opts.token).await;
+    loop {
+        let read_deadline = deadline.unwrap_or_else(|| Instant::now() + READ_TIMEOUT);
+        match time::timeout_at(read_deadline, stream.next()).await {
+            // Waiting for the password to be accepted on the remote side
+            Err(_) if session.password_required.load(Ordering::SeqCst) => {
+                return Err(CliError::Auth(LOGIN_MSG_PASSWORD_EMPTY.to_owned()));
+            }
+            Err(_) => return Err(CliError::Other("Timeout".to_owned())),
+            Ok(Some(Ok(bytes))) => {
+                session.update_received(true);
+                let msg_in = Message::parse_from_bytes(&bytes)
+                    .map_err(|e| CliError::Other(e.to_string()))?;
+                match msg_in.union {
+                    Some(message::Union::Hash(hash)) => {
+                        session
+                            .handle_hash(&session.password, hash, &mut stream)
+                            .await;
+                    }
+                    Some(message::Union::LoginResponse(lr)) => match lr.union {
+                        Some(login_response::Union::Error(err)) => {
+                            return Err(CliError::from_login_error(err));
+                        }
+                        Some(login_response::Union::PeerInfo(pi)) => {
+                            session.handle_peer_info(pi.clone());
+                            return Ok((
+                                session,
+                                LoggedIn {
+                                    stream,
+                                    peer_info: pi,
+                                    _keep_it,
+                                },
+                            ));
+                        }
+                        _ => {}
+                    },
+                    Some(message::Union::TestDelay(t)) => {
+                        session.handle_test_delay(t, &mut stream).await;
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Some(Err(err))) => {
+                return Err(CliError::Other(format!("Connection closed: {}", err)));
+            }
+            _ => return Err(CliError::Other("Reset by the peer".to_owned())),
+        }
+    }
+}
+
+/// Wait for the next message, `deadline` bounds the whole subcommand.
+async fn next_message(stream: &mut Stream, deadline: Option<Instant>) -> Result<Message, CliError> {
+    let res = match deadline {
+        Some(deadline) => match time::timeout_at(deadline, stream.next()).await {
+            Ok(res) => res,
+            Err(_) => return Err(CliError::Other("Timeout".to_owned())),
+        },
+        None => stream.next().await,
+    };
+    match res {
+        Some(Ok(bytes)) => {
+            Message::parse_from_bytes(&bytes).map_err(|e| CliError::Other(e.to_string()))
+        }
+        Some(Err(err)) => Err(CliError::Other(format!("Connection closed: {}", err))),
+        None => Err(CliError::Other("Reset by the peer".to_owned())),
+    }
+}
+
+fn deadline(opts: &HeadlessOptions) -> Option<Instant> {
+    opts.timeout.map(|t| Instant::now() + t)
+}
+
 #[tokio::main(flavor = "current_thread")]
 pub async fn connect_test(id: &str, key: String, token: String) {
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+    let (sender, _receiver) = mpsc::unbounded_channel::<Data>();
     let handler = Session::new(&id, sender);
     match crate::client::Client::start(id, &key, &token, ConnType::PORT_FORWARD, handler).await {
         Err(err) => {
             log::error!("Failed to connect {}: {}", &id, err);
         }
-        Ok((mut stream, direct)) => {
+        Ok(((mut stream, direct, ..), _)) => {
             log::info!("direct: {}", direct);
             // rpassword::prompt_password("Input anything to exit").ok();
             loop {
@@ -144,7 +432,7 @@ pub async fn connect_test(id: &str, key: String, token: String) {
                         Ok(Some(Ok(bytes))) => {
                             if let Ok(msg_in) = Message::parse_from_bytes(&bytes) {
                                 match msg_in.union {
-                                    Some(message::Union::Hash(hash)) => {
+                                    Some(message::Union::Hash(_hash)) => {
                                         log::info!("Got hash");
                                         break;
                                     }
@@ -160,6 +448,328 @@ pub async fn connect_test(id: &str, key: String, token: String) {
     }
 }
 
+/// Log in and print the peer info.
+#[tokio::main(flavor = "current_thread")]
+pub async fn connect(opts: HeadlessOptions) -> i32 {
+    let res = login(&opts, ConnType::PORT_FORWARD, deadline(&opts))
+        .await
+        .map(|(session, c)| {
+            let direct = session.get_lch().read().unwrap().direct;
+            json!({
+                "direct": direct,
+                "version": c.peer_info.version,
+                "platform": c.peer_info.platform,
+                "hostname": c.peer_info.hostname,
+                "username": c.peer_info.username,
+            })
+        });
+    print_result("connect", &opts.id, &res)
+}
+
+/// Run `command` in a remote terminal, its exit code is reported in the JSON output.
+#[tokio::main(flavor = "current_thread")]
+pub async fn exec(opts: HeadlessOptions, command: String) -> i32 {
+    let res = exec_(&opts, command).await;
+    print_result("exec", &opts.id, &res)
+}
+
+async fn exec_(opts: &HeadlessOptions, command: String) -> Result<Value, CliError> {
+    let deadline = deadline(opts);
+    let (_session, mut c) = login(opts, ConnType::TERMINAL, deadline).await?;
+    let mut action = TerminalAction::new();
+    action.set_open(OpenTerminal {
+        terminal_id: HEADLESS_TERMINAL_ID,
+        rows: 24,
+        cols: 200,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_terminal_action(action);
+    allow_err!(c.stream.send(&msg_out).await);
+
+    let mut output = Vec::new();
+    loop {
+        let msg_in = next_message(&mut c.stream, deadline).await?;
+        let Some(message::Union::TerminalResponse(response)) = msg_in.union else {
+            continue;
+        };
+        use hbb_common::message_proto::terminal_response::Union;
+        match response.union {
+            Some(Union::Opened(opened)) => {
+                if !opened.success {
+                    return Err(CliError::Other(opened.message));
+                }
+                // The shell exits with the status of the last command.
+                let mut action = TerminalAction::new();
+                action.set_data(TerminalData {
+                    terminal_id: HEADLESS_TERMINAL_ID,
+                    data: bytes::Bytes::from(format!("{}\rexit\r", command).into_bytes()),
+                    ..Default::default()
+                });
+                let mut msg_out = Message::new();
+                msg_out.set_terminal_action(action);
+                allow_err!(c.stream.send(&msg_out).await);
+            }
+            Some(Union::Data(data)) => {
+                if data.compressed {
+                    output.extend(hbb_common::compress::decompress(&data.data));
+                } else {
+                    output.extend_from_slice(&data.data);
+                }
+            }
+            Some(Union::Closed(closed)) => {
+                return Ok(json!({
+                    "exit_code": closed.exit_code,
+                    "output": String::from_utf8_lossy(&output),
+                }));
+            }
+            Some(Union::Error(error)) => {
+                return Err(CliError::Other(error.message));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Upload `local` to `remote` through a file transfer job.
+#[tokio::main(flavor = "current_thread")]
+pub async fn push(
+    opts: HeadlessOptions,
+    local: String,
+    remote: String,
+    include_hidden: bool,
+) -> i32 {
+    let res = push_(&opts, local, remote, include_hidden).await;
+    print_result("push", &opts.id, &res)
+}
+
+async fn push_(
+    opts: &HeadlessOptions,
+    local: String,
+    remote: String,
+    include_hidden: bool,
+) -> Result<Value, CliError> {
+    let deadline = deadline(opts);
+    let (session, mut c) = login(opts, ConnType::FILE_TRANSFER, deadline).await?;
+    let od = fs::can_enable_overwrite_detection(session.get_lch().read().unwrap().version);
+    let id = fs::get_next_job_id();
+    let job = fs::TransferJob::new_read(
+        id,
+        fs::JobType::Generic,
+        remote.clone(),
+        fs::DataSource::FilePath(PathBuf::from(&local)),
+        0,
+        include_hidden,
+        false,
+        od,
+    )
+    .map_err(|e| CliError::Transfer(e.to_string()))?;
+    #[cfg(not(windows))]
+    let files = job.files().clone();
+    #[cfg(windows)]
+    let mut files = job.files().clone();
+    #[cfg(windows)]
+    if c.peer_info.platform != "Windows" {
+        fs::transform_windows_path(&mut files);
+    }
+    let file_count = files.len();
+    let total_size = job.total_size();
+    let mut read_jobs = vec![job];
+    allow_err!(
+        c.stream
+            .send(&fs::new_receive(id, remote, 0, files, total_size))
+            .await
+    );
+    let mut timer = crate::rustdesk_interval(time::interval(MILLI1));
+    loop {
+        tokio::select! {
+            res = next_message(&mut c.stream, deadline) => {
+                let Some(message::Union::FileResponse(fr)) = res?.union else {
+                    continue;
+                };
+                match fr.union {
+                    Some(file_response::Union::Digest(digest)) if digest.is_upload => {
+                        if let Some(job) = fs::get_job(digest.id, &mut read_jobs) {
+                            let req = FileTransferSendConfirmRequest {
+                                id: digest.id,
+                                file_num: digest.file_num,
+                                union: Some(file_transfer_send_confirm_request::Union::OffsetBlk(0)),
+                                ..Default::default()
+                            };
+                            job.confirm(&req).await;
+                            allow_err!(c.stream.send(&new_send_confirm(req)).await);
+                        }
+                    }
+                    Some(file_response::Union::Done(d)) if d.id == id => {
+                        return Ok(json!({
+                            "local": local,
+                            "files": file_count,
+                            "total_size": total_size,
+                        }));
+                    }
+                    Some(file_response::Union::Error(e)) if e.id == id => {
+                        return Err(CliError::Transfer(e.error));
+                    }
+                    _ => {}
+                }
+            }
+            _ = timer.tick(), if !read_jobs.is_empty() => {
+                if let Err(err) = fs::handle_read_jobs(&mut read_jobs, &mut c.stream).await {
+                    return Err(CliError::Transfer(err.to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Download `remote` into `local` through a file transfer job.
+#[tokio::main(flavor = "current_thread")]
+pub async fn pull(
+    opts: HeadlessOptions,
+    remote: String,
+    local: String,
+    include_hidden: bool,
+) -> i32 {
+    let res = pull_(&opts, remote, local, include_hidden).await;
+    print_result("pull", &opts.id, &res)
+}
+
+async fn pull_(
+    opts: &HeadlessOptions,
+    remote: String,
+    local: String,
+    include_hidden: bool,
+) -> Result<Value, CliError> {
+    let deadline = deadline(opts);
+    let (session, mut c) = login(opts, ConnType::FILE_TRANSFER, deadline).await?;
+    let od = fs::can_enable_overwrite_detection(session.get_lch().read().unwrap().version);
+    let id = fs::get_next_job_id();
+    let mut write_jobs = vec![fs::TransferJob::new_write(
+        id,
+        fs::JobType::Generic,
+        remote.clone(),
+        fs::DataSource::FilePath(PathBuf::from(&local)),
+        0,
+        include_hidden,
+        true,
+        Vec::new(),
+        od,
+    )];
+    allow_err!(
+        c.stream
+            .send(&fs::new_send(
+                id,
+                fs::JobType::Generic,
+                remote,
+                0,
+                include_hidden
+            ))
+            .await
+    );
+    loop {
+        let Some(message::Union::FileResponse(fr)) =
+            next_message(&mut c.stream, deadline).await?.union
+        else {
+            continue;
+        };
+        match fr.union {
+            Some(file_response::Union::Dir(fd)) => {
+                #[cfg(windows)]
+                let entries = fd.entries.to_vec();
+                #[cfg(not(windows))]
+                let mut entries = fd.entries.to_vec();
+                #[cfg(not(windows))]
+                if c.peer_info.platform == "Windows" {
+                    fs::transform_windows_path(&mut entries);
+                }
+                if let Some(job) = fs::get_job(fd.id, &mut write_jobs) {
+                    job.set_files(entries);
+                }
+            }
+            Some(file_response::Union::Digest(digest)) if !digest.is_upload => {
+                if let Some(job) = fs::get_job(digest.id, &mut write_jobs) {
+                    job.set_digest(digest.file_size, digest.last_modified);
+                    let req = FileTransferSendConfirmRequest {
+                        id: digest.id,
+                        file_num: digest.file_num,
+                        union: Some(file_transfer_send_confirm_request::Union::OffsetBlk(0)),
+                        ..Default::default()
+                    };
+                    job.confirm(&req).await;
+                    allow_err!(c.stream.send(&new_send_confirm(req)).await);
+                }
+            }
+            Some(file_response::Union::Block(block)) => {
+                if let Some(job) = fs::get_job(block.id, &mut write_jobs) {
+                    if let Err(err) = job.write(block).await {
+                        return Err(CliError::Transfer(err.to_string()));
+                    }
+                }
+            }
+            Some(file_response::Union::Done(d)) if d.id == id => {
+                let Some(job) = fs::remove_job(d.id, &mut write_jobs) else {
+                    continue;
+                };
+                job.modify_time();
+                if let Some(err) = job.job_error() {
+                    return Err(CliError::Transfer(err));
+                }
+                let files = job.files();
+                return Ok(json!({
+                    "local": local,
+                    "files": files.len(),
+                    "total_size": files.iter().map(|f| f.size).sum::<u64>(),
+                }));
+            }
+            Some(file_response::Union::Error(e)) if e.id == id => {
+                return Err(CliError::Transfer(e.error));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Save a png screenshot of `display` to `path`.
+#[tokio::main(flavor = "current_thread")]
+pub async fn screenshot(opts: HeadlessOptions, display: i32, path: String) -> i32 {
+    let res = screenshot_(&opts, display, path).await;
+    print_result("screenshot", &opts.id, &res)
+}
+
+async fn screenshot_(
+    opts: &HeadlessOptions,
+    display: i32,
+    path: String,
+) -> Result<Value, CliError> {
+    let deadline = deadline(opts);
+    let (_session, mut c) = login(opts, ConnType::DEFAULT_CONN, deadline).await?;
+    let mut msg_out = Message::new();
+    msg_out.set_screenshot_request(ScreenshotRequest {
+        display,
+        sid: HEADLESS_SCREENSHOT_SID.to_owned(),
+        ..Default::default()
+    });
+    allow_err!(c.stream.send(&msg_out).await);
+    loop {
+        let Some(message::Union::ScreenshotResponse(response)) =
+            next_message(&mut c.stream, deadline).await?.union
+        else {
+            continue;
+        };
+        if response.sid != HEADLESS_SCREENSHOT_SID {
+            continue;
+        }
+        if !response.msg.is_empty() {
+            return Err(CliError::Other(response.msg));
+        }
+        std::fs::write(&path, &response.data).map_err(|e| CliError::Transfer(e.to_string()))?;
+        return Ok(json!({
+            "path": path,
+            "size": response.data.len(),
+        }));
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 pub async fn start_one_port_forward(
     id: String,
@@ -171,7 +781,7 @@ pub async fn start_one_port_forward(
 ) {
     crate::common::test_rendezvous_server();
     crate::common::test_nat_type();
-    let (sender, mut receiver) = mpsc::unbounded_channel::<Data>();
+    let (sender, receiver) = mpsc::unbounded_channel::<Data>();
     let handler = Session::new(&id, sender);
     if let Err(err) = crate::port_forward::listen(
         handler.id.clone(),
@@ -191,3 +801,37 @@ pub async fn start_one_port_forward(
     }
     log::info!("port forward (:{}) exit", port);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_exit_codes() {
+        let auth = CliError::from_login_error(LOGIN_MSG_PASSWORD_WRONG.to_owned());
+        assert_eq!(auth.exit_code(), EXIT_AUTH_FAILED);
+        let offline = CliError::from_connect_error("Remote desktop is offline".to_owned());
+        assert_eq!(offline.exit_code(), EXIT_PEER_OFFLINE);
+        assert_eq!(
+            CliError::from_login_error(LOGIN_MSG_OFFLINE.to_owned()).exit_code(),
+            EXIT_PEER_OFFLINE
+        );
+        assert_eq!(
+            CliError::Transfer("".to_owned()).exit_code(),
+            EXIT_TRANSFER_FAILED
+        );
+        assert_eq!(
+            CliError::from_connect_error("Failed to connect".to_owned()).exit_code(),
+            EXIT_ERROR
+        );
+    }
+
+    #[test]
+    fn test_exec_exit_code() {
+        // The remote exit code must not be taken for one of the reserved codes.
+        let res = Ok(json!({ "exit_code": EXIT_AUTH_FAILED }));
+        assert_eq!(print_result("exec", "", &res), EXIT_COMMAND_FAILED);
+        let res = Ok(json!({ "exit_code": 0 }));
+        assert_eq!(print_result("exec", "", &res), EXIT_OK);
+    }
+}
@@ -75,7 +75,7 @@ use hbb_common::{
 pub use helper::*;
 use scrap::{
     codec::Decoder,
-    record::{Recorder, RecorderContext},
+    record::{RecordPeer, Recorder, RecorderContext, OPTION_RECORD_AUDIO},
     CodecFormat, ImageFormat, ImageRgb, ImageTexture,
 };
 
@@ -93,6 +93,7 @@ pub mod file_trait;
 pub mod helper;
 pub mod io_loop;
 pub mod screenshot;
+pub mod transport_stats;
 
 pub const MILLI1: Duration = Duration::from_millis(1);
 pub const SEC30: Duration = Duration::from_secs(30);
@@ -1651,24 +1652,51 @@ impl VideoHandler {
     }
 
     /// Start or stop screen record.
-    pub fn record_screen(&mut self, start: bool, id: String, display_idx: usize, camera: bool) {
+    pub fn record_screen(
+        &mut self,
+        start: bool,
+        peer: RecordPeer,
+        display_idx: usize,
+        camera: bool,
+    ) {
         self.record = false;
         if start {
             self.recorder = Recorder::new(RecorderContext {
                 server: false,
-                id,
+                id: peer.id.clone(),
                 dir: crate::ui_interface::video_save_directory(false),
                 display_idx,
                 camera,
+                audio: LocalConfig::get_option(OPTION_RECORD_AUDIO) == "Y",
                 tx: None,
             })
-            .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))));
+            .map_or(Default::default(), |mut r| {
+                r.add_peer(peer);
+                Arc::new(Mutex::new(Some(r)))
+            });
         } else {
             self.recorder = Default::default();
         }
 
         self.record = start;
     }
+
+    /// Record the audio of the peer along with the screen.
+    pub fn record_audio(&mut self, data: &[u8]) {
+        if self.record {
+            if let Some(r) = self.recorder.lock().unwrap().as_mut() {
+                r.write_audio(data);
+            }
+        }
+    }
+
+    pub fn record_chapter(&mut self, title: &str) {
+        if self.record {
+            if let Some(r) = self.recorder.lock().unwrap().as_mut() {
+                r.add_chapter(title);
+            }
+        }
+    }
 }
 
 // The source of sent password
@@ -2295,7 +2323,7 @@ impl LoginConfigHandler {
     fn get_image_quality_enum(&self, q: &str, ignore_default: bool) -> Option<ImageQuality> {
         if q == "low" {
             Some(ImageQuality::Low)
-        } else if q == "best" {
+        } else if q == "best" || q == scrap::codec::IMAGE_QUALITY_SCREEN_CONTENT {
             Some(ImageQuality::Best)
         } else if q == "balanced" {
             if ignore_default {
@@ -2403,22 +2431,36 @@ impl LoginConfigHandler {
     /// * `value` - The image quality.
     pub fn save_image_quality(&mut self, value: String) -> Option<Message> {
         let mut res = None;
-        if let Some(q) = self.get_image_quality_enum(&value, false) {
+        let mut config = self.load_config();
+        let screen_content = scrap::codec::IMAGE_QUALITY_SCREEN_CONTENT;
+        let screen_content_changed =
+            (config.image_quality == screen_content) != (value == screen_content);
+        config.image_quality = value.clone();
+        self.save_config(config);
+        let q = self.get_image_quality_enum(&value, false);
+        if q.is_some() || screen_content_changed {
+            let mut option = OptionMessage::new();
+            if let Some(q) = q {
+                option.image_quality = q.into();
+            }
+            // The screen content mode is negotiated with the codec.
+            if screen_content_changed {
+                option.supported_decoding = MessageField::some(self.get_supported_decoding());
+            }
             let mut misc = Misc::new();
-            misc.set_option(OptionMessage {
-                image_quality: q.into(),
-                ..Default::default()
-            });
+            misc.set_option(option);
             let mut msg_out = Message::new();
             msg_out.set_misc(misc);
             res = Some(msg_out);
         }
-        let mut config = self.load_config();
-        config.image_quality = value;
-        self.save_config(config);
         res
     }
 
+    /// Whether the peer supports the screen content mode.
+    pub fn screen_content_supported(&self) -> bool {
+        self.is_peer_support(scrap::codec::PEER_SUPPORT_SCREEN_CONTENT)
+    }
+
     pub fn save_trackpad_speed(&mut self, speed: i32) {
         let mut config = self.load_config();
         config.trackpad_speed = speed;
@@ -2592,6 +2634,14 @@ impl LoginConfigHandler {
         log::info!("peer info supported_encoding:{:?}", self.supported_encoding);
     }
 
+    /// Whether the peer announces `key` in the platform additions of its peer info.
+    pub fn is_peer_support(&self, key: &str) -> bool {
+        self.peer_info.as_ref().is_some_and(|pi| {
+            serde_json::from_str::<serde_json::Value>(&pi.platform_additions)
+                .is_ok_and(|v| v[key] == true)
+        })
+    }
+
     pub fn get_remote_dir(&self) -> String {
         serde_json::from_str::<HashMap<String, String>>(&self.get_option("remote_dir"))
             .unwrap_or_default()
@@ -2787,6 +2837,7 @@ pub enum MediaData {
     AudioFormat(AudioFormat),
     Reset,
     RecordScreen(bool),
+    RecordChapter(String),
 }
 
 pub type MediaSender = mpsc::Sender<MediaData>;
@@ -2852,9 +2903,9 @@ pub fn start_video_thread<F, T>(
                             let mut handler = VideoHandler::new(format, display);
                             let record_state = session.lc.read().unwrap().record_state;
                             let record_permission = session.lc.read().unwrap().record_permission;
-                            let id = session.lc.read().unwrap().id.clone();
                             if record_state && record_permission {
-                                handler.record_screen(true, id, display, is_view_camera);
+                                let peer = record_peer(&session);
+                                handler.record_screen(true, peer, display, is_view_camera);
                             }
                             video_handler = Some(handler);
                         }
@@ -2933,9 +2984,19 @@ pub fn start_video_thread<F, T>(
                         }
                     }
                     MediaData::RecordScreen(start) => {
-                        let id = session.lc.read().unwrap().id.clone();
+                        let peer = record_peer(&session);
                         if let Some(handler) = video_handler.as_mut() {
-                            handler.record_screen(start, id, display, is_view_camera);
+                            handler.record_screen(start, peer, display, is_view_camera);
+                        }
+                    }
+                    MediaData::AudioFrame(af) => {
+                        if let Some(handler) = video_handler.as_mut() {
+                            handler.record_audio(&af.data);
+                        }
+                    }
+                    MediaData::RecordChapter(title) => {
+                        if let Some(handler) = video_handler.as_mut() {
+                            handler.record_chapter(&title);
                         }
                     }
                     _ => {}
@@ -2948,6 +3009,26 @@ pub fn start_video_thread<F, T>(
     });
 }
 
+// The peer in the metadata of the recordings, operated by the local user.
+fn record_peer<T: InvokeUiSession>(session: &Session<T>) -> RecordPeer {
+    let lc = session.lc.read().unwrap();
+    let permissions = [
+        ("keyboard", *session.server_keyboard_enabled.read().unwrap()),
+        ("clipboard", *session.server_clipboard_enabled.read().unwrap()),
+        ("file", *session.server_file_transfer_enabled.read().unwrap()),
+        ("recording", lc.record_permission),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| name.to_string())
+    .collect();
+    RecordPeer {
+        id: lc.id.clone(),
+        name: crate::username(),
+        permissions,
+    }
+}
+
 /// Start an audio thread
 /// Return a audio [`MediaSender`]
 pub fn start_audio_thread() -> MediaSender {
@@ -4182,13 +4263,14 @@ async fn udp_nat_connect(
     typ: &'static str,
     ms_timeout: u64,
 ) -> ResultType<(Stream, Option<KcpStream>, &'static str)> {
-    crate::punch_udp(socket.clone(), false)
+    let punch = crate::punch_udp(socket.clone(), false, &[])
         .await
         .map_err(|err| {
             log::debug!("{err}");
             anyhow!(err)
         })?;
-    let res = KcpStream::connect(socket, Duration::from_millis(ms_timeout))
+    let supports_hello = KcpStream::is_hello_supported(&punch);
+    let res = KcpStream::connect(socket, Duration::from_millis(ms_timeout), supports_hello)
         .await
         .map_err(|err| {
             log::debug!("Failed to connect KCP stream: {}", err);
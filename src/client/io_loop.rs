@@ -4,8 +4,9 @@ use crate::clipboard::{update_clipboard, ClipboardSide};
 use crate::{audio_service, clipboard::CLIPBOARD_INTERVAL, ConnInner, CLIENT_SERVER};
 use crate::{
     client::{
-        self, new_voice_call_request, Client, Data, Interface, MediaData, MediaSender,
-        QualityStatus, MILLI1, SEC30,
+        self, new_voice_call_request,
+        transport_stats::{ConnectionKind, MessageClass},
+        Client, Data, Interface, MediaData, MediaSender, QualityStatus, MILLI1, SEC30,
     },
     common::get_default_sound_input,
     ui_session_interface::{InvokeUiSession, Session},
@@ -182,6 +183,16 @@ impl<T: InvokeUiSession> Remote<T> {
                     .set_connected();
                 self.handler
                     .set_connection_type(peer.is_secured(), direct, stream_type); // flutter -> connection_ready
+                let id = self.handler.get_id();
+                self.handler.transport_stats.lock().unwrap().on_connected(
+                    ConnectionKind::new(
+                        stream_type,
+                        direct,
+                        hbb_common::is_ip_str(&id) || hbb_common::is_domain_port_str(&id),
+                    ),
+                    stream_type,
+                    peer.is_secured(),
+                );
                 self.handler.update_direct(Some(direct));
                 if conn_type == ConnType::DEFAULT_CONN || conn_type == ConnType::VIEW_CAMERA {
                     self.handler
@@ -269,10 +280,15 @@ impl<T: InvokeUiSession> Remote<T> {
                                 break;
                             }
                             if !self.read_jobs.is_empty() {
+                                // Finished jobs are removed, so their last block is not counted.
+                                let transferred = |jobs: &Vec<fs::TransferJob>| jobs.iter().map(|x| x.transferred()).sum::<u64>();
+                                let before = transferred(&self.read_jobs);
                                 if let Err(err) = fs::handle_read_jobs(&mut self.read_jobs, &mut peer).await {
                                     self.handler.msgbox("error", "Connection Error", &err.to_string(), "");
                                     break;
                                 }
+                                let sent = transferred(&self.read_jobs).saturating_sub(before);
+                                self.handler.transport_stats.lock().unwrap().bytes_out.add(MessageClass::File, sent as _);
                                 self.update_jobs_status();
                             } else {
                                 self.timer = crate::rustdesk_interval(time::interval_at(Instant::now() + SEC30, SEC30));
@@ -315,6 +331,8 @@ impl<T: InvokeUiSession> Remote<T> {
                                 codec_format,
                                 ..Default::default()
                             });
+                            self.handler.transport_stats.lock().unwrap().kcp = kcp.as_ref().map(|x| x.stats());
+                            self.handler.update_transport_stats();
                         }
                     }
                 }
@@ -397,6 +415,7 @@ impl<T: InvokeUiSession> Remote<T> {
                         };
                         log::debug!("Send system clipboard message to remote");
                         let msg = crate::clipboard_file::clip_2_msg(clip);
+                        self.handler.transport_stats.lock().unwrap().add_out(&msg);
                         allow_err!(peer.send(&msg).await);
                     }
                 }
@@ -565,6 +584,7 @@ impl<T: InvokeUiSession> Remote<T> {
                     },
                     _ => {}
                 }
+                self.handler.transport_stats.lock().unwrap().add_out(&msg);
                 allow_err!(peer.send(&msg).await);
             }
             Data::SendFiles((id, r#type, path, to, file_num, include_hidden, is_remote)) => {
@@ -1282,6 +1302,11 @@ impl<T: InvokeUiSession> Remote<T> {
 
     async fn handle_msg_from_peer(&mut self, data: &[u8], peer: &mut Stream) -> bool {
         if let Ok(msg_in) = Message::parse_from_bytes(&data) {
+            self.handler
+                .transport_stats
+                .lock()
+                .unwrap()
+                .add_in(&msg_in, data.len());
             match msg_in.union {
                 Some(message::Union::VideoFrame(vf)) => {
                     if !self.first_frame {
@@ -1754,6 +1779,12 @@ impl<T: InvokeUiSession> Remote<T> {
                     }
                     Some(misc::Union::SwitchDisplay(s)) => {
                         self.handler.handle_peer_switch_display(&s);
+                        if self.last_record_state {
+                            for (_, v) in self.video_threads.iter() {
+                                let title = format!("display {}", s.display);
+                                v.video_sender.send(MediaData::RecordChapter(title)).ok();
+                            }
+                        }
                         if let Some(thread) = self.video_threads.get_mut(&(s.display as usize)) {
                             thread.video_sender.send(MediaData::Reset).ok();
                         }
@@ -1876,6 +1907,12 @@ impl<T: InvokeUiSession> Remote<T> {
                         #[cfg(feature = "flutter")]
                         self.handler.switch_back(&self.handler.get_id());
                     }
+                    #[cfg(not(target_os = "ios"))]
+                    Some(misc::Union::PluginRequest(p))
+                        if p.id == crate::lan::WOL_RELAY_REQUEST_ID =>
+                    {
+                        self.handler.handle_wol_relay_response(&p.content);
+                    }
                     #[cfg(all(feature = "flutter", feature = "plugin_framework"))]
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     Some(misc::Union::PluginRequest(p)) => {
@@ -1909,6 +1946,12 @@ impl<T: InvokeUiSession> Remote<T> {
                     self.handler.handle_test_delay(t, peer).await;
                 }
                 Some(message::Union::AudioFrame(frame)) => {
+                    if self.last_record_state {
+                        for (_, v) in self.video_threads.iter() {
+                            let frame = Box::new(frame.clone());
+                            v.video_sender.send(MediaData::AudioFrame(frame)).ok();
+                        }
+                    }
                     if !self.handler.lc.read().unwrap().disable_audio.v {
                         self.audio_sender
                             .send(MediaData::AudioFrame(Box::new(frame)))
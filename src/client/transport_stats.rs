@@ -0,0 +1,165 @@
+use crate::kcp_stream::KcpStats;
+use hbb_common::{
+    message_proto::{message, Message},
+    protobuf::Message as _,
+};
+use serde_derive::Serialize;
+use std::collections::VecDeque;
+
+const MAX_RTT_SAMPLES: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionKind {
+    #[default]
+    Unknown,
+    /// TCP to an ip / domain, or in the LAN
+    Direct,
+    /// TCP through the punched hole
+    Punched,
+    Relayed,
+    /// KCP over IPv6
+    Ipv6,
+    /// KCP over the punched UDP hole
+    Kcp,
+}
+
+impl ConnectionKind {
+    pub fn new(stream_type: &str, direct: bool, is_local: bool) -> Self {
+        match stream_type {
+            "Relay" | "WebSocket" => Self::Relayed,
+            "IPv6" => Self::Ipv6,
+            "UDP" => Self::Kcp,
+            _ if !direct => Self::Relayed,
+            _ if is_local => Self::Direct,
+            _ => Self::Punched,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageClass {
+    Video,
+    Audio,
+    File,
+    Clipboard,
+    Other,
+}
+
+impl MessageClass {
+    pub fn of(msg: &Message) -> Self {
+        match msg.union {
+            Some(message::Union::VideoFrame(_)) => Self::Video,
+            Some(message::Union::AudioFrame(_)) => Self::Audio,
+            Some(message::Union::FileAction(_)) | Some(message::Union::FileResponse(_)) => {
+                Self::File
+            }
+            Some(message::Union::Clipboard(_))
+            | Some(message::Union::MultiClipboards(_))
+            | Some(message::Union::Cliprdr(_)) => Self::Clipboard,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ByteCount {
+    pub video: u64,
+    pub audio: u64,
+    pub file: u64,
+    pub clipboard: u64,
+    pub other: u64,
+}
+
+impl ByteCount {
+    pub fn add(&mut self, class: MessageClass, n: usize) {
+        let v = match class {
+            MessageClass::Video => &mut self.video,
+            MessageClass::Audio => &mut self.audio,
+            MessageClass::File => &mut self.file,
+            MessageClass::Clipboard => &mut self.clipboard,
+            MessageClass::Other => &mut self.other,
+        };
+        *v += n as u64;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.video + self.audio + self.file + self.clipboard + self.other
+    }
+}
+
+/// Transport statistics of a session, kept across reconnections.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TransportStats {
+    pub kind: ConnectionKind,
+    /// "TCP", "UDP", "IPv6", "Relay" or "WebSocket"
+    pub stream_type: String,
+    pub secured: bool,
+    pub connected_at: i64,
+    pub reconnects: usize,
+    /// Round trip times in ms measured by the peer, the latest last.
+    pub rtt_samples: VecDeque<u32>,
+    pub bytes_in: ByteCount,
+    pub bytes_out: ByteCount,
+    pub kcp: Option<KcpStats>,
+}
+
+impl TransportStats {
+    pub fn on_connected(&mut self, kind: ConnectionKind, stream_type: &str, secured: bool) {
+        self.kind = kind;
+        self.stream_type = stream_type.to_owned();
+        self.secured = secured;
+        self.connected_at = hbb_common::get_time();
+        self.kcp = None;
+    }
+
+    pub fn add_rtt_sample(&mut self, rtt: u32) {
+        if self.rtt_samples.len() >= MAX_RTT_SAMPLES {
+            self.rtt_samples.pop_front();
+        }
+        self.rtt_samples.push_back(rtt);
+    }
+
+    pub fn add_in(&mut self, msg: &Message, n: usize) {
+        self.bytes_in.add(MessageClass::of(msg), n);
+    }
+
+    pub fn add_out(&mut self, msg: &Message) {
+        self.bytes_out
+            .add(MessageClass::of(msg), msg.compute_size() as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transport_stats() {
+        assert_eq!(
+            ConnectionKind::new("TCP", true, false),
+            ConnectionKind::Punched
+        );
+        assert_eq!(
+            ConnectionKind::new("TCP", false, false),
+            ConnectionKind::Relayed
+        );
+        assert_eq!(ConnectionKind::new("UDP", true, false), ConnectionKind::Kcp);
+
+        let mut stats = TransportStats::default();
+        for i in 0..MAX_RTT_SAMPLES + 2 {
+            stats.add_rtt_sample(i as _);
+        }
+        assert_eq!(stats.rtt_samples.len(), MAX_RTT_SAMPLES);
+        assert_eq!(stats.rtt_samples.front(), Some(&2));
+
+        let mut msg = Message::new();
+        msg.set_audio_frame(Default::default());
+        stats.add_in(&msg, 100);
+        stats.add_in(&Message::new(), 10);
+        assert_eq!(stats.bytes_in.audio, 100);
+        assert_eq!(stats.bytes_in.other, 10);
+        assert_eq!(stats.bytes_in.total(), 110);
+    }
+}
@@ -38,6 +38,7 @@ use hbb_common::{
 };
 
 use crate::{
+    custom_server::{CustomServer, ServerBundle},
     hbbs_http::{create_http_client_async, get_url_for_tls},
     ui_interface::{get_option, is_installed, set_option},
 };
@@ -63,8 +64,56 @@ pub const PLATFORM_ANDROID: &str = "Android";
 pub const TIMER_OUT: Duration = Duration::from_secs(1);
 pub const DEFAULT_KEEP_ALIVE: i32 = 60_000;
 
+// `PortForward` has no protocol field, so udp targets are sent as "udp://host".
+// Older peers fail to resolve such a host and reject the login.
+pub const PORT_FORWARD_UDP_SCHEME: &str = "udp://";
+// A port forward session carrying many channels, see `port_forward_mux`.
+pub const PORT_FORWARD_MUX_SCHEME: &str = "mux://";
+// The peer listens on the port and tunnels the connections back, the host is left empty.
+pub const PORT_FORWARD_REVERSE_SCHEME: &str = "reverse://";
+// Off by default, a reverse tunnel opens a listener on the controlled machine.
+pub const OPTION_ALLOW_REVERSE_TUNNEL: &str = "allow-reverse-tunnel";
+
 const MIN_VER_MULTI_UI_SESSION: &str = "1.2.4";
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortForwardKind {
+    Tcp,
+    Udp,
+    Mux,
+    Reverse,
+}
+
+impl PortForwardKind {
+    /// Split the scheme off the host of a port forward target.
+    pub fn parse(host: &str) -> (Self, &str) {
+        if let Some(host) = host.strip_prefix(PORT_FORWARD_UDP_SCHEME) {
+            (Self::Udp, host)
+        } else if let Some(host) = host.strip_prefix(PORT_FORWARD_MUX_SCHEME) {
+            (Self::Mux, host)
+        } else if let Some(host) = host.strip_prefix(PORT_FORWARD_REVERSE_SCHEME) {
+            (Self::Reverse, host)
+        } else {
+            (Self::Tcp, host)
+        }
+    }
+
+    pub fn with_host(self, host: &str) -> String {
+        match self {
+            Self::Tcp => host.to_owned(),
+            Self::Udp => format!("{}{}", PORT_FORWARD_UDP_SCHEME, host),
+            Self::Mux => format!("{}{}", PORT_FORWARD_MUX_SCHEME, host),
+            Self::Reverse => format!("{}{}", PORT_FORWARD_REVERSE_SCHEME, host),
+        }
+    }
+}
+
+/// A reverse tunnel needs the tunnel permission and the local opt-in of
+/// `OPTION_ALLOW_REVERSE_TUNNEL`, the access mode does not grant it.
+pub fn is_reverse_tunnel_allowed(tunnel: bool, opt_in: &str) -> bool {
+    tunnel && config::option2bool(OPTION_ALLOW_REVERSE_TUNNEL, opt_in)
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct ServerConfigData {
     pub id_server: String,
@@ -300,6 +349,7 @@ lazy_static::lazy_static! {
     pub static ref DEVICE_ID: Arc<Mutex<String>> = Default::default();
     pub static ref DEVICE_NAME: Arc<Mutex<String>> = Default::default();
     static ref PUBLIC_IPV6_ADDR: Arc<Mutex<(Option<SocketAddr>, Option<Instant>)>> = Default::default();
+    static ref BUNDLED_SERVER: Arc<RwLock<Option<CustomServer>>> = Default::default();
 }
 
 lazy_static::lazy_static! {
@@ -1996,6 +2046,12 @@ pub fn rustdesk_interval(i: Interval) -> ThrottledInterval {
 }
 
 pub fn load_custom_client() {
+    load_custom_txt();
+    // Last, so that the settings of custom.txt can't replace the server it locks.
+    load_server_bundle();
+}
+
+fn load_custom_txt() {
     #[cfg(debug_assertions)]
     if let Ok(data) = std::fs::read_to_string("./custom.txt") {
         read_custom_client(data.trim());
@@ -2017,6 +2073,56 @@ pub fn load_custom_client() {
     }
 }
 
+/// Also called by the service when a new bundle is installed, see `--config`.
+pub fn load_server_bundle() {
+    if crate::custom_server::get_server_bundle_pk().is_none() {
+        return;
+    }
+    let Some(path) = get_server_bundle_path() else {
+        return;
+    };
+    let Ok(data) = std::fs::read_to_string(&path) else {
+        log::error!("Failed to read server bundle {:?}", path);
+        return;
+    };
+    match crate::custom_server::get_server_bundle(&data) {
+        Ok(bundle) => apply_server_bundle(bundle),
+        Err(err) => log::error!("Invalid server bundle: {}", err),
+    }
+}
+
+pub fn get_server_bundle_path() -> Option<std::path::PathBuf> {
+    let path = std::env::current_exe().ok()?.parent()?.to_path_buf();
+    #[cfg(target_os = "macos")]
+    let path = path.join("../Resources");
+    Some(path.join(crate::custom_server::SERVER_BUNDLE_FILE))
+}
+
+fn apply_server_bundle(bundle: ServerBundle) {
+    let mut overwrite_settings = config::OVERWRITE_SETTINGS.write().unwrap();
+    // Locked even if empty, so they can't point to another server.
+    for (k, v) in [
+        ("custom-rendezvous-server", &bundle.server.host),
+        ("key", &bundle.server.key),
+        ("api-server", &bundle.server.api),
+        ("relay-server", &bundle.server.relay),
+    ] {
+        overwrite_settings.insert(k.to_owned(), v.clone());
+    }
+    overwrite_settings.extend(bundle.permissions);
+    config::DEFAULT_SETTINGS
+        .write()
+        .unwrap()
+        .extend(bundle.options);
+    log::info!("Server bundle loaded, host: {}", bundle.server.host);
+    *BUNDLED_SERVER.write().unwrap() = Some(bundle.server);
+}
+
+#[inline]
+pub fn get_bundled_server() -> Option<CustomServer> {
+    BUNDLED_SERVER.read().unwrap().clone()
+}
+
 fn read_custom_client_advanced_settings(
     settings: serde_json::Value,
     map_display_settings: &HashMap<String, &&str>,
@@ -2414,15 +2520,18 @@ pub async fn test_ipv6() -> Option<tokio::task::JoinHandle<()>> {
     }))
 }
 
+/// `payload` is sent as the punch packets. Returns the first non-empty packet received if
+/// `listen`, otherwise the first packet received.
 pub async fn punch_udp(
     socket: Arc<UdpSocket>,
     listen: bool,
-) -> ResultType<Option<bytes::BytesMut>> {
+    payload: &[u8],
+) -> ResultType<bytes::BytesMut> {
     let mut retry_interval = Duration::from_millis(20);
     const MAX_INTERVAL: Duration = Duration::from_millis(200);
     const MAX_TIME: Duration = Duration::from_secs(20);
     let mut packets_sent = 0;
-    socket.send(&[]).await.ok();
+    socket.send(payload).await.ok();
     packets_sent += 1;
     let mut last_send_time = Instant::now();
     let tm = Instant::now();
@@ -2437,7 +2546,7 @@ pub async fn punch_udp(
                 let elapsed = last_send_time.elapsed();
 
                 if elapsed >= retry_interval {
-                    socket.send(&[]).await.ok();
+                    socket.send(payload).await.ok();
                     packets_sent += 1;
 
                     // Exponentially increase interval to reduce network pressure
@@ -2452,13 +2561,10 @@ pub async fn punch_udp(
                 Err(e) => bail!("UDP punch failed, {packets_sent} packets sent: {e}"),
                 Ok(n) => {
                     // log::debug!("UDP punch succeeded after sending {} packets after {:?}", packets_sent, tm.elapsed());
-                    if listen {
-                        if n == 0 {
-                            continue;
-                        }
-                        return Ok(Some(bytes::BytesMut::from(&data[..n])));
+                    if listen && n == 0 {
+                        continue;
                     }
-                    return Ok(None);
+                    return Ok(bytes::BytesMut::from(&data[..n]));
                 }
             }
         }
@@ -2534,6 +2640,98 @@ pub fn get_control_permission(
     }
 }
 
+/// Check a port forward target against the comma separated rules of
+/// `port-forward-allowed-targets`, empty rules allow everything.
+///
+/// A rule is `host`, `host:port` or `host:port-port`. The host may be a name, `*.suffix`,
+/// `*`, an ip or a cidr, ipv6 must be bracketed when a port is given, e.g. `[fd00::/8]:22`.
+/// Names are not resolved here, the server also checks the addresses a name resolves to.
+pub fn is_port_forward_target_allowed(rules: &str, host: &str, port: i32) -> bool {
+    use cidr_utils::cidr::IpCidr;
+    use std::{net::IpAddr, str::FromStr};
+
+    fn split_rule(rule: &str) -> (&str, Option<&str>) {
+        if let Some(rest) = rule.strip_prefix('[') {
+            if let Some((host, ports)) = rest.split_once(']') {
+                return (host, ports.strip_prefix(':'));
+            }
+        }
+        match rule.rsplit_once(':') {
+            Some((host, ports)) if !host.contains(':') => (host, Some(ports)),
+            _ => (rule, None),
+        }
+    }
+
+    fn port_matches(ports: Option<&str>, port: i32) -> bool {
+        let Some(ports) = ports else {
+            return true;
+        };
+        if ports == "*" {
+            return true;
+        }
+        match ports.split_once('-') {
+            Some((min, max)) => match (min.parse::<i32>(), max.parse::<i32>()) {
+                (Ok(min), Ok(max)) => min <= port && port <= max,
+                _ => false,
+            },
+            None => ports.parse::<i32>().map_or(false, |p| p == port),
+        }
+    }
+
+    let rules: Vec<&str> = rules
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|x| !x.is_empty())
+        .collect();
+    if rules.is_empty() {
+        return true;
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let ip = IpAddr::from_str(host).ok();
+    rules.iter().any(|rule| {
+        let (rule_host, ports) = split_rule(rule);
+        if !port_matches(ports, port) {
+            return false;
+        }
+        if rule_host == "*" {
+            return true;
+        }
+        if let Some(ip) = ip {
+            if let Ok(cidr) = IpCidr::from_str(rule_host) {
+                return cidr.contains(ip);
+            }
+        }
+        if let Some(suffix) = rule_host.strip_prefix("*.") {
+            let host = host.to_lowercase();
+            let suffix = suffix.to_lowercase();
+            return host.ends_with(&format!(".{}", suffix));
+        }
+        rule_host.eq_ignore_ascii_case(host)
+    })
+}
+
+/// Check the port a reverse tunnel listens on against the comma separated rules of
+/// `reverse-tunnel-allowed-ports`, each `port` or `port-port`.
+/// Empty rules allow the unprivileged ports only.
+pub fn is_reverse_tunnel_port_allowed(rules: &str, port: i32) -> bool {
+    if port <= 0 || port > u16::MAX as i32 {
+        return false;
+    }
+    let rules: Vec<&str> = rules
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|x| !x.is_empty())
+        .collect();
+    if rules.is_empty() {
+        return port >= 1024;
+    }
+    rules.iter().any(|rule| match rule.split_once('-') {
+        Some((min, max)) => match (min.parse::<i32>(), max.parse::<i32>()) {
+            (Ok(min), Ok(max)) => min <= port && port <= max,
+            _ => false,
+        },
+        None => rule.parse::<i32>().map_or(false, |p| p == port),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2729,4 +2927,49 @@ mod tests {
         assert_eq!(combined_mask & MOUSE_TYPE_MASK, MOUSE_TYPE_DOWN);
         assert_eq!(combined_mask >> 3, MOUSE_BUTTON_LEFT | MOUSE_BUTTON_RIGHT);
     }
+
+    #[test]
+    fn test_reverse_tunnel_allowed() {
+        assert!(is_reverse_tunnel_allowed(true, "Y"));
+        // off unless opted in
+        assert!(!is_reverse_tunnel_allowed(true, ""));
+        assert!(!is_reverse_tunnel_allowed(true, "N"));
+        assert!(!is_reverse_tunnel_allowed(false, "Y"));
+        assert_eq!(
+            PortForwardKind::parse("reverse://10.0.0.2"),
+            (PortForwardKind::Reverse, "10.0.0.2")
+        );
+        assert_eq!(
+            PortForwardKind::Reverse.with_host(""),
+            PORT_FORWARD_REVERSE_SCHEME
+        );
+    }
+
+    #[test]
+    fn test_port_forward_target_allowed() {
+        assert!(is_port_forward_target_allowed("", "10.0.0.1", 22));
+        let rules = "10.0.0.0/8:22, *.corp.lan:80-443, db.lan, [fd00::/8]:5432";
+        assert!(is_port_forward_target_allowed(rules, "10.1.2.3", 22));
+        assert!(!is_port_forward_target_allowed(rules, "10.1.2.3", 23));
+        assert!(!is_port_forward_target_allowed(rules, "192.168.1.1", 22));
+        assert!(is_port_forward_target_allowed(rules, "Web.Corp.lan", 443));
+        assert!(!is_port_forward_target_allowed(rules, "corp.lan", 443));
+        assert!(is_port_forward_target_allowed(rules, "db.lan", 3306));
+        assert!(is_port_forward_target_allowed(rules, "[fd00::1]", 5432));
+        assert!(!is_port_forward_target_allowed(rules, "fe80::1", 5432));
+        // names are not resolved against ip rules
+        assert!(!is_port_forward_target_allowed("127.0.0.1", "localhost", 22));
+        assert!(is_port_forward_target_allowed("*:3389", "localhost", 3389));
+    }
+
+    #[test]
+    fn test_reverse_tunnel_port_allowed() {
+        assert!(is_reverse_tunnel_port_allowed("", 8080));
+        assert!(!is_reverse_tunnel_port_allowed("", 80));
+        assert!(!is_reverse_tunnel_port_allowed("", 70000));
+        let rules = "80, 9000-9100";
+        assert!(is_reverse_tunnel_port_allowed(rules, 80));
+        assert!(is_reverse_tunnel_port_allowed(rules, 9050));
+        assert!(!is_reverse_tunnel_port_allowed(rules, 8080));
+    }
 }
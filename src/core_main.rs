@@ -6,7 +6,7 @@ use crate::platform::breakdown_callback;
 #[cfg(not(debug_assertions))]
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use hbb_common::platform::register_breakdown_handler;
-use hbb_common::{config, log};
+use hbb_common::{bail, config, log, ResultType};
 #[cfg(windows)]
 use tauri_winrt_notification::{Duration, Sound, Toast};
 
@@ -228,6 +228,11 @@ pub fn core_main() -> Option<Vec<String>> {
                     .show()
                     .ok();
                 return None;
+            } else if args[0] == "--update-rollback" {
+                if args.len() == 2 {
+                    crate::updater::run_rollback_watchdog(&args[1]);
+                }
+                return None;
             } else if args[0] == "--after-install" {
                 if let Err(err) = platform::run_after_install() {
                     log::error!("Failed to after-install: {}", err);
@@ -453,6 +458,59 @@ pub fn core_main() -> Option<Vec<String>> {
         } else if args[0] == "--get-id" {
             println!("{}", crate::ipc::get_id());
             return None;
+        } else if args[0] == "--export-audit-journal" {
+            if args.len() == 2 {
+                if is_root() {
+                    match crate::ipc::export_audit_journal()
+                        .and_then(|journal| Ok(std::fs::write(&args[1], journal)?))
+                    {
+                        Ok(_) => println!("Done!"),
+                        Err(err) => println!("{err}"),
+                    }
+                } else {
+                    println!("Administrative privileges required!");
+                }
+            }
+            return None;
+        } else if args[0] == "--dry-run-permission-policy" {
+            // <file> <peer id> [ip] ["YYYY-MM-DD HH:MM"]
+            if args.len() >= 3 {
+                let arg = |i: usize| args.get(i).map(|x| x.as_str()).unwrap_or_default();
+                match crate::server::permission_policy::dry_run(
+                    std::path::Path::new(&args[1]),
+                    &args[2],
+                    arg(3),
+                    arg(4),
+                ) {
+                    Ok(out) => print!("{out}"),
+                    Err(err) => println!("{err}"),
+                }
+            }
+            return None;
+        } else if args[0] == "--simulate-qos" {
+            // <trace file> [base kbps]
+            #[cfg(feature = "qos_simulator")]
+            {
+                if args.len() >= 2 {
+                    let base_kbps = args.get(2).and_then(|x| x.parse().ok());
+                    match crate::server::qos_simulator::simulate_file(&args[1], base_kbps) {
+                        Ok(out) => print!("{out}"),
+                        Err(err) => {
+                            eprintln!("{err}");
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    eprintln!("Usage: --simulate-qos <trace file> [base kbps]");
+                    std::process::exit(1);
+                }
+                return None;
+            }
+            #[cfg(not(feature = "qos_simulator"))]
+            {
+                eprintln!("This build lacks the qos_simulator feature");
+                std::process::exit(1);
+            }
         } else if args[0] == "--set-id" {
             if config::is_disable_settings() {
                 println!("Settings are disabled!");
@@ -476,7 +534,18 @@ pub fn core_main() -> Option<Vec<String>> {
             }
             return None;
         } else if args[0] == "--config" {
-            if args.len() == 2 && !args[0].contains("host=") {
+            if args.len() == 2 && crate::custom_server::get_server_bundle_pk().is_some() {
+                if crate::platform::is_installed() && is_root() {
+                    // signed bundle, or the path of it
+                    let data = std::fs::read_to_string(&args[1]).unwrap_or(args[1].clone());
+                    match install_server_bundle(&data) {
+                        Ok(_) => println!("Done!"),
+                        Err(err) => println!("{}", err),
+                    }
+                } else {
+                    println!("Installation and administrative privileges required!");
+                }
+            } else if args.len() == 2 && !args[0].contains("host=") {
                 if crate::platform::is_installed() && is_root() {
                     // encrypted string used in renaming exe.
                     let name = if args[1].ends_with(".exe") {
@@ -516,6 +585,67 @@ pub fn core_main() -> Option<Vec<String>> {
                 println!("Installation and administrative privileges required!");
             }
             return None;
+        } else if args[0] == "--access-grant" {
+            // list | revoke <id> | add [--peer <id>] [--token <token>] [--permissions <a,b>] --minutes <n>
+            if !(crate::platform::is_installed() && is_root()) {
+                println!("Installation and administrative privileges required!");
+                return None;
+            }
+            use crate::server::access_grant::GrantUpdate;
+            let get_value = |c: &str| {
+                args.iter()
+                    .position(|x| x == c)
+                    .and_then(|pos| args.get(pos + 1))
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            let res = match args.get(1).map(|x| x.as_str()) {
+                Some("list") => crate::ipc::get_access_grants(),
+                Some("revoke") if args.len() == 3 => {
+                    crate::ipc::update_access_grants(GrantUpdate::Revoke(args[2].clone()))
+                }
+                Some("add") => crate::ipc::update_access_grants(GrantUpdate::Add {
+                    peer_id: get_value("--peer"),
+                    token: get_value("--token"),
+                    permissions: get_value("--permissions")
+                        .split(',')
+                        .filter(|x| !x.is_empty())
+                        .map(|x| x.trim().to_owned())
+                        .collect(),
+                    minutes: get_value("--minutes").parse().unwrap_or_default(),
+                }),
+                _ => {
+                    println!("Invalid arguments");
+                    return None;
+                }
+            };
+            match res {
+                Ok(grants) => {
+                    for g in grants {
+                        let expiry = chrono::DateTime::from_timestamp_millis(g.expiry)
+                            .map(|t| t.with_timezone(&chrono::Local).format("%F %R").to_string())
+                            .unwrap_or_default();
+                        println!(
+                            "{} peer: {} token: {} permissions: {} expiry: {}",
+                            g.id,
+                            if g.peer_id.is_empty() {
+                                "*"
+                            } else {
+                                &g.peer_id
+                            },
+                            if g.token_h1.is_empty() { "no" } else { "yes" },
+                            if g.permissions.is_empty() {
+                                "*".to_owned()
+                            } else {
+                                g.permissions.join(",")
+                            },
+                            expiry
+                        );
+                    }
+                }
+                Err(err) => println!("{err}"),
+            }
+            return None;
         } else if args[0] == "--assign" {
             if config::Config::no_register_device() {
                 println!("Cannot assign an unregistrable device!");
@@ -703,6 +833,23 @@ fn init_plugins(args: &Vec<String>) {
     }
 }
 
+// Saved next to the executable to be loaded on start, the running service reloads it now,
+// so the permissions are locked rather than set as options the user could change.
+fn install_server_bundle(data: &str) -> ResultType<()> {
+    let bundle = crate::custom_server::get_server_bundle(data)?;
+    let Some(path) = crate::common::get_server_bundle_path() else {
+        bail!("Failed to get the path of the server bundle");
+    };
+    std::fs::write(&path, data.trim())?;
+    crate::ipc::set_option("key", &bundle.server.key);
+    crate::ipc::set_option("custom-rendezvous-server", &bundle.server.host);
+    crate::ipc::set_option("api-server", &bundle.server.api);
+    crate::ipc::set_option("relay-server", &bundle.server.relay);
+    // Loaded on start if the service is not running.
+    hbb_common::allow_err!(crate::ipc::set_config("server-bundle", "".to_owned()));
+    Ok(())
+}
+
 fn import_config(path: &str) {
     use hbb_common::{config::*, get_exe_time, get_modified_time};
     let path2 = path.replace(".toml", "2.toml");
@@ -1,10 +1,45 @@
 use hbb_common::{
     bail,
-    base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _},
+    base64::{
+        engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+        Engine as _,
+    },
+    config::keys,
     sodiumoxide::crypto::sign,
     ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Looked up next to the executable, like `custom.txt`.
+pub const SERVER_BUNDLE_FILE: &str = "server-bundle.txt";
+
+// Base64 public key the bundles are signed for, set when building for a deployment.
+// With it, the server can no longer be changed by renaming the executable.
+const SERVER_BUNDLE_PK: Option<&str> = option_env!("RUSTDESK_SERVER_BUNDLE_PK");
+
+// Options a bundle may lock as permission presets.
+const PERMISSION_OPTIONS: &[&str] = &[
+    "access-mode",
+    "approve-mode",
+    "verification-method",
+    "allow-remote-config-modification",
+    "allow-reverse-tunnel",
+    "reverse-tunnel-allowed-ports",
+    "allow-wol-relay",
+    keys::OPTION_ENABLE_KEYBOARD,
+    keys::OPTION_ENABLE_REMOTE_PRINTER,
+    keys::OPTION_ENABLE_CLIPBOARD,
+    keys::OPTION_ENABLE_FILE_TRANSFER,
+    keys::OPTION_ENABLE_FILE_COPY_PASTE,
+    keys::OPTION_ENABLE_AUDIO,
+    keys::OPTION_ENABLE_CAMERA,
+    keys::OPTION_ENABLE_TERMINAL,
+    keys::OPTION_ENABLE_TUNNEL,
+    keys::OPTION_ENABLE_REMOTE_RESTART,
+    keys::OPTION_ENABLE_RECORD_SESSION,
+    keys::OPTION_ENABLE_BLOCK_INPUT,
+];
 
 #[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
 pub struct CustomServer {
@@ -18,6 +53,62 @@ pub struct CustomServer {
     pub relay: String,
 }
 
+/// Signed deployment configuration, see `naming sign`.
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize, Clone)]
+pub struct ServerBundle {
+    #[serde(flatten)]
+    pub server: CustomServer,
+    /// Initial values of server options, can be changed by the user.
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+    /// Locked server options, only permission related ones are accepted.
+    #[serde(default)]
+    pub permissions: HashMap<String, String>,
+}
+
+impl ServerBundle {
+    fn check(&self) -> ResultType<()> {
+        if self.server.host.is_empty() {
+            bail!("No host in server bundle");
+        }
+        for k in self.permissions.keys() {
+            if !PERMISSION_OPTIONS.contains(&k.as_str()) {
+                bail!("Not a permission option: {}", k);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn get_server_bundle_pk() -> Option<sign::PublicKey> {
+    let pk = STANDARD.decode(SERVER_BUNDLE_PK?.trim()).ok()?;
+    sign::PublicKey::from_slice(&pk)
+}
+
+pub fn sign_server_bundle(bundle: &ServerBundle, sk: &sign::SecretKey) -> ResultType<String> {
+    bundle.check()?;
+    let data = serde_json::to_vec(bundle)?;
+    Ok(URL_SAFE_NO_PAD.encode(sign::sign(&data, sk)))
+}
+
+pub fn verify_server_bundle(s: &str, pk: &sign::PublicKey) -> ResultType<ServerBundle> {
+    let data = URL_SAFE_NO_PAD.decode(s.trim())?;
+    let Ok(data) = sign::verify(&data, pk) else {
+        bail!("Server bundle signature mismatch");
+    };
+    let bundle = serde_json::from_slice::<ServerBundle>(&data)?;
+    bundle.check()?;
+    Ok(bundle)
+}
+
+/// Verify against the embedded public key.
+pub fn get_server_bundle(s: &str) -> ResultType<ServerBundle> {
+    let Some(pk) = get_server_bundle_pk() else {
+        bail!("No server bundle public key built in");
+    };
+    verify_server_bundle(s, &pk)
+}
+
 fn get_custom_server_from_config_string(s: &str) -> ResultType<CustomServer> {
     let tmp: String = s.chars().rev().collect();
     const PK: &[u8; 32] = &[
@@ -37,6 +128,9 @@ fn get_custom_server_from_config_string(s: &str) -> ResultType<CustomServer> {
 }
 
 pub fn get_custom_server_from_string(s: &str) -> ResultType<CustomServer> {
+    if get_server_bundle_pk().is_some() {
+        bail!("Only signed server bundles are accepted");
+    }
     let s = if s.to_lowercase().ends_with(".exe.exe") {
         &s[0..s.len() - 8]
     } else if s.to_lowercase().ends_with(".exe") {
@@ -111,6 +205,44 @@ pub fn get_custom_server_from_string(s: &str) -> ResultType<CustomServer> {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_server_bundle() {
+        let (pk, sk) = sign::gen_keypair();
+        let mut bundle = ServerBundle {
+            server: CustomServer {
+                host: "server.example.net".to_owned(),
+                key: "Zm9vYmFyLiwyCg==".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        bundle
+            .options
+            .insert("enable-lan-discovery".to_owned(), "N".to_owned());
+        bundle
+            .permissions
+            .insert("enable-file-transfer".to_owned(), "N".to_owned());
+        let signed = sign_server_bundle(&bundle, &sk).unwrap();
+        assert_eq!(verify_server_bundle(&signed, &pk).unwrap(), bundle);
+
+        let (other_pk, _) = sign::gen_keypair();
+        assert!(verify_server_bundle(&signed, &other_pk).is_err());
+        let mut data = URL_SAFE_NO_PAD.decode(&signed).unwrap();
+        let n = data.len();
+        data[n - 2] ^= 1;
+        assert!(verify_server_bundle(&URL_SAFE_NO_PAD.encode(data), &pk).is_err());
+
+        for k in [
+            "custom-rendezvous-server",
+            "allow-auto-update",
+            "enable-lan-discovery",
+        ] {
+            let mut bundle = bundle.clone();
+            bundle.permissions.insert(k.to_owned(), "Y".to_owned());
+            assert!(sign_server_bundle(&bundle, &sk).is_err(), "{}", k);
+        }
+    }
+
     #[test]
     fn test_filename_license_string() {
         assert!(get_custom_server_from_string("rustdesk.exe").is_err());
@@ -732,6 +732,10 @@ impl InvokeUiSession for FlutterHandler {
         );
     }
 
+    fn update_transport_stats(&self, stats: String) {
+        self.push_event("update_transport_stats", &[("stats", &stats)], &[]);
+    }
+
     fn set_connection_type(&self, is_secured: bool, direct: bool, stream_type: &str) {
         self.push_event(
             "connection_ready",
@@ -515,6 +515,14 @@ pub fn session_get_custom_image_quality(session_id: SessionID) -> Option<Vec<i32
     }
 }
 
+pub fn session_get_transport_stats(session_id: SessionID) -> Option<String> {
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        Some(session.get_transport_stats())
+    } else {
+        None
+    }
+}
+
 pub fn session_is_keyboard_mode_supported(session_id: SessionID, mode: String) -> SyncReturn<bool> {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         SyncReturn(session.is_keyboard_mode_supported(mode))
@@ -2000,6 +2008,21 @@ pub fn session_restart_remote_device(session_id: SessionID) {
     }
 }
 
+pub fn session_wake_peer(session_id: SessionID, id: String) {
+    #[cfg(not(target_os = "ios"))]
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        session.wake_peer(id);
+    }
+}
+
+pub fn session_get_wol_targets(session_id: SessionID) -> SyncReturn<String> {
+    #[cfg(not(target_os = "ios"))]
+    if let Some(session) = sessions::get_session_by_session_id(&session_id) {
+        return SyncReturn(serde_json::to_string(&session.get_wol_targets()).unwrap_or_default());
+    }
+    SyncReturn("[]".to_owned())
+}
+
 pub fn session_get_audit_server_sync(session_id: SessionID, typ: String) -> SyncReturn<String> {
     let res = if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         session.get_audit_server(typ)
@@ -2048,7 +2071,13 @@ pub fn session_get_conn_session_id(session_id: SessionID) -> SyncReturn<String>
 pub fn session_alternative_codecs(session_id: SessionID) -> String {
     if let Some(session) = sessions::get_session_by_session_id(&session_id) {
         let (vp8, av1, h264, h265) = session.alternative_codecs();
-        let msg = HashMap::from([("vp8", vp8), ("av1", av1), ("h264", h264), ("h265", h265)]);
+        let msg = HashMap::from([
+            ("vp8", vp8),
+            ("av1", av1),
+            ("h264", h264),
+            ("h265", h265),
+            ("screen_content", av1 && session.screen_content_supported()),
+        ]);
         serde_json::ser::to_string(&msg).unwrap_or("".to_owned())
     } else {
         String::new()
@@ -2155,6 +2184,10 @@ pub fn main_wol(id: String) {
     crate::lan::send_wol(id)
 }
 
+pub fn main_get_wol_relays(id: String) -> SyncReturn<String> {
+    SyncReturn(serde_json::to_string(&crate::lan::find_wol_relays(&id)).unwrap_or_default())
+}
+
 pub fn main_create_shortcut(_id: String) {
     #[cfg(windows)]
     create_shortcut(_id);
@@ -2201,6 +2234,16 @@ pub fn cm_switch_permission(conn_id: i32, name: String, enabled: bool) {
     crate::ui_cm_interface::switch_permission(conn_id, name, enabled)
 }
 
+pub fn cm_grant_access(conn_id: i32, minutes: u64) {
+    #[cfg(not(any(target_os = "ios")))]
+    crate::ui_cm_interface::grant_access(conn_id, minutes)
+}
+
+pub fn cm_revoke_access(conn_id: i32) {
+    #[cfg(not(any(target_os = "ios")))]
+    crate::ui_cm_interface::revoke_access(conn_id)
+}
+
 pub fn cm_can_elevate() -> SyncReturn<bool> {
     SyncReturn(crate::ui_cm_interface::can_elevate())
 }
@@ -2725,6 +2768,18 @@ pub fn main_verify2fa(code: String) -> bool {
     verify2fa(code)
 }
 
+pub fn main_take_2fa_recovery_codes() -> String {
+    take_2fa_recovery_codes()
+}
+
+pub fn main_regenerate_2fa_recovery_codes() -> String {
+    regenerate_2fa_recovery_codes()
+}
+
+pub fn main_get_record_uploads() -> String {
+    get_record_uploads()
+}
+
 pub fn main_has_valid_2fa_sync() -> SyncReturn<bool> {
     SyncReturn(has_valid_2fa())
 }
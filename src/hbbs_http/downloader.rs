@@ -1,23 +1,46 @@
 use super::create_http_client_async_with_url;
 use hbb_common::{
     bail,
+    futures::future::try_join_all,
     lazy_static::lazy_static,
     log,
+    sha2::{Digest, Sha256},
     tokio::{
         self,
-        fs::File,
-        io::AsyncWriteExt,
+        fs::{File, OpenOptions},
+        io::{AsyncSeekExt, AsyncWriteExt},
         sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     },
     ResultType,
 };
-use serde_derive::Serialize;
-use std::{collections::HashMap, path::PathBuf, sync::Mutex, time::Duration};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{Read, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 lazy_static! {
     static ref DOWNLOADERS: Mutex<HashMap<String, Downloader>> = Default::default();
 }
 
+// Segments smaller than this are not worth another connection.
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+const MAX_SEGMENTS: usize = 8;
+const SAVE_STATE_INTERVAL: Duration = Duration::from_secs(1);
+// The written data is synced to the disk in batches of this size before it counts as done.
+const SYNC_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// Hex SHA-256 the downloaded content must match.
+    pub sha256: Option<String>,
+    /// Parallel range requests of a file download, if the server accepts ranges.
+    pub segments: usize,
+}
+
 /// This struct is used to return the download data to the caller.
 /// The caller should check if the file is downloaded successfully and remove the job from the map.
 /// If the file is not downloaded successfully, the `data` field will be empty.
@@ -46,11 +69,122 @@ struct Downloader {
     tx_cancel: UnboundedSender<()>,
 }
 
+/// Progress of a file download, kept next to it as `<file>.part.json`,
+/// so that the download can be resumed after a restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+struct PartialState {
+    url: String,
+    total_size: u64,
+    // ETag or Last-Modified, to not mix two versions of the file
+    #[serde(default)]
+    validator: Option<String>,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+struct Segment {
+    start: u64,
+    // exclusive
+    end: u64,
+    done: u64,
+}
+
+impl Segment {
+    fn is_done(&self) -> bool {
+        self.start + self.done >= self.end
+    }
+}
+
+impl PartialState {
+    fn new(url: &str, total_size: u64, validator: Option<String>, segments: usize) -> Self {
+        let n = (total_size / MIN_SEGMENT_SIZE).clamp(1, segments.clamp(1, MAX_SEGMENTS) as u64);
+        let len = total_size / n;
+        let segments = (0..n)
+            .map(|i| Segment {
+                start: i * len,
+                end: if i == n - 1 {
+                    total_size
+                } else {
+                    (i + 1) * len
+                },
+                done: 0,
+            })
+            .collect();
+        Self {
+            url: url.to_owned(),
+            total_size,
+            validator,
+            segments,
+        }
+    }
+
+    fn load(path: &Path) -> Option<Self> {
+        serde_json::from_slice(&std::fs::read(state_path(path)).ok()?).ok()
+    }
+
+    fn save(&self, path: &Path) {
+        if let Ok(v) = serde_json::to_vec(self) {
+            std::fs::write(state_path(path), v).ok();
+        }
+    }
+
+    fn downloaded_size(&self) -> u64 {
+        self.segments.iter().map(|s| s.done).sum()
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".part")
+}
+
+fn state_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".part.json")
+}
+
+fn remove_partial(path: &Path) {
+    std::fs::remove_file(part_path(path)).ok();
+    std::fs::remove_file(state_path(path)).ok();
+}
+
+fn check_sha256(data: &mut impl Read, sha256: &str) -> ResultType<()> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = data.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let digest = hex::encode(hasher.finalize());
+    if !digest.eq_ignore_ascii_case(sha256.trim()) {
+        bail!("SHA-256 mismatch, expected {}, got {}", sha256, digest);
+    }
+    Ok(())
+}
+
 // The caller should check if the file is downloaded successfully and remove the job from the map.
 pub fn download_file(
     url: String,
     path: Option<PathBuf>,
     auto_del_dur: Option<Duration>,
+) -> ResultType<String> {
+    download_file_with_options(url, path, auto_del_dur, Default::default())
+}
+
+/// Like `download_file`, a file download resumes the partial one of a previous run
+/// and is removed if it doesn't match `options.sha256`.
+pub fn download_file_with_options(
+    url: String,
+    path: Option<PathBuf>,
+    auto_del_dur: Option<Duration>,
+    options: DownloadOptions,
 ) -> ResultType<String> {
     let id = url.clone();
     // First pass: if a non-error downloader exists for this URL, reuse it.
@@ -115,7 +249,7 @@ pub fn download_file(
 
     let id2 = id.clone();
     std::thread::spawn(
-        move || match do_download(&id2, url, path, auto_del_dur, rx) {
+        move || match do_download(&id2, url, path, auto_del_dur, options, rx) {
             Ok(is_all_downloaded) => {
                 let mut downloaded_size = 0;
                 let mut total_size = 0;
@@ -140,8 +274,9 @@ pub fn download_file(
                     if let Some(downloader) = DOWNLOADERS.lock().unwrap().remove(&id2) {
                         if let Some(p) = downloader.path {
                             if p.exists() {
-                                std::fs::remove_file(p).ok();
+                                std::fs::remove_file(&p).ok();
                             }
+                            remove_partial(&p);
                         }
                     }
                 }
@@ -159,90 +294,232 @@ pub fn download_file(
     Ok(id)
 }
 
+/// Downloads `url` to `path` in the calling thread, resuming a previous partial download.
+pub fn download_file_sync(url: String, path: PathBuf, options: DownloadOptions) -> ResultType<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let (_tx, rx) = unbounded_channel();
+    do_download(&url.clone(), url, Some(path), None, options, rx)?;
+    Ok(())
+}
+
+fn update_downloader(id: &str, f: impl FnOnce(&mut Downloader)) {
+    if let Some(downloader) = DOWNLOADERS.lock().unwrap().get_mut(id) {
+        f(downloader);
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn do_download(
     id: &str,
     url: String,
     path: Option<PathBuf>,
     auto_del_dur: Option<Duration>,
+    options: DownloadOptions,
     mut rx_cancel: UnboundedReceiver<()>,
 ) -> ResultType<bool> {
     let client = create_http_client_async_with_url(&url).await;
 
-    let mut is_all_downloaded = false;
+    let head;
     tokio::select! {
         _ = rx_cancel.recv() => {
-            return Ok(is_all_downloaded);
+            if let Some(p) = path.as_ref() {
+                remove_partial(p);
+            }
+            return Ok(false);
         }
         head_resp = client.head(&url).send() => {
-            match head_resp {
-                Ok(resp) => {
-                    if resp.status().is_success() {
-                        let total_size = resp
-                            .headers()
-                            .get(reqwest::header::CONTENT_LENGTH)
-                            .and_then(|ct_len| ct_len.to_str().ok())
-                            .and_then(|ct_len| ct_len.parse::<u64>().ok());
-                        let Some(total_size) = total_size else {
-                            bail!("Failed to get content length");
-                        };
-                        DOWNLOADERS.lock().unwrap().get_mut(id).map(|downloader| {
-                            downloader.total_size = Some(total_size);
-                        });
-                    } else {
-                        bail!("Failed to get content length: {}", resp.status());
-                    }
-                }
-                Err(e) => {
-                    return Err(e.into());
+            head = head_resp?;
+        }
+    }
+    if !head.status().is_success() {
+        bail!("Failed to get content length: {}", head.status());
+    }
+    let header = |name: reqwest::header::HeaderName| {
+        head.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+    };
+    let Some(total_size) =
+        header(reqwest::header::CONTENT_LENGTH).and_then(|v| v.parse::<u64>().ok())
+    else {
+        bail!("Failed to get content length");
+    };
+    let accept_ranges = header(reqwest::header::ACCEPT_RANGES).as_deref() == Some("bytes");
+    let validator =
+        header(reqwest::header::ETAG).or_else(|| header(reqwest::header::LAST_MODIFIED));
+    update_downloader(id, |d| d.total_size = Some(total_size));
+
+    let Some(path) = path else {
+        return download_to_memory(id, &client, url, auto_del_dur, options, rx_cancel).await;
+    };
+
+    let part = part_path(&path);
+    let mut state = PartialState::new(
+        &url,
+        total_size,
+        validator.clone(),
+        if accept_ranges { options.segments } else { 1 },
+    );
+    match PartialState::load(&path) {
+        Some(saved)
+            if accept_ranges
+                && saved.url == url
+                && saved.total_size == total_size
+                && saved.validator == validator
+                && part.exists() =>
+        {
+            log::info!(
+                "Resume download {} from {}/{}",
+                id,
+                saved.downloaded_size(),
+                total_size
+            );
+            state = saved;
+        }
+        _ => {
+            let f = File::create(&part).await?;
+            f.set_len(total_size).await?;
+        }
+    }
+    state.save(&path);
+    update_downloader(id, |d| d.downloaded_size = state.downloaded_size());
+
+    let state = Arc::new(Mutex::new(state));
+    let segments = state.lock().unwrap().segments.clone();
+    let jobs = segments
+        .into_iter()
+        .enumerate()
+        .filter(|(_, s)| !s.is_done())
+        .map(|(i, _)| download_segment(id, &client, &url, &part, i, accept_ranges, state.clone()));
+    let mut jobs = Box::pin(try_join_all(jobs));
+    let mut interval = tokio::time::interval(SAVE_STATE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = rx_cancel.recv() => {
+                remove_partial(&path);
+                return Ok(false);
+            }
+            res = &mut jobs => {
+                if let Err(e) = res {
+                    // Keep the partial download to resume it.
+                    state.lock().unwrap().save(&path);
+                    return Err(e);
                 }
+                break;
+            }
+            _ = interval.tick() => {
+                state.lock().unwrap().save(&path);
             }
         }
     }
 
+    if let Some(sha256) = options.sha256.as_ref() {
+        if let Err(e) = check_sha256(&mut std::fs::File::open(&part)?, sha256) {
+            remove_partial(&path);
+            return Err(e);
+        }
+    }
+    std::fs::rename(&part, &path)?;
+    std::fs::remove_file(state_path(&path)).ok();
+    finish(id, auto_del_dur);
+    Ok(true)
+}
+
+async fn download_segment(
+    id: &str,
+    client: &reqwest::Client,
+    url: &str,
+    part: &Path,
+    index: usize,
+    accept_ranges: bool,
+    state: Arc<Mutex<PartialState>>,
+) -> ResultType<()> {
+    let mut segment = state.lock().unwrap().segments[index];
+    let mut request = client.get(url);
+    if accept_ranges {
+        request = request.header(
+            reqwest::header::RANGE,
+            format!("bytes={}-{}", segment.start + segment.done, segment.end - 1),
+        );
+    }
+    let mut response = request.send().await?;
+    let status = response.status();
+    let is_partial = status == reqwest::StatusCode::PARTIAL_CONTENT;
+    if !status.is_success() || (accept_ranges && !is_partial) {
+        bail!("Failed to download segment {}: {}", index, status);
+    }
+    let mut f = OpenOptions::new().write(true).open(part).await?;
+    f.seek(SeekFrom::Start(segment.start + segment.done))
+        .await?;
+    let mut synced = segment.done;
+    while let Some(chunk) = response.chunk().await? {
+        let n = (chunk.len() as u64).min(segment.end - segment.start - segment.done);
+        f.write_all(&chunk[..n as usize]).await?;
+        segment.done += n;
+        update_downloader(id, |d| d.downloaded_size += n);
+        if segment.is_done() {
+            break;
+        }
+        if segment.done - synced >= SYNC_SIZE {
+            sync_segment(&mut f, &state, index, segment.done).await?;
+            synced = segment.done;
+        }
+    }
+    sync_segment(&mut f, &state, index, segment.done).await?;
+    if !segment.is_done() {
+        bail!("Download of segment {} ended early", index);
+    }
+    Ok(())
+}
+
+// The state is saved with the progress only once the data is on the disk, so a resume after
+// a crash never skips bytes which were not written.
+async fn sync_segment(
+    f: &mut File,
+    state: &Mutex<PartialState>,
+    index: usize,
+    done: u64,
+) -> ResultType<()> {
+    f.flush().await?;
+    f.sync_data().await?;
+    state.lock().unwrap().segments[index].done = done;
+    Ok(())
+}
+
+async fn download_to_memory(
+    id: &str,
+    client: &reqwest::Client,
+    url: String,
+    auto_del_dur: Option<Duration>,
+    options: DownloadOptions,
+    mut rx_cancel: UnboundedReceiver<()>,
+) -> ResultType<bool> {
     let mut response;
     tokio::select! {
         _ = rx_cancel.recv() => {
-            return Ok(is_all_downloaded);
+            return Ok(false);
         }
         resp = client.get(url).send() => {
             response = resp?;
         }
     }
 
-    let mut dest: Option<File> = None;
-    if let Some(p) = path {
-        dest = Some(File::create(p).await?);
-    }
-
+    let mut data = Vec::new();
     loop {
         tokio::select! {
             _ = rx_cancel.recv() => {
-                break;
+                return Ok(false);
             }
             chunk = response.chunk() => {
                 match chunk {
                     Ok(Some(chunk)) => {
-                        match dest {
-                            Some(ref mut f) => {
-                                f.write_all(&chunk).await?;
-                                f.flush().await?;
-                                DOWNLOADERS.lock().unwrap().get_mut(id).map(|downloader| {
-                                    downloader.downloaded_size += chunk.len() as u64;
-                                });
-                            }
-                            None => {
-                                DOWNLOADERS.lock().unwrap().get_mut(id).map(|downloader| {
-                                    downloader.data.extend_from_slice(&chunk);
-                                    downloader.downloaded_size += chunk.len() as u64;
-                                });
-                            }
-                        }
+                        data.extend_from_slice(&chunk);
+                        update_downloader(id, |d| d.downloaded_size += chunk.len() as u64);
                     }
-                    Ok(None) => {
-                        is_all_downloaded = true;
-                        break;
-                    },
+                    Ok(None) => break,
                     Err(e) => {
                         log::error!("Download {} failed: {}", id, e);
                         return Err(e.into());
@@ -251,24 +528,23 @@ async fn do_download(
             }
         }
     }
-
-    if let Some(mut f) = dest.take() {
-        f.flush().await?;
+    if let Some(sha256) = options.sha256.as_ref() {
+        check_sha256(&mut data.as_slice(), sha256)?;
     }
+    update_downloader(id, |d| d.data = data);
+    finish(id, auto_del_dur);
+    Ok(true)
+}
 
-    if let Some(ref mut downloader) = DOWNLOADERS.lock().unwrap().get_mut(id) {
-        downloader.finished = true;
+fn finish(id: &str, auto_del_dur: Option<Duration>) {
+    update_downloader(id, |d| d.finished = true);
+    let id_del = id.to_string();
+    if let Some(dur) = auto_del_dur {
+        tokio::spawn(async move {
+            tokio::time::sleep(dur).await;
+            DOWNLOADERS.lock().unwrap().remove(&id_del);
+        });
     }
-    if is_all_downloaded {
-        let id_del = id.to_string();
-        if let Some(dur) = auto_del_dur {
-            tokio::spawn(async move {
-                tokio::time::sleep(dur).await;
-                DOWNLOADERS.lock().unwrap().remove(&id_del);
-            });
-        }
-    }
-    Ok(is_all_downloaded)
 }
 
 pub fn get_download_data(id: &str) -> ResultType<DownloadData> {
@@ -307,3 +583,32 @@ pub fn cancel(id: &str) {
 pub fn remove(id: &str) {
     let _ = DOWNLOADERS.lock().unwrap().remove(id);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_state() {
+        let state = PartialState::new("u", 10 * MIN_SEGMENT_SIZE + 3, None, 4);
+        assert_eq!(state.segments.len(), 4);
+        assert_eq!(state.segments[0].start, 0);
+        assert_eq!(state.segments[3].end, 10 * MIN_SEGMENT_SIZE + 3);
+        for w in state.segments.windows(2) {
+            assert_eq!(w[0].end, w[1].start);
+        }
+        assert_eq!(PartialState::new("u", 100, None, 4).segments.len(), 1);
+        assert_eq!(PartialState::new("u", 0, None, 4).segments.len(), 1);
+        assert!(PartialState::new("u", 0, None, 4).segments[0].is_done());
+
+        let path = std::env::temp_dir().join("rustdesk-downloader-test.bin");
+        state.save(&path);
+        assert_eq!(PartialState::load(&path), Some(state));
+        remove_partial(&path);
+        assert!(PartialState::load(&path).is_none());
+
+        let hash = hex::encode(Sha256::digest(b"abc"));
+        assert!(check_sha256(&mut &b"abc"[..], &hash.to_uppercase()).is_ok());
+        assert!(check_sha256(&mut &b"abd"[..], &hash).is_err());
+    }
+}
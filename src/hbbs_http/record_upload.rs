@@ -1,95 +1,349 @@
 use crate::hbbs_http::create_http_client_with_url;
 use bytes::Bytes;
-use hbb_common::{bail, config::Config, lazy_static, log, ResultType};
+use hbb_common::{bail, config::Config, get_time, lazy_static, log, ResultType};
 use reqwest::blocking::{Body, Client};
 use scrap::record::RecordState;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Map;
 use std::{
     fs::File,
     io::{prelude::*, SeekFrom},
-    sync::{mpsc::Receiver, Arc, Mutex},
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 const MAX_HEADER_LEN: usize = 1024;
 const SHOULD_SEND_TIME: Duration = Duration::from_secs(1);
-const SHOULD_SEND_SIZE: u64 = 1024 * 1024;
+// Largest part of one request, the rest is sent in the following requests.
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const QUEUE_FILE: &str = "record_upload.json";
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Max size in MB of the recording directory, the oldest recordings are removed beyond it.
+pub const OPTION_RECORD_MAX_DISK_USAGE: &str = "record-max-disk-usage";
+/// Max age in days of the recordings.
+pub const OPTION_RECORD_MAX_AGE: &str = "record-max-age";
 
 lazy_static::lazy_static! {
     static ref ENABLE: Arc<Mutex<bool>> = Default::default();
+    static ref QUEUE: Mutex<Option<Vec<RecordUpload>>> = Default::default();
+    static ref WORKER: Mutex<Option<Sender<()>>> = Default::default();
 }
 
 pub fn is_enable() -> bool {
     ENABLE.lock().unwrap().clone()
 }
 
+/// A recording to upload, persisted so that it is resumed after a restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecordUpload {
+    pub path: String,
+    pub file: String,
+    pub size: u64,
+    /// Bytes the server has, the next part starts there.
+    pub uploaded: u64,
+    /// Still being written, the tail is sent after it is finished.
+    pub recording: bool,
+    /// The server was told about the file with "new".
+    pub announced: bool,
+    /// The recorder removed it, the server is to remove its copy.
+    pub removed: bool,
+    pub attempts: u32,
+    /// Time in ms of the next attempt after a failure.
+    pub next_retry: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+fn queue_path() -> PathBuf {
+    Config::path(QUEUE_FILE)
+}
+
+fn read_queue<T>(f: impl FnOnce(&mut Vec<RecordUpload>) -> T) -> T {
+    let mut lock = QUEUE.lock().unwrap();
+    let queue = lock.get_or_insert_with(|| {
+        std::fs::read(queue_path())
+            .ok()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .unwrap_or_default()
+    });
+    f(queue)
+}
+
+fn with_queue<T>(f: impl FnOnce(&mut Vec<RecordUpload>) -> T) -> T {
+    read_queue(|queue| {
+        let res = f(queue);
+        save_queue(queue);
+        res
+    })
+}
+
+fn save_queue(queue: &[RecordUpload]) {
+    match serde_json::to_vec(queue) {
+        Ok(v) => {
+            if let Err(e) = std::fs::write(queue_path(), v) {
+                log::error!("Failed to save the record upload queue: {}", e);
+            }
+        }
+        Err(e) => log::error!("Failed to serialize the record upload queue: {}", e),
+    }
+}
+
+fn update(path: &str, f: impl FnOnce(&mut RecordUpload)) {
+    with_queue(|queue| {
+        if let Some(upload) = queue.iter_mut().find(|x| x.path == path) {
+            f(upload);
+        }
+    });
+}
+
+/// Pending uploads, for the UI.
+pub fn get_uploads() -> Vec<RecordUpload> {
+    read_queue(|queue| queue.clone())
+}
+
+fn wake() {
+    if let Some(tx) = WORKER.lock().unwrap().as_ref() {
+        tx.send(()).ok();
+    }
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    RETRY_MIN
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(RETRY_MAX)
+}
+
+/// Starts the upload and retention worker, uploads left by the previous run are resumed.
+pub fn start() {
+    let mut worker = WORKER.lock().unwrap();
+    if worker.is_some() {
+        return;
+    }
+    let (tx, rx) = channel();
+    *worker = Some(tx);
+    // The recordings of the previous run are over, even if their tails were not written.
+    with_queue(|queue| queue.iter_mut().for_each(|x| x.recording = false));
+    std::thread::spawn(move || {
+        let mut uploader = None;
+        let mut last_retention: Option<Instant> = None;
+        loop {
+            let retention_due = match last_retention {
+                Some(t) => t.elapsed() >= RETENTION_INTERVAL,
+                None => true,
+            };
+            if retention_due {
+                // Being written or not uploaded yet
+                let (queued, recording): (Vec<String>, Vec<String>) = read_queue(|queue| {
+                    let queued = queue.iter().filter(|x| !x.removed);
+                    (
+                        queued.clone().map(|x| x.path.clone()).collect(),
+                        queued
+                            .filter(|x| x.recording)
+                            .map(|x| x.path.clone())
+                            .collect(),
+                    )
+                });
+                let max_usage = Config::get_option(OPTION_RECORD_MAX_DISK_USAGE)
+                    .parse::<u64>()
+                    .unwrap_or(0);
+                let max_age = Config::get_option(OPTION_RECORD_MAX_AGE)
+                    .parse::<u64>()
+                    .unwrap_or(0);
+                let dropped = apply_retention(
+                    Path::new(&recording_dir()),
+                    &queued,
+                    &recording,
+                    max_usage * 1024 * 1024,
+                    Duration::from_secs(max_age * 24 * 60 * 60),
+                );
+                if !dropped.is_empty() {
+                    // The server is told to remove the parts it already has.
+                    with_queue(|queue| {
+                        queue.retain(|x| !dropped.contains(&x.path) || x.announced);
+                        queue
+                            .iter_mut()
+                            .filter(|x| dropped.contains(&x.path))
+                            .for_each(|x| x.removed = true);
+                    });
+                }
+                last_retention = Some(Instant::now());
+            }
+            if !read_queue(|queue| queue.is_empty()) {
+                let uploader = uploader.get_or_insert_with(RecordUploader::new);
+                uploader.process();
+            }
+            match rx.recv_timeout(SHOULD_SEND_TIME) {
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+}
+
 pub fn run(rx: Receiver<RecordState>) {
+    start();
     std::thread::spawn(move || {
-        let api_server = crate::get_api_server(
-            Config::get_option("api-server"),
-            Config::get_option("custom-rendezvous-server"),
-        );
-        // This URL is used for TLS connectivity testing and fallback detection.
-        let login_option_url = format!("{}/api/login-options", &api_server);
-        let client = create_http_client_with_url(&login_option_url);
-        let mut uploader = RecordUploader {
-            client,
-            api_server,
-            filepath: Default::default(),
-            filename: Default::default(),
-            upload_size: Default::default(),
-            running: Default::default(),
-            last_send: Instant::now(),
-        };
+        let mut filepath = String::new();
         loop {
-            if let Err(e) = match rx.recv() {
+            match rx.recv() {
                 Ok(state) => match state {
-                    RecordState::NewFile(filepath) => uploader.handle_new_file(filepath),
-                    RecordState::NewFrame => {
-                        if uploader.running {
-                            uploader.handle_frame(false)
-                        } else {
-                            Ok(())
-                        }
+                    RecordState::NewFile(path) => {
+                        filepath = path;
+                        let file = match Path::new(&filepath).file_name() {
+                            Some(name) => name.to_string_lossy().to_string(),
+                            None => {
+                                log::error!("can't parse filepath:{}", filepath);
+                                continue;
+                            }
+                        };
+                        with_queue(|queue| {
+                            queue.push(RecordUpload {
+                                path: filepath.clone(),
+                                file,
+                                recording: true,
+                                ..Default::default()
+                            })
+                        });
+                        wake();
                     }
+                    // Parts are sent by the worker every `SHOULD_SEND_TIME`.
+                    RecordState::NewFrame => {}
                     RecordState::WriteTail => {
-                        if uploader.running {
-                            uploader.handle_tail()
-                        } else {
-                            Ok(())
-                        }
+                        update(&filepath, |x| x.recording = false);
+                        wake();
                     }
                     RecordState::RemoveFile => {
-                        if uploader.running {
-                            uploader.handle_remove()
-                        } else {
-                            Ok(())
-                        }
+                        with_queue(|queue| {
+                            queue.retain(|x| x.path != filepath || x.announced);
+                            if let Some(x) = queue.iter_mut().find(|x| x.path == filepath) {
+                                x.removed = true;
+                                x.recording = false;
+                            }
+                        });
+                        wake();
                     }
                 },
                 Err(e) => {
                     log::trace!("upload thread stop: {}", e);
                     break;
                 }
-            } {
-                uploader.running = false;
-                log::error!("upload stop: {}", e);
             }
         }
     });
 }
 
+fn recording_dir() -> String {
+    #[cfg(windows)]
+    let root = crate::platform::is_root();
+    #[cfg(not(windows))]
+    let root = false;
+    crate::ui_interface::video_save_directory(root)
+}
+
+fn is_recording_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    (name.starts_with("incoming_") || name.starts_with("outgoing_"))
+        && (name.ends_with(".webm") || name.ends_with(".mkv") || name.ends_with(".mp4"))
+}
+
+/// Removes the recordings older than `max_age`, then the oldest ones beyond
+/// `max_usage` bytes, 0 for no limit. The `queued` ones, not uploaded yet, count in the usage
+/// but are kept from the age limit, and removed for the size limit only after all the others.
+/// The `recording` ones are never removed. Returns the queued recordings removed.
+fn apply_retention(
+    dir: &Path,
+    queued: &[String],
+    recording: &[String],
+    max_usage: u64,
+    max_age: Duration,
+) -> Vec<String> {
+    let mut dropped = Vec::new();
+    if max_usage == 0 && max_age.is_zero() {
+        return dropped;
+    }
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return dropped;
+    };
+    let mut files: Vec<(String, u64, SystemTime)> = entries
+        .flatten()
+        .filter(|e| is_recording_file(&e.path()))
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            let path = e.path().to_string_lossy().to_string();
+            Some((path, meta.len(), meta.modified().ok()?))
+        })
+        .collect();
+    // oldest first
+    files.sort_by_key(|x| x.2);
+    let now = SystemTime::now();
+    let mut usage: u64 = files.iter().map(|x| x.1).sum();
+    for (path, size, modified) in files.iter().filter(|x| !queued.contains(&x.0)) {
+        let age = now.duration_since(*modified).unwrap_or_default();
+        let too_old = !max_age.is_zero() && age > max_age;
+        let too_much = max_usage > 0 && usage > max_usage;
+        if !too_old && !too_much {
+            continue;
+        }
+        if remove_recording(path) {
+            log::info!("Recording {:?} removed by the retention policy", path);
+            usage -= size;
+        }
+    }
+    // Uploads failing for long, e.g. while the api server is down, must not fill the disk.
+    for (path, size, _) in files
+        .iter()
+        .filter(|x| queued.contains(&x.0) && !recording.contains(&x.0))
+    {
+        if max_usage == 0 || usage <= max_usage {
+            break;
+        }
+        if remove_recording(path) {
+            log::warn!(
+                "Recording {:?} removed by the retention policy before it was uploaded",
+                path
+            );
+            usage -= size;
+            dropped.push(path.clone());
+        }
+    }
+    dropped
+}
+
+fn remove_recording(path: &str) -> bool {
+    match std::fs::remove_file(path) {
+        Ok(_) => true,
+        Err(e) => {
+            log::error!("Failed to remove recording {:?}: {}", path, e);
+            false
+        }
+    }
+}
+
 struct RecordUploader {
     client: Client,
     api_server: String,
-    filepath: String,
-    filename: String,
-    upload_size: u64,
-    running: bool,
-    last_send: Instant,
 }
 impl RecordUploader {
+    fn new() -> Self {
+        let api_server = crate::get_api_server(
+            Config::get_option("api-server"),
+            Config::get_option("custom-rendezvous-server"),
+        );
+        // This URL is used for TLS connectivity testing and fallback detection.
+        let login_option_url = format!("{}/api/login-options", &api_server);
+        let client = create_http_client_with_url(&login_option_url);
+        Self { client, api_server }
+    }
+
     fn send<Q, B>(&self, query: &Q, body: B) -> ResultType<()>
     where
         Q: Serialize + ?Sized,
@@ -114,98 +368,159 @@ impl RecordUploader {
         }
     }
 
-    fn handle_new_file(&mut self, filepath: String) -> ResultType<()> {
-        match std::path::PathBuf::from(&filepath).file_name() {
-            Some(filename) => match filename.to_owned().into_string() {
-                Ok(filename) => {
-                    self.filename = filename.clone();
-                    self.filepath = filepath.clone();
-                    self.upload_size = 0;
-                    self.running = true;
-                    self.last_send = Instant::now();
-                    self.send(&[("type", "new"), ("file", &filename)], Bytes::new())?;
-                    Ok(())
+    fn process(&self) {
+        let now = get_time();
+        let uploads: Vec<RecordUpload> = read_queue(|queue| {
+            queue
+                .iter()
+                .filter(|x| x.next_retry <= now)
+                .cloned()
+                .collect()
+        });
+        for upload in uploads {
+            match self.handle_upload(&upload) {
+                Ok(true) => {
+                    with_queue(|queue| queue.retain(|x| x.path != upload.path));
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    log::error!("upload {} failed: {}", upload.file, e);
+                    update(&upload.path, |x| {
+                        x.attempts += 1;
+                        x.next_retry = get_time() + retry_delay(x.attempts).as_millis() as i64;
+                        x.last_error = Some(e.to_string());
+                    });
                 }
-                Err(_) => bail!("can't parse filename:{:?}", filename),
-            },
-            None => bail!("can't parse filepath:{}", filepath),
+            }
         }
     }
 
-    fn handle_frame(&mut self, flush: bool) -> ResultType<()> {
-        if !flush && self.last_send.elapsed() < SHOULD_SEND_TIME {
-            return Ok(());
+    // Returns true when nothing is left to do for the upload.
+    fn handle_upload(&self, upload: &RecordUpload) -> ResultType<bool> {
+        if upload.removed {
+            self.handle_remove(&upload.file)?;
+            return Ok(true);
         }
-        match File::open(&self.filepath) {
-            Ok(mut file) => match file.metadata() {
-                Ok(m) => {
-                    let len = m.len();
-                    if len <= self.upload_size {
-                        return Ok(());
-                    }
-                    if !flush && len - self.upload_size < SHOULD_SEND_SIZE {
-                        return Ok(());
-                    }
-                    let mut buf = Vec::new();
-                    match file.seek(SeekFrom::Start(self.upload_size)) {
-                        Ok(_) => match file.read_to_end(&mut buf) {
-                            Ok(length) => {
-                                self.send(
-                                    &[
-                                        ("type", "part"),
-                                        ("file", &self.filename),
-                                        ("offset", &self.upload_size.to_string()),
-                                        ("length", &length.to_string()),
-                                    ],
-                                    buf,
-                                )?;
-                                self.upload_size = len;
-                                self.last_send = Instant::now();
-                                Ok(())
-                            }
-                            Err(e) => bail!(e.to_string()),
-                        },
-                        Err(e) => bail!(e.to_string()),
-                    }
-                }
-                Err(e) => bail!(e.to_string()),
-            },
-            Err(e) => bail!(e.to_string()),
+        if !upload.announced {
+            self.send(&[("type", "new"), ("file", &upload.file)], Bytes::new())?;
+            update(&upload.path, |x| x.announced = true);
         }
-    }
-
-    fn handle_tail(&mut self) -> ResultType<()> {
-        self.handle_frame(true)?;
-        match File::open(&self.filepath) {
-            Ok(mut file) => {
-                let mut buf = vec![0u8; MAX_HEADER_LEN];
-                match file.read(&mut buf) {
-                    Ok(length) => {
-                        buf.truncate(length);
-                        self.send(
-                            &[
-                                ("type", "tail"),
-                                ("file", &self.filename),
-                                ("offset", "0"),
-                                ("length", &length.to_string()),
-                            ],
-                            buf,
-                        )?;
-                        log::info!("upload success, file: {}", self.filename);
-                        Ok(())
-                    }
-                    Err(e) => bail!(e.to_string()),
-                }
+        let mut file = match File::open(&upload.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn!("upload {} dropped, the file is gone", upload.file);
+                return Ok(true);
             }
             Err(e) => bail!(e.to_string()),
+        };
+        let len = file.metadata()?.len();
+        let mut offset = upload.uploaded.min(len);
+        while offset < len {
+            let mut buf = Vec::new();
+            file.seek(SeekFrom::Start(offset))?;
+            (&mut file).take(CHUNK_SIZE).read_to_end(&mut buf)?;
+            if buf.is_empty() {
+                break;
+            }
+            let length = buf.len() as u64;
+            self.handle_part(&upload.file, offset, buf)?;
+            offset += length;
+            update(&upload.path, |x| {
+                x.uploaded = offset;
+                x.size = len;
+                x.attempts = 0;
+                x.last_error = None;
+            });
+        }
+        // The latest state, the recording may have been finished meanwhile.
+        let recording =
+            read_queue(|queue| queue.iter().any(|x| x.path == upload.path && x.recording));
+        if recording || offset < len {
+            return Ok(false);
         }
+        self.handle_tail(&upload.file, &mut file)?;
+        Ok(true)
     }
 
-    fn handle_remove(&mut self) -> ResultType<()> {
+    fn handle_part(&self, filename: &str, offset: u64, buf: Vec<u8>) -> ResultType<()> {
         self.send(
-            &[("type", "remove"), ("file", &self.filename)],
-            Bytes::new(),
+            &[
+                ("type", "part"),
+                ("file", filename),
+                ("offset", &offset.to_string()),
+                ("length", &buf.len().to_string()),
+            ],
+            buf,
+        )
+    }
+
+    // The header is written last by the recorder, so it is sent again.
+    fn handle_tail(&self, filename: &str, file: &mut File) -> ResultType<()> {
+        let mut buf = vec![0u8; MAX_HEADER_LEN];
+        file.seek(SeekFrom::Start(0))?;
+        let length = file.read(&mut buf)?;
+        buf.truncate(length);
+        self.send(
+            &[
+                ("type", "tail"),
+                ("file", filename),
+                ("offset", "0"),
+                ("length", &length.to_string()),
+            ],
+            buf,
         )?;
+        log::info!("upload success, file: {}", filename);
+        Ok(())
+    }
+
+    fn handle_remove(&self, filename: &str) -> ResultType<()> {
+        self.send(&[("type", "remove"), ("file", filename)], Bytes::new())?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retention() {
+        assert_eq!(retry_delay(1), RETRY_MIN);
+        assert_eq!(retry_delay(3), RETRY_MIN * 4);
+        assert_eq!(retry_delay(100), RETRY_MAX);
+
+        let dir = std::env::temp_dir().join("rustdesk-record-retention-test");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let names = [
+            "incoming_1_a.webm",
+            "incoming_1_b.webm",
+            "outgoing_1_c.mp4",
+            "other.webm",
+        ];
+        for name in names {
+            std::fs::write(dir.join(name), vec![0u8; 600 * 1024]).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let path = |i: usize| dir.join(names[i]).to_string_lossy().to_string();
+        let left = || -> Vec<bool> { names.iter().map(|x| dir.join(x).exists()).collect() };
+        let dropped = apply_retention(&dir, &[path(0)], &[], 1300 * 1024, Duration::ZERO);
+        // the queued one counts, the oldest one not queued for upload is removed to get
+        // under 1.3MB
+        assert!(dropped.is_empty());
+        assert_eq!(left(), vec![true, false, true, true]);
+        // queued ones are removed too if it is not enough, but not the one being written
+        let dropped = apply_retention(
+            &dir,
+            &[path(0), path(2)],
+            &[path(2)],
+            500 * 1024,
+            Duration::ZERO,
+        );
+        assert_eq!(dropped, vec![path(0)]);
+        assert_eq!(left(), vec![false, false, true, true]);
+        apply_retention(&dir, &[], &[], 0, Duration::from_millis(1));
+        assert_eq!(left(), vec![false, false, false, true]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
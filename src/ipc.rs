@@ -258,6 +258,11 @@ pub enum Data {
     OnlineStatus(Option<(i64, bool)>),
     Config((String, Option<String>)),
     Options(Option<HashMap<String, String>>),
+    RecoveryCodes(Option<Vec<String>>),
+    RecordUploads(Option<Vec<crate::hbbs_http::record_upload::RecordUpload>>),
+    AuditJournal(Option<String>),
+    AccessGrants(Option<Vec<crate::server::access_grant::AccessGrant>>),
+    UpdateAccessGrants(crate::server::access_grant::GrantUpdate),
     NatType(Option<i32>),
     ConfirmedKey(Option<(Vec<u8>, Vec<u8>)>),
     RawMessage(Vec<u8>),
@@ -714,6 +719,9 @@ async fn handle(data: Data, stream: &mut Connection) {
                     crate::audio_service::set_voice_call_input_device(Some(value), true);
                 } else if name == "unlock-pin" {
                     Config::set_unlock_pin(&value);
+                } else if name == "server-bundle" {
+                    // The installed bundle file, the value is not trusted.
+                    crate::common::load_server_bundle();
                 } else {
                     return;
                 }
@@ -737,6 +745,32 @@ async fn handle(data: Data, stream: &mut Connection) {
                 allow_err!(stream.send(&Data::Options(None)).await);
             }
         },
+        Data::RecoveryCodes(None) => {
+            let codes = crate::auth_2fa::regenerate_recovery_codes().unwrap_or_else(|err| {
+                log::error!("Failed to regenerate recovery codes: {}", err);
+                vec![]
+            });
+            allow_err!(stream.send(&Data::RecoveryCodes(Some(codes))).await);
+        }
+        Data::RecordUploads(None) => {
+            let uploads = crate::hbbs_http::record_upload::get_uploads();
+            allow_err!(stream.send(&Data::RecordUploads(Some(uploads))).await);
+        }
+        Data::AuditJournal(None) => {
+            let journal = crate::server::audit_journal::export();
+            allow_err!(stream.send(&Data::AuditJournal(Some(journal))).await);
+        }
+        Data::AccessGrants(None) => {
+            let grants = crate::server::access_grant::list();
+            allow_err!(stream.send(&Data::AccessGrants(Some(grants))).await);
+        }
+        Data::UpdateAccessGrants(update) => {
+            let grants = crate::server::access_grant::update(update).unwrap_or_else(|err| {
+                log::error!("Failed to update the access grants: {}", err);
+                crate::server::access_grant::list()
+            });
+            allow_err!(stream.send(&Data::AccessGrants(Some(grants))).await);
+        }
         Data::NatType(_) => {
             let t = Config::get_nat_type();
             allow_err!(stream.send(&Data::NatType(Some(t))).await);
@@ -1416,6 +1450,63 @@ pub async fn set_options(value: HashMap<String, String>) -> ResultType<()> {
     Ok(())
 }
 
+// Regenerated by the server, which owns the config and checks the codes.
+#[tokio::main(flavor = "current_thread")]
+pub async fn regenerate_recovery_codes() -> ResultType<Vec<String>> {
+    let mut c = connect(1000, "").await?;
+    c.send(&Data::RecoveryCodes(None)).await?;
+    if let Some(Data::RecoveryCodes(Some(codes))) = c.next_timeout(1000).await? {
+        if !codes.is_empty() {
+            return Ok(codes);
+        }
+    }
+    bail!("Failed to regenerate recovery codes");
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_record_uploads() -> ResultType<Vec<crate::hbbs_http::record_upload::RecordUpload>>
+{
+    let mut c = connect(1000, "").await?;
+    c.send(&Data::RecordUploads(None)).await?;
+    if let Some(Data::RecordUploads(Some(uploads))) = c.next_timeout(1000).await? {
+        return Ok(uploads);
+    }
+    bail!("Failed to get record uploads");
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn export_audit_journal() -> ResultType<String> {
+    let mut c = connect(1000, "").await?;
+    c.send(&Data::AuditJournal(None)).await?;
+    if let Some(Data::AuditJournal(Some(journal))) = c.next_timeout(3000).await? {
+        return Ok(journal);
+    }
+    bail!("Failed to export the audit journal");
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn get_access_grants() -> ResultType<Vec<crate::server::access_grant::AccessGrant>> {
+    let mut c = connect(1000, "").await?;
+    c.send(&Data::AccessGrants(None)).await?;
+    if let Some(Data::AccessGrants(Some(grants))) = c.next_timeout(1000).await? {
+        return Ok(grants);
+    }
+    bail!("Failed to get the access grants");
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn update_access_grants(
+    update: crate::server::access_grant::GrantUpdate,
+) -> ResultType<Vec<crate::server::access_grant::AccessGrant>> {
+    update.check()?;
+    let mut c = connect(1000, "").await?;
+    c.send(&Data::UpdateAccessGrants(update)).await?;
+    if let Some(Data::AccessGrants(Some(grants))) = c.next_timeout(1000).await? {
+        return Ok(grants);
+    }
+    bail!("Failed to update the access grants");
+}
+
 #[inline]
 async fn get_nat_type_(ms_timeout: u64) -> ResultType<i32> {
     let mut c = connect(ms_timeout, "").await?;
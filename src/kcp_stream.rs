@@ -9,17 +9,180 @@ use hbb_common::{
 };
 use kcp_sys::{
     endpoint::KcpEndpoint,
+    ffi_safe::KcpConfig,
     packet_def::{KcpPacket, KcpPacketHeader},
     stream,
 };
-use std::{net::SocketAddr, sync::Arc};
+use serde_derive::Serialize;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+mod fec;
+
+/// "low-latency", "balanced" or "bandwidth-saving", the server side one wins if set.
+pub const OPTION_KCP_PROFILE: &str = "kcp-profile";
+/// Reed-Solomon FEC on the datagrams, used if either side asks for it.
+pub const OPTION_KCP_FEC: &str = "kcp-fec";
+
+// Sent by the connecting side after the punch, before KCP starts:
+// magic | kind | profile | fec
+// Only if the accepting side punched with `KcpStream::PUNCH_PAYLOAD`, older peers punch with
+// empty packets and nothing is negotiated.
+const HELLO_MAGIC: &[u8; 5] = b"RDKCP";
+const HELLO_LEN: usize = HELLO_MAGIC.len() + 3;
+const HELLO: u8 = 0;
+const HELLO_ACK: u8 = 1;
+const NEGOTIATE_TIMEOUT: Duration = Duration::from_millis(1_000);
+const NEGOTIATE_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KcpProfile {
+    LowLatency,
+    #[default]
+    Balanced,
+    BandwidthSaving,
+}
+
+impl KcpProfile {
+    pub fn from_option(v: &str) -> Self {
+        match v {
+            "low-latency" => Self::LowLatency,
+            "bandwidth-saving" => Self::BandwidthSaving,
+            _ => Self::Balanced,
+        }
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Self::LowLatency,
+            2 => Self::BandwidthSaving,
+            _ => Self::Balanced,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Balanced => 0,
+            Self::LowLatency => 1,
+            Self::BandwidthSaving => 2,
+        }
+    }
+
+    // (nodelay, interval ms, fast resend, no congestion control, send window, receive window, mtu)
+    fn params(self) -> (bool, u32, u32, bool, u32, u32, u32) {
+        match self {
+            Self::LowLatency => (true, 10, 2, true, 256, 256, 1400),
+            Self::Balanced => (true, 20, 2, true, 128, 128, 1350),
+            // Fewer spurious retransmits and no IP fragmentation on satellite / LTE.
+            Self::BandwidthSaving => (false, 40, 0, false, 128, 256, 1200),
+        }
+    }
+
+    fn config(self, conv: u32, fec: bool) -> KcpConfig {
+        let (nodelay, interval, resend, nc, snd_wnd, rcv_wnd, mtu) = self.params();
+        let mtu = if fec { mtu - fec::OVERHEAD as u32 } else { mtu };
+        let mut config = KcpConfig::new_turbo(conv);
+        config.nodelay = Some(nodelay);
+        config.interval = Some(interval as _);
+        config.resend = Some(resend as _);
+        config.nc = Some(nc);
+        config.snd_wnd = Some(snd_wnd as _);
+        config.rcv_wnd = Some(rcv_wnd as _);
+        config.mtu = Some(mtu as _);
+        config
+    }
+}
+
+fn hello(kind: u8, profile: KcpProfile, fec: bool) -> [u8; HELLO_LEN] {
+    let mut v = [0u8; HELLO_LEN];
+    v[..HELLO_MAGIC.len()].copy_from_slice(HELLO_MAGIC);
+    v[HELLO_MAGIC.len()] = kind;
+    v[HELLO_MAGIC.len() + 1] = profile.to_u8();
+    v[HELLO_MAGIC.len() + 2] = fec as u8;
+    v
+}
+
+fn parse_hello(data: &[u8]) -> Option<(u8, KcpProfile, bool)> {
+    if data.len() != HELLO_LEN || !data.starts_with(HELLO_MAGIC) {
+        return None;
+    }
+    let n = HELLO_MAGIC.len();
+    Some((data[n], KcpProfile::from_u8(data[n + 1]), data[n + 2] != 0))
+}
+
+// Retransmitted PUSH segments in an outgoing datagram, the KCP segments follow the
+// endpoint header, see ikcp.c. New segments are sent with increasing sn, so an sn
+// not above the highest one sent is a retransmit.
+fn count_retransmits(data: &[u8], max_sn: &mut Option<u32>) -> u64 {
+    const IKCP_OVERHEAD: usize = 24;
+    const IKCP_CMD_PUSH: u8 = 81;
+    const IKCP_CMD_WINS: u8 = 84;
+    let mut n = 0;
+    let mut p = data
+        .get(std::mem::size_of::<KcpPacketHeader>()..)
+        .unwrap_or_default();
+    while p.len() >= IKCP_OVERHEAD {
+        let cmd = p[4];
+        let sn = u32::from_le_bytes([p[12], p[13], p[14], p[15]]);
+        let len = u32::from_le_bytes([p[20], p[21], p[22], p[23]]) as usize;
+        if !(IKCP_CMD_PUSH..=IKCP_CMD_WINS).contains(&cmd) || len > p.len() - IKCP_OVERHEAD {
+            break;
+        }
+        if cmd == IKCP_CMD_PUSH {
+            match max_sn {
+                Some(max) if sn <= *max => n += 1,
+                _ => *max_sn = Some(sn),
+            }
+        }
+        p = &p[IKCP_OVERHEAD + len..];
+    }
+    n
+}
+
+#[derive(Default)]
+struct Counters {
+    sent_packets: AtomicU64,
+    received_packets: AtomicU64,
+    retransmits: AtomicU64,
+    fec_recovered: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct KcpStats {
+    pub profile: KcpProfile,
+    pub fec: bool,
+    pub sent_packets: u64,
+    pub received_packets: u64,
+    pub retransmits: u64,
+    pub fec_recovered: u64,
+}
 
 pub struct KcpStream {
     _endpoint: KcpEndpoint,
     stop_sender: Option<oneshot::Sender<()>>,
+    profile: KcpProfile,
+    fec: bool,
+    counters: Arc<Counters>,
 }
 
 impl KcpStream {
+    /// The punch packets of the accepting side, the magic and a kind after `HELLO_ACK`,
+    /// telling it understands the hello. Shorter than a KCP packet header, so the KCP io
+    /// of any version drops it.
+    pub const PUNCH_PAYLOAD: &'static [u8] = b"RDKCP\x02";
+
+    /// Whether the punch packet received by the connecting side is `PUNCH_PAYLOAD`.
+    pub fn is_hello_supported(punch: &[u8]) -> bool {
+        punch == Self::PUNCH_PAYLOAD
+    }
+
     fn create_framed(stream: stream::KcpStream, local_addr: Option<SocketAddr>) -> Stream {
         Stream::Tcp(FramedStream(
             tokio_util::codec::Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
@@ -29,13 +192,37 @@ impl KcpStream {
         ))
     }
 
+    async fn create_endpoint(profile: KcpProfile, fec: bool) -> KcpEndpoint {
+        let mut endpoint = KcpEndpoint::new();
+        endpoint.set_kcp_config_factory(Box::new(move |conv| profile.config(conv, fec)));
+        endpoint.run().await;
+        endpoint
+    }
+
     pub async fn accept(
         udp_socket: Arc<UdpSocket>,
         timeout: std::time::Duration,
-        init_packet: Option<BytesMut>,
+        mut init_packet: Option<BytesMut>,
     ) -> ResultType<(Self, Stream)> {
-        let mut endpoint = KcpEndpoint::new();
-        endpoint.run().await;
+        let (mut profile, mut fec) = (KcpProfile::default(), false);
+        let mut ack = None;
+        if let Some((HELLO, their_profile, their_fec)) =
+            init_packet.as_ref().and_then(|x| parse_hello(x))
+        {
+            let mine = config::Config::get_option(OPTION_KCP_PROFILE);
+            profile = if mine.is_empty() {
+                their_profile
+            } else {
+                KcpProfile::from_option(&mine)
+            };
+            fec = their_fec || config::Config::get_option(OPTION_KCP_FEC) == "Y";
+            let v = hello(HELLO_ACK, profile, fec);
+            udp_socket.send(&v).await?;
+            ack = Some(v);
+            init_packet = None;
+            log::info!("KCP profile {:?} negotiated, fec: {}", profile, fec);
+        }
+        let mut endpoint = Self::create_endpoint(profile, fec).await;
 
         let (input, output) = (
             endpoint.input_sender(),
@@ -49,7 +236,17 @@ impl KcpStream {
                 input.send(packet.into()).await?;
             }
         }
-        Self::kcp_io(udp_socket.clone(), input, output, stop_receiver).await;
+        let counters = Arc::new(Counters::default());
+        Self::kcp_io(
+            udp_socket.clone(),
+            input,
+            output,
+            stop_receiver,
+            fec,
+            ack,
+            counters.clone(),
+        )
+        .await;
 
         let conn_id = tokio::time::timeout(timeout, endpoint.accept()).await??;
         if let Some(stream) = stream::KcpStream::new(&endpoint, conn_id) {
@@ -57,6 +254,9 @@ impl KcpStream {
                 Self {
                     _endpoint: endpoint,
                     stop_sender: Some(stop_sender),
+                    profile,
+                    fec,
+                    counters,
                 },
                 Self::create_framed(stream, udp_socket.local_addr().ok()),
             ))
@@ -68,9 +268,15 @@ impl KcpStream {
     pub async fn connect(
         udp_socket: Arc<UdpSocket>,
         timeout: std::time::Duration,
+        supports_hello: bool,
     ) -> ResultType<(Self, Stream)> {
-        let mut endpoint = KcpEndpoint::new();
-        endpoint.run().await;
+        let (profile, fec) = if supports_hello {
+            Self::negotiate(&udp_socket).await
+        } else {
+            log::debug!("KCP profile not negotiated, peer is an older version");
+            Default::default()
+        };
+        let mut endpoint = Self::create_endpoint(profile, fec).await;
 
         let (input, output) = (
             endpoint.input_sender(),
@@ -79,7 +285,17 @@ impl KcpStream {
                 .ok_or_else(|| anyhow::anyhow!("Failed to get output receiver"))?,
         );
         let (stop_sender, stop_receiver) = oneshot::channel();
-        Self::kcp_io(udp_socket.clone(), input, output, stop_receiver).await;
+        let counters = Arc::new(Counters::default());
+        Self::kcp_io(
+            udp_socket.clone(),
+            input,
+            output,
+            stop_receiver,
+            fec,
+            None,
+            counters.clone(),
+        )
+        .await;
 
         let conn_id = endpoint.connect(timeout, 0, 0, Bytes::new()).await?;
         if let Some(stream) = stream::KcpStream::new(&endpoint, conn_id) {
@@ -87,6 +303,9 @@ impl KcpStream {
                 Self {
                     _endpoint: endpoint,
                     stop_sender: Some(stop_sender),
+                    profile,
+                    fec,
+                    counters,
                 },
                 Self::create_framed(stream, udp_socket.local_addr().ok()),
             ))
@@ -95,15 +314,59 @@ impl KcpStream {
         }
     }
 
+    // No answer in time, e.g. all lost, then the default profile without FEC is used.
+    async fn negotiate(udp_socket: &UdpSocket) -> (KcpProfile, bool) {
+        let profile = KcpProfile::from_option(&config::LocalConfig::get_option(OPTION_KCP_PROFILE));
+        let fec = config::LocalConfig::get_option(OPTION_KCP_FEC) == "Y";
+        let request = hello(HELLO, profile, fec);
+        let tm = Instant::now();
+        let mut buf = [0u8; 1500];
+        while tm.elapsed() < NEGOTIATE_TIMEOUT {
+            if udp_socket.send(&request).await.is_err() {
+                break;
+            }
+            let deadline = Instant::now() + NEGOTIATE_RETRY_INTERVAL;
+            while let Ok(Ok(n)) =
+                tokio::time::timeout_at(deadline.into(), udp_socket.recv(&mut buf)).await
+            {
+                if let Some((HELLO_ACK, profile, fec)) = parse_hello(&buf[..n]) {
+                    log::info!("KCP profile {:?} negotiated, fec: {}", profile, fec);
+                    return (profile, fec);
+                }
+            }
+        }
+        log::debug!("KCP profile not negotiated, no answer");
+        Default::default()
+    }
+
+    pub fn stats(&self) -> KcpStats {
+        KcpStats {
+            profile: self.profile,
+            fec: self.fec,
+            sent_packets: self.counters.sent_packets.load(Ordering::Relaxed),
+            received_packets: self.counters.received_packets.load(Ordering::Relaxed),
+            retransmits: self.counters.retransmits.load(Ordering::Relaxed),
+            fec_recovered: self.counters.fec_recovered.load(Ordering::Relaxed),
+        }
+    }
+
     async fn kcp_io(
         udp_socket: Arc<UdpSocket>,
         input: mpsc::Sender<KcpPacket>,
         mut output: mpsc::Receiver<KcpPacket>,
         mut stop_receiver: oneshot::Receiver<()>,
+        fec: bool,
+        ack: Option<[u8; HELLO_LEN]>,
+        counters: Arc<Counters>,
     ) {
         let udp = udp_socket.clone();
         tokio::spawn(async move {
             let mut buf = vec![0; 1500];
+            let mut encoder = if fec { Some(fec::Encoder::new()) } else { None };
+            // Always on, to understand a peer sending FEC while the ack got lost.
+            let mut decoder = fec::Decoder::new();
+            let mut max_sn = None;
+            let mut flush_timer = tokio::time::interval(fec::FLUSH_DELAY / 2);
             loop {
                 tokio::select! {
                     _ = &mut stop_receiver => {
@@ -111,19 +374,63 @@ impl KcpStream {
                         break;
                     }
                     Some(data) = output.recv() => {
-                        if let Err(e) = udp.send(&data.inner()).await {
+                        let data = data.inner();
+                        counters.sent_packets.fetch_add(1, Ordering::Relaxed);
+                        counters.retransmits.fetch_add(count_retransmits(&data, &mut max_sn), Ordering::Relaxed);
+                        let res = match encoder.as_mut() {
+                            Some(encoder) => {
+                                let mut res = Ok(0);
+                                for frame in encoder.encode(&data) {
+                                    res = udp.send(&frame).await;
+                                    if res.is_err() {
+                                        break;
+                                    }
+                                }
+                                res
+                            }
+                            None => udp.send(&data).await,
+                        };
+                        if let Err(e) = res {
                             log::debug!("KCP send error: {:?}", e);
                             break;
                         }
                     }
+                    _ = flush_timer.tick(), if encoder.is_some() => {
+                        if let Some(encoder) = encoder.as_mut() {
+                            for frame in encoder.flush() {
+                                udp.send(&frame).await.ok();
+                            }
+                        }
+                    }
                     result = udp.recv_from(&mut buf) => {
                         match result {
                             Ok((size, _)) => {
+                                let data = &buf[..size];
+                                if parse_hello(data).is_some() {
+                                    // the connecting side did not get our ack
+                                    if let Some(ack) = ack.as_ref() {
+                                        udp.send(ack).await.ok();
+                                    }
+                                    continue;
+                                }
+                                if fec::is_fec_frame(data) {
+                                    for (packet, recovered) in decoder.decode(data) {
+                                        if recovered {
+                                            counters.fec_recovered.fetch_add(1, Ordering::Relaxed);
+                                        }
+                                        counters.received_packets.fetch_add(1, Ordering::Relaxed);
+                                        if packet.len() >= std::mem::size_of::<KcpPacketHeader>() {
+                                            input.send(BytesMut::from(&packet[..]).into()).await.ok();
+                                        }
+                                    }
+                                    continue;
+                                }
                                 if size < std::mem::size_of::<KcpPacketHeader>() {
                                     continue;
                                 }
+                                counters.received_packets.fetch_add(1, Ordering::Relaxed);
                                 input
-                                    .send(BytesMut::from(&buf[..size]).into())
+                                    .send(BytesMut::from(data).into())
                                     .await.ok();
                             }
                             Err(e) => {
@@ -144,8 +451,62 @@ impl KcpStream {
 
 impl Drop for KcpStream {
     fn drop(&mut self) {
+        log::info!("KCP stream closed, {:?}", self.stats());
         if let Some(sender) = self.stop_sender.take() {
             let _ = sender.send(());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello() {
+        let v = hello(HELLO, KcpProfile::BandwidthSaving, true);
+        assert_eq!(
+            parse_hello(&v),
+            Some((HELLO, KcpProfile::BandwidthSaving, true))
+        );
+        assert_eq!(parse_hello(&v[1..]), None);
+        assert!(KcpStream::is_hello_supported(KcpStream::PUNCH_PAYLOAD));
+        assert!(!KcpStream::is_hello_supported(&[]));
+        assert!(KcpStream::PUNCH_PAYLOAD.len() < std::mem::size_of::<KcpPacketHeader>());
+        assert_eq!(parse_hello(KcpStream::PUNCH_PAYLOAD), None);
+        assert_eq!(
+            KcpProfile::from_option("low-latency"),
+            KcpProfile::LowLatency
+        );
+        assert_eq!(KcpProfile::from_option(""), KcpProfile::Balanced);
+    }
+
+    #[test]
+    fn test_count_retransmits() {
+        let segment = |cmd: u8, sn: u32| {
+            let mut v = vec![0u8; 24];
+            v[4] = cmd;
+            v[12..16].copy_from_slice(&sn.to_le_bytes());
+            v
+        };
+        let datagram = |segments: &[Vec<u8>]| {
+            let mut v = vec![0u8; std::mem::size_of::<KcpPacketHeader>()];
+            segments.iter().for_each(|x| v.extend_from_slice(x));
+            v
+        };
+        let mut max_sn = None;
+        assert_eq!(
+            count_retransmits(&datagram(&[segment(81, 0), segment(81, 1)]), &mut max_sn),
+            0
+        );
+        assert_eq!(
+            count_retransmits(&datagram(&[segment(82, 0), segment(81, 2)]), &mut max_sn),
+            0
+        );
+        assert_eq!(
+            count_retransmits(&datagram(&[segment(81, 1), segment(81, 3)]), &mut max_sn),
+            1
+        );
+        assert_eq!(max_sn, Some(3));
+    }
+}
@@ -0,0 +1,302 @@
+// Reed-Solomon forward error correction over the KCP datagrams.
+//
+// Frame: magic | group u16 | index u8 | data shards u8 | parity shards u8 | payload
+//
+// Data frames carry the datagram as is and are delivered at once, the data shard
+// counts are 0 in them because the group is not complete yet. Parity frames carry
+// the parity of `len u16 | datagram` padded to the longest datagram of the group.
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+const MAGIC: &[u8; 4] = b"RDFE";
+const HEADER_LEN: usize = MAGIC.len() + 5;
+/// Bytes added to the largest datagram, to be taken from the KCP MTU.
+pub const OVERHEAD: usize = HEADER_LEN + 2;
+pub const DATA_SHARDS: usize = 8;
+pub const PARITY_SHARDS: usize = 2;
+// Incomplete groups are closed when no datagram came for this long.
+pub const FLUSH_DELAY: Duration = Duration::from_millis(20);
+const MAX_GROUPS: usize = 64;
+
+fn frame(
+    group: u16,
+    index: usize,
+    data_count: usize,
+    parity_count: usize,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut v = Vec::with_capacity(HEADER_LEN + payload.len());
+    v.extend_from_slice(MAGIC);
+    v.extend_from_slice(&group.to_le_bytes());
+    v.push(index as _);
+    v.push(data_count as _);
+    v.push(parity_count as _);
+    v.extend_from_slice(payload);
+    v
+}
+
+pub fn is_fec_frame(data: &[u8]) -> bool {
+    data.len() > HEADER_LEN && data.starts_with(MAGIC)
+}
+
+#[derive(Default)]
+struct Codecs(HashMap<usize, ReedSolomon>);
+
+impl Codecs {
+    fn get(&mut self, data_count: usize) -> Option<&ReedSolomon> {
+        if !self.0.contains_key(&data_count) {
+            let codec = ReedSolomon::new(data_count, PARITY_SHARDS).ok()?;
+            self.0.insert(data_count, codec);
+        }
+        self.0.get(&data_count)
+    }
+}
+
+fn to_shard(data: &[u8], shard_len: usize) -> Vec<u8> {
+    let mut shard = Vec::with_capacity(shard_len);
+    shard.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    shard.extend_from_slice(data);
+    shard.resize(shard_len, 0);
+    shard
+}
+
+fn from_shard(shard: &[u8]) -> Option<&[u8]> {
+    let len = u16::from_le_bytes([*shard.first()?, *shard.get(1)?]) as usize;
+    shard.get(2..2 + len)
+}
+
+pub struct Encoder {
+    group: u16,
+    pending: Vec<Vec<u8>>,
+    last_push: Instant,
+    codecs: Codecs,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self {
+            group: 0,
+            pending: Vec::with_capacity(DATA_SHARDS),
+            last_push: Instant::now(),
+            codecs: Default::default(),
+        }
+    }
+
+    /// Frames to send for `data`, with the parity ones if the group is full.
+    pub fn encode(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = vec![frame(self.group, self.pending.len(), 0, 0, data)];
+        self.pending.push(data.to_vec());
+        self.last_push = Instant::now();
+        if self.pending.len() >= DATA_SHARDS {
+            frames.extend(self.parity());
+        }
+        frames
+    }
+
+    /// Parity of the incomplete group once it is idle, so that recovery does not
+    /// wait for more traffic.
+    pub fn flush(&mut self) -> Vec<Vec<u8>> {
+        if self.pending.is_empty() || self.last_push.elapsed() < FLUSH_DELAY {
+            return vec![];
+        }
+        self.parity()
+    }
+
+    fn parity(&mut self) -> Vec<Vec<u8>> {
+        let pending = std::mem::take(&mut self.pending);
+        let group = self.group;
+        self.group = self.group.wrapping_add(1);
+        let data_count = pending.len();
+        let shard_len = 2 + pending.iter().map(|x| x.len()).max().unwrap_or_default();
+        let mut shards: Vec<Vec<u8>> = pending.iter().map(|x| to_shard(x, shard_len)).collect();
+        shards.resize(data_count + PARITY_SHARDS, vec![0; shard_len]);
+        let Some(codec) = self.codecs.get(data_count) else {
+            return vec![];
+        };
+        if let Err(err) = codec.encode(&mut shards) {
+            log::debug!("FEC encode error: {:?}", err);
+            return vec![];
+        }
+        shards
+            .iter()
+            .enumerate()
+            .skip(data_count)
+            .map(|(i, shard)| frame(group, i, data_count, PARITY_SHARDS, shard))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    data: Vec<Option<Vec<u8>>>,
+    parity: Vec<Option<Vec<u8>>>,
+    data_count: usize,
+    done: bool,
+}
+
+#[derive(Default)]
+pub struct Decoder {
+    groups: HashMap<u16, Group>,
+    order: VecDeque<u16>,
+    codecs: Codecs,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Datagrams for KCP in `frame`, the flag is set on the recovered ones.
+    pub fn decode(&mut self, frame: &[u8]) -> Vec<(Vec<u8>, bool)> {
+        if !is_fec_frame(frame) {
+            return vec![];
+        }
+        let group_id = u16::from_le_bytes([frame[4], frame[5]]);
+        let (index, data_count, parity_count) =
+            (frame[6] as usize, frame[7] as usize, frame[8] as usize);
+        let payload = &frame[HEADER_LEN..];
+        if index >= DATA_SHARDS + PARITY_SHARDS
+            || data_count > DATA_SHARDS
+            || (data_count > 0 && parity_count != PARITY_SHARDS)
+        {
+            return vec![];
+        }
+        if !self.groups.contains_key(&group_id) {
+            if self.order.len() >= MAX_GROUPS {
+                if let Some(old) = self.order.pop_front() {
+                    self.groups.remove(&old);
+                }
+            }
+            self.order.push_back(group_id);
+            self.groups.insert(
+                group_id,
+                Group {
+                    data: vec![None; DATA_SHARDS],
+                    parity: vec![None; PARITY_SHARDS],
+                    ..Default::default()
+                },
+            );
+        }
+        let Some(group) = self.groups.get_mut(&group_id) else {
+            return vec![];
+        };
+        let mut out = vec![];
+        if data_count == 0 {
+            if index >= DATA_SHARDS {
+                return vec![];
+            }
+            group.data[index] = Some(payload.to_vec());
+            out.push((payload.to_vec(), false));
+        } else {
+            if index < data_count {
+                return vec![];
+            }
+            group.data_count = data_count;
+            group.parity[index - data_count] = Some(payload.to_vec());
+        }
+        if group.data_count > 0 {
+            if let Some(codec) = self.codecs.get(group.data_count) {
+                out.extend(Self::recover(group, codec).into_iter().map(|x| (x, true)));
+            }
+        }
+        out
+    }
+
+    fn recover(group: &mut Group, codec: &ReedSolomon) -> Vec<Vec<u8>> {
+        let data_count = group.data_count;
+        if group.done {
+            return vec![];
+        }
+        let received = group.data[..data_count].iter().flatten().count();
+        if received == data_count {
+            group.done = true;
+            return vec![];
+        }
+        if received + group.parity.iter().flatten().count() < data_count {
+            return vec![];
+        }
+        let Some(shard_len) = group.parity.iter().flatten().map(|x| x.len()).next() else {
+            return vec![];
+        };
+        let mut shards: Vec<Option<Vec<u8>>> = group.data[..data_count]
+            .iter()
+            .map(|x| {
+                x.as_ref()
+                    .filter(|x| x.len() + 2 <= shard_len)
+                    .map(|x| to_shard(x, shard_len))
+            })
+            .chain(group.parity.iter().cloned())
+            .collect();
+        if let Err(err) = codec.reconstruct_data(&mut shards) {
+            log::debug!("FEC reconstruct error: {:?}", err);
+            return vec![];
+        }
+        group.done = true;
+        let mut recovered = vec![];
+        for (i, shard) in shards.iter().enumerate().take(data_count) {
+            if group.data[i].is_some() {
+                continue;
+            }
+            if let Some(data) = shard.as_deref().and_then(from_shard) {
+                recovered.push(data.to_vec());
+            }
+        }
+        recovered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets(n: usize) -> Vec<Vec<u8>> {
+        (0..n).map(|i| vec![i as u8; 10 + i * 7]).collect()
+    }
+
+    #[test]
+    fn test_recover_lost_packets() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let packets = packets(DATA_SHARDS);
+        let frames: Vec<_> = packets.iter().flat_map(|x| encoder.encode(x)).collect();
+        assert_eq!(frames.len(), DATA_SHARDS + PARITY_SHARDS);
+        let mut received = vec![];
+        for (i, frame) in frames.iter().enumerate() {
+            // lose two data frames
+            if i == 1 || i == 5 {
+                continue;
+            }
+            received.extend(decoder.decode(frame));
+        }
+        assert_eq!(received.iter().filter(|x| x.1).count(), 2);
+        let mut received: Vec<_> = received.into_iter().map(|x| x.0).collect();
+        received.sort();
+        let mut expected = packets.clone();
+        expected.sort();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_flush_incomplete_group() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        let packets = packets(3);
+        for (i, p) in packets.iter().enumerate() {
+            let frames = encoder.encode(p);
+            assert_eq!(frames.len(), 1);
+            if i != 1 {
+                assert_eq!(decoder.decode(&frames[0]), vec![(p.clone(), false)]);
+            }
+        }
+        assert!(encoder.flush().is_empty());
+        std::thread::sleep(FLUSH_DELAY);
+        let parity = encoder.flush();
+        assert_eq!(parity.len(), PARITY_SHARDS);
+        assert_eq!(decoder.decode(&parity[0]), vec![(packets[1].clone(), true)]);
+        assert!(decoder.decode(&parity[1]).is_empty());
+        assert!(decoder.decode(b"not a fec frame").is_empty());
+    }
+}
@@ -14,21 +14,52 @@ use hbb_common::{
     },
     ResultType,
 };
+use serde_derive::{Deserialize, Serialize};
 
 use std::{
     collections::{HashMap, HashSet},
     net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
+mod mdns;
+
 type Message = RendezvousMessage;
 
+// The proto has no message for it, so the request and the result are sent as json
+// in a plugin request with this id. It is handled before the plugin dispatch on both
+// sides and plugins can not be loaded with it, see `plugins::load_plugin_path`.
+// Older peers would take it for a plugin request, so it is only sent to the peers
+// announcing `PEER_SUPPORT_WOL_RELAY`.
+pub const WOL_RELAY_REQUEST_ID: &str = "wol-relay";
+/// Key in the `platform_additions` of the peer info, set by peers which handle relay requests.
+pub const PEER_SUPPORT_WOL_RELAY: &str = "support_wol_relay";
+// Off by default, the relaying peer must opt in.
+pub const OPTION_ALLOW_WOL_RELAY: &str = "allow-wol-relay";
+const WOL_RELAY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WolRelayRequest {
+    pub id: String,
+    pub ip_mac: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WolRelayResponse {
+    pub id: String,
+    pub sent: usize,
+    pub error: String,
+}
+
 #[cfg(not(target_os = "ios"))]
 pub(super) fn start_listening() -> ResultType<()> {
     let addr = SocketAddr::from(([0, 0, 0, 0], get_broadcast_port()));
     let socket = std::net::UdpSocket::bind(addr)?;
     socket.set_read_timeout(Some(std::time::Duration::from_millis(1000)))?;
     log::info!("lan discovery listener started");
+    std::thread::spawn(|| allow_err!(start_mdns_responder()));
     loop {
         let mut buf = [0; 2048];
         if let Ok((len, addr)) = socket.recv_from(&mut buf) {
@@ -73,10 +104,43 @@ pub(super) fn start_listening() -> ResultType<()> {
     }
 }
 
+#[cfg(not(target_os = "ios"))]
+fn start_mdns_responder() -> ResultType<()> {
+    let socket = mdns::bind_multicast(mdns::MDNS_GROUP)?;
+    log::info!("mdns responder started");
+    let port = crate::rendezvous_mediator::get_direct_port() as u16;
+    mdns::serve(&socket, mdns::MDNS_GROUP, port, || {
+        if !config::option2bool(
+            "enable-lan-discovery",
+            &Config::get_option("enable-lan-discovery"),
+        ) {
+            return None;
+        }
+        let mut hostname = crate::whoami_hostname();
+        if hostname == "localhost" {
+            hostname = "unknown".to_owned();
+        }
+        Some(mdns::ServiceRecord {
+            id: Config::get_id(),
+            hostname,
+            platform: whoami::platform().to_string(),
+            version: crate::VERSION.to_owned(),
+        })
+    })
+}
+
 #[tokio::main(flavor = "current_thread")]
 pub async fn discover() -> ResultType<()> {
     let sockets = send_query()?;
-    let rx = spawn_wait_responses(sockets);
+    // Browsing via mDNS is best effort, the broadcast is still the main way.
+    let mdns_socket = match mdns::send_query(mdns::MDNS_GROUP) {
+        Ok(socket) => Some(socket),
+        Err(err) => {
+            log::warn!("Failed to send mdns query: {}", err);
+            None
+        }
+    };
+    let rx = spawn_wait_responses(sockets, mdns_socket);
     handle_received_peers(rx).await?;
 
     log::info!("discover ping done");
@@ -104,6 +168,128 @@ pub fn send_wol(id: String) {
     }
 }
 
+/// The request to wake `id`, built from the ip / mac pairs found by lan discovery.
+pub fn wol_relay_request(id: &str) -> Option<WolRelayRequest> {
+    let peers = config::LanPeers::load().peers;
+    let peer = peers.into_iter().find(|p| p.id == id)?;
+    let ip_mac: HashMap<_, _> = peer
+        .ip_mac
+        .into_iter()
+        .filter(|(_, mac)| !mac.is_empty())
+        .collect();
+    if ip_mac.is_empty() {
+        return None;
+    }
+    Some(WolRelayRequest {
+        id: id.to_owned(),
+        ip_mac,
+    })
+}
+
+/// Discovered peers which may relay wake-on-lan to `id`, the most likely first.
+pub fn find_wol_relays(id: &str) -> Vec<String> {
+    let peers = config::LanPeers::load().peers;
+    let Some(target) = peers.iter().find(|p| p.id == id) else {
+        return vec![];
+    };
+    rank_by_common_prefix(target, peers.iter().filter(|p| p.id != id))
+}
+
+/// Discovered peers with a known mac which `relay` may wake, the most likely first.
+pub fn find_wol_targets(relay: &str) -> Vec<String> {
+    let peers = config::LanPeers::load().peers;
+    let Some(relay_peer) = peers.iter().find(|p| p.id == relay) else {
+        return vec![];
+    };
+    rank_by_common_prefix(
+        relay_peer,
+        peers
+            .iter()
+            .filter(|p| p.id != relay && p.ip_mac.values().any(|mac| !mac.is_empty())),
+    )
+}
+
+// The netmask of a remote subnet is unknown here, only the relay knows its own,
+// see `relay_wol`. So peers are ordered by the longest common ipv4 prefix instead.
+fn rank_by_common_prefix<'a>(
+    peer: &config::DiscoveryPeer,
+    others: impl Iterator<Item = &'a config::DiscoveryPeer>,
+) -> Vec<String> {
+    let ips = |p: &config::DiscoveryPeer| -> Vec<Ipv4Addr> {
+        p.ip_mac.keys().filter_map(|ip| ip.parse().ok()).collect()
+    };
+    let peer_ips = ips(peer);
+    let mut ranked: Vec<(u32, String)> = others
+        .filter_map(|other| {
+            ips(other)
+                .iter()
+                .flat_map(|a| {
+                    peer_ips
+                        .iter()
+                        .map(move |b| (u32::from(*a) ^ u32::from(*b)).leading_zeros())
+                })
+                .max()
+                .map(|n| (n, other.id.clone()))
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    ranked.into_iter().map(|x| x.1).collect()
+}
+
+#[inline]
+fn in_subnet(addr: Ipv4Addr, netmask: Ipv4Addr, ip: Ipv4Addr) -> bool {
+    let mask = u32::from(netmask);
+    u32::from(addr) & mask == u32::from(ip) & mask
+}
+
+/// Send the magic packets of a relay request, only on interfaces in the subnet of the target.
+/// Returns the number of packets sent.
+#[cfg(not(target_os = "ios"))]
+pub fn relay_wol(req: &WolRelayRequest) -> ResultType<usize> {
+    let interfaces = default_net::get_interfaces();
+    let mut sent = 0;
+    for (ip, mac) in req.ip_mac.iter() {
+        let Ok(ip) = ip.parse::<Ipv4Addr>() else {
+            continue;
+        };
+        if let Ok(mac_addr) = mac.parse() {
+            for interface in &interfaces {
+                for ipv4 in &interface.ipv4 {
+                    if !in_subnet(ipv4.addr, ipv4.netmask, ip) {
+                        continue;
+                    }
+                    log::info!("Relay wol to {mac_addr} of {} for {}", ipv4.addr, req.id);
+                    if wol::send_wol(mac_addr, None, Some(IpAddr::V4(ipv4.addr))).is_ok() {
+                        sent += 1;
+                    }
+                }
+            }
+        }
+    }
+    if sent == 0 {
+        bail!("No interface in the subnet of {}", req.id);
+    }
+    Ok(sent)
+}
+
+/// Wait until `id` is registered on the rendezvous server again.
+#[tokio::main(flavor = "current_thread")]
+pub async fn wait_online(id: String, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        let mut online = false;
+        crate::client::peer_online::query_online_states(vec![id.clone()], |onlines, _| {
+            online = onlines.contains(&id);
+        })
+        .await;
+        if online {
+            return true;
+        }
+        tokio::time::sleep(WOL_RELAY_POLL_INTERVAL).await;
+    }
+    false
+}
+
 #[inline]
 fn get_broadcast_port() -> u16 {
     (RENDEZVOUS_PORT + 3) as _
@@ -287,8 +473,38 @@ fn wait_response(
     Ok(())
 }
 
-fn spawn_wait_responses(sockets: Vec<UdpSocket>) -> UnboundedReceiver<config::DiscoveryPeer> {
+fn wait_mdns_response(
+    socket: UdpSocket,
+    tx: UnboundedSender<config::DiscoveryPeer>,
+) -> ResultType<()> {
+    // The broadcast responder filters by mac, here our own id is filtered.
+    let self_id = Config::get_id();
+    mdns::wait_response(&socket, std::time::Duration::from_millis(3_000), |ip, r| {
+        if r.id == self_id {
+            return;
+        }
+        allow_err!(tx.send(config::DiscoveryPeer {
+            id: r.id,
+            ip_mac: HashMap::from([(ip.to_string(), "".to_owned())]),
+            username: "".to_owned(),
+            hostname: r.hostname,
+            platform: r.platform,
+            online: true,
+        }));
+    })
+}
+
+fn spawn_wait_responses(
+    sockets: Vec<UdpSocket>,
+    mdns_socket: Option<UdpSocket>,
+) -> UnboundedReceiver<config::DiscoveryPeer> {
     let (tx, rx) = unbounded_channel::<_>();
+    if let Some(socket) = mdns_socket {
+        let tx_clone = tx.clone();
+        std::thread::spawn(move || {
+            allow_err!(wait_mdns_response(socket, tx_clone));
+        });
+    }
     for socket in sockets {
         let tx_clone = tx.clone();
         std::thread::spawn(move || {
@@ -302,6 +518,32 @@ fn spawn_wait_responses(sockets: Vec<UdpSocket>) -> UnboundedReceiver<config::Di
     rx
 }
 
+// mDNS answers carry no username or mac, they do not wipe what a broadcast answer told.
+fn merge_peer(peer: &mut config::DiscoveryPeer, old: config::DiscoveryPeer, same_round: bool) {
+    if same_round {
+        peer.online = true;
+    }
+    for (ip, mac) in old.ip_mac {
+        match peer.ip_mac.get_mut(&ip) {
+            Some(x) if x.is_empty() => *x = mac,
+            Some(_) => {}
+            None if same_round => {
+                peer.ip_mac.insert(ip, mac);
+            }
+            None => {}
+        }
+    }
+    if peer.username.is_empty() {
+        peer.username = old.username;
+    }
+    if peer.hostname.is_empty() {
+        peer.hostname = old.hostname;
+    }
+    if peer.platform.is_empty() {
+        peer.platform = old.platform;
+    }
+}
+
 async fn handle_received_peers(mut rx: UnboundedReceiver<config::DiscoveryPeer>) -> ResultType<()> {
     let mut peers = config::LanPeers::load().peers;
     peers.iter_mut().for_each(|peer| {
@@ -317,10 +559,7 @@ async fn handle_received_peers(mut rx: UnboundedReceiver<config::DiscoveryPeer>)
                     let in_response_set = !response_set.insert(peer.id.clone());
                     if let Some(pos) = peers.iter().position(|x| x.is_same_peer(&peer) ) {
                         let peer1 = peers.remove(pos);
-                        if in_response_set {
-                            peer.ip_mac.extend(peer1.ip_mac);
-                            peer.online = true;
-                        }
+                        merge_peer(&mut peer, peer1, in_response_set);
                     }
                     peers.insert(0, peer);
                     if last_write_time.map(|t| t.elapsed().as_millis() > 300).unwrap_or(true)  {
@@ -342,3 +581,86 @@ async fn handle_received_peers(mut rx: UnboundedReceiver<config::DiscoveryPeer>)
     crate::flutter_ffi::main_load_lan_peers();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(ip: &str, mac: &str, username: &str) -> config::DiscoveryPeer {
+        config::DiscoveryPeer {
+            id: "123".to_owned(),
+            ip_mac: HashMap::from([(ip.to_owned(), mac.to_owned())]),
+            username: username.to_owned(),
+            hostname: "host".to_owned(),
+            platform: "Linux".to_owned(),
+            online: true,
+        }
+    }
+
+    #[test]
+    fn test_wol_relay_ranking() {
+        let mut target = peer("192.168.1.20", "aa:bb", "");
+        target.id = "target".to_owned();
+        let mut near = peer("192.168.1.7", "", "");
+        near.id = "near".to_owned();
+        let mut far = peer("192.168.7.3", "", "");
+        far.id = "far".to_owned();
+        let mut v6 = peer("fd00::1", "", "");
+        v6.id = "v6".to_owned();
+        // no fixed /24, a relay on a wider subnet is still offered, just later
+        assert_eq!(
+            rank_by_common_prefix(&target, [&far, &near, &v6].into_iter()),
+            vec!["near", "far"]
+        );
+        assert!(in_subnet(
+            "192.168.1.1".parse().unwrap(),
+            "255.255.255.0".parse().unwrap(),
+            "192.168.1.20".parse().unwrap()
+        ));
+        assert!(in_subnet(
+            "10.1.0.1".parse().unwrap(),
+            "255.255.0.0".parse().unwrap(),
+            "10.1.7.20".parse().unwrap()
+        ));
+        assert!(!in_subnet(
+            "192.168.1.1".parse().unwrap(),
+            "255.255.255.0".parse().unwrap(),
+            "192.168.2.20".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_wol_relay_json() {
+        let req = WolRelayRequest {
+            id: "target".to_owned(),
+            ip_mac: HashMap::from([("192.168.1.20".to_owned(), "aa:bb".to_owned())]),
+        };
+        let req2: WolRelayRequest =
+            serde_json::from_slice(&serde_json::to_vec(&req).unwrap()).unwrap();
+        assert_eq!(req2.id, req.id);
+        assert_eq!(req2.ip_mac, req.ip_mac);
+        // an older or newer peer may leave fields out
+        let res: WolRelayResponse = serde_json::from_str(r#"{"id":"target","sent":1}"#).unwrap();
+        assert_eq!(res.sent, 1);
+        assert!(res.error.is_empty());
+    }
+
+    #[test]
+    fn test_merge_peer() {
+        // an mDNS answer after the broadcast answer of the same round
+        let mut p = peer("10.0.0.2", "", "");
+        merge_peer(&mut p, peer("10.0.0.2", "aa:bb", "alice"), true);
+        assert_eq!(p.username, "alice");
+        assert_eq!(p.ip_mac["10.0.0.2"], "aa:bb");
+        // a new round starts over with the addresses, known fields are kept
+        let mut p = peer("10.0.0.3", "", "");
+        merge_peer(&mut p, peer("10.0.0.2", "aa:bb", "alice"), false);
+        assert_eq!(p.username, "alice");
+        assert_eq!(p.ip_mac.len(), 1);
+        // a broadcast answer wins over what was known
+        let mut p = peer("10.0.0.2", "cc:dd", "bob");
+        merge_peer(&mut p, peer("10.0.0.2", "aa:bb", "alice"), true);
+        assert_eq!(p.username, "bob");
+        assert_eq!(p.ip_mac["10.0.0.2"], "cc:dd");
+    }
+}
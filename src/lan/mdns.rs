@@ -0,0 +1,465 @@
+//! DNS-SD over mDNS (RFC 6762 / 6763), so peers can be found across mDNS reflectors
+//! and by other tooling, e.g. `avahi-browse _rustdesk._tcp`.
+//!
+//! Only what discovery needs is implemented: PTR queries for `_rustdesk._tcp.local` and
+//! answers with PTR, SRV, TXT and A records.
+
+use hbb_common::{log, ResultType};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+pub const SERVICE_TYPE: &str = "_rustdesk._tcp.local";
+pub const MDNS_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// Top bit of the class, "unicast response" in questions and "cache flush" in answers.
+const CLASS_FLAG: u16 = 0x8000;
+const FLAGS_RESPONSE: u16 = 0x8400;
+const TTL: u32 = 120;
+const MAX_PACKET_SIZE: usize = 9000;
+
+/// The TXT record of a published peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceRecord {
+    pub id: String,
+    pub hostname: String,
+    pub platform: String,
+    pub version: String,
+}
+
+impl ServiceRecord {
+    fn txt(&self) -> Vec<(&str, &str)> {
+        vec![
+            ("id", &self.id),
+            ("hostname", &self.hostname),
+            ("platform", &self.platform),
+            ("version", &self.version),
+        ]
+    }
+
+    fn from_txt(txt: &HashMap<String, String>) -> Option<Self> {
+        let get = |k: &str| txt.get(k).cloned().unwrap_or_default();
+        let record = Self {
+            id: get("id"),
+            hostname: get("hostname"),
+            platform: get("platform"),
+            version: get("version"),
+        };
+        if record.id.is_empty() {
+            return None;
+        }
+        Some(record)
+    }
+}
+
+/// Bind `group`'s port with address reuse, mDNS responders of the OS usually hold it too.
+pub fn bind_multicast(group: SocketAddrV4) -> ResultType<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+    let socket: UdpSocket = socket.into();
+    socket.set_multicast_loop_v4(true)?;
+    let mut joined = false;
+    #[cfg(not(target_os = "ios"))]
+    for interface in default_net::get_interfaces() {
+        for ipv4 in &interface.ipv4 {
+            if socket.join_multicast_v4(group.ip(), &ipv4.addr).is_ok() {
+                joined = true;
+            }
+        }
+    }
+    if !joined {
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    }
+    Ok(socket)
+}
+
+/// Answer queries for our service, it only returns if the socket cannot be set up.
+/// Receive errors are skipped, e.g. the read timeout or a reset from an earlier answer.
+/// `record` is called per query, `None` means not to answer, e.g. discovery is disabled.
+pub fn serve(
+    socket: &UdpSocket,
+    group: SocketAddrV4,
+    port: u16,
+    record: impl Fn() -> Option<ServiceRecord>,
+) -> ResultType<()> {
+    socket.set_read_timeout(Some(Duration::from_millis(1000)))?;
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let Ok((len, addr)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        let Some(unicast) = parse_query(&buf[..len]) else {
+            continue;
+        };
+        let Some(record) = record() else {
+            continue;
+        };
+        let Some(IpAddr::V4(ip)) = super::get_ipaddr_by_peer(addr) else {
+            continue;
+        };
+        let response = build_response(&record, ip, port);
+        // Legacy resolvers send from another port and expect the answer there.
+        if unicast || addr.port() != group.port() {
+            socket.send_to(&response, addr).ok();
+        } else {
+            socket.send_to(&response, group).ok();
+        }
+    }
+}
+
+/// Send a query from an ephemeral port, responders answer it with unicast.
+pub fn send_query(group: SocketAddrV4) -> ResultType<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.send_to(&build_query(), group)?;
+    log::info!("mdns query sent");
+    Ok(socket)
+}
+
+/// Collect answers until nothing has been received for `idle`.
+pub fn wait_response(
+    socket: &UdpSocket,
+    idle: Duration,
+    mut f: impl FnMut(IpAddr, ServiceRecord),
+) -> ResultType<()> {
+    socket.set_read_timeout(Some(Duration::from_millis(10)))?;
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    let mut last_recv_time = Instant::now();
+    while last_recv_time.elapsed() < idle {
+        if let Ok((len, addr)) = socket.recv_from(&mut buf) {
+            last_recv_time = Instant::now();
+            for record in parse_response(&buf[..len]) {
+                f(addr.ip(), record);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn put_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|x| !x.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_record(buf: &mut Vec<u8>, name: &str, t: u16, class: u16, rdata: &[u8]) {
+    put_name(buf, name);
+    put_u16(buf, t);
+    put_u16(buf, class);
+    buf.extend_from_slice(&TTL.to_be_bytes());
+    put_u16(buf, rdata.len() as u16);
+    buf.extend_from_slice(rdata);
+}
+
+fn build_header(questions: u16, answers: u16, flags: u16) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(512);
+    put_u16(&mut buf, 0);
+    put_u16(&mut buf, flags);
+    put_u16(&mut buf, questions);
+    put_u16(&mut buf, answers);
+    put_u16(&mut buf, 0);
+    put_u16(&mut buf, 0);
+    buf
+}
+
+fn build_query() -> Vec<u8> {
+    let mut buf = build_header(1, 0, 0);
+    put_name(&mut buf, SERVICE_TYPE);
+    put_u16(&mut buf, TYPE_PTR);
+    put_u16(&mut buf, CLASS_IN | CLASS_FLAG);
+    buf
+}
+
+fn build_response(record: &ServiceRecord, ip: Ipv4Addr, port: u16) -> Vec<u8> {
+    let instance = format!("{}.{}", record.id, SERVICE_TYPE);
+    let host = format!("rustdesk-{}.local", record.id);
+    let mut buf = build_header(0, 4, FLAGS_RESPONSE);
+
+    let mut rdata = Vec::new();
+    put_name(&mut rdata, &instance);
+    put_record(&mut buf, SERVICE_TYPE, TYPE_PTR, CLASS_IN, &rdata);
+
+    let mut rdata = Vec::new();
+    put_u16(&mut rdata, 0);
+    put_u16(&mut rdata, 0);
+    put_u16(&mut rdata, port);
+    put_name(&mut rdata, &host);
+    put_record(&mut buf, &instance, TYPE_SRV, CLASS_IN | CLASS_FLAG, &rdata);
+
+    let mut rdata = Vec::new();
+    for (k, v) in record.txt() {
+        let entry = format!("{}={}", k, v);
+        let entry = &entry.as_bytes()[..entry.len().min(255)];
+        rdata.push(entry.len() as u8);
+        rdata.extend_from_slice(entry);
+    }
+    put_record(&mut buf, &instance, TYPE_TXT, CLASS_IN | CLASS_FLAG, &rdata);
+
+    put_record(&mut buf, &host, TYPE_A, CLASS_IN | CLASS_FLAG, &ip.octets());
+    buf
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u16(&mut self) -> Option<u16> {
+        let v = self.packet.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some(u16::from_be_bytes([v[0], v[1]]))
+    }
+
+    fn skip(&mut self, n: usize) -> Option<&'a [u8]> {
+        let v = self.packet.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(v)
+    }
+
+    // Names may be compressed with pointers to earlier names.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        for _ in 0..128 {
+            let len = *self.packet.get(pos)? as usize;
+            if len == 0 {
+                self.pos = end.unwrap_or(pos + 1);
+                return Some(labels.join("."));
+            }
+            if len & 0xc0 == 0xc0 {
+                let low = *self.packet.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = ((len & 0x3f) << 8) | low;
+                continue;
+            }
+            let label = self.packet.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).to_string());
+            pos += 1 + len;
+        }
+        None
+    }
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+// Returns whether a unicast response is requested, `None` if the packet does not ask for us.
+fn parse_query(packet: &[u8]) -> Option<bool> {
+    let mut r = Reader { packet, pos: 0 };
+    r.skip(2)?;
+    let flags = r.u16()?;
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+    let questions = r.u16()?;
+    r.skip(6)?;
+    for _ in 0..questions {
+        let name = r.name()?;
+        let t = r.u16()?;
+        let class = r.u16()?;
+        if (t == TYPE_PTR || t == TYPE_ANY) && same_name(&name, SERVICE_TYPE) {
+            return Some(class & CLASS_FLAG != 0);
+        }
+    }
+    None
+}
+
+fn parse_response(packet: &[u8]) -> Vec<ServiceRecord> {
+    let mut res = Vec::new();
+    let mut r = Reader { packet, pos: 0 };
+    let Some(header) = r.skip(12) else {
+        return res;
+    };
+    if header[2] & 0x80 == 0 {
+        return res;
+    }
+    let count = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]) as usize;
+    for _ in 0..count(4) {
+        if r.name().is_none() || r.skip(4).is_none() {
+            return res;
+        }
+    }
+    // Answers and additional records, authority records are not expected here.
+    for _ in 0..count(6) + count(8) + count(10) {
+        let (Some(name), Some(t), Some(_), Some(_), Some(len)) =
+            (r.name(), r.u16(), r.u16(), r.skip(4), r.u16())
+        else {
+            break;
+        };
+        let Some(rdata) = r.skip(len as _) else {
+            break;
+        };
+        let suffix = format!(".{}", SERVICE_TYPE);
+        if t != TYPE_TXT || !name.to_lowercase().ends_with(&suffix) {
+            continue;
+        }
+        let mut txt = HashMap::new();
+        let mut pos = 0;
+        while pos < rdata.len() {
+            let n = rdata[pos] as usize;
+            let Some(entry) = rdata.get(pos + 1..pos + 1 + n) else {
+                break;
+            };
+            let entry = String::from_utf8_lossy(entry);
+            if let Some((k, v)) = entry.split_once('=') {
+                txt.insert(k.to_lowercase(), v.to_owned());
+            }
+            pos += 1 + n;
+        }
+        if let Some(record) = ServiceRecord::from_txt(&txt) {
+            res.push(record);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> ServiceRecord {
+        ServiceRecord {
+            id: "123456789".to_owned(),
+            hostname: "build-01".to_owned(),
+            platform: "Linux".to_owned(),
+            version: "1.4.6".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_query_and_response() {
+        assert_eq!(parse_query(&build_query()), Some(true));
+        let response = build_response(&record(), Ipv4Addr::new(192, 168, 1, 2), 21118);
+        assert_eq!(parse_query(&response), None);
+        assert_eq!(parse_response(&response), vec![record()]);
+        assert!(parse_response(&build_query()).is_empty());
+    }
+
+    #[test]
+    fn test_encoding() {
+        let query = build_query();
+        // header with one question
+        assert_eq!(&query[..12], &[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let mut r = Reader {
+            packet: &query,
+            pos: 12,
+        };
+        assert_eq!(r.name().as_deref(), Some(SERVICE_TYPE));
+        assert_eq!(r.u16(), Some(TYPE_PTR));
+        assert_eq!(r.u16(), Some(CLASS_IN | CLASS_FLAG));
+        assert_eq!(r.pos, query.len());
+
+        // multicast, ANY and foreign queries
+        let question = |name: &str, t: u16, class: u16| {
+            let mut buf = build_header(1, 0, 0);
+            put_name(&mut buf, name);
+            put_u16(&mut buf, t);
+            put_u16(&mut buf, class);
+            buf
+        };
+        assert_eq!(
+            parse_query(&question(SERVICE_TYPE, TYPE_PTR, CLASS_IN)),
+            Some(false)
+        );
+        assert_eq!(
+            parse_query(&question("_RustDesk._tcp.local.", TYPE_ANY, CLASS_IN)),
+            Some(false)
+        );
+        assert_eq!(
+            parse_query(&question("_ssh._tcp.local", TYPE_PTR, CLASS_IN)),
+            None
+        );
+        assert_eq!(parse_query(&question(SERVICE_TYPE, TYPE_A, CLASS_IN)), None);
+        assert_eq!(parse_query(&query[..20]), None);
+
+        let response = build_response(&record(), Ipv4Addr::new(192, 168, 1, 2), 21118);
+        // header with four answers
+        assert_eq!(&response[..12], &[0, 0, 0x84, 0, 0, 0, 0, 4, 0, 0, 0, 0]);
+        let mut r = Reader {
+            packet: &response,
+            pos: 12,
+        };
+        let instance = format!("123456789.{}", SERVICE_TYPE);
+        let mut answers = Vec::new();
+        for _ in 0..4 {
+            let name = r.name().unwrap();
+            let t = r.u16().unwrap();
+            r.skip(6).unwrap();
+            let len = r.u16().unwrap();
+            answers.push((name, t, r.skip(len as _).unwrap().to_vec()));
+        }
+        assert_eq!(r.pos, response.len());
+        assert_eq!(answers[0].0, SERVICE_TYPE);
+        assert_eq!(answers[0].1, TYPE_PTR);
+        assert_eq!(
+            (answers[1].0.as_str(), answers[1].1),
+            (instance.as_str(), TYPE_SRV)
+        );
+        // priority, weight and port
+        assert_eq!(&answers[1].2[..6], &[0, 0, 0, 0, 0x52, 0x7e]);
+        assert_eq!(
+            (answers[2].0.as_str(), answers[2].1),
+            (instance.as_str(), TYPE_TXT)
+        );
+        assert_eq!(&answers[2].2[..13], b"\x0cid=123456789");
+        assert_eq!(answers[3].0, "rustdesk-123456789.local");
+        assert_eq!(
+            (answers[3].1, answers[3].2.as_slice()),
+            (TYPE_A, &[192, 168, 1, 2][..])
+        );
+    }
+
+    #[test]
+    fn test_compressed_name() {
+        // "a.local" followed by a pointer to "local" at offset 14.
+        let packet = [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, b'a', 5, b'l', b'o', b'c', b'a', b'l', 0, 0xc0,
+            14,
+        ];
+        let mut r = Reader {
+            packet: &packet,
+            pos: 12,
+        };
+        assert_eq!(r.name().as_deref(), Some("a.local"));
+        assert_eq!(r.name().as_deref(), Some("local"));
+        assert_eq!(r.pos, packet.len());
+    }
+
+    // Goes through the multicast loopback of this host on a private port,
+    // run with `cargo test -- --ignored` on a host with a multicast capable interface.
+    #[test]
+    #[ignore = "needs a multicast capable network interface"]
+    fn test_multicast_loopback() {
+        let group = SocketAddrV4::new(*MDNS_GROUP.ip(), 53530);
+        let server = bind_multicast(group).expect("bind multicast");
+        std::thread::spawn(move || serve(&server, group, 21118, || Some(record())));
+        let client = send_query(group).expect("send query");
+        let mut found = Vec::new();
+        wait_response(&client, Duration::from_millis(1500), |_, r| found.push(r)).unwrap();
+        assert_eq!(found.first(), Some(&record()));
+    }
+}
@@ -746,5 +746,17 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Display Name", "显示名称"),
         ("password-hidden-tip", "永久密码已设置（已隐藏）"),
         ("preset-password-in-use-tip", "当前使用预设密码"),
+        ("Recovery codes", ""),
+        ("recovery-codes-tip", ""),
+        ("Screen content", ""),
+        ("Record audio of incoming sessions", ""),
+        ("Record audio of outgoing sessions", ""),
+        ("Enable reverse TCP tunneling", ""),
+        ("reverse-tunnel-tip", ""),
+        ("Enable Wake-on-LAN relay", ""),
+        ("Wake-on-LAN relay", ""),
+        ("wol-relay-tip", ""),
+        ("Regenerate recovery codes", ""),
+        ("regenerate-recovery-codes-confirm-tip", ""),
     ].iter().cloned().collect();
 }
@@ -277,5 +277,9 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("keep-awake-during-incoming-sessions-label", "Keep screen awake during incoming sessions"),
         ("password-hidden-tip", "Permanent password is set (hidden)."),
         ("preset-password-in-use-tip", "Preset password is currently in use."),
+        ("recovery-codes-tip", "Save these recovery codes somewhere safe. Each code can be used once instead of the 2FA code, they will not be shown again."),
+        ("reverse-tunnel-tip", "The peer listens on 127.0.0.1 for the forwarded port, or on the address of the reverse-tunnel-bind-address option when it is set."),
+        ("wol-relay-tip", "If the peer is on another subnet, connect to one of these peers and wake it from the Wake-on-LAN relay entry of the session menu:"),
+        ("regenerate-recovery-codes-confirm-tip", "The current recovery codes will stop working. Generate new ones?"),
     ].iter().cloned().collect();
 }
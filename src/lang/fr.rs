@@ -743,5 +743,17 @@ pub static ref T: std::collections::HashMap<&'static str, &'static str> =
         ("Display Name", "Nom d’affichage"),
         ("password-hidden-tip", ""),
         ("preset-password-in-use-tip", ""),
+        ("Recovery codes", ""),
+        ("recovery-codes-tip", ""),
+        ("Screen content", ""),
+        ("Record audio of incoming sessions", ""),
+        ("Record audio of outgoing sessions", ""),
+        ("Enable reverse TCP tunneling", ""),
+        ("reverse-tunnel-tip", ""),
+        ("Enable Wake-on-LAN relay", ""),
+        ("Wake-on-LAN relay", ""),
+        ("wol-relay-tip", ""),
+        ("Regenerate recovery codes", ""),
+        ("regenerate-recovery-codes-confirm-tip", ""),
     ].iter().cloned().collect();
 }
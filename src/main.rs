@@ -38,24 +38,151 @@ fn main() {
     if !common::global_init() {
         return;
     }
-    use clap::App;
+    use clap::{Arg, ArgAction, ArgMatches, Command};
     use hbb_common::log;
-    let args = format!(
-        "-p, --port-forward=[PORT-FORWARD-OPTIONS] 'Format: remote-id:local-port:remote-port[:remote-host]'
-        -c, --connect=[REMOTE_ID] 'test only'
-        -k, --key=[KEY] ''
-       -s, --server=[] 'Start server'",
-    );
-    let matches = App::new("rustdesk")
+    let peer_args = || {
+        [
+            Arg::new("id").required(true).help("Remote id"),
+            Arg::new("password")
+                .long("password")
+                .help("Login password, the saved one is used if not set"),
+            Arg::new("conn-token")
+                .long("conn-token")
+                .help("Connection token of an existing session"),
+            Arg::new("timeout")
+                .long("timeout")
+                .value_parser(clap::value_parser!(u64))
+                .help("Timeout in seconds"),
+        ]
+    };
+    let matches = Command::new("rustdesk")
         .version(crate::VERSION)
         .author("Purslane Ltd<info@rustdesk.com>")
         .about("RustDesk command line tool")
-        .args_from_usage(&args)
+        .arg(
+            Arg::new("port-forward")
+                .short('p')
+                .long("port-forward")
+                .help("Format: remote-id:local-port:remote-port[:remote-host], remote-host \"socks://\" starts a SOCKS5 / HTTP CONNECT proxy, \"reverse://[local-host]\" makes the peer listen on remote-port for local-host:local-port"),
+        )
+        .arg(
+            Arg::new("connect")
+                .short('c')
+                .long("connect")
+                .help("test only"),
+        )
+        .arg(Arg::new("key").short('k').long("key").global(true))
+        .arg(
+            Arg::new("server")
+                .short('s')
+                .long("server")
+                .action(ArgAction::SetTrue)
+                .help("Start server"),
+        )
+        .subcommand(
+            Command::new("connect")
+                .about("Log in and print the peer info")
+                .args(peer_args()),
+        )
+        .subcommand(
+            Command::new("exec")
+                .about("Run a command in a remote terminal, its exit code is in the JSON output")
+                .args(peer_args())
+                .arg(Arg::new("command").required(true)),
+        )
+        .subcommand(
+            Command::new("push")
+                .about("Upload a file or directory")
+                .args(peer_args())
+                .arg(Arg::new("local").required(true))
+                .arg(Arg::new("remote").required(true))
+                .arg(
+                    Arg::new("include-hidden")
+                        .long("include-hidden")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("pull")
+                .about("Download a file or directory")
+                .args(peer_args())
+                .arg(Arg::new("remote").required(true))
+                .arg(Arg::new("local").required(true))
+                .arg(
+                    Arg::new("include-hidden")
+                        .long("include-hidden")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("screenshot")
+                .about("Save a png screenshot of a remote display")
+                .args(peer_args())
+                .arg(Arg::new("path").required(true))
+                .arg(
+                    Arg::new("display")
+                        .long("display")
+                        .value_parser(clap::value_parser!(i32))
+                        .default_value("0"),
+                ),
+        )
         .get_matches();
     use hbb_common::{config::LocalConfig, env_logger::*};
-    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
-    if let Some(p) = matches.value_of("port-forward") {
-        let options: Vec<String> = p.split(":").map(|x| x.to_owned()).collect();
+    // stdout is reserved for the json output of the subcommands.
+    let filter = if matches.subcommand().is_some() {
+        "warn"
+    } else {
+        "info"
+    };
+    init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, filter));
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .unwrap_or_default();
+    let token = LocalConfig::get_option("access_token");
+    let get_str =
+        |m: &ArgMatches, name: &str| m.get_one::<String>(name).cloned().unwrap_or_default();
+    let headless_options = |m: &ArgMatches| cli::HeadlessOptions {
+        id: get_str(m, "id"),
+        password: get_str(m, "password"),
+        conn_token: m.get_one::<String>("conn-token").cloned(),
+        key: key.clone(),
+        token: token.clone(),
+        timeout: m
+            .get_one::<u64>("timeout")
+            .map(|t| std::time::Duration::from_secs(*t)),
+    };
+    if let Some((name, m)) = matches.subcommand() {
+        common::test_rendezvous_server();
+        common::test_nat_type();
+        let opts = headless_options(m);
+        let code = match name {
+            "connect" => cli::connect(opts),
+            "exec" => cli::exec(opts, get_str(m, "command")),
+            "push" => cli::push(
+                opts,
+                get_str(m, "local"),
+                get_str(m, "remote"),
+                m.get_flag("include-hidden"),
+            ),
+            "pull" => cli::pull(
+                opts,
+                get_str(m, "remote"),
+                get_str(m, "local"),
+                m.get_flag("include-hidden"),
+            ),
+            "screenshot" => cli::screenshot(
+                opts,
+                m.get_one::<i32>("display").cloned().unwrap_or_default(),
+                get_str(m, "path"),
+            ),
+            _ => cli::EXIT_USAGE,
+        };
+        common::global_clean();
+        std::process::exit(code);
+    } else if let Some(p) = matches.get_one::<String>("port-forward") {
+        // The remote host may contain ':', e.g. "udp://host" or "socks://".
+        let options: Vec<String> = p.splitn(4, ":").map(|x| x.to_owned()).collect();
         if options.len() < 3 {
             log::error!("Wrong port-forward options");
             return;
@@ -80,8 +207,6 @@ fn main() {
         }
         common::test_rendezvous_server();
         common::test_nat_type();
-        let key = matches.value_of("key").unwrap_or("").to_owned();
-        let token = LocalConfig::get_option("access_token");
         cli::start_one_port_forward(
             options[0].clone(),
             port,
@@ -90,13 +215,11 @@ fn main() {
             key,
             token,
         );
-    } else if let Some(p) = matches.value_of("connect") {
+    } else if let Some(p) = matches.get_one::<String>("connect") {
         common::test_rendezvous_server();
         common::test_nat_type();
-        let key = matches.value_of("key").unwrap_or("").to_owned();
-        let token = LocalConfig::get_option("access_token");
         cli::connect_test(p, key, token);
-    } else if let Some(p) = matches.value_of("server") {
+    } else if matches.get_flag("server") {
         log::info!("id={}", hbb_common::config::Config::get_id());
         crate::start_server(true, false);
     }
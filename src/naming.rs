@@ -1,5 +1,14 @@
+#[allow(dead_code)]
 mod custom_server;
-use hbb_common::{ResultType, base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _}};
+use hbb_common::{
+    base64::{
+        engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+        Engine as _,
+    },
+    bail,
+    sodiumoxide::crypto::sign,
+    ResultType,
+};
 use custom_server::*;
 
 fn gen_name(lic: &CustomServer) -> ResultType<String> {
@@ -7,8 +16,64 @@ fn gen_name(lic: &CustomServer) -> ResultType<String> {
     Ok(tmp.chars().rev().collect())
 }
 
+fn gen_keypair() {
+    let (pk, sk) = sign::gen_keypair();
+    println!("public key: {}", STANDARD.encode(pk));
+    println!("secret key: {}", STANDARD.encode(sk));
+}
+
+// The secret key is read from a file, or from `RUSTDESK_BUNDLE_SECRET_KEY` if the file is `-`,
+// so that it does not show up in the process list or the shell history.
+// The bundle is a json file of `ServerBundle`.
+fn sign_bundle(sk_path: &str, path: &str) -> ResultType<String> {
+    let sk = if sk_path == "-" {
+        std::env::var("RUSTDESK_BUNDLE_SECRET_KEY")?
+    } else {
+        std::fs::read_to_string(sk_path)?
+    };
+    let Some(sk) = sign::SecretKey::from_slice(&STANDARD.decode(sk.trim())?) else {
+        bail!("Invalid secret key");
+    };
+    let bundle = serde_json::from_slice::<ServerBundle>(&std::fs::read(path)?)?;
+    sign_server_bundle(&bundle, &sk)
+}
+
+fn verify_bundle(pk: &str, path: &str) -> ResultType<ServerBundle> {
+    let Some(pk) = sign::PublicKey::from_slice(&STANDARD.decode(pk.trim())?) else {
+        bail!("Invalid public key");
+    };
+    verify_server_bundle(&std::fs::read_to_string(path)?, &pk)
+}
+
 fn main() {
     let args: Vec<_> = std::env::args().skip(1).collect();
+    match (args.first().map(|x| x.as_str()), args.len()) {
+        (Some("keygen"), 1) => {
+            gen_keypair();
+            return;
+        }
+        (Some("sign"), 3) => {
+            match sign_bundle(&args[1], &args[2]) {
+                Ok(bundle) => println!("{}", bundle),
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        (Some("verify"), 3) => {
+            match verify_bundle(&args[1], &args[2]) {
+                Ok(bundle) => println!("{:?}", bundle),
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
     let api = args.get(2).cloned().unwrap_or_default();
     let relay = args.get(3).cloned().unwrap_or_default();
     if args.len() >= 2 {
@@ -2015,6 +2015,9 @@ pub fn prepare_custom_client_update() -> ResultType<bool> {
 }
 
 pub fn get_license_from_exe_name() -> ResultType<CustomServer> {
+    if let Some(server) = crate::common::get_bundled_server() {
+        return Ok(server);
+    }
     let mut exe = std::env::current_exe()?.to_str().unwrap_or("").to_owned();
     // if defined portable appname entry, replace original executable name with it.
     if let Ok(portable_exe) = std::env::var(PORTABLE_APPNAME_RUNTIME_ENV_KEY) {
@@ -3248,6 +3251,27 @@ taskkill /F /IM {app_name}.exe{filter}
     Ok(())
 }
 
+/// Puts back the installation backed up before an update, see `updater::run_rollback_watchdog`.
+pub fn restore_install_dir(
+    app_name: &str,
+    backup_dir: &std::path::Path,
+    install_dir: &std::path::Path,
+) -> ResultType<()> {
+    let filter = format!(" /FI \"PID ne {}\"", get_current_pid());
+    let cmds = format!(
+        "
+chcp 65001
+sc stop {app_name}
+taskkill /F /IM {app_name}.exe{filter}
+xcopy \"{backup}\" \"{install}\" /Y /E /H /C /I /K /R /Z
+sc start {app_name}
+    ",
+        backup = backup_dir.to_string_lossy(),
+        install = install_dir.to_string_lossy(),
+    );
+    run_cmds(cmds, false, "rollback")
+}
+
 fn get_reg_msi_key(subkey: &str, is_msi: Option<bool>) -> Option<String> {
     // Only proceed if it's a custom client and MSI is installed.
     // `is_msi.unwrap_or(true)` is intentional: subsequent code validates the registry,
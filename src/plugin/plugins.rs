@@ -362,6 +362,9 @@ fn load_plugin_path(path: &str) -> ResultType<()> {
     // to-do check the plugin id (make sure it does not use another plugin's id)
 
     let id = desc.meta().id.clone();
+    if id == crate::lan::WOL_RELAY_REQUEST_ID {
+        bail!("Plugin id {} is reserved", id);
+    }
     let plugin_info = PluginInfo {
         path: path.to_string(),
         uninstalled: false,
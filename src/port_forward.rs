@@ -1,20 +1,99 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
 
-use crate::client::*;
+use crate::{
+    client::*,
+    common::PortForwardKind,
+    port_forward_mux::{Connector, LocalChannel, Mux, Role, PEER_SUPPORT_MUX},
+};
 use hbb_common::{
     allow_err, bail,
+    bytes::Bytes,
     config::READ_TIMEOUT,
-    futures::{SinkExt, StreamExt},
+    futures::{future::BoxFuture, SinkExt, StreamExt},
     log,
     message_proto::*,
     protobuf::Message as _,
     rendezvous_proto::ConnType,
     tcp, timeout,
-    tokio::{self, net::TcpStream, sync::mpsc},
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UdpSocket},
+        sync::mpsc,
+        time::{self, Duration, Instant},
+    },
     tokio_util::codec::{BytesCodec, Framed},
     ResultType, Stream,
 };
 
+// Udp has no close, a client address is forgotten after this long without traffic.
+const UDP_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const UDP_MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// A port forward with this remote host is a local SOCKS5 / HTTP CONNECT proxy,
+/// each proxied connection picks its own target and the remote port is ignored.
+pub const DYNAMIC_FORWARD_HOST: &str = "socks://";
+const MAX_HTTP_CONNECT_HEADER_SIZE: usize = 8192;
+/// Peer option, "N" opens one peer connection per forwarded tcp connection again.
+///
+/// By default the tcp forwards to a peer carry their connections over one logged-in peer
+/// connection once a login told that the peer supports it, see `PEER_SUPPORT_MUX`.
+pub const OPTION_PORT_FORWARD_MUX: &str = "port-forward-mux";
+
+#[derive(Default)]
+struct MuxPeer {
+    supported: AtomicBool,
+    // Also held while logging in, so a peer gets only one mux connection.
+    tx: tokio::sync::Mutex<Option<mpsc::UnboundedSender<LocalChannel>>>,
+}
+
+// The first forwarded connection to a peer always logs in by itself and tells whether
+// the following ones can go over a mux.
+lazy_static::lazy_static! {
+    static ref MUX_PEERS: Mutex<HashMap<String, Arc<MuxPeer>>> = Default::default();
+}
+
+fn mux_peer(id: &str) -> Arc<MuxPeer> {
+    MUX_PEERS
+        .lock()
+        .unwrap()
+        .entry(id.to_owned())
+        .or_default()
+        .clone()
+}
+
+// Forget the closed mux connection of the peer, unless a newer entry took its place.
+fn remove_mux_peer(id: &str, peer: &Arc<MuxPeer>) {
+    let mut peers = MUX_PEERS.lock().unwrap();
+    if peers.get(id).is_some_and(|x| Arc::ptr_eq(x, peer)) {
+        peers.remove(id);
+    }
+}
+
+fn is_mux_supported(pi: &PeerInfo) -> bool {
+    serde_json::from_str::<serde_json::Value>(&pi.platform_additions)
+        .map(|v| v[PEER_SUPPORT_MUX] == true)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyProtocol {
+    Socks5,
+    HttpConnect,
+}
+
+#[inline]
+pub fn is_dynamic_forward(remote_host: &str) -> bool {
+    remote_host == DYNAMIC_FORWARD_HOST
+}
+
 fn run_rdp(port: u16) {
     std::process::Command::new("cmdkey")
         .arg("/delete:localhost")
@@ -54,6 +133,36 @@ pub async fn listen(
     remote_host: String,
     remote_port: i32,
 ) -> ResultType<()> {
+    if PortForwardKind::parse(&remote_host).0 == PortForwardKind::Reverse {
+        return listen_reverse(
+            id,
+            password,
+            port,
+            interface,
+            ui_receiver,
+            key,
+            token,
+            lc,
+            remote_host,
+            remote_port,
+        )
+        .await;
+    }
+    if PortForwardKind::parse(&remote_host).0 == PortForwardKind::Udp {
+        return listen_udp(
+            id,
+            password,
+            port,
+            interface,
+            ui_receiver,
+            key,
+            token,
+            lc,
+            remote_host,
+            remote_port,
+        )
+        .await;
+    }
     let listener = tcp::new_listener(format!("127.0.0.1:{}", port), true).await?;
     let addr = listener.local_addr()?;
     log::info!("listening on port {:?}", addr);
@@ -61,30 +170,36 @@ pub async fn listen(
     if is_rdp {
         run_rdp(addr.port());
     }
+    let use_mux = !is_rdp && lc.read().unwrap().get_option(OPTION_PORT_FORWARD_MUX) != "N";
+    let ctx = Arc::new(ForwardContext {
+        id,
+        password,
+        key: key.to_owned(),
+        token: token.to_owned(),
+        lc,
+        target: (remote_host.clone(), remote_port),
+        is_rdp,
+        is_dynamic: is_dynamic_forward(&remote_host),
+        use_mux,
+    });
+    let (login_tx, login_rx) = mpsc::unbounded_channel();
+    let slot = Arc::new(tokio::sync::Mutex::new(LoginSlot {
+        ui_receiver: login_rx,
+    }));
+    let mut logins: Vec<tokio::task::JoinHandle<()>> = Vec::new();
     let mut ui_receiver = ui_receiver;
     loop {
         tokio::select! {
             Ok((forward, addr)) = listener.accept() => {
                 log::info!("new connection from {:?}", addr);
-                lc.write().unwrap().port_forward = (remote_host.clone(), remote_port);
-                let id = id.clone();
-                let password = password.clone();
-                let mut forward = Framed::new(forward, BytesCodec::new());
-                match connect_and_login(&id, &password, &mut ui_receiver, interface.clone(), &mut forward, key, token, is_rdp).await {
-                    Ok(Some(stream)) => {
-                        let interface = interface.clone();
-                        tokio::spawn(async move {
-                            if let Err(err) = run_forward(forward, stream).await {
-                                interface.msgbox("error", "Error", &err.to_string(), "");
-                            }
-                            log::info!("connection from {:?} closed", addr);
-                       });
-                    }
-                    Err(err) => {
-                        interface.on_establish_connection_error(err.to_string());
-                    }
-                    _ => {}
-                }
+                logins.retain(|x| !x.is_finished());
+                logins.push(tokio::spawn(accept_forward(
+                    forward,
+                    addr,
+                    ctx.clone(),
+                    slot.clone(),
+                    interface.clone(),
+                )));
             }
             d = ui_receiver.recv() => {
                 match d {
@@ -95,6 +210,440 @@ pub async fn listen(
                         println!("receive run_rdp from ui_receiver");
                         run_rdp(addr.port());
                     }
+                    Some(d) => {
+                        login_tx.send(d).ok();
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+    // Established forwards run in their own tasks and are kept.
+    for login in logins {
+        login.abort();
+    }
+    Ok(())
+}
+
+struct ForwardContext {
+    id: String,
+    password: String,
+    key: String,
+    token: String,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    target: (String, i32),
+    is_rdp: bool,
+    is_dynamic: bool,
+    use_mux: bool,
+}
+
+// The logins of one forward share `lc.port_forward` and the login prompts of the ui,
+// so they take turns on this slot while the listener keeps accepting.
+struct LoginSlot {
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+}
+
+async fn accept_forward(
+    forward: TcpStream,
+    addr: SocketAddr,
+    ctx: Arc<ForwardContext>,
+    slot: Arc<tokio::sync::Mutex<LoginSlot>>,
+    interface: impl Interface,
+) {
+    let mut forward = forward;
+    let mut proxy = None;
+    let mut target = ctx.target.clone();
+    if ctx.is_dynamic {
+        match timeout(READ_TIMEOUT, read_proxy_request(&mut forward)).await {
+            Ok(Ok((protocol, host, port))) => {
+                log::info!(
+                    "{:?} request from {:?} to {}:{}",
+                    protocol,
+                    addr,
+                    host,
+                    port
+                );
+                target = (host, port);
+                proxy = Some(protocol);
+            }
+            Ok(Err(err)) => {
+                log::warn!("Invalid proxy request from {:?}: {}", addr, err);
+                return;
+            }
+            Err(_) => {
+                log::warn!("Proxy request from {:?} timed out", addr);
+                return;
+            }
+        }
+    }
+    let peer = mux_peer(&ctx.id);
+    if ctx.use_mux && peer.supported.load(Ordering::SeqCst) {
+        let mut mux_tx = peer.tx.lock().await;
+        let mut channel = LocalChannel {
+            socket: forward,
+            target: format!("{}:{}", target.0, target.1),
+            reply: proxy.map(|p| (proxy_reply(p, true), proxy_reply(p, false))),
+        };
+        if let Some(tx) = mux_tx.as_ref() {
+            match tx.send(channel) {
+                Ok(_) => return,
+                // The mux connection is gone, log in again.
+                Err(err) => channel = err.0,
+            }
+        }
+        let mut slot = slot.lock().await;
+        ctx.lc.write().unwrap().port_forward = (PortForwardKind::Mux.with_host(""), 0);
+        match connect_and_login(
+            &ctx.id,
+            &ctx.password,
+            &mut slot.ui_receiver,
+            interface.clone(),
+            None,
+            &ctx.key,
+            &ctx.token,
+            false,
+        )
+        .await
+        {
+            Ok(Some(stream)) => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tx.send(channel).ok();
+                *mux_tx = Some(tx);
+                let id = ctx.id.clone();
+                let peer = peer.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = run_mux_forward(stream, rx, &peer).await {
+                        interface.msgbox("error", "Error", &err.to_string(), "");
+                    }
+                    remove_mux_peer(&id, &peer);
+                    log::info!("port forward mux connection closed");
+                });
+            }
+            res => {
+                if let Some((_, failure)) = channel.reply.as_ref() {
+                    if let Err(err) = channel.socket.write_all(failure).await {
+                        log::warn!("Failed to reply proxy request from {:?}: {}", addr, err);
+                    }
+                }
+                if let Err(err) = res {
+                    interface.on_establish_connection_error(err.to_string());
+                }
+            }
+        }
+        return;
+    }
+    let mut slot = slot.lock().await;
+    ctx.lc.write().unwrap().port_forward = target;
+    let mut forward = Framed::new(forward, BytesCodec::new());
+    let res = connect_and_login(
+        &ctx.id,
+        &ctx.password,
+        &mut slot.ui_receiver,
+        interface.clone(),
+        Some(&mut forward),
+        &ctx.key,
+        &ctx.token,
+        ctx.is_rdp,
+    )
+    .await;
+    drop(slot);
+    if let Some(protocol) = proxy {
+        let ok = matches!(res, Ok(Some(_)));
+        if let Err(err) = forward
+            .get_mut()
+            .write_all(&proxy_reply(protocol, ok))
+            .await
+        {
+            log::warn!("Failed to reply proxy request from {:?}: {}", addr, err);
+            return;
+        }
+    }
+    match res {
+        Ok(Some(stream)) => {
+            tokio::spawn(async move {
+                if let Err(err) = run_forward(forward, stream).await {
+                    interface.msgbox("error", "Error", &err.to_string(), "");
+                }
+                log::info!("connection from {:?} closed", addr);
+            });
+        }
+        Err(err) if ctx.is_dynamic => {
+            // One unreachable target should not tear down the whole proxy.
+            log::warn!("Proxy connection from {:?} failed: {}", addr, err);
+        }
+        Err(err) => {
+            interface.on_establish_connection_error(err.to_string());
+        }
+        _ => {}
+    }
+}
+
+/// Listen on a local udp port, each client address gets its own peer connection.
+async fn listen_udp(
+    id: String,
+    password: String,
+    port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    remote_host: String,
+    remote_port: i32,
+) -> ResultType<()> {
+    let socket = Arc::new(UdpSocket::bind(format!("127.0.0.1:{}", port)).await?);
+    log::info!("listening on udp port {:?}", socket.local_addr()?);
+    let ctx = Arc::new(ForwardContext {
+        id,
+        password,
+        key: key.to_owned(),
+        token: token.to_owned(),
+        lc,
+        target: (remote_host, remote_port),
+        is_rdp: false,
+        is_dynamic: false,
+        use_mux: false,
+    });
+    let (login_tx, login_rx) = mpsc::unbounded_channel();
+    let slot = Arc::new(tokio::sync::Mutex::new(LoginSlot {
+        ui_receiver: login_rx,
+    }));
+    let (result_tx, mut result_rx) = mpsc::unbounded_channel();
+    let mut logins: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+    let mut ui_receiver = ui_receiver;
+    let mut sessions = UdpSessions::default();
+    let mut buf = vec![0u8; UDP_MAX_DATAGRAM_SIZE];
+    let mut timer = crate::rustdesk_interval(time::interval(UDP_SESSION_IDLE_TIMEOUT));
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let (n, addr) = match res {
+                    Ok(x) => x,
+                    Err(err) => {
+                        // e.g. ICMP port unreachable of a previous reply on Windows
+                        log::debug!("udp recv error: {}", err);
+                        continue;
+                    }
+                };
+                let data = Bytes::copy_from_slice(&buf[..n]);
+                let rx = match sessions.route(addr, data, Instant::now()) {
+                    UdpRoute::Queued => continue,
+                    UdpRoute::Dropped => {
+                        log::debug!("udp datagram from {:?} dropped", addr);
+                        continue;
+                    }
+                    UdpRoute::NewSession(rx) => rx,
+                };
+                log::info!("new udp session from {:?}", addr);
+                logins.retain(|x| !x.is_finished());
+                logins.push(tokio::spawn(login_udp_session(
+                    socket.clone(),
+                    addr,
+                    rx,
+                    ctx.clone(),
+                    slot.clone(),
+                    result_tx.clone(),
+                    interface.clone(),
+                )));
+            }
+            Some((addr, ok)) = result_rx.recv() => {
+                sessions.on_login(addr, ok, Instant::now());
+            }
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Close) => {
+                        break;
+                    }
+                    Some(d) => {
+                        login_tx.send(d).ok();
+                    }
+                    None => {}
+                }
+            }
+            _ = timer.tick() => {
+                sessions.expire(Instant::now());
+            }
+        }
+    }
+    for login in logins {
+        login.abort();
+    }
+    Ok(())
+}
+
+// Datagrams of a client address wait here while its session logs in.
+const UDP_SESSION_QUEUE_SIZE: usize = 64;
+// A client address whose login failed is ignored for a while, doubling up to the max,
+// so that a client which keeps sending does not start a login per datagram.
+const UDP_LOGIN_RETRY_MIN: Duration = Duration::from_secs(1);
+const UDP_LOGIN_RETRY_MAX: Duration = Duration::from_secs(60);
+
+enum UdpRoute {
+    Queued,
+    Dropped,
+    NewSession(mpsc::Receiver<Bytes>),
+}
+
+#[derive(Default)]
+struct UdpSessions {
+    sessions: HashMap<SocketAddr, mpsc::Sender<Bytes>>,
+    // failed logins in a row and when to try again
+    failures: HashMap<SocketAddr, (u32, Instant)>,
+}
+
+impl UdpSessions {
+    fn route(&mut self, addr: SocketAddr, data: Bytes, now: Instant) -> UdpRoute {
+        let mut data = data;
+        if let Some(tx) = self.sessions.get(&addr) {
+            match tx.try_send(data) {
+                Ok(_) => return UdpRoute::Queued,
+                Err(mpsc::error::TrySendError::Full(_)) => return UdpRoute::Dropped,
+                Err(mpsc::error::TrySendError::Closed(x)) => {
+                    self.sessions.remove(&addr);
+                    data = x;
+                }
+            }
+        }
+        if let Some((_, retry_at)) = self.failures.get(&addr) {
+            if now < *retry_at {
+                return UdpRoute::Dropped;
+            }
+        }
+        let (tx, rx) = mpsc::channel(UDP_SESSION_QUEUE_SIZE);
+        tx.try_send(data).ok();
+        self.sessions.insert(addr, tx);
+        UdpRoute::NewSession(rx)
+    }
+
+    fn on_login(&mut self, addr: SocketAddr, ok: bool, now: Instant) {
+        if ok {
+            self.failures.remove(&addr);
+            return;
+        }
+        self.sessions.remove(&addr);
+        let n = self.failures.get(&addr).map(|x| x.0).unwrap_or_default() + 1;
+        let delay = UDP_LOGIN_RETRY_MIN
+            .saturating_mul(1 << (n - 1).min(16))
+            .min(UDP_LOGIN_RETRY_MAX);
+        self.failures.insert(addr, (n, now + delay));
+    }
+
+    // A session ends by itself after `UDP_SESSION_IDLE_TIMEOUT` without traffic either way.
+    fn expire(&mut self, now: Instant) {
+        self.sessions.retain(|_, tx| !tx.is_closed());
+        self.failures
+            .retain(|_, (_, retry_at)| now < *retry_at + UDP_SESSION_IDLE_TIMEOUT);
+    }
+}
+
+async fn login_udp_session(
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    rx: mpsc::Receiver<Bytes>,
+    ctx: Arc<ForwardContext>,
+    slot: Arc<tokio::sync::Mutex<LoginSlot>>,
+    result_tx: mpsc::UnboundedSender<(SocketAddr, bool)>,
+    interface: impl Interface,
+) {
+    let mut slot = slot.lock().await;
+    ctx.lc.write().unwrap().port_forward = ctx.target.clone();
+    let res = connect_and_login(
+        &ctx.id,
+        &ctx.password,
+        &mut slot.ui_receiver,
+        interface.clone(),
+        None,
+        &ctx.key,
+        &ctx.token,
+        false,
+    )
+    .await;
+    drop(slot);
+    result_tx.send((addr, matches!(res, Ok(Some(_))))).ok();
+    match res {
+        Ok(Some(stream)) => {
+            tokio::spawn(async move {
+                if let Err(err) = run_udp_forward(socket, addr, rx, stream).await {
+                    interface.msgbox("error", "Error", &err.to_string(), "");
+                }
+                log::info!("udp session from {:?} closed", addr);
+            });
+        }
+        Err(err) => {
+            log::warn!("udp session from {:?} failed: {}", addr, err);
+            interface.on_establish_connection_error(err.to_string());
+        }
+        _ => {}
+    }
+}
+
+/// The peer listens on `remote_port`, its connections go to `port` on the host
+/// after "reverse://" as seen from this side, localhost by default.
+async fn listen_reverse(
+    id: String,
+    password: String,
+    port: i32,
+    interface: impl Interface,
+    ui_receiver: mpsc::UnboundedReceiver<Data>,
+    key: &str,
+    token: &str,
+    lc: Arc<RwLock<LoginConfigHandler>>,
+    remote_host: String,
+    remote_port: i32,
+) -> ResultType<()> {
+    let mut target_host = PortForwardKind::parse(&remote_host).1.to_owned();
+    if target_host.is_empty() {
+        target_host = "127.0.0.1".to_owned();
+    }
+    let target = format!("{}:{}", target_host, port);
+    let mut ui_receiver = ui_receiver;
+    // The target stays on this side, the peer only learns the port to listen on.
+    lc.write().unwrap().port_forward = (PortForwardKind::Reverse.with_host(""), remote_port);
+    let mut stream = match connect_and_login(
+        &id,
+        &password,
+        &mut ui_receiver,
+        interface.clone(),
+        None,
+        key,
+        token,
+        false,
+    )
+    .await
+    {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            interface.on_establish_connection_error(err.to_string());
+            return Ok(());
+        }
+    };
+    log::info!("peer listens on port {} for {}", remote_port, target);
+    let connector: Connector = Arc::new(move |_| {
+        let target = target.clone();
+        let f: BoxFuture<'static, ResultType<TcpStream>> =
+            Box::pin(async move { Ok(timeout(3000, TcpStream::connect(&target)).await??) });
+        f
+    });
+    let mut mux = Mux::new(Role::Client, connector);
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                if let Some(Ok(bytes)) = res {
+                    mux.handle_frame(&bytes)?;
+                } else {
+                    interface.msgbox("error", "Error", "Reset by the peer", "");
+                    break;
+                }
+            },
+            Some(frame) = mux.next_frame() => {
+                stream.send_bytes(frame).await?;
+            },
+            d = ui_receiver.recv() => {
+                match d {
+                    Some(Data::Close) | None => {
+                        break;
+                    }
                     _ => {}
                 }
             }
@@ -108,7 +657,7 @@ async fn connect_and_login(
     password: &str,
     ui_receiver: &mut mpsc::UnboundedReceiver<Data>,
     interface: impl Interface,
-    forward: &mut Framed<TcpStream, BytesCodec>,
+    mut forward: Option<&mut Framed<TcpStream, BytesCodec>>,
     key: &str,
     token: &str,
     is_rdp: bool,
@@ -149,6 +698,7 @@ async fn connect_and_login(
                                 }
                             }
                             Some(login_response::Union::PeerInfo(pi)) => {
+                                mux_peer(id).supported.store(is_mux_supported(&pi), Ordering::SeqCst);
                                 interface.handle_peer_info(pi);
                                 break;
                             }
@@ -178,7 +728,7 @@ async fn connect_and_login(
                     _ => {}
                 }
             },
-            res = forward.next() => {
+            res = async { forward.as_mut().unwrap().next().await }, if forward.is_some() => {
                 if let Some(Ok(bytes)) = res {
                     buffer.extend(bytes);
                 } else {
@@ -218,3 +768,304 @@ async fn run_forward(forward: Framed<TcpStream, BytesCodec>, stream: Stream) ->
     }
     Ok(())
 }
+
+// Runs until the last channel is closed, the peer connection is closed with it.
+async fn run_mux_forward(
+    stream: Stream,
+    rx: mpsc::UnboundedReceiver<LocalChannel>,
+    peer: &MuxPeer,
+) -> ResultType<()> {
+    log::info!("new port forwarding mux connection started");
+    let mut stream = stream;
+    let mut rx = rx;
+    // Reverse channels are not requested by this side.
+    let mut mux = Mux::new(Role::Client, Mux::reject_all());
+    loop {
+        tokio::select! {
+            res = stream.next() => {
+                if let Some(Ok(bytes)) = res {
+                    mux.handle_frame(&bytes)?;
+                } else {
+                    break;
+                }
+            },
+            Some(frame) = mux.next_frame() => {
+                stream.send_bytes(frame).await?;
+            },
+            res = rx.recv() => {
+                if let Some(channel) = res {
+                    mux.open(channel);
+                } else {
+                    break;
+                }
+            },
+        }
+        if mux.channel_count() == 0 {
+            // The channels are sent with the lock held, none is lost after the check.
+            let mut tx = peer.tx.lock().await;
+            match rx.try_recv() {
+                Ok(channel) => mux.open(channel),
+                Err(_) => {
+                    tx.take();
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Every datagram is sent as one frame, so the peer gets the datagram boundaries back.
+async fn run_udp_forward(
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    rx: mpsc::Receiver<Bytes>,
+    stream: Stream,
+) -> ResultType<()> {
+    log::info!("new udp port forwarding session started");
+    let mut rx = rx;
+    let mut stream = stream;
+    let idle = time::sleep(UDP_SESSION_IDLE_TIMEOUT);
+    tokio::pin!(idle);
+    loop {
+        tokio::select! {
+            res = rx.recv() => {
+                if let Some(bytes) = res {
+                    idle.as_mut().reset(Instant::now() + UDP_SESSION_IDLE_TIMEOUT);
+                    allow_err!(stream.send_bytes(bytes).await);
+                } else {
+                    break;
+                }
+            },
+            res = stream.next() => {
+                if let Some(Ok(bytes)) = res {
+                    idle.as_mut().reset(Instant::now() + UDP_SESSION_IDLE_TIMEOUT);
+                    allow_err!(socket.send_to(&bytes, addr).await);
+                } else {
+                    break;
+                }
+            },
+            _ = &mut idle => {
+                log::info!("udp session from {:?} expired", addr);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a SOCKS5 or HTTP CONNECT request, return the requested target.
+async fn read_proxy_request(socket: &mut TcpStream) -> ResultType<(ProxyProtocol, String, i32)> {
+    let first = socket.read_u8().await?;
+    if first == 5 {
+        let n = socket.read_u8().await? as usize;
+        let mut methods = vec![0u8; n];
+        socket.read_exact(&mut methods).await?;
+        // Only "no authentication", the listener is bound to localhost.
+        if !methods.contains(&0) {
+            socket.write_all(&[5, 0xff]).await?;
+            bail!("No acceptable SOCKS5 authentication method");
+        }
+        socket.write_all(&[5, 0]).await?;
+        let mut head = [0u8; 4];
+        socket.read_exact(&mut head).await?;
+        if head[0] != 5 {
+            bail!("Invalid SOCKS5 version {}", head[0]);
+        }
+        if head[1] != 1 {
+            // command not supported
+            socket.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            bail!("Unsupported SOCKS5 command {}", head[1]);
+        }
+        let host = match head[3] {
+            1 => {
+                let mut ip = [0u8; 4];
+                socket.read_exact(&mut ip).await?;
+                std::net::Ipv4Addr::from(ip).to_string()
+            }
+            3 => {
+                let n = socket.read_u8().await? as usize;
+                let mut name = vec![0u8; n];
+                socket.read_exact(&mut name).await?;
+                String::from_utf8(name)?
+            }
+            4 => {
+                let mut ip = [0u8; 16];
+                socket.read_exact(&mut ip).await?;
+                format!("[{}]", std::net::Ipv6Addr::from(ip))
+            }
+            atyp => {
+                // address type not supported
+                socket.write_all(&[5, 8, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                bail!("Unsupported SOCKS5 address type {}", atyp);
+            }
+        };
+        let port = socket.read_u16().await?;
+        return check_proxy_target(socket, ProxyProtocol::Socks5, host, port as _).await;
+    }
+    // The client sends nothing else before our reply, so reading byte by byte
+    // up to the end of the headers does not swallow tunnel data.
+    let mut header = vec![first];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_CONNECT_HEADER_SIZE {
+            bail!("HTTP CONNECT header too large");
+        }
+        header.push(socket.read_u8().await?);
+    }
+    let header = String::from_utf8_lossy(&header);
+    let mut parts = header.lines().next().unwrap_or_default().split_whitespace();
+    if parts.next() != Some("CONNECT") {
+        socket
+            .write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n")
+            .await?;
+        bail!("Only HTTP CONNECT is supported");
+    }
+    let target = parts.next().unwrap_or_default();
+    let Some((host, port)) = target.rsplit_once(':') else {
+        bail!("Invalid HTTP CONNECT target {}", target);
+    };
+    let port = port.parse::<u16>()?;
+    check_proxy_target(
+        socket,
+        ProxyProtocol::HttpConnect,
+        host.to_owned(),
+        port as _,
+    )
+    .await
+}
+
+async fn check_proxy_target(
+    socket: &mut TcpStream,
+    protocol: ProxyProtocol,
+    host: String,
+    port: i32,
+) -> ResultType<(ProxyProtocol, String, i32)> {
+    if !is_valid_proxy_host(&host) {
+        socket.write_all(&proxy_reply(protocol, false)).await?;
+        bail!("Invalid proxy target host {:?}", host);
+    }
+    Ok((protocol, host, port))
+}
+
+/// Whether the host of a proxy request is a hostname or an IP. It becomes the target of the
+/// peer, where anything else, e.g. "reverse://", could pick another kind of forward.
+fn is_valid_proxy_host(host: &str) -> bool {
+    if let Some(ip) = host.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+        return ip.parse::<std::net::Ipv6Addr>().is_ok();
+    }
+    if host.parse::<std::net::Ipv4Addr>().is_ok() {
+        return true;
+    }
+    let name = host.strip_suffix('.').unwrap_or(host);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+fn proxy_reply(protocol: ProxyProtocol, ok: bool) -> Vec<u8> {
+    match protocol {
+        ProxyProtocol::Socks5 => {
+            // The bound address is unknown on this side, zeros are allowed.
+            let rep = if ok { 0 } else { 1 };
+            vec![5, rep, 0, 1, 0, 0, 0, 0, 0, 0]
+        }
+        ProxyProtocol::HttpConnect => {
+            if ok {
+                b"HTTP/1.1 200 Connection Established\r\n\r\n".to_vec()
+            } else {
+                b"HTTP/1.1 502 Bad Gateway\r\n\r\n".to_vec()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_host() {
+        assert!(is_valid_proxy_host("example.com"));
+        assert!(is_valid_proxy_host("my-host_1.lan."));
+        assert!(is_valid_proxy_host("localhost"));
+        assert!(is_valid_proxy_host("10.0.0.1"));
+        assert!(is_valid_proxy_host("[::1]"));
+        assert!(!is_valid_proxy_host(""));
+        assert!(!is_valid_proxy_host("reverse://"));
+        assert!(!is_valid_proxy_host("udp://example.com"));
+        assert!(!is_valid_proxy_host("mux://"));
+        assert!(!is_valid_proxy_host("a..b"));
+        assert!(!is_valid_proxy_host("::1"));
+        assert!(!is_valid_proxy_host("[example.com]"));
+        assert!(!is_valid_proxy_host(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn test_udp_sessions() {
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        let now = Instant::now();
+        let mut sessions = UdpSessions::default();
+        let data = Bytes::from_static(b"x");
+
+        // queued while the login is pending, dropped once the queue is full
+        let UdpRoute::NewSession(mut rx) = sessions.route(addr, data.clone(), now) else {
+            panic!("expected a new session");
+        };
+        for _ in 1..UDP_SESSION_QUEUE_SIZE {
+            assert!(matches!(
+                sessions.route(addr, data.clone(), now),
+                UdpRoute::Queued
+            ));
+        }
+        assert!(matches!(
+            sessions.route(addr, data.clone(), now),
+            UdpRoute::Dropped
+        ));
+        assert!(matches!(
+            sessions.route(other, data.clone(), now),
+            UdpRoute::NewSession(_)
+        ));
+        assert_eq!(rx.try_recv().unwrap(), data);
+
+        // a session which ended, e.g. idle, is forgotten and the next datagram starts a new one
+        sessions.on_login(addr, true, now);
+        drop(rx);
+        sessions.expire(now);
+        assert!(!sessions.sessions.contains_key(&addr));
+        assert!(matches!(
+            sessions.route(addr, data.clone(), now),
+            UdpRoute::NewSession(_)
+        ));
+
+        // failed logins back off instead of logging in per datagram
+        sessions.on_login(addr, false, now);
+        assert!(matches!(
+            sessions.route(addr, data.clone(), now),
+            UdpRoute::Dropped
+        ));
+        let now = now + UDP_LOGIN_RETRY_MIN;
+        assert!(matches!(
+            sessions.route(addr, data.clone(), now),
+            UdpRoute::NewSession(_)
+        ));
+        sessions.on_login(addr, false, now);
+        let retry = now + UDP_LOGIN_RETRY_MIN * 2;
+        assert!(matches!(
+            sessions.route(addr, data.clone(), retry - Duration::from_millis(1)),
+            UdpRoute::Dropped
+        ));
+        sessions.expire(retry + UDP_SESSION_IDLE_TIMEOUT);
+        assert!(sessions.failures.is_empty());
+        assert!(matches!(
+            sessions.route(addr, data, retry),
+            UdpRoute::NewSession(_)
+        ));
+    }
+}
@@ -0,0 +1,738 @@
+//! Many port forward channels over one logged-in peer connection.
+//!
+//! Every frame is sent as one message on the raw `hbb_common::Stream`:
+//! `channel id (u32 be) | frame type (u8) | payload`.
+//!
+//! The side that opens a channel picks its id, the client uses odd ids and the controlled side
+//! even ids, so both can open channels at the same time. An OPEN with an id of the wrong parity
+//! or one in use is reset. The receiver grants send credits with `FRAME_WINDOW` after the data
+//! is written to the local socket, so a slow local socket only stalls its own channel. A peer sending past the credits it was granted gets the channel reset.
+
+use hbb_common::{
+    bail,
+    bytes::{BufMut, Bytes, BytesMut},
+    futures::future::BoxFuture,
+    log,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::{mpsc, Semaphore},
+        task::JoinHandle,
+    },
+    ResultType,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Key in the `platform_additions` of the peer info of a port forward login,
+/// set by peers which accept a mux.
+pub const PEER_SUPPORT_MUX: &str = "support_port_forward_mux";
+
+const FRAME_OPEN: u8 = 1;
+const FRAME_OPEN_OK: u8 = 2;
+const FRAME_OPEN_FAIL: u8 = 3;
+const FRAME_DATA: u8 = 4;
+const FRAME_WINDOW: u8 = 5;
+// No more data from the sender, the receiver shuts down the write half of its socket.
+const FRAME_FIN: u8 = 6;
+const FRAME_RESET: u8 = 7;
+
+const FRAME_HEADER_SIZE: usize = 5;
+const CHUNK_SIZE: usize = 16 * 1024;
+const INITIAL_WINDOW: usize = 256 * 1024;
+// Credits are returned in batches to keep WINDOW frames rare.
+const WINDOW_UPDATE_THRESHOLD: usize = INITIAL_WINDOW / 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Connect a channel opened by the peer, the target is "host:port" or empty.
+pub type Connector = Arc<dyn Fn(String) -> BoxFuture<'static, ResultType<TcpStream>> + Send + Sync>;
+
+/// A locally accepted connection to be tunnelled to `target` on the peer.
+pub struct LocalChannel {
+    pub socket: TcpStream,
+    pub target: String,
+    /// Written to the socket once the peer accepted or rejected the channel,
+    /// e.g. the SOCKS5 / HTTP CONNECT reply.
+    pub reply: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+enum Control {
+    Connected(u32, TcpStream),
+    ConnectFailed(u32, String),
+    ReadClosed(u32),
+    Reset(u32),
+}
+
+struct Channel {
+    // Dropped on FIN, the writer task then shuts down the socket.
+    data_tx: Option<mpsc::UnboundedSender<Bytes>>,
+    window: Arc<Semaphore>,
+    // The credits granted to the peer which it has not used yet.
+    recv_window: Arc<AtomicUsize>,
+    fin_sent: bool,
+    fin_received: bool,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl Channel {
+    // A gracefully closed channel is just forgotten, its writer may still be flushing.
+    fn abort(&self) {
+        for t in self.tasks.iter() {
+            t.abort();
+        }
+    }
+}
+
+pub struct Mux {
+    next_id: u32,
+    connector: Connector,
+    channels: HashMap<u32, Channel>,
+    pending: HashMap<u32, LocalChannel>,
+    // Channels opened by the peer which are still connecting locally.
+    connecting: HashMap<u32, JoinHandle<()>>,
+    out_tx: mpsc::UnboundedSender<Bytes>,
+    out_rx: mpsc::UnboundedReceiver<Bytes>,
+    ctl_tx: mpsc::UnboundedSender<Control>,
+    ctl_rx: mpsc::UnboundedReceiver<Control>,
+}
+
+fn frame(id: u32, t: u8, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    buf.put_u32(id);
+    buf.put_u8(t);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+impl Mux {
+    pub fn new(role: Role, connector: Connector) -> Self {
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let (ctl_tx, ctl_rx) = mpsc::unbounded_channel();
+        Self {
+            next_id: if role == Role::Client { 1 } else { 2 },
+            connector,
+            channels: Default::default(),
+            pending: Default::default(),
+            connecting: Default::default(),
+            out_tx,
+            out_rx,
+            ctl_tx,
+            ctl_rx,
+        }
+    }
+
+    /// A connector for sides that never accept channels opened by the peer.
+    pub fn reject_all() -> Connector {
+        Arc::new(|_| {
+            let f: BoxFuture<'static, ResultType<TcpStream>> =
+                Box::pin(async { bail!("Opening channels is not allowed") });
+            f
+        })
+    }
+
+    #[inline]
+    pub fn channel_count(&self) -> usize {
+        self.channels.len() + self.pending.len() + self.connecting.len()
+    }
+
+    pub fn open(&mut self, local: LocalChannel) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(2);
+        self.send(frame(id, FRAME_OPEN, local.target.as_bytes()));
+        self.pending.insert(id, local);
+    }
+
+    /// The next frame to write to the stream.
+    pub async fn next_frame(&mut self) -> Option<Bytes> {
+        loop {
+            tokio::select! {
+                res = self.out_rx.recv() => return res,
+                Some(ctl) = self.ctl_rx.recv() => self.handle_control(ctl),
+            }
+        }
+    }
+
+    /// Handle a frame read from the stream.
+    pub fn handle_frame(&mut self, bytes: &[u8]) -> ResultType<()> {
+        if bytes.len() < FRAME_HEADER_SIZE {
+            bail!("Invalid mux frame of {} bytes", bytes.len());
+        }
+        let id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let payload = &bytes[FRAME_HEADER_SIZE..];
+        match bytes[4] {
+            FRAME_OPEN => {
+                // The peer opens the ids of the other parity, and only new ones.
+                if id % 2 == self.next_id % 2
+                    || self.channels.contains_key(&id)
+                    || self.connecting.contains_key(&id)
+                    || self.pending.contains_key(&id)
+                {
+                    log::warn!("Mux channel {} opened by the peer is invalid, reset", id);
+                    self.reset(id);
+                    return Ok(());
+                }
+                let target = String::from_utf8_lossy(payload).to_string();
+                let connector = self.connector.clone();
+                let ctl_tx = self.ctl_tx.clone();
+                let task = tokio::spawn(async move {
+                    match connector(target.clone()).await {
+                        Ok(socket) => {
+                            ctl_tx.send(Control::Connected(id, socket)).ok();
+                        }
+                        Err(err) => {
+                            log::warn!("Mux channel {} to {} failed: {}", id, target, err);
+                            ctl_tx
+                                .send(Control::ConnectFailed(id, err.to_string()))
+                                .ok();
+                        }
+                    }
+                });
+                self.connecting.insert(id, task);
+            }
+            FRAME_OPEN_OK => {
+                if let Some(local) = self.pending.remove(&id) {
+                    let ok = local.reply.map(|r| r.0);
+                    self.start_channel(id, local.socket, ok);
+                }
+            }
+            FRAME_OPEN_FAIL => {
+                if let Some(mut local) = self.pending.remove(&id) {
+                    log::warn!(
+                        "Mux channel {} to {} rejected: {}",
+                        id,
+                        local.target,
+                        String::from_utf8_lossy(payload)
+                    );
+                    if let Some((_, fail)) = local.reply.take() {
+                        tokio::spawn(async move {
+                            local.socket.write_all(&fail).await.ok();
+                        });
+                    }
+                }
+            }
+            FRAME_DATA => {
+                let Some(c) = self.channels.get(&id) else {
+                    return Ok(());
+                };
+                let within_window = c
+                    .recv_window
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |w| {
+                        w.checked_sub(payload.len())
+                    })
+                    .is_ok();
+                if !within_window {
+                    log::warn!("Mux channel {} sent past its window, reset", id);
+                    self.handle_control(Control::Reset(id));
+                    return Ok(());
+                }
+                if let Some(tx) = c.data_tx.as_ref() {
+                    tx.send(Bytes::copy_from_slice(payload)).ok();
+                }
+            }
+            FRAME_WINDOW => {
+                if payload.len() >= 4 {
+                    let credit =
+                        u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                    if let Some(c) = self.channels.get(&id) {
+                        c.window.add_permits(credit as _);
+                    }
+                }
+            }
+            FRAME_FIN => {
+                if let Some(c) = self.channels.get_mut(&id) {
+                    c.data_tx.take();
+                    c.fin_received = true;
+                    if c.fin_sent {
+                        self.channels.remove(&id);
+                    }
+                }
+            }
+            FRAME_RESET => {
+                self.remove(id);
+            }
+            t => {
+                log::warn!("Unknown mux frame type {}", t);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_control(&mut self, ctl: Control) {
+        match ctl {
+            Control::Connected(id, socket) => {
+                // Reset by the peer while connecting, the socket is just dropped.
+                if self.connecting.remove(&id).is_none() {
+                    return;
+                }
+                self.send(frame(id, FRAME_OPEN_OK, &[]));
+                self.start_channel(id, socket, None);
+            }
+            Control::ConnectFailed(id, err) => {
+                if self.connecting.remove(&id).is_some() {
+                    self.send(frame(id, FRAME_OPEN_FAIL, err.as_bytes()));
+                }
+            }
+            Control::ReadClosed(id) => {
+                if let Some(c) = self.channels.get_mut(&id) {
+                    c.fin_sent = true;
+                    if c.fin_received {
+                        self.channels.remove(&id);
+                    }
+                }
+            }
+            Control::Reset(id) => {
+                if let Some(c) = self.channels.remove(&id) {
+                    c.abort();
+                    self.send(frame(id, FRAME_RESET, &[]));
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u32) {
+        if let Some(c) = self.channels.remove(&id) {
+            c.abort();
+        }
+        if let Some(task) = self.connecting.remove(&id) {
+            task.abort();
+        }
+        self.pending.remove(&id);
+    }
+
+    // Drops whatever uses the id on both sides.
+    fn reset(&mut self, id: u32) {
+        self.remove(id);
+        self.send(frame(id, FRAME_RESET, &[]));
+    }
+
+    #[inline]
+    fn send(&self, frame: Bytes) {
+        self.out_tx.send(frame).ok();
+    }
+
+    fn start_channel(&mut self, id: u32, socket: TcpStream, reply: Option<Vec<u8>>) {
+        let (mut reader, mut writer) = socket.into_split();
+        let (data_tx, mut data_rx) = mpsc::unbounded_channel::<Bytes>();
+        let window = Arc::new(Semaphore::new(INITIAL_WINDOW));
+        let recv_window = Arc::new(AtomicUsize::new(INITIAL_WINDOW));
+
+        let out_tx = self.out_tx.clone();
+        let ctl_tx = self.ctl_tx.clone();
+        let recv_window_writer = recv_window.clone();
+        let writer_task = tokio::spawn(async move {
+            if let Some(reply) = reply {
+                if writer.write_all(&reply).await.is_err() {
+                    ctl_tx.send(Control::Reset(id)).ok();
+                    return;
+                }
+            }
+            let mut consumed = 0;
+            while let Some(data) = data_rx.recv().await {
+                if writer.write_all(&data).await.is_err() {
+                    ctl_tx.send(Control::Reset(id)).ok();
+                    return;
+                }
+                consumed += data.len();
+                if consumed >= WINDOW_UPDATE_THRESHOLD {
+                    // Before the peer knows the credits, so it never gets ahead of them.
+                    recv_window_writer.fetch_add(consumed, Ordering::SeqCst);
+                    out_tx
+                        .send(frame(id, FRAME_WINDOW, &(consumed as u32).to_be_bytes()))
+                        .ok();
+                    consumed = 0;
+                }
+            }
+            writer.shutdown().await.ok();
+        });
+
+        let out_tx = self.out_tx.clone();
+        let ctl_tx = self.ctl_tx.clone();
+        let window_reader = window.clone();
+        let reader_task = tokio::spawn(async move {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            loop {
+                let n = match reader.read(&mut buf).await {
+                    Ok(0) => {
+                        out_tx.send(frame(id, FRAME_FIN, &[])).ok();
+                        ctl_tx.send(Control::ReadClosed(id)).ok();
+                        return;
+                    }
+                    Ok(n) => n,
+                    Err(_) => {
+                        ctl_tx.send(Control::Reset(id)).ok();
+                        return;
+                    }
+                };
+                match window_reader.acquire_many(n as _).await {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => return,
+                }
+                if out_tx.send(frame(id, FRAME_DATA, &buf[..n])).is_err() {
+                    return;
+                }
+            }
+        });
+
+        self.channels.insert(
+            id,
+            Channel {
+                data_tx: Some(data_tx),
+                window,
+                recv_window,
+                fin_sent: false,
+                fin_received: false,
+                tasks: [reader_task, writer_task],
+            },
+        );
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        for c in self.channels.values() {
+            c.abort();
+        }
+        for task in self.connecting.values() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio::{
+        net::TcpListener,
+        time::{timeout, Duration},
+    };
+
+    #[test]
+    fn test_frame_layout() {
+        let f = frame(0x01020304, FRAME_DATA, b"abc");
+        assert_eq!(&f[..], &[1, 2, 3, 4, FRAME_DATA, b'a', b'b', b'c']);
+    }
+
+    #[tokio::test]
+    async fn test_channel_ids_by_role() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut mux = Mux::new(Role::Client, Mux::reject_all());
+        for _ in 0..2 {
+            let socket = TcpStream::connect(addr).await.unwrap();
+            mux.open(LocalChannel {
+                socket,
+                target: "localhost:22".to_owned(),
+                reply: None,
+            });
+        }
+        let first = mux.next_frame().await.unwrap();
+        let second = mux.next_frame().await.unwrap();
+        assert_eq!(&first[..5], &[0, 0, 0, 1, FRAME_OPEN]);
+        assert_eq!(&second[..5], &[0, 0, 0, 3, FRAME_OPEN]);
+        assert_eq!(&first[5..], b"localhost:22");
+        assert_eq!(mux.channel_count(), 2);
+        mux.handle_frame(&frame(1, FRAME_OPEN_FAIL, b"denied"))
+            .unwrap();
+        assert_eq!(mux.channel_count(), 1);
+    }
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // Runs a mux, frames from `rx` are handled and frames it sends go to `tx`.
+    fn run_mux(
+        mut mux: Mux,
+        mut rx: mpsc::UnboundedReceiver<Bytes>,
+        tx: mpsc::UnboundedSender<Bytes>,
+        mut open_rx: mpsc::UnboundedReceiver<LocalChannel>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(bytes) = rx.recv() => mux.handle_frame(&bytes).unwrap(),
+                    Some(frame) = mux.next_frame() => {
+                        if tx.send(frame).is_err() {
+                            break;
+                        }
+                    }
+                    Some(channel) = open_rx.recv() => mux.open(channel),
+                    else => break,
+                }
+            }
+        })
+    }
+
+    // A client mux and a server mux connecting its channels to a local listener,
+    // returns the client application side socket and the server target side socket.
+    async fn channel_pair() -> (TcpStream, TcpStream, [JoinHandle<()>; 2]) {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let connector: Connector = Arc::new(move |t| {
+            let f: BoxFuture<'static, ResultType<TcpStream>> =
+                Box::pin(async move { Ok(TcpStream::connect(t).await?) });
+            f
+        });
+        let (c2s_tx, c2s_rx) = mpsc::unbounded_channel();
+        let (s2c_tx, s2c_rx) = mpsc::unbounded_channel();
+        let (open_tx, open_rx) = mpsc::unbounded_channel();
+        let client = run_mux(
+            Mux::new(Role::Client, Mux::reject_all()),
+            s2c_rx,
+            c2s_tx,
+            open_rx,
+        );
+        let server = run_mux(
+            Mux::new(Role::Server, connector),
+            c2s_rx,
+            s2c_tx,
+            mpsc::unbounded_channel().1,
+        );
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let app = TcpStream::connect(local.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = local.accept().await.unwrap();
+        open_tx
+            .send(LocalChannel {
+                socket,
+                target: target_addr.to_string(),
+                reply: None,
+            })
+            .ok();
+        let (remote, _) = timeout(TEST_TIMEOUT, target.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        (app, remote, [client, server])
+    }
+
+    #[tokio::test]
+    async fn test_data_and_fin() {
+        let (mut app, mut remote, _tasks) = channel_pair().await;
+        app.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        timeout(TEST_TIMEOUT, remote.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        remote.write_all(b"world").await.unwrap();
+        timeout(TEST_TIMEOUT, app.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"world");
+        // FIN only closes one direction
+        app.shutdown().await.unwrap();
+        let n = timeout(TEST_TIMEOUT, remote.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, 0);
+        remote.write_all(b"again").await.unwrap();
+        timeout(TEST_TIMEOUT, app.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"again");
+        remote.shutdown().await.unwrap();
+        let n = timeout(TEST_TIMEOUT, app.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[tokio::test]
+    async fn test_window() {
+        let (mut app, mut remote, _tasks) = channel_pair().await;
+        // Several windows worth, it only gets through if credits come back.
+        let size = INITIAL_WINDOW * 4 + 1;
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            app.write_all(&data).await.unwrap();
+            app
+        });
+        let mut received = vec![0u8; size];
+        timeout(TEST_TIMEOUT, remote.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received == expected);
+        writer.await.unwrap();
+    }
+
+    // A reverse tunnel, the controlled side opens the channel and the client connects it.
+    #[tokio::test]
+    async fn test_reverse_channel() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let connector: Connector = Arc::new(move |t: String| {
+            assert!(t.is_empty());
+            let f: BoxFuture<'static, ResultType<TcpStream>> =
+                Box::pin(async move { Ok(TcpStream::connect(target_addr).await?) });
+            f
+        });
+        let (c2s_tx, c2s_rx) = mpsc::unbounded_channel();
+        let (s2c_tx, s2c_rx) = mpsc::unbounded_channel();
+        let (open_tx, open_rx) = mpsc::unbounded_channel();
+        let _client = run_mux(
+            Mux::new(Role::Client, connector),
+            s2c_rx,
+            c2s_tx,
+            mpsc::unbounded_channel().1,
+        );
+        let _server = run_mux(
+            Mux::new(Role::Server, Mux::reject_all()),
+            c2s_rx,
+            s2c_tx,
+            open_rx,
+        );
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut app = TcpStream::connect(local.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = local.accept().await.unwrap();
+        open_tx
+            .send(LocalChannel {
+                socket,
+                target: String::new(),
+                reply: None,
+            })
+            .ok();
+        let (mut remote, _) = timeout(TEST_TIMEOUT, target.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        app.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        timeout(TEST_TIMEOUT, remote.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut app = TcpStream::connect(local.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = local.accept().await.unwrap();
+        let mut mux = Mux::new(Role::Client, Mux::reject_all());
+        mux.open(LocalChannel {
+            socket,
+            target: "localhost:22".to_owned(),
+            reply: Some((b"ok".to_vec(), b"fail".to_vec())),
+        });
+        mux.handle_frame(&frame(1, FRAME_OPEN_OK, &[])).unwrap();
+        mux.handle_frame(&frame(1, FRAME_DATA, b"data")).unwrap();
+        let mut buf = [0u8; 6];
+        timeout(TEST_TIMEOUT, app.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"okdata");
+        // The peer reset the channel, the application side is closed.
+        mux.handle_frame(&frame(1, FRAME_RESET, &[])).unwrap();
+        assert_eq!(mux.channel_count(), 0);
+        let res = timeout(TEST_TIMEOUT, app.read(&mut buf)).await.unwrap();
+        assert!(!matches!(res, Ok(n) if n > 0));
+    }
+
+    #[tokio::test]
+    async fn test_reset_past_window() {
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _app = TcpStream::connect(local.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = local.accept().await.unwrap();
+        let mut mux = Mux::new(Role::Client, Mux::reject_all());
+        mux.open(LocalChannel {
+            socket,
+            target: "localhost:22".to_owned(),
+            reply: None,
+        });
+        mux.next_frame().await.unwrap();
+        mux.handle_frame(&frame(1, FRAME_OPEN_OK, &[])).unwrap();
+        let chunk = vec![0u8; CHUNK_SIZE];
+        for _ in 0..INITIAL_WINDOW / CHUNK_SIZE {
+            mux.handle_frame(&frame(1, FRAME_DATA, &chunk)).unwrap();
+        }
+        assert_eq!(mux.channel_count(), 1);
+        // No credits were returned to the peer yet.
+        mux.handle_frame(&frame(1, FRAME_DATA, b"x")).unwrap();
+        assert_eq!(mux.channel_count(), 0);
+        let reset = timeout(TEST_TIMEOUT, mux.next_frame())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&reset[..], &[0, 0, 0, 1, FRAME_RESET]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_open() {
+        let mut mux = Mux::new(Role::Server, Mux::reject_all());
+        // an id of this side
+        mux.handle_frame(&frame(2, FRAME_OPEN, b"x")).unwrap();
+        assert_eq!(mux.channel_count(), 0);
+        let reset = timeout(TEST_TIMEOUT, mux.next_frame())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&reset[..], &[0, 0, 0, 2, FRAME_RESET]);
+        // an id in use
+        mux.handle_frame(&frame(1, FRAME_OPEN, b"x")).unwrap();
+        assert_eq!(mux.channel_count(), 1);
+        mux.handle_frame(&frame(1, FRAME_OPEN, b"x")).unwrap();
+        assert_eq!(mux.channel_count(), 0);
+        let reset = timeout(TEST_TIMEOUT, mux.next_frame())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&reset[..], &[0, 0, 0, 1, FRAME_RESET]);
+    }
+
+    #[tokio::test]
+    async fn test_reset_while_connecting() {
+        let (release_tx, release_rx) = tokio::sync::oneshot::channel::<()>();
+        let release_rx = Arc::new(std::sync::Mutex::new(Some(release_rx)));
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let connector: Connector = Arc::new(move |_| {
+            let release_rx = release_rx.lock().unwrap().take();
+            let f: BoxFuture<'static, ResultType<TcpStream>> = Box::pin(async move {
+                if let Some(rx) = release_rx {
+                    rx.await.ok();
+                }
+                Ok(TcpStream::connect(target_addr).await?)
+            });
+            f
+        });
+        let mut mux = Mux::new(Role::Server, connector);
+        mux.handle_frame(&frame(1, FRAME_OPEN, b"x")).unwrap();
+        assert_eq!(mux.channel_count(), 1);
+        mux.handle_frame(&frame(1, FRAME_RESET, &[])).unwrap();
+        assert_eq!(mux.channel_count(), 0);
+        release_tx.send(()).ok();
+        assert!(
+            timeout(Duration::from_millis(200), mux.next_frame())
+                .await
+                .is_err(),
+            "a reset channel must not be opened"
+        );
+        assert_eq!(mux.channel_count(), 0);
+    }
+}
@@ -65,8 +65,11 @@ impl RendezvousMediator {
             }
         }
         crate::hbbs_http::sync::start();
+        crate::hbbs_http::record_upload::start();
+        crate::server::audit_journal::start();
         #[cfg(target_os = "windows")]
         if crate::platform::is_installed() && crate::is_server() {
+            crate::updater::confirm_update();
             crate::updater::start_auto_update();
         }
         check_zombie();
@@ -751,7 +754,7 @@ impl RendezvousMediator {
     }
 }
 
-fn get_direct_port() -> i32 {
+pub(crate) fn get_direct_port() -> i32 {
     let mut port = Config::get_option("direct-access-port")
         .parse::<i32>()
         .unwrap_or(0);
@@ -880,11 +883,16 @@ async fn udp_nat_listen(
     let socket_cloned = socket.clone();
     let func = async {
         socket.connect(peer_addr).await?;
-        let res = crate::punch_udp(socket.clone(), true).await?;
+        let res = crate::punch_udp(
+            socket.clone(),
+            true,
+            crate::kcp_stream::KcpStream::PUNCH_PAYLOAD,
+        )
+        .await?;
         let stream = crate::kcp_stream::KcpStream::accept(
             socket,
             Duration::from_millis(CONNECT_TIMEOUT as _),
-            res,
+            Some(res),
         )
         .await?;
         crate::server::create_tcp_connection(
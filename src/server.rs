@@ -66,14 +66,22 @@ pub mod input_service {
     pub const NAME_WINDOW_FOCUS: &'static str = "";
 }
 
+pub mod access_grant;
+pub mod audit_journal;
+pub mod audit_sink;
 mod connection;
 pub mod display_service;
+pub mod ip_filter;
+pub mod permission_policy;
 #[cfg(windows)]
 pub mod portable_service;
 mod service;
 mod video_qos;
 pub mod video_service;
 
+#[cfg(feature = "qos_simulator")]
+pub use video_qos::simulator as qos_simulator;
+
 #[cfg(all(target_os = "windows", feature = "flutter"))]
 pub mod printer_service;
 
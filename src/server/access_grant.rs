@@ -0,0 +1,238 @@
+// Time-boxed access grants.
+//
+// A grant is bound to a peer ID and / or a token, and expires at a given time, when the
+// connections which rely on it are closed. A token is accepted as a password until then.
+// Peer IDs are claimed by the peers themselves, so a grant without a token does not let a
+// peer in, it bounds the sessions the peer opens with the password or an approval.
+// Only the grant found at the login applies to a session, a later one waits for the next.
+use super::permission_policy::PERMISSIONS;
+use hbb_common::{
+    bail,
+    config::Config,
+    get_time, lazy_static, log, rand,
+    sha2::{Digest, Sha256},
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Mutex};
+
+const GRANTS_FILE: &str = "access_grants.json";
+
+lazy_static::lazy_static! {
+    static ref GRANTS: Mutex<Option<Vec<AccessGrant>>> = Default::default();
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessGrant {
+    pub id: String,
+    #[serde(default)]
+    pub peer_id: String,
+    /// Hex of the salted hash of the token, the same one as of a password.
+    #[serde(default)]
+    pub token_h1: String,
+    /// Names of `permission_policy::PERMISSIONS`, all of them if empty.
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    /// Unix time in ms
+    pub expiry: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "t", content = "c")]
+pub enum GrantUpdate {
+    Add {
+        peer_id: String,
+        token: String,
+        permissions: Vec<String>,
+        minutes: u64,
+    },
+    Revoke(String),
+}
+
+impl GrantUpdate {
+    pub fn check(&self) -> ResultType<()> {
+        if let Self::Add {
+            peer_id,
+            token,
+            permissions,
+            minutes,
+        } = self
+        {
+            if peer_id.is_empty() && token.is_empty() {
+                bail!("A peer ID or a token is required");
+            }
+            if *minutes == 0 {
+                bail!("The duration must be positive");
+            }
+            for p in permissions.iter() {
+                if !PERMISSIONS.iter().any(|(name, _)| name == p) {
+                    bail!("Unknown permission: {}", p);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AccessGrant {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expiry <= now
+    }
+}
+
+fn grants_path() -> PathBuf {
+    Config::path(GRANTS_FILE)
+}
+
+fn token_h1(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    hasher.update(Config::get_salt().as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+// Expired grants are dropped on the way.
+fn with_grants<T>(f: impl FnOnce(&mut Vec<AccessGrant>) -> T) -> T {
+    let mut lock = GRANTS.lock().unwrap();
+    let grants = lock.get_or_insert_with(|| {
+        std::fs::read_to_string(grants_path())
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    });
+    let now = get_time();
+    let len = grants.len();
+    grants.retain(|g| !g.is_expired(now));
+    let saved = grants.clone();
+    let res = f(grants);
+    if grants.len() != len || *grants != saved {
+        save(grants);
+    }
+    res
+}
+
+fn save(grants: &[AccessGrant]) {
+    let res = serde_json::to_string(grants)
+        .map_err(std::io::Error::from)
+        .and_then(|s| std::fs::write(grants_path(), s));
+    if let Err(e) = res {
+        log::error!("Failed to save the access grants: {}", e);
+    }
+}
+
+fn new_grant(
+    peer_id: String,
+    token: String,
+    permissions: Vec<String>,
+    minutes: u64,
+    now: i64,
+) -> AccessGrant {
+    AccessGrant {
+        id: hex::encode(rand::random::<[u8; 4]>()),
+        peer_id,
+        token_h1: if token.is_empty() {
+            "".to_owned()
+        } else {
+            token_h1(&token)
+        },
+        permissions,
+        expiry: now + minutes as i64 * 60_000,
+    }
+}
+
+/// The grants which have not expired.
+pub fn list() -> Vec<AccessGrant> {
+    with_grants(|grants| grants.clone())
+}
+
+pub fn update(update: GrantUpdate) -> ResultType<Vec<AccessGrant>> {
+    update.check()?;
+    match update {
+        GrantUpdate::Add {
+            peer_id,
+            token,
+            permissions,
+            minutes,
+        } => {
+            let grant = new_grant(peer_id, token, permissions, minutes, get_time());
+            log::info!(
+                "Access granted to {} for {} minutes, id {}",
+                grant.peer_id,
+                minutes,
+                grant.id
+            );
+            with_grants(|grants| grants.push(grant));
+        }
+        GrantUpdate::Revoke(id) => {
+            log::info!("Access grant {} revoked", id);
+            with_grants(|grants| grants.retain(|g| g.id != id));
+        }
+    }
+    Ok(list())
+}
+
+fn matches_peer(grant: &AccessGrant, peer_id: &str) -> bool {
+    grant.peer_id.is_empty() || grant.peer_id == peer_id
+}
+
+/// The grant whose token `verify_h1` accepts, for `peer_id`.
+pub fn find_by_token(peer_id: &str, verify_h1: impl Fn(&[u8]) -> bool) -> Option<AccessGrant> {
+    with_grants(|grants| {
+        grants
+            .iter()
+            .filter(|g| !g.token_h1.is_empty() && matches_peer(g, peer_id))
+            .find(|g| hex::decode(&g.token_h1).is_ok_and(|h1| verify_h1(&h1)))
+            .cloned()
+    })
+}
+
+/// The grant of `peer_id` without a token, the one expiring last if more.
+pub fn find_by_peer(peer_id: &str) -> Option<AccessGrant> {
+    if peer_id.is_empty() {
+        return None;
+    }
+    with_grants(|grants| {
+        grants
+            .iter()
+            .filter(|g| g.token_h1.is_empty() && g.peer_id == peer_id)
+            .max_by_key(|g| g.expiry)
+            .cloned()
+    })
+}
+
+/// Whether the grant is neither expired nor revoked.
+pub fn is_active(id: &str) -> bool {
+    with_grants(|grants| grants.iter().any(|g| g.id == id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_grant() {
+        let grant = new_grant("123".to_owned(), "".to_owned(), vec![], 90, 1000);
+        assert_eq!(grant.expiry, 1000 + 90 * 60_000);
+        assert!(grant.token_h1.is_empty());
+        assert!(!grant.is_expired(grant.expiry - 1));
+        assert!(grant.is_expired(grant.expiry));
+        assert!(matches_peer(&grant, "123"));
+        assert!(!matches_peer(&grant, "456"));
+
+        let grant = new_grant("".to_owned(), "secret".to_owned(), vec![], 1, 0);
+        assert_eq!(grant.token_h1, token_h1("secret"));
+        assert!(matches_peer(&grant, "456"));
+
+        let add = |peer_id: &str, token: &str, permissions: &[&str], minutes| GrantUpdate::Add {
+            peer_id: peer_id.to_owned(),
+            token: token.to_owned(),
+            permissions: permissions.iter().map(|x| x.to_string()).collect(),
+            minutes,
+        };
+        assert!(add("123", "", &["file", "keyboard"], 1).check().is_ok());
+        assert!(add("", "secret", &[], 1).check().is_ok());
+        assert!(add("", "", &[], 1).check().is_err());
+        assert!(add("123", "", &[], 0).check().is_err());
+        assert!(add("123", "", &["all"], 1).check().is_err());
+    }
+}
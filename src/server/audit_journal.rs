@@ -0,0 +1,304 @@
+// Durable journal of the audit events.
+//
+// Events are appended to a JSON Lines file before they are posted, and removed once the
+// api server has them, so that the ones raised while it is unreachable are not lost.
+// They are posted one by one in order, a failed post blocks the following ones until it
+// succeeds. Every event carries an `event_id`, for the api server to drop the ones
+// posted again after a crash.
+use hbb_common::{bail, config::Config, get_time, lazy_static, log, rand, ResultType};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Write,
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const JOURNAL_FILE: &str = "audit_journal.jsonl";
+// The oldest events are dropped beyond it, e.g. if there is no api server to flush to.
+const MAX_EVENTS: usize = 10_000;
+// Dropped at once, so that the journal file is not rewritten on every event when full.
+const TRIM_BATCH: usize = MAX_EVENTS / 10;
+// The journal file is rewritten after this many events are flushed.
+const FLUSH_BATCH: usize = 100;
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(5 * 60);
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+/// Journal the audit events even without an api server, to export them. Off unless "Y",
+/// unlike the other options without the `allow-` prefix.
+pub const OPTION_AUDIT_JOURNAL: &str = "audit-journal";
+
+lazy_static::lazy_static! {
+    static ref JOURNAL: Mutex<Option<VecDeque<AuditEvent>>> = Default::default();
+    static ref WORKER: Mutex<Option<Sender<()>>> = Default::default();
+    static ref WRITER: Mutex<Option<Sender<AuditEvent>>> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditKind {
+    Conn,
+    File,
+    Alarm,
+}
+
+impl AuditKind {
+    fn url(&self) -> String {
+        crate::get_audit_server(
+            Config::get_option("api-server"),
+            Config::get_option("custom-rendezvous-server"),
+            match self {
+                Self::Conn => "conn",
+                Self::File => "file",
+                Self::Alarm => "alarm",
+            }
+            .to_owned(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub event_id: String,
+    pub kind: AuditKind,
+    pub time: i64,
+    pub body: Value,
+}
+
+fn journal_path() -> PathBuf {
+    Config::path(JOURNAL_FILE)
+}
+
+// Lines which can't be parsed, e.g. one cut by a crash, are skipped.
+fn parse_journal(data: &str) -> VecDeque<AuditEvent> {
+    let mut ids = HashSet::new();
+    data.lines()
+        .filter_map(|line| serde_json::from_str::<AuditEvent>(line).ok())
+        .filter(|e| ids.insert(e.event_id.clone()))
+        .collect()
+}
+
+fn to_lines<'a>(events: impl Iterator<Item = &'a AuditEvent>) -> String {
+    events
+        .filter_map(|e| serde_json::to_string(e).ok())
+        .map(|line| line + "\n")
+        .collect()
+}
+
+fn with_journal<T>(f: impl FnOnce(&mut VecDeque<AuditEvent>) -> T) -> T {
+    let mut lock = JOURNAL.lock().unwrap();
+    let journal = lock.get_or_insert_with(|| {
+        parse_journal(&std::fs::read_to_string(journal_path()).unwrap_or_default())
+    });
+    f(journal)
+}
+
+fn save(journal: &VecDeque<AuditEvent>) {
+    let path = journal_path();
+    let tmp = path.with_extension("jsonl.tmp");
+    let res =
+        std::fs::write(&tmp, to_lines(journal.iter())).and_then(|_| std::fs::rename(&tmp, &path));
+    if let Err(e) = res {
+        log::error!("Failed to save the audit journal: {}", e);
+    }
+}
+
+fn append(event: &AuditEvent) -> ResultType<()> {
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(journal_path())?;
+    f.write_all(to_lines(std::iter::once(event)).as_bytes())?;
+    Ok(())
+}
+
+fn new_event_id() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// Whether to journal the audit events which go to `url`.
+pub fn is_enabled(url: &str) -> bool {
+    !url.is_empty() || Config::get_option(OPTION_AUDIT_JOURNAL) == "Y"
+}
+
+// A server error, e.g. from a proxy in front of the api server, is retried. An event the
+// api server rejects is not, it would block the following ones forever.
+fn post(url: String, body: String) -> ResultType<()> {
+    let header = json!({ "Content-Type": "application/json" }).to_string();
+    let res = crate::http_request_sync(url, "post".to_owned(), Some(body), header)?;
+    let status = serde_json::from_str::<Value>(&res)?["status_code"]
+        .as_u64()
+        .unwrap_or_default();
+    if status >= 500 || status < 200 {
+        bail!("HTTP status {}", status);
+    }
+    if status >= 400 {
+        log::warn!("Audit event rejected, HTTP status {}", status);
+    }
+    Ok(())
+}
+
+/// Journals an audit event in the background, it is posted to the api server after.
+pub fn push(kind: AuditKind, mut body: Value) {
+    let event_id = new_event_id();
+    body["event_id"] = json!(event_id);
+    let event = AuditEvent {
+        event_id,
+        kind,
+        time: get_time(),
+        body,
+    };
+    let mut writer = WRITER.lock().unwrap();
+    let tx = writer.get_or_insert_with(|| {
+        let (tx, rx) = channel::<AuditEvent>();
+        std::thread::spawn(move || {
+            while let Ok(event) = rx.recv() {
+                write(event);
+            }
+        });
+        tx
+    });
+    tx.send(event).ok();
+}
+
+fn write(event: AuditEvent) {
+    with_journal(|journal| {
+        if journal.len() >= MAX_EVENTS {
+            trim(journal);
+            journal.push_back(event);
+            save(journal);
+        } else {
+            if let Err(e) = append(&event) {
+                log::error!("Failed to journal audit event {}: {}", event.event_id, e);
+            }
+            journal.push_back(event);
+        }
+    });
+    start();
+    if let Some(tx) = WORKER.lock().unwrap().as_ref() {
+        tx.send(()).ok();
+    }
+}
+
+fn trim(journal: &mut VecDeque<AuditEvent>) {
+    let n = (journal.len() + TRIM_BATCH).saturating_sub(MAX_EVENTS);
+    if let Some(first) = journal.front() {
+        log::warn!(
+            "Audit journal full, {} events dropped from {}",
+            n,
+            first.event_id
+        );
+    }
+    journal.drain(..n.min(journal.len()));
+}
+
+/// The events not posted yet, as JSON Lines. All of them if there is no api server.
+pub fn export() -> String {
+    with_journal(|journal| to_lines(journal.iter()))
+}
+
+fn remove_flushed(flushed: &HashSet<String>) {
+    with_journal(|journal| {
+        journal.retain(|e| !flushed.contains(&e.event_id));
+        save(journal);
+    });
+}
+
+/// Starts posting the journal, the events left by the previous run go first.
+pub fn start() {
+    let mut worker = WORKER.lock().unwrap();
+    if worker.is_some() {
+        return;
+    }
+    let (tx, rx) = channel::<()>();
+    *worker = Some(tx);
+    std::thread::spawn(move || {
+        let mut delay = RETRY_MIN;
+        let mut flushed = HashSet::new();
+        loop {
+            let next = with_journal(|journal| {
+                journal
+                    .iter()
+                    .find(|e| !flushed.contains(&e.event_id))
+                    .cloned()
+            });
+            // Waiting for a retry, which new events do not cut short, they come after the
+            // failed one.
+            let mut backoff = false;
+            let wait = match next {
+                Some(event) => {
+                    let url = event.kind.url();
+                    if url.is_empty() {
+                        // Kept for the export.
+                        IDLE_INTERVAL
+                    } else {
+                        match post(url, event.body.to_string()) {
+                            Ok(_) => {
+                                flushed.insert(event.event_id);
+                                delay = RETRY_MIN;
+                                if flushed.len() >= FLUSH_BATCH {
+                                    remove_flushed(&std::mem::take(&mut flushed));
+                                }
+                                continue;
+                            }
+                            Err(e) => {
+                                log::error!("Failed to post audit {}: {}", event.event_id, e);
+                                let wait = delay;
+                                delay = (delay * 2).min(RETRY_MAX);
+                                backoff = true;
+                                wait
+                            }
+                        }
+                    }
+                }
+                None => IDLE_INTERVAL,
+            };
+            if !flushed.is_empty() {
+                remove_flushed(&std::mem::take(&mut flushed));
+            }
+            let until = Instant::now() + wait;
+            loop {
+                match rx.recv_timeout(until.saturating_duration_since(Instant::now())) {
+                    Ok(_) if backoff => {}
+                    Ok(_) | Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_journal() {
+        let event = |id: &str| AuditEvent {
+            event_id: id.to_owned(),
+            kind: AuditKind::Alarm,
+            time: 1,
+            body: json!({ "typ": 7 }),
+        };
+        let events = [event("a"), event("b"), event("a")];
+        let mut data = to_lines(events.iter());
+        assert_eq!(data.lines().count(), 3);
+        // cut by a crash
+        data.push_str("{\"event_id\":\"c\",\"ki");
+        let journal = parse_journal(&data);
+        let ids: Vec<_> = journal.iter().map(|e| e.event_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        assert_eq!(journal[0].kind, AuditKind::Alarm);
+        assert_ne!(new_event_id(), new_event_id());
+
+        let mut journal: VecDeque<_> = (0..MAX_EVENTS).map(|i| event(&i.to_string())).collect();
+        trim(&mut journal);
+        assert_eq!(journal.len(), MAX_EVENTS - TRIM_BATCH);
+        assert_eq!(journal[0].event_id, TRIM_BATCH.to_string());
+    }
+}
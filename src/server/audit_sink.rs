@@ -0,0 +1,339 @@
+// Local sink of the session events, for servers without an api server.
+//
+// Each event is a JSON object, sent as the message of an RFC 5424 syslog record,
+// and / or appended to a JSON Lines file which is rotated by size. The file is kept in the
+// config directory, as the options can be set by any local user through IPC.
+use hbb_common::{config::Config, lazy_static, log, ResultType};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+use std::{
+    io::Write,
+    net::{TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Sender},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// `udp://host:port`, `tcp://host:port` or `unix:///dev/log`.
+pub const OPTION_AUDIT_SYSLOG: &str = "audit-syslog";
+/// Name of the JSON Lines file, in the `audit` directory of the config directory.
+pub const OPTION_AUDIT_LOG_FILE: &str = "audit-log-file";
+/// Size in MB the file is rotated at, 10 by default.
+pub const OPTION_AUDIT_LOG_MAX_SIZE: &str = "audit-log-max-size";
+const DEFAULT_MAX_SIZE_MB: u64 = 10;
+const LOG_DIR: &str = "audit";
+const ROTATED_FILES: usize = 5;
+// authpriv
+const SYSLOG_FACILITY: u8 = 10;
+const TCP_TIMEOUT: Duration = Duration::from_secs(3);
+
+lazy_static::lazy_static! {
+    static ref SENDER: Mutex<Option<Sender<(SessionEvent, Value)>>> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionEvent {
+    Connect,
+    Authorize,
+    Permission,
+    File,
+    Clipboard,
+    Terminal,
+    Disconnect,
+    Alarm,
+}
+
+impl SessionEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Authorize => "authorize",
+            Self::Permission => "permission",
+            Self::File => "file",
+            Self::Clipboard => "clipboard",
+            Self::Terminal => "terminal",
+            Self::Disconnect => "disconnect",
+            Self::Alarm => "alarm",
+        }
+    }
+
+    // RFC 5424 severity
+    fn severity(&self) -> u8 {
+        match self {
+            // warning
+            Self::Alarm => 4,
+            // informational
+            _ => 6,
+        }
+    }
+}
+
+fn is_enabled() -> bool {
+    !Config::get_option(OPTION_AUDIT_SYSLOG).is_empty()
+        || !Config::get_option(OPTION_AUDIT_LOG_FILE).is_empty()
+}
+
+/// Sends `details` of `event` to the configured sinks in the background.
+pub fn emit(event: SessionEvent, details: Value) {
+    if !is_enabled() {
+        return;
+    }
+    let mut sender = SENDER.lock().unwrap();
+    let tx = sender.get_or_insert_with(|| {
+        let (tx, rx) = channel::<(SessionEvent, Value)>();
+        std::thread::spawn(move || {
+            let mut writer = Writer::default();
+            while let Ok((event, details)) = rx.recv() {
+                writer.write(event, details);
+            }
+        });
+        tx
+    });
+    tx.send((event, details)).ok();
+}
+
+enum Syslog {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+impl Syslog {
+    fn connect(target: &str) -> ResultType<Self> {
+        if let Some(addr) = target.strip_prefix("udp://") {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(addr)?;
+            return Ok(Self::Udp(socket));
+        }
+        if let Some(addr) = target.strip_prefix("tcp://") {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+            return Ok(Self::Tcp(stream));
+        }
+        #[cfg(unix)]
+        if let Some(path) = target.strip_prefix("unix://") {
+            let socket = std::os::unix::net::UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            return Ok(Self::Unix(socket));
+        }
+        hbb_common::bail!("Unsupported syslog target: {}", target);
+    }
+
+    fn send(&mut self, record: &str) -> ResultType<()> {
+        match self {
+            Self::Udp(socket) => {
+                socket.send(record.as_bytes())?;
+            }
+            // octet counting framing of RFC 6587
+            Self::Tcp(stream) => {
+                stream.write_all(format!("{} {}", record.len(), record).as_bytes())?;
+            }
+            #[cfg(unix)]
+            Self::Unix(socket) => {
+                socket.send(record.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    syslog: Option<(String, Syslog)>,
+}
+
+impl Writer {
+    fn write(&mut self, event: SessionEvent, details: Value) {
+        let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let record = to_record(event, &now, details);
+        let target = Config::get_option(OPTION_AUDIT_SYSLOG);
+        if !target.is_empty() {
+            if let Err(e) = self.send_syslog(&target, event, &now, &record) {
+                log::error!("Failed to send the audit event to {}: {}", target, e);
+                // reconnect next time
+                self.syslog = None;
+            }
+        }
+        let name = Config::get_option(OPTION_AUDIT_LOG_FILE);
+        if !name.is_empty() {
+            let Some(path) = log_file_path(&Config::path(LOG_DIR), &name) else {
+                log::error!("Invalid audit log file name: {}", name);
+                return;
+            };
+            let max_size = Config::get_option(OPTION_AUDIT_LOG_MAX_SIZE)
+                .parse()
+                .unwrap_or(DEFAULT_MAX_SIZE_MB);
+            if let Err(e) = append_line(&path, &record, max_size * 1024 * 1024) {
+                log::error!("Failed to write the audit event to {:?}: {}", path, e);
+            }
+        }
+    }
+
+    fn send_syslog(
+        &mut self,
+        target: &str,
+        event: SessionEvent,
+        time: &str,
+        record: &str,
+    ) -> ResultType<()> {
+        if self
+            .syslog
+            .as_ref()
+            .map(|(t, _)| t != target)
+            .unwrap_or(true)
+        {
+            self.syslog = Some((target.to_owned(), Syslog::connect(target)?));
+        }
+        if let Some((_, syslog)) = self.syslog.as_mut() {
+            syslog.send(&format_syslog(
+                event,
+                time,
+                &crate::whoami_hostname(),
+                &crate::get_app_name(),
+                record,
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+fn to_record(event: SessionEvent, time: &str, details: Value) -> String {
+    let mut v = json!({
+        "time": time,
+        "event": event,
+        "id": Config::get_id(),
+    });
+    if let (Some(v), Value::Object(details)) = (v.as_object_mut(), details) {
+        for (k, x) in details {
+            v.entry(k).or_insert(x);
+        }
+    }
+    v.to_string()
+}
+
+// PRINTUSASCII without spaces, as required by the header fields.
+fn header_field(s: &str, max_len: usize) -> String {
+    let s: String = s
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if s.is_empty() {
+        "-".to_owned()
+    } else {
+        s
+    }
+}
+
+// <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+fn format_syslog(event: SessionEvent, time: &str, hostname: &str, app: &str, msg: &str) -> String {
+    format!(
+        "<{}>1 {} {} {} {} {} - {}",
+        SYSLOG_FACILITY * 8 + event.severity(),
+        time,
+        header_field(hostname, 255),
+        header_field(app, 48),
+        std::process::id(),
+        event.name(),
+        msg
+    )
+}
+
+// A plain file name only, so that the file can't be placed out of `dir`.
+fn log_file_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(std::path::Component::Normal(name)), None) => Some(dir.join(name)),
+        _ => None,
+    }
+}
+
+fn rotated_path(path: &Path, i: usize) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(format!(".{}", i));
+    PathBuf::from(s)
+}
+
+// `path` is rotated to `path.1`, `path.1` to `path.2` and so on, the last one is dropped.
+fn rotate(path: &Path) -> std::io::Result<()> {
+    std::fs::remove_file(rotated_path(path, ROTATED_FILES)).ok();
+    for i in (1..ROTATED_FILES).rev() {
+        let from = rotated_path(path, i);
+        if from.exists() {
+            std::fs::rename(&from, rotated_path(path, i + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated_path(path, 1))
+}
+
+fn append_line(path: &Path, line: &str, max_size: u64) -> std::io::Result<()> {
+    if let Ok(meta) = std::fs::metadata(path) {
+        if meta.len() > 0 && meta.len() + line.len() as u64 + 1 > max_size {
+            rotate(path)?;
+        }
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    f.write_all(format!("{}\n", line).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_sink() {
+        let record = r#"{"event":"alarm"}"#;
+        assert_eq!(
+            format_syslog(
+                SessionEvent::Alarm,
+                "2024-01-01T00:00:00.000Z",
+                "my host",
+                "RustDesk",
+                record
+            ),
+            format!(
+                "<84>1 2024-01-01T00:00:00.000Z myhost RustDesk {} alarm - {}",
+                std::process::id(),
+                record
+            )
+        );
+
+        let dir = std::env::temp_dir().join("rustdesk-audit-sink-test");
+        assert_eq!(
+            log_file_path(&dir, "audit.jsonl"),
+            Some(dir.join("audit.jsonl"))
+        );
+        assert_eq!(log_file_path(&dir, "/etc/passwd"), None);
+        assert_eq!(log_file_path(&dir, "../passwd"), None);
+        assert_eq!(log_file_path(&dir, "a/b.jsonl"), None);
+        std::fs::remove_dir_all(&dir).ok();
+        let path = dir.join("audit.jsonl");
+        let n = ROTATED_FILES * 2 + 3;
+        for i in 0..n {
+            append_line(&path, &format!("{:09}", i), 20).unwrap();
+        }
+        // two lines per file
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{:09}\n", n - 1)
+        );
+        assert_eq!(
+            std::fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            format!("{:09}\n{:09}\n", n - 3, n - 2)
+        );
+        assert!(rotated_path(&path, ROTATED_FILES).exists());
+        assert!(!rotated_path(&path, ROTATED_FILES + 1).exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,4 +1,12 @@
-use super::{input_service::*, *};
+use super::{
+    access_grant::{self, AccessGrant},
+    audit_journal::{self, AuditKind},
+    audit_sink::{self, SessionEvent},
+    input_service::*,
+    ip_filter,
+    permission_policy::PeerPolicy,
+    *,
+};
 #[cfg(feature = "unix-file-copy-paste")]
 use crate::clipboard::try_empty_clipboard_files;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -15,13 +23,17 @@ use crate::platform::WallPaperRemover;
 use crate::portable_service::client as portable_client;
 use crate::{
     client::{
-        new_voice_call_request, new_voice_call_response, start_audio_thread, MediaData, MediaSender,
+        new_voice_call_request, new_voice_call_response, start_audio_thread,
+        transport_stats::{ByteCount, MessageClass},
+        MediaData, MediaSender,
     },
-    display_service, ipc, privacy_mode, video_service, VERSION,
+    common::{PortForwardKind, OPTION_ALLOW_REVERSE_TUNNEL},
+    display_service, ipc,
+    port_forward_mux::{self, LocalChannel, Mux},
+    privacy_mode, video_service, VERSION,
 };
 #[cfg(any(target_os = "android", target_os = "ios"))]
 use crate::{common::DEVICE_NAME, flutter::connection_manager::start_channel};
-use cidr_utils::cidr::IpCidr;
 #[cfg(target_os = "linux")]
 use hbb_common::platform::linux::run_cmds;
 #[cfg(target_os = "android")]
@@ -30,14 +42,14 @@ use hbb_common::{
     config::decode_permanent_password_h1_from_storage,
     config::{self, keys, Config, TrustedDevice},
     fs::{self, can_enable_overwrite_detection, JobType},
-    futures::{SinkExt, StreamExt},
+    futures::{future::BoxFuture, SinkExt, StreamExt},
     get_time, get_version_number,
     message_proto::{option_message::BoolOption, permission_info::Permission},
     password_security::{self as password, ApproveMode},
     sha2::{Digest, Sha256},
     sleep, timeout,
     tokio::{
-        net::TcpStream,
+        net::{TcpListener, TcpStream, UdpSocket},
         sync::mpsc,
         time::{self, Duration, Instant},
     },
@@ -199,6 +211,43 @@ pub enum AuthConnType {
     Terminal,
 }
 
+enum PortForwardSocket {
+    Tcp(Framed<TcpStream, BytesCodec>),
+    // The buffer is reused for every datagram.
+    Udp(UdpSocket, Vec<u8>),
+    // Channels are connected on demand, see `port_forward_mux_loop`.
+    Mux,
+    // Accepted connections are opened as channels back to the client.
+    Reverse(TcpListener),
+}
+
+impl PortForwardSocket {
+    async fn recv(&mut self) -> Option<ResultType<bytes::BytesMut>> {
+        match self {
+            Self::Tcp(forward) => forward.next().await.map(|res| res.map_err(|e| e.into())),
+            Self::Udp(socket, buf) => Some(
+                socket
+                    .recv(buf)
+                    .await
+                    .map(|n| bytes::BytesMut::from(&buf[..n]))
+                    .map_err(|e| e.into()),
+            ),
+            Self::Mux | Self::Reverse(_) => None,
+        }
+    }
+
+    async fn send(&mut self, data: bytes::BytesMut) -> ResultType<()> {
+        match self {
+            Self::Tcp(forward) => forward.send(data.freeze()).await?,
+            Self::Udp(socket, _) => {
+                socket.send(&data).await?;
+            }
+            Self::Mux | Self::Reverse(_) => bail!("No socket to forward to"),
+        }
+        Ok(())
+    }
+}
+
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 #[derive(Clone, Debug)]
 enum TerminalUserToken {
@@ -229,11 +278,11 @@ pub struct Connection {
     file_transfer: Option<(String, bool)>,
     view_camera: bool,
     terminal: bool,
-    port_forward_socket: Option<Framed<TcpStream, BytesCodec>>,
+    port_forward_socket: Option<PortForwardSocket>,
     port_forward_address: String,
     tx_to_cm: mpsc::UnboundedSender<ipc::Data>,
     authorized: bool,
-    require_2fa: Option<totp_rs::TOTP>,
+    require_2fa: Option<Box<dyn crate::auth_2fa::SecondFactor>>,
     keyboard: bool,
     clipboard: bool,
     audio: bool,
@@ -242,8 +291,14 @@ pub struct Connection {
     recording: bool,
     block_input: bool,
     control_permissions: Option<ControlPermissions>,
+    // Loaded once the peer is known at login.
+    permission_policy: Option<PeerPolicy>,
+    access_grant: Option<AccessGrant>,
     last_test_delay: Option<Instant>,
     network_delay: u32,
+    // Sent since the last report to the video qos
+    sent_bytes: ByteCount,
+    sent_bytes_instant: Instant,
     lock_after_session_end: bool,
     show_remote_cursor: bool,
     // by peer
@@ -299,9 +354,6 @@ pub struct Connection {
     multi_ui_session: bool,
     tx_from_authed: mpsc::UnboundedSender<ipc::Data>,
     printer_data: Vec<(Instant, String, Vec<u8>)>,
-    // For post requests that need to be sent sequentially.
-    // eg. post_conn_audit
-    tx_post_seq: mpsc::UnboundedSender<(String, Value)>,
     // Tracks read job IDs delegated to CM process.
     // When a read job is delegated to CM (via FS::ReadFile), the job id is added here.
     // Used to filter stale responses (FileBlockFromCM, FileReadDone, etc.) for
@@ -395,11 +447,6 @@ impl Connection {
         let linux_headless_handle =
             LinuxHeadlessHandle::new(_rx_cm_stream_ready, _tx_desktop_ready);
 
-        let (tx_post_seq, rx_post_seq) = mpsc::unbounded_channel();
-        tokio::spawn(async move {
-            Self::post_seq_loop(rx_post_seq).await;
-        });
-
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         let tx_cloned = tx.clone();
         let mut conn = Self {
@@ -408,7 +455,7 @@ impl Connection {
                 tx: Some(tx),
                 tx_video: Some(tx_video),
             },
-            require_2fa: crate::auth_2fa::get_2fa(None),
+            require_2fa: crate::auth_2fa::get_second_factor(),
             display_idx: *display_service::PRIMARY_DISPLAY_IDX,
             stream,
             server,
@@ -423,17 +470,37 @@ impl Connection {
             port_forward_address: "".to_owned(),
             tx_to_cm,
             authorized: false,
-            keyboard: Self::permission(keys::OPTION_ENABLE_KEYBOARD, &control_permissions),
-            clipboard: Self::permission(keys::OPTION_ENABLE_CLIPBOARD, &control_permissions),
-            audio: Self::permission(keys::OPTION_ENABLE_AUDIO, &control_permissions),
+            keyboard: Self::permission(keys::OPTION_ENABLE_KEYBOARD, &control_permissions, &None),
+            clipboard: Self::permission(keys::OPTION_ENABLE_CLIPBOARD, &control_permissions, &None),
+            audio: Self::permission(keys::OPTION_ENABLE_AUDIO, &control_permissions, &None),
             // to-do: make sure is the option correct here
-            file: Self::permission(keys::OPTION_ENABLE_FILE_TRANSFER, &control_permissions),
-            restart: Self::permission(keys::OPTION_ENABLE_REMOTE_RESTART, &control_permissions),
-            recording: Self::permission(keys::OPTION_ENABLE_RECORD_SESSION, &control_permissions),
-            block_input: Self::permission(keys::OPTION_ENABLE_BLOCK_INPUT, &control_permissions),
+            file: Self::permission(
+                keys::OPTION_ENABLE_FILE_TRANSFER,
+                &control_permissions,
+                &None,
+            ),
+            restart: Self::permission(
+                keys::OPTION_ENABLE_REMOTE_RESTART,
+                &control_permissions,
+                &None,
+            ),
+            recording: Self::permission(
+                keys::OPTION_ENABLE_RECORD_SESSION,
+                &control_permissions,
+                &None,
+            ),
+            block_input: Self::permission(
+                keys::OPTION_ENABLE_BLOCK_INPUT,
+                &control_permissions,
+                &None,
+            ),
             control_permissions,
+            permission_policy: None,
+            access_grant: None,
             last_test_delay: None,
             network_delay: 0,
+            sent_bytes: Default::default(),
+            sent_bytes_instant: Instant::now(),
             lock_after_session_end: false,
             show_remote_cursor: false,
             follow_remote_cursor: false,
@@ -485,7 +552,6 @@ impl Connection {
             retina: Retina::default(),
             tx_from_authed,
             printer_data: Vec::new(),
-            tx_post_seq,
             cm_read_job_ids: HashSet::new(),
             terminal_service_id: "".to_owned(),
             terminal_persistent: false,
@@ -607,6 +673,16 @@ impl Connection {
                         }
                         ipc::Data::SwitchPermission{name, enabled} => {
                             log::info!("Change permission {} -> {}", name, enabled);
+                            let denied = enabled && conn.permission_policy.as_ref()
+                                .and_then(|p| p.allowed_by_name(&name)) == Some(false);
+                            conn.emit_session_event(
+                                SessionEvent::Permission,
+                                json!({ "name": name, "enabled": enabled, "denied": denied }),
+                            );
+                            if denied {
+                                log::warn!("Permission {} denied by the permission policy", name);
+                                continue;
+                            }
                             if &name == "keyboard" {
                                 conn.keyboard = enabled;
                                 conn.send_permission(Permission::Keyboard, enabled).await;
@@ -812,7 +888,11 @@ impl Connection {
                 _ = conn.file_timer.tick() => {
                     if !conn.read_jobs.is_empty() {
                         conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), fs::serialize_transfer_jobs(&conn.read_jobs))));
-                        match fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await {
+                        let finished_size = |jobs: &Vec<fs::TransferJob>| jobs.iter().map(|j| j.finished_size()).sum::<u64>();
+                        let last_finished_size = finished_size(&conn.read_jobs);
+                        let res = fs::handle_read_jobs(&mut conn.read_jobs, &mut conn.stream).await;
+                        conn.sent_bytes.file += finished_size(&conn.read_jobs).saturating_sub(last_finished_size);
+                        match res {
                             Ok(log) => {
                                 if !log.is_empty() {
                                     conn.send_to_cm(ipc::Data::FileTransferLog(("transfer".to_string(), log)));
@@ -840,6 +920,7 @@ impl Connection {
                             video_service::notify_video_frame_fetched(vf.display as usize, id, Some(instant.into()));
                         }
                     }
+                    conn.sent_bytes.add(MessageClass::Video, value.compute_size() as _);
                     if let Err(err) = conn.stream.send(&value as &Message).await {
                         conn.on_close(&err.to_string(), false).await;
                         break;
@@ -888,6 +969,8 @@ impl Connection {
                             }
                         }
                         Some(message::Union::MultiClipboards(_multi_clipboards)) => {
+                            let size: usize = _multi_clipboards.clipboards.iter().map(|c| c.content.len()).sum();
+                            conn.emit_session_event(SessionEvent::Clipboard, json!({ "direction": "send", "size": size }));
                             #[cfg(not(target_os = "ios"))]
                             if let Some(msg_out) = crate::clipboard::get_msg_if_not_support_multi_clip(&conn.lr.version, &conn.lr.my_platform, _multi_clipboards) {
                                 if let Err(err) = conn.stream.send(&msg_out).await {
@@ -901,6 +984,7 @@ impl Connection {
                     }
 
                     let msg: &Message = &msg;
+                    conn.sent_bytes.add(MessageClass::of(msg), msg.compute_size() as _);
                     if let Err(err) = conn.stream.send(msg).await {
                         conn.on_close(&err.to_string(), false).await;
                         break;
@@ -910,7 +994,7 @@ impl Connection {
                     match data {
                         #[cfg(all(target_os = "windows", feature = "flutter"))]
                         ipc::Data::PrinterData(data) => {
-                            if Self::permission(keys::OPTION_ENABLE_REMOTE_PRINTER, &conn.control_permissions) {
+                            if Self::permission(keys::OPTION_ENABLE_REMOTE_PRINTER, &conn.control_permissions, &conn.permission_policy) {
                                 conn.send_printer_request(data).await;
                             } else {
                                 conn.send_remote_printing_disallowed().await;
@@ -930,7 +1014,27 @@ impl Connection {
                             break;
                         }
                     }
+                    if let Ok(ip) = conn.ip.parse() {
+                        // The whitelist may have changed since the login.
+                        if let ip_filter::Access::Denied(entry) = ip_filter::current().check(ip) {
+                            log::info!("#{} {} denied by {}", conn.inner.id(), conn.ip, entry);
+                            conn.send_close_reason_no_retry("Your ip is blocked by the peer").await;
+                            conn.on_close("ip denied", true).await;
+                            break;
+                        }
+                    }
+                    // Only the grant of the login, whose permissions are applied
+                    if let Some(grant) = conn.access_grant.as_ref() {
+                        if conn.authorized && !access_grant::is_active(&grant.id) {
+                            conn.send_close_reason_no_retry("Access grant expired").await;
+                            conn.on_close("access grant expired", true).await;
+                            break;
+                        }
+                    }
                     conn.file_remove_log_control.on_timer().drain(..).map(|x| conn.send_to_cm(x)).count();
+                    if conn.is_authed_remote_conn() || conn.view_camera {
+                        conn.update_throughput();
+                    }
                     #[cfg(feature = "hwcodec")]
                     conn.update_supported_encoding();
                 }
@@ -1114,18 +1218,20 @@ impl Connection {
         log::debug!("Input thread exited");
     }
 
-    async fn post_seq_loop(mut rx: mpsc::UnboundedReceiver<(String, Value)>) {
-        while let Some((url, v)) = rx.recv().await {
-            allow_err!(Self::post_audit_async(url, v).await);
-        }
-        log::debug!("post_seq_loop exited");
-    }
-
     async fn try_port_forward_loop(
         &mut self,
         rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
     ) -> ResultType<()> {
         let mut last_recv_time = Instant::now();
+        match self.port_forward_socket.take() {
+            Some(PortForwardSocket::Mux) => {
+                return self.port_forward_mux_loop(rx_from_cm, None).await;
+            }
+            Some(PortForwardSocket::Reverse(listener)) => {
+                return self.port_forward_mux_loop(rx_from_cm, Some(listener)).await;
+            }
+            socket => self.port_forward_socket = socket,
+        }
         if let Some(mut forward) = self.port_forward_socket.take() {
             log::info!("Running port forwarding loop");
             self.stream.set_raw();
@@ -1144,7 +1250,7 @@ impl Connection {
                             _ => {}
                         }
                     }
-                    res = forward.next() => {
+                    res = forward.recv() => {
                         if let Some(res) = res {
                             last_recv_time = Instant::now();
                             self.stream.send_bytes(res?.into()).await?;
@@ -1177,6 +1283,133 @@ impl Connection {
         Ok(())
     }
 
+    // With a listener this is a reverse tunnel, the client may not open channels then.
+    async fn port_forward_mux_loop(
+        &mut self,
+        rx_from_cm: &mut mpsc::UnboundedReceiver<Data>,
+        listener: Option<TcpListener>,
+    ) -> ResultType<()> {
+        log::info!("Running port forwarding mux loop");
+        self.stream.set_raw();
+        let connector: port_forward_mux::Connector = if listener.is_some() {
+            Mux::reject_all()
+        } else {
+            Arc::new(|target| {
+                let f: BoxFuture<'static, ResultType<TcpStream>> =
+                    Box::pin(Self::connect_port_forward_channel(target));
+                f
+            })
+        };
+        let mut mux = Mux::new(port_forward_mux::Role::Server, connector);
+        let mut last_recv_time = Instant::now();
+        let mut hbbs_rx = crate::hbbs_http::sync::signal_receiver();
+        loop {
+            tokio::select! {
+                Some(data) = rx_from_cm.recv() => {
+                    match data {
+                        ipc::Data::Close => {
+                            bail!("Close requested from connection manager");
+                        }
+                        ipc::Data::CmErr(e) => {
+                            log::error!("Connection manager error: {e}");
+                            bail!("{e}");
+                        }
+                        _ => {}
+                    }
+                }
+                res = self.stream.next() => {
+                    if let Some(res) = res {
+                        last_recv_time = Instant::now();
+                        mux.handle_frame(&res?)?;
+                    } else {
+                        bail!("Stream reset by the peer");
+                    }
+                }
+                Some(frame) = mux.next_frame() => {
+                    self.stream.send_bytes(frame).await?;
+                }
+                res = async { listener.as_ref().unwrap().accept().await }, if listener.is_some() => {
+                    match res {
+                        Ok((socket, addr)) => {
+                            log::info!("Reverse port forward connection from {:?}", addr);
+                            mux.open(LocalChannel {
+                                socket,
+                                target: String::new(),
+                                reply: None,
+                            });
+                        }
+                        Err(e) => log::warn!("Reverse port forward accept failed: {}", e),
+                    }
+                }
+                _ = self.timer.tick() => {
+                    // An idle reverse tunnel still waits for local connections.
+                    if listener.is_none() && last_recv_time.elapsed() >= H1 && mux.channel_count() == 0 {
+                        bail!("Timeout");
+                    }
+                }
+                Ok(conns) = hbbs_rx.recv() => {
+                    if conns.contains(&self.inner.id) {
+                        bail!("Closed manually by the web console");
+                    }
+                }
+            }
+        }
+    }
+
+    // Every channel of a mux session goes through the same policy as a single port forward.
+    async fn connect_port_forward_channel(target: String) -> ResultType<TcpStream> {
+        let Some((host, port)) = target.rsplit_once(':') else {
+            bail!("Invalid port forward target {}", target);
+        };
+        let mut pf = PortForward {
+            host: host.to_owned(),
+            port: port.parse()?,
+            ..Default::default()
+        };
+        if PortForwardKind::parse(&pf.host).0 != PortForwardKind::Tcp {
+            bail!("Only tcp channels are supported");
+        }
+        let (addr, _is_rdp) = Self::normalize_port_forward_target(&mut pf)?;
+        let addrs = Self::resolve_port_forward_target(&pf, &addr).await?;
+        log::info!("Port forward channel to {}", addr);
+        Ok(timeout(3000, TcpStream::connect(&addrs[..])).await??)
+    }
+
+    // Wake a peer on one of our subnets for the controlling side.
+    #[cfg(not(target_os = "ios"))]
+    async fn handle_wol_relay(&mut self, content: &[u8]) {
+        let mut res = crate::lan::WolRelayResponse::default();
+        match serde_json::from_slice::<crate::lan::WolRelayRequest>(content) {
+            Ok(req) => {
+                res.id = req.id.clone();
+                if !self.keyboard
+                    || !config::option2bool(
+                        crate::lan::OPTION_ALLOW_WOL_RELAY,
+                        &Config::get_option(crate::lan::OPTION_ALLOW_WOL_RELAY),
+                    )
+                {
+                    res.error = "No permission of wake-on-lan relay".to_owned();
+                } else {
+                    log::info!("Wake-on-lan relay for {} from {}", req.id, self.lr.my_id);
+                    match crate::lan::relay_wol(&req) {
+                        Ok(sent) => res.sent = sent,
+                        Err(err) => res.error = err.to_string(),
+                    }
+                }
+            }
+            Err(err) => res.error = err.to_string(),
+        }
+        let mut misc = Misc::new();
+        misc.set_plugin_request(PluginRequest {
+            id: crate::lan::WOL_RELAY_REQUEST_ID.to_owned(),
+            content: serde_json::to_vec(&res).unwrap_or_default().into(),
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(msg_out).await;
+    }
+
     async fn send_permission(&mut self, permission: Permission, enabled: bool) {
         let mut misc = Misc::new();
         misc.set_permission_info(PermissionInfo {
@@ -1187,6 +1420,35 @@ impl Connection {
         let mut msg_out = Message::new();
         msg_out.set_misc(misc);
         self.send(msg_out).await;
+        self.update_record_peer();
+    }
+
+    // The peer in the metadata of the recordings.
+    fn update_record_peer(&self) {
+        if !self.is_authed_remote_conn() && !self.is_authed_view_camera_conn() {
+            return;
+        }
+        let permissions = [
+            ("keyboard", self.keyboard),
+            ("clipboard", self.clipboard),
+            ("audio", self.audio),
+            ("file", self.file),
+            ("restart", self.restart),
+            ("recording", self.recording),
+            ("block_input", self.block_input),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name.to_string())
+        .collect();
+        video_service::update_record_peer(
+            self.inner.id(),
+            Some(scrap::record::RecordPeer {
+                id: self.lr.my_id.clone(),
+                name: self.lr.my_name.clone(),
+                permissions,
+            }),
+        );
     }
 
     async fn check_privacy_mode_on(&mut self) -> bool {
@@ -1200,28 +1462,17 @@ impl Connection {
     }
 
     async fn check_whitelist(&mut self, addr: &SocketAddr) -> bool {
-        let whitelist: Vec<String> = Config::get_option("whitelist")
-            .split(",")
-            .filter(|x| !x.is_empty())
-            .map(|x| x.to_owned())
-            .collect();
-        if !whitelist.is_empty()
-            && whitelist
-                .iter()
-                .filter(|x| x == &"0.0.0.0")
-                .next()
-                .is_none()
-            && whitelist
-                .iter()
-                .filter(|x| IpCidr::from_str(x).map_or(false, |y| y.contains(addr.ip())))
-                .next()
-                .is_none()
-        {
+        let access = ip_filter::current().check(addr.ip());
+        if access != ip_filter::Access::Allowed {
             self.send_login_error("Your ip is blocked by the peer")
                 .await;
+            let mut body = json!({ "ip": addr.ip() });
+            if let ip_filter::Access::Denied(entry) = access {
+                body["rule"] = json!(entry);
+            }
             Self::post_alarm_audit(
                 AlarmAuditType::IpWhitelist, //"ip whitelist",
-                json!({ "ip":addr.ip() }),
+                body,
             );
             return false;
         }
@@ -1265,17 +1516,31 @@ impl Connection {
         );
     }
 
+    fn emit_session_event(&self, event: SessionEvent, mut details: Value) {
+        details["conn_id"] = json!(self.inner.id);
+        details["ip"] = json!(self.ip);
+        details["peer_id"] = json!(self.lr.my_id);
+        details["peer_name"] = json!(self.lr.my_name);
+        audit_sink::emit(event, details);
+    }
+
     fn post_conn_audit(&self, v: Value) {
-        if self.server_audit_conn.is_empty() {
+        // "new" is posted on open, "close" on exit and the one without action on login.
+        let event = match v["action"].as_str() {
+            Some("new") => SessionEvent::Connect,
+            Some("close") => SessionEvent::Disconnect,
+            _ => SessionEvent::Authorize,
+        };
+        self.emit_session_event(event, v.clone());
+        if !audit_journal::is_enabled(&self.server_audit_conn) {
             return;
         }
-        let url = self.server_audit_conn.clone();
         let mut v = v;
         v["id"] = json!(Config::get_id());
         v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
         v["conn_id"] = json!(self.inner.id);
         v["session_id"] = json!(self.lr.session_id);
-        allow_err!(self.tx_post_seq.send((url, v)));
+        audit_journal::push(AuditKind::Conn, v);
     }
 
     fn get_files_for_audit(job_type: fs::JobType, mut files: Vec<FileEntry>) -> Vec<(String, i64)> {
@@ -1301,15 +1566,24 @@ impl Connection {
         files: Vec<(String, i64)>,
         info: Value,
     ) {
-        if self.server_audit_file.is_empty() {
-            return;
-        }
-        let url = self.server_audit_file.clone();
         let file_num = files.len();
         let mut files = files;
         files.sort_by(|a, b| b.1.cmp(&a.1));
         files.truncate(10);
         let is_file = files.len() == 1 && files[0].0.is_empty();
+        self.emit_session_event(
+            SessionEvent::File,
+            json!({
+                "type": r#type as i8,
+                "path": path,
+                "is_file": is_file,
+                "num": file_num,
+                "files": files,
+            }),
+        );
+        if !audit_journal::is_enabled(&self.server_audit_file) {
+            return;
+        }
         let mut info = info;
         info["ip"] = json!(self.ip.clone());
         info["name"] = json!(self.lr.my_name.clone());
@@ -1324,18 +1598,42 @@ impl Connection {
             "is_file":is_file,
             "info":json!(info).to_string(),
         });
-        tokio::spawn(async move {
-            allow_err!(Self::post_audit_async(url, v).await);
-        });
+        audit_journal::push(AuditKind::File, v);
+    }
+
+    fn try_recovery_code(&self, code: &str) -> bool {
+        let Some(remaining) = crate::auth_2fa::consume_recovery_code(code) else {
+            return false;
+        };
+        log::warn!(
+            "2fa recovery code used by {} ({}), {} left",
+            self.lr.my_id,
+            self.ip,
+            remaining
+        );
+        Self::post_alarm_audit(
+            AlarmAuditType::RecoveryCodeUsed,
+            json!({
+                "ip": self.ip,
+                "id": self.lr.my_id.clone(),
+                "name": self.lr.my_name.clone(),
+                "remaining": remaining,
+            }),
+        );
+        true
     }
 
     pub fn post_alarm_audit(typ: AlarmAuditType, info: Value) {
+        audit_sink::emit(
+            SessionEvent::Alarm,
+            json!({ "typ": typ as i8, "info": info.clone() }),
+        );
         let url = crate::get_audit_server(
             Config::get_option("api-server"),
             Config::get_option("custom-rendezvous-server"),
             "alarm".to_owned(),
         );
-        if url.is_empty() {
+        if !audit_journal::is_enabled(&url) {
             return;
         }
         let mut v = Value::default();
@@ -1343,19 +1641,37 @@ impl Connection {
         v["uuid"] = json!(crate::encode64(hbb_common::get_uuid()));
         v["typ"] = json!(typ as i8);
         v["info"] = serde_json::Value::String(info.to_string());
-        tokio::spawn(async move {
-            allow_err!(Self::post_audit_async(url, v).await);
-        });
+        audit_journal::push(AuditKind::Alarm, v);
     }
 
-    #[inline]
-    async fn post_audit_async(url: String, v: Value) -> ResultType<String> {
-        crate::post_request(url, v.to_string(), "").await
-    }
-
-    fn normalize_port_forward_target(pf: &mut PortForward) -> (String, bool) {
+    // Dynamic (SOCKS5 / HTTP CONNECT) forwarding lets the client pick any target per connection,
+    // so the target is checked against the local policy here rather than trusted.
+    fn normalize_port_forward_target(pf: &mut PortForward) -> ResultType<(String, bool)> {
         let mut is_rdp = false;
-        if pf.host == "RDP" && pf.port == 0 {
+        let (kind, host) = PortForwardKind::parse(&pf.host);
+        pf.host = host.to_owned();
+        if kind == PortForwardKind::Mux {
+            // Channels are checked one by one in `connect_port_forward_channel`.
+            return Ok((String::new(), false));
+        }
+        if kind == PortForwardKind::Reverse {
+            // The client does not pick the interface to listen on.
+            let bind = Config::get_option("reverse-tunnel-bind-address");
+            pf.host = if bind.is_empty() {
+                "127.0.0.1".to_owned()
+            } else {
+                bind
+            };
+            let rules = Config::get_option("reverse-tunnel-allowed-ports");
+            if !crate::common::is_reverse_tunnel_port_allowed(&rules, pf.port) {
+                bail!(
+                    "Reverse port forward on port {} is not allowed by the peer",
+                    pf.port
+                );
+            }
+            return Ok((format!("{}:{}", pf.host, pf.port), false));
+        }
+        if kind == PortForwardKind::Tcp && pf.host == "RDP" && pf.port == 0 {
             pf.host = "localhost".to_owned();
             pf.port = 3389;
             is_rdp = true;
@@ -1363,7 +1679,29 @@ impl Connection {
         if pf.host.is_empty() {
             pf.host = "localhost".to_owned();
         }
-        (format!("{}:{}", pf.host, pf.port), is_rdp)
+        Ok((format!("{}:{}", pf.host, pf.port), is_rdp))
+    }
+
+    // Names are resolved before the policy check, a name is allowed if it matches a rule
+    // itself or if every address it resolves to does. The checked addresses are returned
+    // so that the connect can not be redirected by a second lookup.
+    async fn resolve_port_forward_target(
+        pf: &PortForward,
+        addr: &str,
+    ) -> ResultType<Vec<std::net::SocketAddr>> {
+        let addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+        if addrs.is_empty() {
+            bail!("No address resolved for {}", addr);
+        }
+        let rules = Config::get_option("port-forward-allowed-targets");
+        let allowed = crate::common::is_port_forward_target_allowed(&rules, &pf.host, pf.port)
+            || addrs.iter().all(|x| {
+                crate::common::is_port_forward_target_allowed(&rules, &x.ip().to_string(), pf.port)
+            });
+        if !allowed {
+            bail!("Port forward to {} is not allowed by the peer", addr);
+        }
+        Ok(addrs)
     }
 
     async fn connect_port_forward_if_needed(&mut self) -> bool {
@@ -1374,11 +1712,60 @@ impl Connection {
             return true;
         };
         let mut pf = pf.clone();
-        let (mut addr, is_rdp) = Self::normalize_port_forward_target(&mut pf);
-        self.port_forward_address = addr.clone();
-        match timeout(3000, TcpStream::connect(&addr)).await {
+        let kind = PortForwardKind::parse(&pf.host).0;
+        let (mut addr, is_rdp) = match Self::normalize_port_forward_target(&mut pf) {
+            Ok(x) => x,
+            Err(err) => {
+                self.send_login_error(err.to_string()).await;
+                return false;
+            }
+        };
+        self.port_forward_address = kind.with_host(&addr);
+        let addrs = match kind {
+            PortForwardKind::Tcp | PortForwardKind::Udp => {
+                match Self::resolve_port_forward_target(&pf, &addr).await {
+                    Ok(addrs) => addrs,
+                    Err(err) => {
+                        log::warn!("Port forward target {} rejected: {}", addr, err);
+                        self.send_login_error(err.to_string()).await;
+                        return false;
+                    }
+                }
+            }
+            _ => vec![],
+        };
+        let res = match kind {
+            PortForwardKind::Tcp => {
+                timeout(3000, TcpStream::connect(&addrs[..]))
+                    .await
+                    .map(|res| {
+                        res.map(|sock| PortForwardSocket::Tcp(Framed::new(sock, BytesCodec::new())))
+                    })
+            }
+            PortForwardKind::Udp => timeout(3000, Self::connect_udp(addrs[0]))
+                .await
+                .map(|res| res.map(|sock| PortForwardSocket::Udp(sock, vec![0u8; 65536]))),
+            PortForwardKind::Mux => {
+                self.port_forward_socket = Some(PortForwardSocket::Mux);
+                return true;
+            }
+            PortForwardKind::Reverse => match TcpListener::bind(&addr).await {
+                Ok(listener) => {
+                    log::info!("Reverse port forward listening on {}", addr);
+                    self.port_forward_socket = Some(PortForwardSocket::Reverse(listener));
+                    return true;
+                }
+                Err(e) => {
+                    log::warn!("Reverse port forward listen failed on {}: {}", addr, e);
+                    self.send_login_error(format!("Failed to listen on {}", addr))
+                        .await;
+                    return false;
+                }
+            },
+        };
+        match res {
             Ok(Ok(sock)) => {
-                self.port_forward_socket = Some(Framed::new(sock, BytesCodec::new()));
+                self.port_forward_socket = Some(sock);
                 true
             }
             Ok(Err(e)) => {
@@ -1408,6 +1795,16 @@ impl Connection {
         }
     }
 
+    async fn connect_udp(target: std::net::SocketAddr) -> std::io::Result<UdpSocket> {
+        let socket = if target.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0").await?
+        } else {
+            UdpSocket::bind("[::]:0").await?
+        };
+        socket.connect(target).await?;
+        Ok(socket)
+    }
+
     // Returns whether this connection should be kept alive.
     // `true` does not necessarily mean authorization succeeded (e.g. REQUIRE_2FA case).
     async fn send_logon_response_and_keep_alive(&mut self) -> bool {
@@ -1415,37 +1812,31 @@ impl Connection {
             return true;
         }
         if self.require_2fa.is_some() && !self.is_recent_session(true) && !self.from_switch {
-            self.require_2fa.as_ref().map(|totp| {
-                let bot = crate::auth_2fa::TelegramBot::get();
-                let bot = match bot {
-                    Ok(Some(bot)) => bot,
-                    Err(err) => {
-                        log::error!("Failed to get telegram bot: {}", err);
-                        return;
-                    }
-                    _ => return,
-                };
-                let code = totp.generate_current();
-                if let Ok(code) = code {
-                    let text = format!(
-                        "2FA code: {}\n\nA new connection has been established to your device with ID {}. The source IP address is {}.",
-                        code,
-                        Config::get_id(),
-                        self.ip,
-                    );
-                    tokio::spawn(async move {
-                        if let Err(err) =
-                            crate::auth_2fa::send_2fa_code_to_telegram(&text, bot).await
-                        {
-                            log::error!("Failed to send 2fa code to telegram bot: {}", err);
-                        }
-                    });
-                }
-            });
+            let ctx = crate::auth_2fa::LoginContext {
+                peer_id: self.lr.my_id.clone(),
+                peer_name: self.lr.my_name.clone(),
+                ip: self.ip.clone(),
+            };
+            if let Some(factor) = self.require_2fa.as_mut() {
+                log::info!("Require 2fa by {}", factor.name());
+                factor.challenge(&ctx);
+            }
             self.send_login_error(crate::client::REQUIRE_2FA).await;
             // Keep the connection alive so the client can continue with 2FA.
             return true;
         }
+        if self.access_grant.is_none() {
+            self.access_grant = access_grant::find_by_peer(&self.lr.my_id);
+        }
+        if let Some(grant) = self.access_grant.as_ref() {
+            log::info!("Login with access grant {}", grant.id);
+            self.apply_permission_policy();
+            if !self.is_login_type_permitted() {
+                self.send_login_error("Not granted for this connection type")
+                    .await;
+                return false;
+            }
+        }
         if !self.connect_port_forward_if_needed().await {
             return false;
         }
@@ -1476,6 +1867,7 @@ impl Connection {
         self.post_conn_audit(
             json!({"peer": ((&self.lr.my_id, &self.lr.my_name)), "type": conn_type}),
         );
+        self.update_record_peer();
         #[allow(unused_mut)]
         let mut username = crate::platform::get_active_username();
         let mut res = LoginResponse::new();
@@ -1564,8 +1956,26 @@ impl Connection {
         if !platform_additions.is_empty() {
             pi.platform_additions = serde_json::to_string(&platform_additions).unwrap_or("".into());
         }
+        #[cfg(not(target_os = "ios"))]
+        {
+            let mut additions: serde_json::Map<String, Value> =
+                serde_json::from_str(&pi.platform_additions).unwrap_or_default();
+            additions.insert(crate::lan::PEER_SUPPORT_WOL_RELAY.into(), json!(true));
+            if scrap::codec::Encoder::screen_content_supported() {
+                additions.insert(
+                    scrap::codec::PEER_SUPPORT_SCREEN_CONTENT.into(),
+                    json!(true),
+                );
+            }
+            pi.platform_additions = serde_json::to_string(&additions).unwrap_or_default();
+        }
 
         if self.port_forward_socket.is_some() {
+            // The client carries its next tcp forwards over one mux connection.
+            let mut additions: serde_json::Map<String, Value> =
+                serde_json::from_str(&pi.platform_additions).unwrap_or_default();
+            additions.insert(port_forward_mux::PEER_SUPPORT_MUX.into(), json!(true));
+            pi.platform_additions = serde_json::to_string(&additions).unwrap_or_default();
             let mut msg_out = Message::new();
             res.set_peer_info(pi);
             msg_out.set_login_response(res);
@@ -2065,6 +2475,10 @@ impl Connection {
                 }
             }
         }
+        if let Some(grant) = access_grant::find_by_token(&self.lr.my_id, |h1| self.verify_h1(h1)) {
+            self.access_grant = Some(grant);
+            return true;
+        }
         false
     }
 
@@ -2112,8 +2526,26 @@ impl Connection {
     fn permission(
         enable_prefix_option: &str,
         control_permissions: &Option<ControlPermissions>,
+        permission_policy: &Option<PeerPolicy>,
     ) -> bool {
         use hbb_common::rendezvous_proto::control_permissions::Permission;
+        if enable_prefix_option == OPTION_ALLOW_REVERSE_TUNNEL {
+            return crate::common::is_reverse_tunnel_allowed(
+                Self::permission(
+                    keys::OPTION_ENABLE_TUNNEL,
+                    control_permissions,
+                    permission_policy,
+                ),
+                &Config::get_option(enable_prefix_option),
+            );
+        }
+        let policy = permission_policy
+            .as_ref()
+            .and_then(|p| p.allowed(enable_prefix_option));
+        // A policy deny wins over the control permissions.
+        if policy == Some(false) {
+            return false;
+        }
         if let Some(control_permissions) = control_permissions {
             let permission = match enable_prefix_option {
                 keys::OPTION_ENABLE_KEYBOARD => Some(Permission::keyboard),
@@ -2137,9 +2569,48 @@ impl Connection {
                 }
             }
         }
+        if let Some(enabled) = policy {
+            return enabled;
+        }
         Self::is_permission_enabled_locally(enable_prefix_option)
     }
 
+    // The permissions are set before the peer is known, the policy may change them.
+    fn apply_permission_policy(&mut self) {
+        self.permission_policy = PeerPolicy::load(&self.lr.my_id, &self.ip);
+        if let Some(grant) = self.access_grant.as_ref() {
+            if !grant.permissions.is_empty() {
+                self.permission_policy
+                    .get_or_insert_with(|| PeerPolicy::new(&self.lr.my_id, &self.ip))
+                    .restrict(&grant.permissions);
+            }
+        }
+        if self.permission_policy.is_none() {
+            return;
+        }
+        let cp = self.control_permissions.clone();
+        let pp = self.permission_policy.clone();
+        self.keyboard = Self::permission(keys::OPTION_ENABLE_KEYBOARD, &cp, &pp);
+        self.clipboard = Self::permission(keys::OPTION_ENABLE_CLIPBOARD, &cp, &pp);
+        self.audio = Self::permission(keys::OPTION_ENABLE_AUDIO, &cp, &pp);
+        self.file = Self::permission(keys::OPTION_ENABLE_FILE_TRANSFER, &cp, &pp);
+        self.restart = Self::permission(keys::OPTION_ENABLE_REMOTE_RESTART, &cp, &pp);
+        self.recording = Self::permission(keys::OPTION_ENABLE_RECORD_SESSION, &cp, &pp);
+        self.block_input = Self::permission(keys::OPTION_ENABLE_BLOCK_INPUT, &cp, &pp);
+    }
+
+    // The connection type is checked before the access grant is known.
+    fn is_login_type_permitted(&self) -> bool {
+        let option = match self.lr.union {
+            Some(login_request::Union::FileTransfer(_)) => keys::OPTION_ENABLE_FILE_TRANSFER,
+            Some(login_request::Union::ViewCamera(_)) => keys::OPTION_ENABLE_CAMERA,
+            Some(login_request::Union::Terminal(_)) => keys::OPTION_ENABLE_TERMINAL,
+            Some(login_request::Union::PortForward(_)) => keys::OPTION_ENABLE_TUNNEL,
+            _ => return true,
+        };
+        Self::permission(option, &self.control_permissions, &self.permission_policy)
+    }
+
     fn update_codec_on_login(&self) {
         use scrap::codec::{Encoder, EncodingUpdate::*};
         if let Some(o) = self.lr.clone().option.as_ref() {
@@ -2167,6 +2638,9 @@ impl Connection {
         if let Some(o) = lr.option.as_ref() {
             self.options_in_login = Some(o.clone());
         }
+        if !self.authorized {
+            self.apply_permission_policy();
+        }
         if self.require_2fa.is_some() && !lr.hwid.is_empty() && Self::enable_trusted_devices() {
             let devices = Config::get_trusted_devices();
             if let Some(device) = devices.iter().find(|d| d.hwid == lr.hwid) {
@@ -2238,6 +2712,7 @@ impl Connection {
                     if !Self::permission(
                         keys::OPTION_ENABLE_FILE_TRANSFER,
                         &self.control_permissions,
+                        &self.permission_policy,
                     ) {
                         self.send_login_error("No permission of file transfer")
                             .await;
@@ -2247,7 +2722,11 @@ impl Connection {
                     self.file_transfer = Some((ft.dir, ft.show_hidden));
                 }
                 Some(login_request::Union::ViewCamera(_vc)) => {
-                    if !Self::permission(keys::OPTION_ENABLE_CAMERA, &self.control_permissions) {
+                    if !Self::permission(
+                        keys::OPTION_ENABLE_CAMERA,
+                        &self.control_permissions,
+                        &self.permission_policy,
+                    ) {
                         self.send_login_error("No permission of viewing camera")
                             .await;
                         sleep(1.).await;
@@ -2256,7 +2735,11 @@ impl Connection {
                     self.view_camera = true;
                 }
                 Some(login_request::Union::Terminal(terminal)) => {
-                    if !Self::permission(keys::OPTION_ENABLE_TERMINAL, &self.control_permissions) {
+                    if !Self::permission(
+                        keys::OPTION_ENABLE_TERMINAL,
+                        &self.control_permissions,
+                        &self.permission_policy,
+                    ) {
                         self.send_login_error("No permission of terminal").await;
                         sleep(1.).await;
                         return false;
@@ -2304,13 +2787,39 @@ impl Connection {
                     }
                 }
                 Some(login_request::Union::PortForward(mut pf)) => {
-                    if !Self::permission(keys::OPTION_ENABLE_TUNNEL, &self.control_permissions) {
+                    if !Self::permission(
+                        keys::OPTION_ENABLE_TUNNEL,
+                        &self.control_permissions,
+                        &self.permission_policy,
+                    ) {
                         self.send_login_error("No permission of IP tunneling").await;
                         sleep(1.).await;
                         return false;
                     }
-                    let (addr, _is_rdp) = Self::normalize_port_forward_target(&mut pf);
-                    self.port_forward_address = addr;
+                    let kind = PortForwardKind::parse(&pf.host).0;
+                    if kind == PortForwardKind::Reverse
+                        && !Self::permission(
+                            OPTION_ALLOW_REVERSE_TUNNEL,
+                            &self.control_permissions,
+                            &self.permission_policy,
+                        )
+                    {
+                        self.send_login_error("No permission of reverse tunneling")
+                            .await;
+                        sleep(1.).await;
+                        return false;
+                    }
+                    match Self::normalize_port_forward_target(&mut pf) {
+                        Ok((addr, _is_rdp)) => {
+                            self.port_forward_address = kind.with_host(&addr);
+                        }
+                        Err(err) => {
+                            log::warn!("{}", err);
+                            self.send_login_error(err.to_string()).await;
+                            sleep(1.).await;
+                            return false;
+                        }
+                    }
                 }
                 _ => {
                     if !self.check_privacy_mode_on().await {
@@ -2435,34 +2944,38 @@ impl Connection {
             if !res {
                 return true;
             }
-            if let Some(totp) = self.require_2fa.as_ref() {
-                if let Ok(res) = totp.check_current(&tfa.code) {
-                    if res {
-                        self.update_failure(failure, true, 1);
-                        self.require_2fa.take();
-                        raii::AuthedConnID::set_session_2fa(self.session_key());
-                        if !self.send_logon_response_and_keep_alive().await {
-                            return false;
-                        }
-                        self.try_start_cm(
-                            self.lr.my_id.to_owned(),
-                            self.lr.my_name.to_owned(),
-                            self.authorized,
-                        );
-                        if !tfa.hwid.is_empty() && Self::enable_trusted_devices() {
-                            Config::add_trusted_device(TrustedDevice {
-                                hwid: tfa.hwid,
-                                time: hbb_common::get_time(),
-                                id: self.lr.my_id.clone(),
-                                name: self.lr.my_name.clone(),
-                                platform: self.lr.my_platform.clone(),
-                            });
-                        }
-                    } else {
-                        self.update_failure(failure, false, 1);
-                        self.send_login_error(crate::client::LOGIN_MSG_2FA_WRONG)
-                            .await;
+            let verified = self
+                .require_2fa
+                .as_mut()
+                .map(|factor| factor.verify(&tfa.code));
+            // Recovery codes are accepted whatever the provider is.
+            let verified = verified.map(|res| res || self.try_recovery_code(&tfa.code));
+            if let Some(res) = verified {
+                if res {
+                    self.update_failure(failure, true, 1);
+                    self.require_2fa.take();
+                    raii::AuthedConnID::set_session_2fa(self.session_key());
+                    if !self.send_logon_response_and_keep_alive().await {
+                        return false;
+                    }
+                    self.try_start_cm(
+                        self.lr.my_id.to_owned(),
+                        self.lr.my_name.to_owned(),
+                        self.authorized,
+                    );
+                    if !tfa.hwid.is_empty() && Self::enable_trusted_devices() {
+                        Config::add_trusted_device(TrustedDevice {
+                            hwid: tfa.hwid,
+                            time: hbb_common::get_time(),
+                            id: self.lr.my_id.clone(),
+                            name: self.lr.my_name.clone(),
+                            platform: self.lr.my_platform.clone(),
+                        });
                     }
+                } else {
+                    self.update_failure(failure, false, 1);
+                    self.send_login_error(crate::client::LOGIN_MSG_2FA_WRONG)
+                        .await;
                 }
             }
         } else if let Some(message::Union::TestDelay(t)) = msg.union {
@@ -2712,6 +3225,10 @@ impl Connection {
                 }
                 Some(message::Union::Clipboard(cb)) => {
                     if self.clipboard {
+                        self.emit_session_event(
+                            SessionEvent::Clipboard,
+                            json!({ "direction": "receive", "size": cb.content.len() }),
+                        );
                         #[cfg(not(any(target_os = "android", target_os = "ios")))]
                         update_clipboard(vec![cb], ClipboardSide::Host);
                         // ios as the controlled side is actually not supported for now.
@@ -2739,6 +3256,13 @@ impl Connection {
                     }
                 }
                 Some(message::Union::MultiClipboards(_mcb)) => {
+                    if self.clipboard {
+                        let size: usize = _mcb.clipboards.iter().map(|c| c.content.len()).sum();
+                        self.emit_session_event(
+                            SessionEvent::Clipboard,
+                            json!({ "direction": "receive", "size": size }),
+                        );
+                    }
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     if self.clipboard {
                         update_clipboard(_mcb.clipboards, ClipboardSide::Host);
@@ -3217,6 +3741,12 @@ impl Connection {
                     Some(misc::Union::ChangeDisplayResolution(dr)) => {
                         self.change_resolution(Some(dr.display as _), &dr.resolution)
                     }
+                    #[cfg(not(target_os = "ios"))]
+                    Some(misc::Union::PluginRequest(p))
+                        if p.id == crate::lan::WOL_RELAY_REQUEST_ID =>
+                    {
+                        self.handle_wol_relay(&p.content).await;
+                    }
                     #[cfg(all(feature = "flutter", feature = "plugin_framework"))]
                     #[cfg(not(any(target_os = "android", target_os = "ios")))]
                     Some(misc::Union::PluginRequest(p)) => {
@@ -3426,14 +3956,7 @@ impl Connection {
     // note: we specifically don't use hbb_common::is_ipv6_str to avoid divergence issues
     // between its regex and the system std::net::Ipv6Addr implementation.
     fn get_ipv6_prefixes(&self) -> Option<(String, String, String)> {
-        fn mask_u128(addr: u128, prefix: u8) -> u128 {
-            let mask = if prefix == 0 || prefix > 128 {
-                0
-            } else {
-                (!0u128) << (128 - prefix)
-            };
-            addr & mask
-        }
+        use ip_filter::mask_u128;
         // eliminate zone-ids like "fe80::1%eth0"
         let ip_only = self.ip.split('%').next().unwrap_or(&self.ip).trim();
         let ip = Ipv6Addr::from_str(ip_only).ok()?;
@@ -3593,6 +4116,10 @@ impl Connection {
         if self.display_idx != display_idx {
             if let Some(server) = self.server.upgrade() {
                 self.switch_display_to(display_idx, server.clone());
+                video_service::record_chapter(&format!(
+                    "{}: display {}",
+                    self.lr.my_id, display_idx
+                ));
 
                 #[cfg(not(any(target_os = "android", target_os = "ios")))]
                 if s.width != 0 && s.height != 0 {
@@ -3703,6 +4230,14 @@ impl Connection {
                 );
             }
             drop(lock);
+            let title = if !add.is_empty() {
+                format!("add displays {:?}", add)
+            } else if !sub.is_empty() {
+                format!("remove displays {:?}", sub)
+            } else {
+                format!("displays {:?}", set)
+            };
+            video_service::record_chapter(&format!("{}: {}", self.lr.my_id, title));
         }
     }
 
@@ -4227,6 +4762,7 @@ impl Connection {
         // But it's not necessary now and we have to consider two audio services(client, server).
         crate::audio_service::set_voice_call_input_device(None, true);
         log::info!("#{} Connection closed: {}", self.inner.id(), reason);
+        video_service::update_record_peer(self.inner.id(), None);
         if lock && self.lock_after_session_end && self.keyboard {
             #[cfg(not(any(target_os = "android", target_os = "ios")))]
             lock_screen().await;
@@ -4542,6 +5078,19 @@ impl Connection {
         allow_err!(self.stream.send(&msg).await);
     }
 
+    // Reports the throughput to the video qos, for the bandwidth cap.
+    fn update_throughput(&mut self) {
+        let ms = (self.sent_bytes_instant.elapsed().as_millis() as u64).max(1);
+        let kbps = |bytes: u64| (bytes * 8 / ms) as u32;
+        let bytes = std::mem::take(&mut self.sent_bytes);
+        self.sent_bytes_instant = Instant::now();
+        video_service::VIDEO_QOS.lock().unwrap().user_throughput(
+            self.inner.id(),
+            kbps(bytes.video),
+            kbps(bytes.audio + bytes.file),
+        );
+    }
+
     pub fn alive_conns() -> Vec<i32> {
         ALIVE_CONNS.lock().unwrap().clone()
     }
@@ -4798,6 +5347,12 @@ impl Connection {
             Some(self.terminal_persistent),
             user_token.to_terminal_service_token(),
         );
+        if let Some(terminal_action::Union::Open(open)) = &action.union {
+            self.emit_session_event(
+                SessionEvent::Terminal,
+                json!({ "terminal_id": open.terminal_id }),
+            );
+        }
 
         match proxy.handle_action(&action) {
             Ok(Some(response)) => {
@@ -5009,6 +5564,7 @@ pub enum AlarmAuditType {
     // MultipleLoginsAttemptsWithinOneMinute = 4,
     // MultipleLoginsAttemptsWithinOneHour = 5,
     ExceedIPv6PrefixAttempts = 6,
+    RecoveryCodeUsed = 7,
 }
 
 pub enum FileAuditType {
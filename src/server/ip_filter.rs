@@ -0,0 +1,224 @@
+// IP filter of the incoming connections.
+//
+// The `whitelist` option is a comma separated list of entries:
+//
+//     1.2.3.4, 10.0.0.0/8, 2001:db8::/32    allowed
+//     !1.2.3.4, !fe80::/10                  denied
+//     @office, !@blocked                    the named lists of `ip-lists`
+//
+// `ip-lists` is a JSON object of names to entries, e.g. `{"office": ["10.0.0.0/8"]}`.
+// Deny rules win, and all the IPs which are not denied are allowed if there is no allow
+// rule. Allow rules which are invalid or name an unknown list still count as rules, so
+// that a typo does not open the filter. The parsed filter is kept until the options change.
+use hbb_common::{config::Config, lazy_static, log};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+pub const OPTION_WHITELIST: &str = "whitelist";
+pub const OPTION_IP_LISTS: &str = "ip-lists";
+
+lazy_static::lazy_static! {
+    static ref CURRENT: Mutex<Option<(String, String, Arc<IpFilter>)>> = Default::default();
+}
+
+pub fn mask_u128(addr: u128, prefix: u8) -> u128 {
+    let mask = if prefix == 0 || prefix > 128 {
+        0
+    } else {
+        (!0u128) << (128 - prefix)
+    };
+    addr & mask
+}
+
+// IPv4 addresses are IPv4-mapped, so that they match the same as when they come in IPv6.
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from_be_bytes(v4.to_ipv6_mapped().octets()),
+        IpAddr::V6(v6) => u128::from_be_bytes(v6.octets()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Prefix {
+    addr: u128,
+    len: u8,
+}
+
+impl Prefix {
+    fn parse(s: &str) -> Option<Self> {
+        // "0.0.0.0" has always meant any address.
+        if s == "0.0.0.0" {
+            return Some(Self { addr: 0, len: 0 });
+        }
+        let (ip, len) = match s.split_once('/') {
+            Some((ip, len)) => (ip, Some(len.parse::<u8>().ok()?)),
+            None => (s, None),
+        };
+        // eliminate zone-ids like "fe80::1%eth0"
+        let ip: IpAddr = ip.split('%').next()?.parse().ok()?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let len = len.unwrap_or(max);
+        if len > max {
+            return None;
+        }
+        let len = if ip.is_ipv4() { 96 + len } else { len };
+        Some(Self {
+            addr: mask_u128(to_u128(ip), len),
+            len,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        mask_u128(to_u128(ip), self.len) == self.addr
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    Allowed,
+    /// By the entry
+    Denied(String),
+    NotAllowed,
+}
+
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    allow: Vec<Prefix>,
+    deny: Vec<(String, Prefix)>,
+    has_allow_rules: bool,
+}
+
+impl IpFilter {
+    pub fn parse(whitelist: &str, lists: &str) -> Self {
+        let lists: HashMap<String, Vec<String>> = if lists.is_empty() {
+            Default::default()
+        } else {
+            serde_json::from_str(lists).unwrap_or_else(|e| {
+                log::error!("Invalid {}: {}", OPTION_IP_LISTS, e);
+                Default::default()
+            })
+        };
+        let mut filter = Self::default();
+        for entry in whitelist
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+        {
+            let (deny, target) = match entry.strip_prefix('!') {
+                Some(x) => (true, x.trim()),
+                None => (false, entry),
+            };
+            if !deny {
+                filter.has_allow_rules = true;
+            }
+            let prefixes: Vec<_> = match target.strip_prefix('@') {
+                Some(name) => match lists.get(name) {
+                    Some(list) => list
+                        .iter()
+                        .map(|x| (x.as_str(), Prefix::parse(x)))
+                        .collect(),
+                    None => {
+                        log::warn!("Unknown ip list: {}", name);
+                        continue;
+                    }
+                },
+                None => vec![(target, Prefix::parse(target))],
+            };
+            for (s, prefix) in prefixes {
+                let Some(prefix) = prefix else {
+                    log::warn!("Invalid ip in whitelist: {}", s);
+                    continue;
+                };
+                if deny {
+                    filter.deny.push((entry.to_owned(), prefix));
+                } else {
+                    filter.allow.push(prefix);
+                }
+            }
+        }
+        filter
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Access {
+        if let Some((entry, _)) = self.deny.iter().find(|(_, p)| p.contains(ip)) {
+            return Access::Denied(entry.clone());
+        }
+        if !self.has_allow_rules || self.allow.iter().any(|p| p.contains(ip)) {
+            Access::Allowed
+        } else {
+            Access::NotAllowed
+        }
+    }
+}
+
+/// The filter of the current options.
+pub fn current() -> Arc<IpFilter> {
+    let whitelist = Config::get_option(OPTION_WHITELIST);
+    let lists = Config::get_option(OPTION_IP_LISTS);
+    let mut lock = CURRENT.lock().unwrap();
+    if let Some((w, l, filter)) = lock.as_ref() {
+        if *w == whitelist && *l == lists {
+            return filter.clone();
+        }
+    }
+    let filter = Arc::new(IpFilter::parse(&whitelist, &lists));
+    *lock = Some((whitelist, lists, filter.clone()));
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_filter() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let filter = IpFilter::parse(
+            "10.0.0.0/8, !10.1.0.0/16, 2001:db8::/32, !2001:db8:0:1::/64, @office, !@blocked, bad",
+            r#"{"office": ["192.168.1.0/24", "fe80::1%eth0"], "blocked": ["192.168.1.7"]}"#,
+        );
+        assert_eq!(filter.check(ip("10.2.3.4")), Access::Allowed);
+        assert_eq!(filter.check(ip("::ffff:10.2.3.4")), Access::Allowed);
+        assert_eq!(
+            filter.check(ip("10.1.3.4")),
+            Access::Denied("!10.1.0.0/16".to_owned())
+        );
+        assert_eq!(filter.check(ip("2001:db8:0:2::1")), Access::Allowed);
+        assert_eq!(
+            filter.check(ip("2001:db8:0:1::1")),
+            Access::Denied("!2001:db8:0:1::/64".to_owned())
+        );
+        assert_eq!(filter.check(ip("2001:db9::1")), Access::NotAllowed);
+        assert_eq!(filter.check(ip("192.168.1.8")), Access::Allowed);
+        assert_eq!(filter.check(ip("fe80::1")), Access::Allowed);
+        assert_eq!(
+            filter.check(ip("192.168.1.7")),
+            Access::Denied("!@blocked".to_owned())
+        );
+        assert_eq!(filter.check(ip("8.8.8.8")), Access::NotAllowed);
+
+        let filter = IpFilter::parse("!8.8.8.8", "");
+        assert_eq!(filter.check(ip("8.8.4.4")), Access::Allowed);
+        assert!(matches!(filter.check(ip("8.8.8.8")), Access::Denied(_)));
+        let filter = IpFilter::parse("1.2.3.4,0.0.0.0", "");
+        assert_eq!(filter.check(ip("2001:db9::1")), Access::Allowed);
+        assert_eq!(
+            IpFilter::parse(",", "").check(ip("1.1.1.1")),
+            Access::Allowed
+        );
+        assert_eq!(
+            IpFilter::parse("1.2.3.4/33", "").check(ip("1.1.1.1")),
+            Access::NotAllowed
+        );
+        assert_eq!(
+            IpFilter::parse("@unknown, !8.8.8.8", "").check(ip("1.1.1.1")),
+            Access::NotAllowed
+        );
+        assert!(Prefix::parse("1.2.3.4/33").is_none());
+        assert!(Prefix::parse("::/129").is_none());
+        assert_eq!(Prefix::parse("1.2.3.4/0").map(|p| p.len), Some(96));
+    }
+}
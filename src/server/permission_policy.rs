@@ -0,0 +1,515 @@
+// Per-peer and per-group permission policies of the controlled side.
+//
+// The policy is a TOML file of ordered rules, each one matching on peer IDs, tags,
+// IP / CIDR and a time window, and allowing or denying permissions. For a permission,
+// the first matching rule which mentions it decides, and the options apply if none does.
+//
+// The tags of a peer are its tags in the address book of the logged in account. The
+// optional `[tags]` table adds local ones, for devices without an account.
+// Peer IDs are claimed by the peers themselves, so rules on peer IDs or tags may only
+// deny, a peer could claim the ID of another one to be allowed more.
+//
+//     [tags]
+//     contractors = ["123456789", "987654321"]
+//
+//     [[rules]]
+//     tags = ["contractors"]
+//     deny = ["file", "terminal"]
+//
+//     [[rules]]
+//     ips = ["10.0.0.0/8"]
+//     days = ["mon", "tue", "wed", "thu", "fri"]
+//     hours = "09:00-18:00"
+//     allow = ["file"]
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use cidr_utils::cidr::IpCidr;
+use hbb_common::{
+    bail,
+    config::{keys, Ab, Config},
+    log, toml, ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, net::IpAddr, path::Path, str::FromStr};
+
+const POLICY_FILE: &str = "permission_policy.toml";
+
+/// Permission names of the policy, with the options they are in place of.
+pub const PERMISSIONS: &[(&str, &str)] = &[
+    ("keyboard", keys::OPTION_ENABLE_KEYBOARD),
+    ("remote_printer", keys::OPTION_ENABLE_REMOTE_PRINTER),
+    ("clipboard", keys::OPTION_ENABLE_CLIPBOARD),
+    ("file", keys::OPTION_ENABLE_FILE_TRANSFER),
+    ("audio", keys::OPTION_ENABLE_AUDIO),
+    ("camera", keys::OPTION_ENABLE_CAMERA),
+    ("terminal", keys::OPTION_ENABLE_TERMINAL),
+    ("tunnel", keys::OPTION_ENABLE_TUNNEL),
+    ("restart", keys::OPTION_ENABLE_REMOTE_RESTART),
+    ("recording", keys::OPTION_ENABLE_RECORD_SESSION),
+    ("block_input", keys::OPTION_ENABLE_BLOCK_INPUT),
+];
+
+fn permission_name(enable_prefix_option: &str) -> Option<&'static str> {
+    PERMISSIONS
+        .iter()
+        .find(|(_, option)| *option == enable_prefix_option)
+        .map(|(name, _)| *name)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rule {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub peers: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// IPs or CIDRs
+    #[serde(default)]
+    pub ips: Vec<String>,
+    /// "mon" to "sun"
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// "HH:MM-HH:MM" in local time, it may span midnight.
+    #[serde(default)]
+    pub hours: String,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Policy {
+    /// Tag to peer IDs
+    #[serde(default)]
+    pub tags: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// Who asks for the permissions and when.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub peer_id: String,
+    /// Address book tags of the peer
+    pub tags: Vec<String>,
+    pub ip: Option<IpAddr>,
+    pub time: NaiveDateTime,
+}
+
+/// Outcome of a permission, `rule` is the index of the rule which decided it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Decision {
+    pub permission: &'static str,
+    pub allowed: Option<bool>,
+    pub rule: Option<usize>,
+}
+
+fn parse_hours(hours: &str) -> ResultType<(NaiveTime, NaiveTime)> {
+    let Some((start, end)) = hours.split_once('-') else {
+        bail!("Invalid hours: {}", hours);
+    };
+    Ok((
+        NaiveTime::parse_from_str(start.trim(), "%H:%M")?,
+        NaiveTime::parse_from_str(end.trim(), "%H:%M")?,
+    ))
+}
+
+fn parse_day(day: &str) -> ResultType<Weekday> {
+    match Weekday::from_str(day) {
+        Ok(day) => Ok(day),
+        Err(_) => bail!("Invalid day: {}", day),
+    }
+}
+
+impl Rule {
+    fn validate(&self) -> ResultType<()> {
+        if !self.allow.is_empty() && (!self.peers.is_empty() || !self.tags.is_empty()) {
+            bail!("Rules on peer IDs or tags may only deny");
+        }
+        for p in self.allow.iter().chain(self.deny.iter()) {
+            if !PERMISSIONS.iter().any(|(name, _)| name == p) {
+                bail!("Unknown permission: {}", p);
+            }
+        }
+        for ip in self.ips.iter() {
+            if IpCidr::from_str(ip).is_err() {
+                bail!("Invalid ip: {}", ip);
+            }
+        }
+        for day in self.days.iter() {
+            parse_day(day)?;
+        }
+        if !self.hours.is_empty() {
+            parse_hours(&self.hours)?;
+        }
+        Ok(())
+    }
+
+    fn matches(&self, policy: &Policy, req: &Request) -> bool {
+        if !self.peers.is_empty() && !self.peers.contains(&req.peer_id) {
+            return false;
+        }
+        if !self.tags.is_empty()
+            && !self.tags.iter().any(|tag| {
+                req.tags.contains(tag)
+                    || policy
+                        .tags
+                        .get(tag)
+                        .is_some_and(|peers| peers.contains(&req.peer_id))
+            })
+        {
+            return false;
+        }
+        if !self.ips.is_empty() {
+            let Some(ip) = req.ip else {
+                return false;
+            };
+            // as on a dual stack listener, to match the IPv4 rules
+            let ip = match ip {
+                IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+                ip => ip,
+            };
+            if !self
+                .ips
+                .iter()
+                .any(|x| IpCidr::from_str(x).is_ok_and(|cidr| cidr.contains(ip)))
+            {
+                return false;
+            }
+        }
+        if !self.days.is_empty()
+            && !self
+                .days
+                .iter()
+                .any(|day| parse_day(day).is_ok_and(|day| day == req.time.weekday()))
+        {
+            return false;
+        }
+        if !self.hours.is_empty() {
+            let Ok((start, end)) = parse_hours(&self.hours) else {
+                return false;
+            };
+            let now = req.time.time();
+            let within = if start <= end {
+                start <= now && now < end
+            } else {
+                now >= start || now < end
+            };
+            if !within {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn decide(&self, permission: &str) -> Option<bool> {
+        if self.deny.iter().any(|x| x == permission) {
+            Some(false)
+        } else if self.allow.iter().any(|x| x == permission) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+impl Policy {
+    pub fn parse(data: &str) -> ResultType<Self> {
+        let policy: Self = toml::from_str(data)?;
+        for (i, rule) in policy.rules.iter().enumerate() {
+            if let Err(e) = rule.validate() {
+                bail!("Rule {} {}: {}", i, rule.name, e);
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn load(path: &Path) -> ResultType<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self::parse(&std::fs::read_to_string(path)?)?))
+    }
+
+    fn decide(&self, permission: &'static str, req: &Request) -> Decision {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if let Some(allowed) = rule.decide(permission) {
+                if rule.matches(self, req) {
+                    return Decision {
+                        permission,
+                        allowed: Some(allowed),
+                        rule: Some(i),
+                    };
+                }
+            }
+        }
+        Decision {
+            permission,
+            allowed: None,
+            rule: None,
+        }
+    }
+
+    /// Dry run of all the permissions for `req`.
+    pub fn evaluate(&self, req: &Request) -> Vec<Decision> {
+        PERMISSIONS
+            .iter()
+            .map(|(name, _)| self.decide(name, req))
+            .collect()
+    }
+}
+
+/// Tags of `peer_id` in the cached address books of the logged in account.
+fn address_book_tags(peer_id: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for entry in Ab::load().ab_entries.iter() {
+        for peer in entry.peers.iter().filter(|p| p.id == peer_id) {
+            for tag in peer.tags.iter() {
+                if !tags.contains(tag) {
+                    tags.push(tag.clone());
+                }
+            }
+        }
+    }
+    tags
+}
+
+/// Dry run of the policy file at `path`, one line per permission.
+/// `time` is "YYYY-MM-DD HH:MM" in local time, now if empty.
+pub fn dry_run(path: &Path, peer_id: &str, ip: &str, time: &str) -> ResultType<String> {
+    let Some(policy) = Policy::load(path)? else {
+        bail!("{} not found", path.display());
+    };
+    let time = if time.is_empty() {
+        chrono::Local::now().naive_local()
+    } else {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M")?
+    };
+    let req = Request {
+        peer_id: peer_id.to_owned(),
+        tags: address_book_tags(peer_id),
+        ip: ip.parse().ok(),
+        time,
+    };
+    let mut out = String::new();
+    for d in policy.evaluate(&req) {
+        let outcome = match (d.allowed, d.rule) {
+            (Some(allowed), Some(i)) => format!(
+                "{} by rule {} {}",
+                if allowed { "allowed" } else { "denied" },
+                i,
+                policy.rules[i].name
+            ),
+            _ => "by the options".to_owned(),
+        };
+        out.push_str(&format!("{}: {}\n", d.permission, outcome.trim_end()));
+    }
+    Ok(out)
+}
+
+/// The policy of a logged in peer.
+#[derive(Debug, Clone)]
+pub struct PeerPolicy {
+    policy: Policy,
+    peer_id: String,
+    tags: Vec<String>,
+    ip: Option<IpAddr>,
+}
+
+impl PeerPolicy {
+    /// `None` if there is no policy file. An invalid one denies all, rather than
+    /// falling back to the options silently.
+    pub fn load(peer_id: &str, ip: &str) -> Option<Self> {
+        let policy = match Policy::load(&Config::path(POLICY_FILE)) {
+            Ok(policy) => policy?,
+            Err(e) => {
+                log::error!("Invalid permission policy: {}", e);
+                Policy {
+                    rules: vec![Rule {
+                        deny: PERMISSIONS
+                            .iter()
+                            .map(|(name, _)| name.to_string())
+                            .collect(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }
+            }
+        };
+        Some(Self {
+            policy,
+            ..Self::new(peer_id, ip)
+        })
+    }
+
+    pub fn new(peer_id: &str, ip: &str) -> Self {
+        Self {
+            policy: Default::default(),
+            peer_id: peer_id.to_owned(),
+            tags: address_book_tags(peer_id),
+            ip: ip.parse().ok(),
+        }
+    }
+
+    /// Denies the permissions which are not in `allowed`, whatever the rules say.
+    pub fn restrict(&mut self, allowed: &[String]) {
+        let deny = PERMISSIONS
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .filter(|name| !allowed.contains(name))
+            .collect();
+        self.policy.rules.insert(
+            0,
+            Rule {
+                name: "restriction".to_owned(),
+                deny,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Whether the policy allows the permission of `enable_prefix_option` now,
+    /// `None` if it does not decide.
+    pub fn allowed(&self, enable_prefix_option: &str) -> Option<bool> {
+        let permission = permission_name(enable_prefix_option)?;
+        let req = Request {
+            peer_id: self.peer_id.clone(),
+            tags: self.tags.clone(),
+            ip: self.ip,
+            time: chrono::Local::now().naive_local(),
+        };
+        let decision = self.policy.decide(permission, &req);
+        if let (Some(allowed), Some(rule)) = (decision.allowed, decision.rule) {
+            log::debug!(
+                "Permission {} of {} {} by policy rule {}",
+                permission,
+                self.peer_id,
+                if allowed { "allowed" } else { "denied" },
+                rule
+            );
+        }
+        decision.allowed
+    }
+
+    /// Whether the policy allows the permission named `name` in the connection manager.
+    pub fn allowed_by_name(&self, name: &str) -> Option<bool> {
+        let (_, option) = PERMISSIONS.iter().find(|(x, _)| *x == name)?;
+        self.allowed(option)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let policy = Policy::parse(
+            r#"
+            [tags]
+            helpdesk = ["111", "222"]
+
+            [[rules]]
+            name = "blocked"
+            ips = ["10.1.0.0/16"]
+            deny = ["keyboard", "file"]
+
+            [[rules]]
+            name = "helpdesk in business hours"
+            tags = ["helpdesk", "support"]
+            days = ["mon", "tue", "wed", "thu", "fri"]
+            hours = "09:00-18:00"
+            deny = ["file"]
+
+            [[rules]]
+            peers = ["333"]
+            hours = "22:00-06:00"
+            deny = ["terminal"]
+
+            [[rules]]
+            ips = ["1.2.0.0/16"]
+            allow = ["file", "terminal"]
+            "#,
+        )
+        .unwrap();
+        let decide_tagged = |peer_id: &str, tags: &[&str], ip: &str, time: &str, permission| {
+            policy
+                .evaluate(&Request {
+                    peer_id: peer_id.to_owned(),
+                    tags: tags.iter().map(|x| x.to_string()).collect(),
+                    ip: ip.parse().ok(),
+                    time: NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap(),
+                })
+                .into_iter()
+                .find(|x| x.permission == permission)
+                .map(|x| (x.allowed, x.rule))
+                .unwrap()
+        };
+        let decide = |peer_id: &str, ip: &str, time: &str, permission| {
+            decide_tagged(peer_id, &[], ip, time, permission)
+        };
+        // 2024-01-01 is a Monday
+        let monday = "2024-01-01 10:00";
+        assert_eq!(
+            decide("111", "1.2.3.4", monday, "file"),
+            (Some(false), Some(1))
+        );
+        assert_eq!(
+            decide("111", "1.2.3.4", "2024-01-01 18:00", "file"),
+            (Some(true), Some(3))
+        );
+        assert_eq!(
+            decide("222", "1.2.3.4", "2024-01-06 10:00", "file"),
+            (Some(true), Some(3))
+        );
+        assert_eq!(
+            decide("444", "1.2.3.4", monday, "file"),
+            (Some(true), Some(3))
+        );
+        // address book tags
+        assert_eq!(
+            decide_tagged("444", &["support"], "1.2.3.4", monday, "file"),
+            (Some(false), Some(1))
+        );
+        assert_eq!(
+            decide_tagged("444", &["sales"], "1.2.3.4", monday, "file"),
+            (Some(true), Some(3))
+        );
+        assert_eq!(
+            decide("111", "10.1.2.3", monday, "file"),
+            (Some(false), Some(0))
+        );
+        assert_eq!(
+            decide("111", "10.1.2.3", monday, "keyboard"),
+            (Some(false), Some(0))
+        );
+        assert_eq!(
+            decide("111", "::ffff:10.1.2.3", monday, "keyboard"),
+            (Some(false), Some(0))
+        );
+        assert_eq!(decide("111", "1.2.3.4", monday, "keyboard"), (None, None));
+        assert_eq!(
+            decide("333", "1.2.3.4", "2024-01-01 23:30", "terminal"),
+            (Some(false), Some(2))
+        );
+        assert_eq!(
+            decide("333", "1.2.3.4", "2024-01-01 05:59", "terminal"),
+            (Some(false), Some(2))
+        );
+        assert_eq!(
+            decide("333", "1.2.3.4", monday, "terminal"),
+            (Some(true), Some(3))
+        );
+        assert_eq!(decide("333", "", monday, "terminal"), (None, None));
+
+        assert!(Policy::parse("[[rules]]\nallow = [\"everything\"]").is_err());
+        assert!(Policy::parse("[[rules]]\nhours = \"9-18\"").is_err());
+        assert!(Policy::parse("[[rules]]\nips = [\"10.0.0.0/33\"]").is_err());
+        assert!(Policy::parse("[[rules]]\ndays = [\"someday\"]").is_err());
+        // peer IDs are claimed
+        assert!(Policy::parse("[[rules]]\npeers = [\"111\"]\nallow = [\"file\"]").is_err());
+        assert!(Policy::parse("[[rules]]\ntags = [\"helpdesk\"]\nallow = [\"file\"]").is_err());
+        assert!(Policy::parse("[[rules]]\ntags = [\"helpdesk\"]\ndeny = [\"file\"]").is_ok());
+    }
+}
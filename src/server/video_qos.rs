@@ -5,6 +5,9 @@ use std::{
     time::{Duration, Instant},
 };
 
+#[cfg(any(test, feature = "qos_simulator"))]
+pub mod simulator;
+
 /*
 FPS adjust:
 a. new user connected =>set to INIT_FPS
@@ -25,6 +28,11 @@ adjust between FPS and ratio:
 
 delay:
     use delay minus RTT as the actual network delay
+
+bandwidth cap:
+    The video budget of a connection is the cap of it, or its share of the total cap, minus its audio
+    and file transfer throughput. The ratio is limited to the bitrate of the lowest budget, and fps is
+    decreased while the video throughput of a connection is over its budget.
 */
 
 // Constants
@@ -43,6 +51,12 @@ const HISTORY_DELAY_LEN: usize = 2;
 const ADJUST_RATIO_INTERVAL: usize = 3; // Adjust quality ratio every 3 seconds
 const DYNAMIC_SCREEN_THRESHOLD: usize = 2; // Allow increase quality ratio if encode more than 2 times in one second
 const DELAY_THRESHOLD_150MS: u32 = 150; // 150ms is the threshold for good network condition
+const MIN_VIDEO_KBPS: u32 = 32; // Keep the video going even if audio and file transfer take the whole budget
+
+/// Kbps of all the connections, no limit if empty or 0
+pub const OPTION_BANDWIDTH_CAP: &str = "bandwidth-cap";
+/// Kbps of each connection, no limit if empty or 0
+pub const OPTION_CONN_BANDWIDTH_CAP: &str = "conn-bandwidth-cap";
 
 #[derive(Default, Debug, Clone)]
 struct UserDelay {
@@ -93,6 +107,36 @@ struct UserData {
     quality: Option<(i64, Quality)>, // (time, quality)
     delay: UserDelay,
     record: bool,
+    throughput: Throughput,
+}
+
+// Throughput of the last second
+#[derive(Default, Debug, Clone, Copy)]
+struct Throughput {
+    video_kbps: u32,
+    other_kbps: u32, // audio and file transfer
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct BandwidthCap {
+    total_kbps: Option<u32>,
+    conn_kbps: Option<u32>,
+}
+
+impl BandwidthCap {
+    fn load() -> Self {
+        let kbps = |option: &str| {
+            Config::get_option(option)
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|v| *v > 0)
+        };
+        Self {
+            total_kbps: kbps(OPTION_BANDWIDTH_CAP),
+            conn_kbps: kbps(OPTION_CONN_BANDWIDTH_CAP),
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -108,9 +152,13 @@ pub struct VideoQoS {
     users: HashMap<i32, UserData>,
     displays: HashMap<String, DisplayData>,
     bitrate_store: u32,
+    bitrate_ratio: f32, // Ratio of the stored bitrate
     adjust_ratio_instant: Instant,
     abr_config: bool,
     new_user_instant: Instant,
+    bandwidth_cap: BandwidthCap,
+    bandwidth_fps: Option<u32>,
+    virtual_now: Option<Instant>, // Set by the simulator
 }
 
 impl Default for VideoQoS {
@@ -121,15 +169,27 @@ impl Default for VideoQoS {
             users: Default::default(),
             displays: Default::default(),
             bitrate_store: 0,
+            bitrate_ratio: 0.0,
             adjust_ratio_instant: Instant::now(),
             abr_config: true,
             new_user_instant: Instant::now(),
+            bandwidth_cap: Default::default(),
+            bandwidth_fps: None,
+            virtual_now: None,
         }
     }
 }
 
 // Basic functionality
 impl VideoQoS {
+    fn now(&self) -> Instant {
+        self.virtual_now.unwrap_or_else(Instant::now)
+    }
+
+    fn elapsed(&self, instant: Instant) -> Duration {
+        self.now().saturating_duration_since(instant)
+    }
+
     // Calculate seconds per frame based on current FPS
     pub fn spf(&self) -> Duration {
         Duration::from_secs_f32(1. / (self.fps() as f32))
@@ -148,6 +208,8 @@ impl VideoQoS {
     // Store bitrate for later use
     pub fn store_bitrate(&mut self, bitrate: u32) {
         self.bitrate_store = bitrate;
+        self.bitrate_ratio = self.ratio;
+        self.ratio = self.capped_ratio(self.ratio);
     }
 
     // Get stored bitrate
@@ -186,14 +248,17 @@ impl VideoQoS {
     pub fn on_connection_open(&mut self, id: i32) {
         self.users.insert(id, UserData::default());
         self.abr_config = Config::get_option("enable-abr") != "N";
-        self.new_user_instant = Instant::now();
+        self.bandwidth_cap = BandwidthCap::load();
+        self.new_user_instant = self.now();
     }
 
     // Clean up user session
     pub fn on_connection_close(&mut self, id: i32) {
         self.users.remove(&id);
         if self.users.is_empty() {
+            let virtual_now = self.virtual_now;
             *self = Default::default();
+            self.virtual_now = virtual_now;
         }
     }
 
@@ -233,7 +298,7 @@ impl VideoQoS {
         if let Some(user) = self.users.get_mut(&id) {
             user.quality = quality;
             // update ratio directly
-            self.ratio = self.latest_quality().ratio();
+            self.ratio = self.capped_ratio(self.latest_quality().ratio());
         }
     }
 
@@ -333,6 +398,19 @@ impl VideoQoS {
         }
     }
 
+    // Kbps sent to the user in the last second
+    pub fn user_throughput(&mut self, id: i32, video_kbps: u32, other_kbps: u32) {
+        if let Some(user) = self.users.get_mut(&id) {
+            user.throughput = Throughput {
+                video_kbps,
+                other_kbps,
+            };
+            self.ratio = self.capped_ratio(self.ratio);
+            self.adjust_bandwidth_fps();
+            self.adjust_fps();
+        }
+    }
+
     pub fn user_delay_response_elapsed(&mut self, id: i32, elapsed: u128) {
         if let Some(user) = self.users.get_mut(&id) {
             user.delay.response_delayed = elapsed > 2000;
@@ -362,7 +440,7 @@ impl VideoQoS {
         self.adjust_fps();
         let abr_enabled = self.in_vbr_state();
         if abr_enabled {
-            if self.adjust_ratio_instant.elapsed().as_secs() >= ADJUST_RATIO_INTERVAL as u64 {
+            if self.elapsed(self.adjust_ratio_instant).as_secs() >= ADJUST_RATIO_INTERVAL as u64 {
                 let dynamic_screen = self
                     .displays
                     .iter()
@@ -373,7 +451,11 @@ impl VideoQoS {
                 self.adjust_ratio(dynamic_screen);
             }
         } else {
-            self.ratio = self.latest_quality().ratio();
+            let ratio = self.capped_ratio(self.latest_quality().ratio());
+            // Avoid switching the encoder for the small changes of the budget
+            if ratio < self.ratio || ratio > self.ratio * 1.1 {
+                self.ratio = ratio;
+            }
         }
     }
 
@@ -503,8 +585,65 @@ impl VideoQoS {
             }
         }
 
-        self.ratio = v.clamp(min, max);
-        self.adjust_ratio_instant = Instant::now();
+        self.ratio = self.capped_ratio(v.clamp(min, max));
+        self.adjust_ratio_instant = self.now();
+    }
+
+    // Video kbps of the connection with the lowest budget
+    fn video_budget(&self) -> Option<u32> {
+        if self.users.is_empty() {
+            return None;
+        }
+        let total = self.bandwidth_cap.total_kbps.map(|cap| {
+            let others: u32 = self.users.values().map(|u| u.throughput.other_kbps).sum();
+            cap.saturating_sub(others) / self.users.len() as u32
+        });
+        let conn = self.bandwidth_cap.conn_kbps.and_then(|cap| {
+            self.users
+                .values()
+                .map(|u| cap.saturating_sub(u.throughput.other_kbps))
+                .min()
+        });
+        [total, conn]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|budget| budget.max(MIN_VIDEO_KBPS))
+    }
+
+    // Limit ratio to the video budget, bitrate is proportional to ratio
+    fn capped_ratio(&self, ratio: f32) -> f32 {
+        let Some(budget) = self.video_budget() else {
+            return ratio;
+        };
+        if self.bitrate_store == 0 || self.bitrate_ratio <= 0.0 {
+            return ratio;
+        }
+        let max = self.bitrate_ratio * budget as f32 / self.bitrate_store as f32;
+        ratio.min(max).max(BR_MIN_HIGH_RESOLUTION)
+    }
+
+    // Decrease fps while the video throughput is over the budget, e.g. on the lowest ratio
+    fn adjust_bandwidth_fps(&mut self) {
+        let Some(budget) = self.video_budget() else {
+            self.bandwidth_fps = None;
+            return;
+        };
+        let video_kbps = self
+            .users
+            .values()
+            .map(|u| u.throughput.video_kbps)
+            .max()
+            .unwrap_or(0);
+        let fps = if video_kbps > budget {
+            // The frames are about the same size at a lower fps
+            (self.fps as u64 * budget as u64 / video_kbps as u64) as u32
+        } else if video_kbps < budget * 4 / 5 {
+            self.bandwidth_fps.unwrap_or(MAX_FPS) + 1
+        } else {
+            self.bandwidth_fps.unwrap_or(MAX_FPS)
+        };
+        self.bandwidth_fps = Some(fps.clamp(MIN_FPS, MAX_FPS));
     }
 
     // Adjust fps based on network delay and user response time
@@ -525,12 +664,17 @@ impl VideoQoS {
         }
 
         // For new connections (within 1 second), cap fps to INIT_FPS to ensure stability
-        if self.new_user_instant.elapsed().as_secs() < 1 {
+        if self.elapsed(self.new_user_instant).as_secs() < 1 {
             if fps > INIT_FPS {
                 fps = INIT_FPS;
             }
         }
 
+        // The bandwidth cap is a hard limit
+        if let Some(bandwidth_fps) = self.bandwidth_fps {
+            fps = fps.min(bandwidth_fps);
+        }
+
         // Ensure fps stays within valid range
         self.fps = fps.clamp(MIN_FPS, highest_fps);
     }
@@ -593,3 +737,61 @@ impl RttCalculator {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_qos(bandwidth_cap: BandwidthCap, users: &[i32]) -> VideoQoS {
+        let mut qos = VideoQoS {
+            bandwidth_cap,
+            ..Default::default()
+        };
+        for id in users {
+            qos.users.insert(*id, UserData::default());
+        }
+        qos
+    }
+
+    #[test]
+    fn test_bandwidth_cap() {
+        let cap = BandwidthCap {
+            total_kbps: Some(1000),
+            conn_kbps: None,
+        };
+        let mut qos = new_qos(cap, &[1]);
+        qos.new_user_instant = Instant::now() - Duration::from_secs(2);
+        qos.store_bitrate(2000);
+        qos.user_throughput(1, 0, 200);
+        assert_eq!(qos.video_budget(), Some(800));
+        qos.user_image_quality(1, ImageQuality::Best.value());
+        assert!((qos.ratio() - BR_BALANCED * 0.4).abs() < 0.001);
+
+        // A good network, with 30 kb frames which do not fit in the budget at a high fps
+        for _ in 0..20 {
+            qos.user_network_delay(1, 20);
+            qos.user_throughput(1, 30 * qos.fps(), 200);
+        }
+        assert_eq!(qos.fps(), 800 / 30);
+        // The file transfer is done
+        for _ in 0..5 {
+            qos.user_network_delay(1, 20);
+            qos.user_throughput(1, 30 * qos.fps(), 0);
+        }
+        assert!(qos.fps() > 800 / 30 && 30 * qos.fps() <= 1000);
+
+        let cap = BandwidthCap {
+            total_kbps: Some(3000),
+            conn_kbps: Some(500),
+        };
+        let mut qos = new_qos(cap, &[1, 2]);
+        qos.user_throughput(1, 0, 100);
+        qos.user_throughput(2, 0, 300);
+        assert_eq!(qos.video_budget(), Some(200));
+        qos.user_throughput(2, 0, 1000);
+        assert_eq!(qos.video_budget(), Some(MIN_VIDEO_KBPS));
+        // no bitrate yet
+        assert_eq!(qos.capped_ratio(BR_BEST), BR_BEST);
+        assert_eq!(new_qos(Default::default(), &[1]).video_budget(), None);
+    }
+}
@@ -0,0 +1,413 @@
+// Deterministic simulator of `VideoQoS`, to evaluate the changes of it offline.
+//
+// A network trace is replayed with a virtual clock, through a link which queues what is sent
+// beyond its throughput. The trace is CSV lines of
+//
+//     time_ms,delay_ms,loss,throughput_kbps[,other_kbps]
+//
+// each one holds until the next, and the last one marks the end. `delay_ms` is the round trip
+// time of the idle link, `loss` the ratio of the lost packets, which are retransmitted, and
+// `other_kbps` the audio and file transfer. Lines starting with `#` are comments.
+use super::*;
+use hbb_common::{bail, ResultType};
+
+const STEP_MS: u64 = 100;
+const TEST_DELAY_INTERVAL_MS: u64 = 1000;
+const RETRANSMISSION_DELAY_MS: u32 = 200;
+const USER_ID: i32 = 1;
+const DISPLAY_NAME: &str = "simulator";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TracePoint {
+    pub time_ms: u64,
+    pub delay_ms: u32,
+    pub loss: f32,
+    pub throughput_kbps: u32,
+    pub other_kbps: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace(Vec<TracePoint>);
+
+impl Trace {
+    pub fn parse(s: &str) -> ResultType<Self> {
+        let mut points: Vec<TracePoint> = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|x| x.trim()).collect();
+            let point = match fields[..] {
+                [time_ms, delay_ms, loss, throughput_kbps] => {
+                    Self::parse_point(time_ms, delay_ms, loss, throughput_kbps, "0")
+                }
+                [time_ms, delay_ms, loss, throughput_kbps, other_kbps] => {
+                    Self::parse_point(time_ms, delay_ms, loss, throughput_kbps, other_kbps)
+                }
+                _ => None,
+            };
+            let Some(point) = point else {
+                bail!("Invalid trace line {}: {}", i + 1, line);
+            };
+            if points.last().is_some_and(|p| p.time_ms >= point.time_ms) {
+                bail!("Trace time is not increasing at line {}", i + 1);
+            }
+            points.push(point);
+        }
+        if points.is_empty() {
+            bail!("Empty trace");
+        }
+        Ok(Self(points))
+    }
+
+    fn parse_point(
+        time_ms: &str,
+        delay_ms: &str,
+        loss: &str,
+        throughput_kbps: &str,
+        other_kbps: &str,
+    ) -> Option<TracePoint> {
+        let point = TracePoint {
+            time_ms: time_ms.parse().ok()?,
+            delay_ms: delay_ms.parse().ok()?,
+            loss: loss.parse().ok()?,
+            throughput_kbps: throughput_kbps.parse().ok()?,
+            other_kbps: other_kbps.parse().ok()?,
+        };
+        if !(0.0..1.0).contains(&point.loss) || point.throughput_kbps == 0 {
+            return None;
+        }
+        Some(point)
+    }
+
+    fn at(&self, time_ms: u64) -> TracePoint {
+        self.0
+            .iter()
+            .rev()
+            .find(|p| p.time_ms <= time_ms)
+            .or(self.0.first())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn end_ms(&self) -> u64 {
+        self.0.last().map(|p| p.time_ms).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// Bitrate of ratio 1.0, `scrap::codec::base_bitrate` of the resolution
+    pub base_kbps: u32,
+    /// A static screen is not encoded
+    pub dynamic_screen: bool,
+    pub image_quality: Option<i32>,
+    pub total_cap_kbps: Option<u32>,
+    pub conn_cap_kbps: Option<u32>,
+    /// Of the packet loss
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            base_kbps: 2073, // 1080p
+            dynamic_screen: true,
+            image_quality: None,
+            total_cap_kbps: None,
+            conn_cap_kbps: None,
+            seed: 1,
+        }
+    }
+}
+
+/// State of each second.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sample {
+    pub time_ms: u64,
+    pub fps: u32,
+    pub ratio: f32,
+    pub bitrate_kbps: u32,
+    /// The last measured one
+    pub delay_ms: u32,
+    pub queue_ms: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Timeline(pub Vec<Sample>);
+
+impl Timeline {
+    pub fn to_csv(&self) -> String {
+        let mut out = "time_ms,fps,ratio,bitrate_kbps,delay_ms,queue_ms\n".to_owned();
+        for s in self.0.iter() {
+            out += &format!(
+                "{},{},{:.3},{},{},{}\n",
+                s.time_ms, s.fps, s.ratio, s.bitrate_kbps, s.delay_ms, s.queue_ms
+            );
+        }
+        out
+    }
+
+    /// Time from which `value` stays within `tolerance` of its final value, relatively.
+    pub fn convergence_time(&self, value: impl Fn(&Sample) -> f32, tolerance: f32) -> Option<u64> {
+        let last = value(self.0.last()?);
+        let outside = |s: &Sample| (value(s) - last).abs() > tolerance * last.abs();
+        match self.0.iter().rposition(outside) {
+            Some(i) => self.0.get(i + 1).map(|s| s.time_ms),
+            None => self.0.first().map(|s| s.time_ms),
+        }
+    }
+
+    /// Peak to peak amplitude of `value` from `from_ms`, relative to its mean.
+    pub fn oscillation(&self, value: impl Fn(&Sample) -> f32, from_ms: u64) -> f32 {
+        let values: Vec<f32> = self
+            .0
+            .iter()
+            .filter(|s| s.time_ms >= from_ms)
+            .map(value)
+            .collect();
+        if values.is_empty() {
+            return 0.0;
+        }
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        let max = values.iter().cloned().fold(f32::MIN, f32::max);
+        let min = values.iter().cloned().fold(f32::MAX, f32::min);
+        if mean > 0.0 {
+            (max - min) / mean
+        } else {
+            0.0
+        }
+    }
+}
+
+// xorshift64, the same losses for the same seed on every platform
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+// The link and the encoder, like `video_service` and `Connection` drive `VideoQoS`.
+struct Simulator {
+    qos: VideoQoS,
+    start: Instant,
+    base_kbps: u32,
+    dynamic_screen: bool,
+    rng: Rng,
+    encoder_ratio: f32,
+    queue_kb: f64,
+    frames: f32,
+    // of the current second
+    send_counter: usize,
+    video_kb: f64,
+    next_tick_ms: u64,
+    // (sent, arrival) in ms
+    test_delay: Option<(u64, u64)>,
+    last_delay: u32,
+}
+
+impl Simulator {
+    fn new(config: &SimConfig) -> Self {
+        let start = Instant::now();
+        let mut qos = VideoQoS {
+            virtual_now: Some(start),
+            adjust_ratio_instant: start,
+            new_user_instant: start,
+            bandwidth_cap: BandwidthCap {
+                total_kbps: config.total_cap_kbps,
+                conn_kbps: config.conn_cap_kbps,
+            },
+            ..Default::default()
+        };
+        qos.users.insert(USER_ID, UserData::default());
+        qos.new_display(DISPLAY_NAME.to_owned());
+        qos.set_support_changing_quality(DISPLAY_NAME, true);
+        if let Some(image_quality) = config.image_quality {
+            qos.user_image_quality(USER_ID, image_quality);
+        }
+        let mut sim = Self {
+            qos,
+            start,
+            base_kbps: config.base_kbps,
+            dynamic_screen: config.dynamic_screen,
+            rng: Rng(config.seed.max(1)),
+            encoder_ratio: 0.0,
+            queue_kb: 0.0,
+            frames: 0.0,
+            send_counter: 0,
+            video_kb: 0.0,
+            next_tick_ms: 0,
+            test_delay: None,
+            last_delay: 0,
+        };
+        sim.check_qos();
+        sim
+    }
+
+    fn bitrate(&self) -> u32 {
+        (self.base_kbps as f32 * self.encoder_ratio) as u32
+    }
+
+    fn check_qos(&mut self) {
+        let ratio = self.qos.ratio();
+        if ratio != self.encoder_ratio {
+            self.encoder_ratio = ratio;
+            self.qos.store_bitrate(self.bitrate());
+        }
+    }
+
+    fn step(&mut self, time_ms: u64, point: &TracePoint) -> Option<Sample> {
+        self.qos.virtual_now = Some(self.start + Duration::from_millis(time_ms));
+        let step_secs = STEP_MS as f64 / 1000.0;
+        self.check_qos();
+
+        // encode
+        if self.dynamic_screen {
+            self.frames += self.qos.fps() as f32 * step_secs as f32;
+            let frames = self.frames.floor();
+            self.frames -= frames;
+            self.send_counter += frames as usize;
+            let kb = self.bitrate() as f64 * step_secs;
+            self.video_kb += kb;
+            self.queue_kb += kb;
+        }
+        self.queue_kb += point.other_kbps as f64 * step_secs;
+
+        // send
+        let throughput_kbps = point.throughput_kbps as f64 * (1.0 - point.loss as f64);
+        self.queue_kb = (self.queue_kb - throughput_kbps * step_secs).max(0.0);
+        let queue_ms = (self.queue_kb / throughput_kbps * 1000.0) as u32;
+
+        // test delay
+        if let Some((sent, arrival)) = self.test_delay {
+            if time_ms >= arrival {
+                self.last_delay = (arrival - sent) as u32;
+                self.qos.user_network_delay(USER_ID, self.last_delay);
+                self.test_delay = None;
+            }
+        }
+        if time_ms < self.next_tick_ms {
+            return None;
+        }
+        self.next_tick_ms += TEST_DELAY_INTERVAL_MS;
+        if self.test_delay.is_none() {
+            let mut delay = point.delay_ms + queue_ms;
+            if self.rng.next() < point.loss {
+                delay += RETRANSMISSION_DELAY_MS;
+            }
+            self.test_delay = Some((time_ms, time_ms + delay as u64));
+        }
+        if let Some((sent, _)) = self.test_delay {
+            self.qos
+                .user_delay_response_elapsed(USER_ID, (time_ms - sent) as u128);
+        }
+
+        // every second
+        if time_ms > 0 {
+            let video_kbps = (self.video_kb * 1000.0 / TEST_DELAY_INTERVAL_MS as f64) as u32;
+            self.qos
+                .user_throughput(USER_ID, video_kbps, point.other_kbps);
+            self.qos
+                .update_display_data(DISPLAY_NAME, std::mem::take(&mut self.send_counter));
+            self.video_kb = 0.0;
+        }
+        self.check_qos();
+        Some(Sample {
+            time_ms,
+            fps: self.qos.fps(),
+            ratio: self.encoder_ratio,
+            bitrate_kbps: self.qos.bitrate(),
+            delay_ms: self.last_delay,
+            queue_ms,
+        })
+    }
+}
+
+/// Replays `trace`, the state is sampled every second.
+pub fn simulate(trace: &Trace, config: &SimConfig) -> Timeline {
+    let mut sim = Simulator::new(config);
+    let mut timeline = Timeline::default();
+    let mut time_ms = 0;
+    while time_ms <= trace.end_ms() {
+        if let Some(sample) = sim.step(time_ms, &trace.at(time_ms)) {
+            timeline.0.push(sample);
+        }
+        time_ms += STEP_MS;
+    }
+    timeline
+}
+
+/// Replays the trace file, for `--simulate-qos`.
+pub fn simulate_file(path: &str, base_kbps: Option<u32>) -> ResultType<String> {
+    let trace = Trace::parse(&std::fs::read_to_string(path)?)?;
+    let mut config = SimConfig::default();
+    if let Some(base_kbps) = base_kbps {
+        config.base_kbps = base_kbps;
+    }
+    Ok(simulate(&trace, &config).to_csv())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulator() {
+        let fps = |s: &Sample| s.fps as f32;
+        let bitrate = |s: &Sample| s.bitrate_kbps as f32;
+
+        // A good link
+        let trace = Trace::parse("0,20,0,20000\n60000,20,0,20000").unwrap();
+        let timeline = simulate(&trace, &SimConfig::default());
+        assert_eq!(timeline.0.len(), 61);
+        assert_eq!(timeline.0.last().map(|s| s.fps), Some(FPS));
+        assert!(timeline.convergence_time(fps, 0.0).unwrap() <= 15_000);
+        assert!(timeline.oscillation(bitrate, 30_000) < 0.05);
+        assert_eq!(timeline.to_csv().lines().count(), 62);
+
+        // The throughput drops to 1 Mbps with some loss at 20s
+        let trace = Trace::parse(
+            "# time_ms,delay_ms,loss,throughput_kbps\n\
+             0,30,0,20000\n\
+             20000,30,0.02,1000\n\
+             120000,30,0.02,1000",
+        )
+        .unwrap();
+        let timeline = simulate(&trace, &SimConfig::default());
+        let recovered = timeline
+            .0
+            .iter()
+            .find(|s| s.time_ms > 20_000 && s.delay_ms < DELAY_THRESHOLD_150MS);
+        assert!(recovered.unwrap().time_ms <= 45_000);
+        // It keeps probing for more bitrate
+        let after = timeline.0.iter().filter(|s| s.time_ms >= 40_000);
+        assert!(after.clone().all(|s| s.bitrate_kbps < 1200));
+        assert!(after.clone().any(|s| s.bitrate_kbps < 1000));
+        assert!(timeline.oscillation(bitrate, 40_000) < 0.6);
+        assert_eq!(
+            simulate(&trace, &SimConfig::default()).to_csv(),
+            timeline.to_csv()
+        );
+
+        // The bandwidth cap with the audio
+        let trace = Trace::parse("0,20,0,20000,100\n60000,20,0,20000,100").unwrap();
+        let config = SimConfig {
+            total_cap_kbps: Some(800),
+            ..Default::default()
+        };
+        let timeline = simulate(&trace, &config);
+        assert!(timeline.0.iter().skip(1).all(|s| s.bitrate_kbps <= 700));
+
+        assert!(Trace::parse("").is_err());
+        assert!(Trace::parse("0,20,0").is_err());
+        assert!(Trace::parse("0,20,1.5,1000").is_err());
+        assert!(Trace::parse("1000,20,0,1000\n0,20,0,1000").is_err());
+        assert_eq!(trace.at(70000).other_kbps, 100);
+    }
+}
@@ -47,7 +47,7 @@ use scrap::Capturer;
 use scrap::{
     aom::AomEncoderConfig,
     codec::{Encoder, EncoderCfg},
-    record::{Recorder, RecorderContext},
+    record::{RecordPeer, Recorder, RecorderContext, OPTION_RECORD_AUDIO},
     vpxcodec::{VpxEncoderConfig, VpxVideoCodecId},
     CodecFormat, Display, EncodeInput, TraitCapturer, TraitPixelBuffer,
 };
@@ -78,6 +78,11 @@ lazy_static::lazy_static! {
     pub static ref IS_UAC_RUNNING: Arc<Mutex<bool>> = Default::default();
     pub static ref IS_FOREGROUND_WINDOW_ELEVATED: Arc<Mutex<bool>> = Default::default();
     static ref SCREENSHOTS: Mutex<HashMap<usize, Screenshot>> = Default::default();
+    // The recorders of the running displays, which also record the audio, the display switches
+    // and the peers.
+    static ref RECORDERS: Mutex<Vec<Weak<Mutex<Option<Recorder>>>>> = Default::default();
+    // conn id -> peer
+    static ref RECORD_PEERS: Mutex<HashMap<i32, RecordPeer>> = Default::default();
 }
 
 struct Screenshot {
@@ -688,6 +693,12 @@ fn run(vs: VideoService) -> ResultType<()> {
             log::info!("switch due to i444 changed");
             bail!("SWITCH");
         }
+        if let EncoderCfg::AOM(aom) = &encoder_cfg {
+            if Encoder::use_screen_content() != aom.screen_content {
+                log::info!("switch due to screen content changed");
+                bail!("SWITCH");
+            }
+        }
         #[cfg(all(windows, feature = "vram"))]
         if c.is_gdi() && encoder.input_texture() {
             log::info!("changed to gdi when using vram");
@@ -764,6 +775,12 @@ fn run(vs: VideoService) -> ResultType<()> {
                         }
                     }
 
+                    // The regions changed since the previous frame, the full frame without them
+                    let damage = match &frame {
+                        scrap::Frame::PixelBuffer(f) => f.damage(),
+                        scrap::Frame::Texture(_) => None,
+                    };
+                    encoder.set_damage(damage);
                     let frame = frame.to(encoder.yuvfmt(), &mut yuv, &mut mid_data)?;
                     let send_conn_ids = handle_one_frame(
                         display_idx,
@@ -1020,6 +1037,7 @@ fn get_encoder_config(
             height: c.height as _,
             quality,
             keyframe_interval,
+            screen_content: Encoder::use_screen_content(),
         }),
         _ => EncoderCfg::VPX(VpxEncoderConfig {
             width: c.width as _,
@@ -1056,9 +1074,17 @@ fn get_recorder(
             dir: crate::ui_interface::video_save_directory(root),
             display_idx,
             camera,
+            audio: Config::get_option(OPTION_RECORD_AUDIO) == "Y",
             tx,
         })
-        .map_or(Default::default(), |r| Arc::new(Mutex::new(Some(r))))
+        .map_or(Default::default(), |mut r| {
+            for peer in RECORD_PEERS.lock().unwrap().values() {
+                r.add_peer(peer.clone());
+            }
+            let r = Arc::new(Mutex::new(Some(r)));
+            RECORDERS.lock().unwrap().push(Arc::downgrade(&r));
+            r
+        })
     } else {
         Default::default()
     };
@@ -1066,6 +1092,37 @@ fn get_recorder(
     recorder
 }
 
+fn for_each_recorder(f: impl Fn(&mut Recorder)) {
+    let mut recorders = RECORDERS.lock().unwrap();
+    recorders.retain(|r| r.strong_count() > 0);
+    for recorder in recorders.iter().filter_map(|r| r.upgrade()) {
+        if let Some(r) = recorder.lock().unwrap().as_mut() {
+            f(r);
+        }
+    }
+}
+
+pub fn record_audio(data: &[u8]) {
+    for_each_recorder(|r| r.write_audio(data));
+}
+
+pub fn record_chapter(title: &str) {
+    for_each_recorder(|r| r.add_chapter(title));
+}
+
+/// `None` when the connection is closed, the recordings keep the peers they have seen.
+pub fn update_record_peer(conn_id: i32, peer: Option<RecordPeer>) {
+    match peer {
+        Some(peer) => {
+            RECORD_PEERS.lock().unwrap().insert(conn_id, peer.clone());
+            for_each_recorder(|r| r.add_peer(peer.clone()));
+        }
+        None => {
+            RECORD_PEERS.lock().unwrap().remove(&conn_id);
+        }
+    }
+}
+
 #[cfg(target_os = "android")]
 fn check_change_scale(hardware: bool) -> ResultType<()> {
     use hbb_common::config::keys::OPTION_ENABLE_ANDROID_SOFTWARE_ENCODING_HALF_SCALE as SCALE_SOFT;
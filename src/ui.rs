@@ -485,6 +485,10 @@ impl UI {
         crate::lan::send_wol(id)
     }
 
+    fn get_wol_relays(&mut self, id: String) -> Value {
+        Value::from_iter(crate::lan::find_wol_relays(&id))
+    }
+
     fn new_remote(&mut self, id: String, remote_type: String, force_relay: bool) {
         new_remote(id, remote_type, force_relay)
     }
@@ -678,6 +682,18 @@ impl UI {
         verify2fa(code)
     }
 
+    fn take_2fa_recovery_codes(&self) -> String {
+        take_2fa_recovery_codes()
+    }
+
+    fn regenerate_2fa_recovery_codes(&self) -> String {
+        regenerate_2fa_recovery_codes()
+    }
+
+    fn get_record_uploads(&self) -> String {
+        get_record_uploads()
+    }
+
     fn verify_login(&self, raw: String, id: String) -> bool {
         crate::verify_login(&raw, &id)
     }
@@ -739,6 +755,7 @@ impl sciter::EventHandler for UI {
         fn get_size();
         fn new_remote(String, String, bool);
         fn send_wol(String);
+        fn get_wol_relays(String);
         fn remove_peer(String);
         fn remove_discovered(String);
         fn get_connect_status();
@@ -811,6 +828,9 @@ impl sciter::EventHandler for UI {
         fn generate2fa();
         fn generate_2fa_img_src(String);
         fn verify2fa(String);
+        fn take_2fa_recovery_codes();
+        fn regenerate_2fa_recovery_codes();
+        fn get_record_uploads();
         fn check_hwcodec();
         fn verify_login(String, String);
         fn is_option_fixed(String);
@@ -121,6 +121,14 @@ impl SciterConnectionManager {
         crate::ui_cm_interface::switch_permission(id, name, enabled);
     }
 
+    fn grant_access(&self, id: i32, minutes: i32) {
+        crate::ui_cm_interface::grant_access(id, minutes.max(0) as _);
+    }
+
+    fn revoke_access(&self, id: i32) {
+        crate::ui_cm_interface::revoke_access(id);
+    }
+
     fn close(&self, id: i32) {
         crate::ui_cm_interface::close(id);
     }
@@ -177,6 +185,8 @@ impl sciter::EventHandler for SciterConnectionManager {
         fn quit();
         fn authorize(i32);
         fn switch_permission(i32, String, bool);
+        fn grant_access(i32, i32);
+        fn revoke_access(i32);
         fn send_msg(i32, String);
         fn can_elevate();
         fn elevate_portable(i32);
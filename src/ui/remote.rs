@@ -179,6 +179,10 @@ impl InvokeUiSession for SciterHandler {
         self.call("setCursorPosition", &make_args!(cp.x, cp.y));
     }
 
+    fn update_transport_stats(&self, stats: String) {
+        self.call("updateTransportStats", &make_args!(stats));
+    }
+
     fn set_connection_type(&self, is_secured: bool, direct: bool, stream_type: &str) {
         self.call(
             "setConnectionType",
@@ -483,6 +487,7 @@ impl sciter::EventHandler for SciterSession {
 
     sciter::dispatch_script_call! {
         fn get_audit_server(String);
+        fn get_transport_stats();
         fn send_note(String);
         fn is_xfce();
         fn get_id();
@@ -559,6 +564,8 @@ impl sciter::EventHandler for SciterSession {
         fn alternative_codecs();
         fn update_supported_decodings();
         fn restart_remote_device();
+        fn wake_peer(String);
+        fn get_wol_targets();
         fn request_voice_call();
         fn close_voice_call();
         fn version_cmp(String, String);
@@ -870,6 +877,10 @@ impl SciterSession {
         (hbb_common::get_version_number(&v1) - hbb_common::get_version_number(&v2)) as i32
     }
 
+    fn get_wol_targets(&self) -> Value {
+        Value::from_iter(self.0.get_wol_targets())
+    }
+
     fn get_printer_names(&self) -> Value {
         #[cfg(target_os = "windows")]
         let printer_names = crate::platform::windows::get_printer_names().unwrap_or_default();
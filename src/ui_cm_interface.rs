@@ -397,6 +397,46 @@ pub fn switch_permission(id: i32, name: String, enabled: bool) {
     };
 }
 
+#[cfg(not(any(target_os = "ios")))]
+fn update_access_grants(update: crate::server::access_grant::GrantUpdate) {
+    #[cfg(not(target_os = "android"))]
+    let res = crate::ipc::update_access_grants(update);
+    #[cfg(target_os = "android")]
+    let res = crate::server::access_grant::update(update);
+    if let Err(err) = res {
+        log::error!("Failed to update the access grants: {}", err);
+    }
+}
+
+/// Grants the peer of the connection access for `minutes`, it is disconnected then.
+#[cfg(not(any(target_os = "ios")))]
+pub fn grant_access(id: i32, minutes: u64) {
+    let Some(peer_id) = CLIENTS.read().unwrap().get(&id).map(|c| c.peer_id.clone()) else {
+        return;
+    };
+    update_access_grants(crate::server::access_grant::GrantUpdate::Add {
+        peer_id,
+        token: "".to_owned(),
+        permissions: vec![],
+        minutes,
+    });
+}
+
+/// Revokes the grants of the peer of the connection, which disconnects it.
+#[cfg(not(any(target_os = "ios")))]
+pub fn revoke_access(id: i32) {
+    let Some(peer_id) = CLIENTS.read().unwrap().get(&id).map(|c| c.peer_id.clone()) else {
+        return;
+    };
+    #[cfg(not(target_os = "android"))]
+    let grants = crate::ipc::get_access_grants().unwrap_or_default();
+    #[cfg(target_os = "android")]
+    let grants = crate::server::access_grant::list();
+    for grant in grants.into_iter().filter(|g| g.peer_id == peer_id) {
+        update_access_grants(crate::server::access_grant::GrantUpdate::Revoke(grant.id));
+    }
+}
+
 #[inline]
 #[cfg(target_os = "android")]
 pub fn switch_permission_all(name: String, enabled: bool) {
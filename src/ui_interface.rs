@@ -446,6 +446,10 @@ pub fn set_option(key: String, value: String) {
     } else if &key == "audio-input" {
         #[cfg(not(target_os = "ios"))]
         crate::audio_service::restart();
+    } else if &key == "2fa" && value.is_empty() {
+        crate::auth_2fa::disable2fa();
+        refresh_options();
+        return;
     }
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     {
@@ -1523,6 +1527,40 @@ pub fn verify2fa(code: String) -> bool {
     res
 }
 
+// The codes generated with the secret just verified, can only be shown once.
+pub fn take_2fa_recovery_codes() -> String {
+    serde_json::to_string(&crate::auth_2fa::take_pending_recovery_codes()).unwrap_or_default()
+}
+
+/// Recordings waiting to be uploaded, as json.
+pub fn get_record_uploads() -> String {
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    let res = crate::ipc::get_record_uploads();
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    let res: hbb_common::ResultType<_> = Ok(crate::hbbs_http::record_upload::get_uploads());
+    match res {
+        Ok(uploads) => serde_json::to_string(&uploads).unwrap_or_default(),
+        Err(err) => {
+            log::error!("{}", err);
+            "".to_owned()
+        }
+    }
+}
+
+pub fn regenerate_2fa_recovery_codes() -> String {
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    let res = crate::ipc::regenerate_recovery_codes();
+    #[cfg(any(target_os = "android", target_os = "ios"))]
+    let res = crate::auth_2fa::regenerate_recovery_codes();
+    match res {
+        Ok(codes) => serde_json::to_string(&codes).unwrap_or_default(),
+        Err(err) => {
+            log::error!("{}", err);
+            "".to_owned()
+        }
+    }
+}
+
 pub fn has_valid_bot() -> bool {
     crate::auth_2fa::TelegramBot::get().map_or(false, |bot| bot.is_some())
 }
@@ -43,8 +43,8 @@ use uuid::Uuid;
 use crate::client::io_loop::Remote;
 use crate::client::{
     check_if_retry, handle_hash, handle_login_error, handle_login_from_ui, handle_test_delay,
-    input_os_password, send_mouse, send_pointer_device_event, FileManager, Key, LoginConfigHandler,
-    QualityStatus, KEY_MAP,
+    input_os_password, send_mouse, send_pointer_device_event, transport_stats::TransportStats,
+    FileManager, Key, LoginConfigHandler, QualityStatus, KEY_MAP,
 };
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
 use crate::common::GrabState;
@@ -52,6 +52,8 @@ use crate::keyboard;
 use crate::{client::Data, client::Interface};
 
 const CHANGE_RESOLUTION_VALID_TIMEOUT_SECS: u64 = 15;
+#[cfg(not(target_os = "ios"))]
+const WOL_RELAY_WAIT_TIMEOUT_SECS: u64 = 120;
 
 #[derive(Clone, Default)]
 pub struct Session<T: InvokeUiSession> {
@@ -72,6 +74,7 @@ pub struct Session<T: InvokeUiSession> {
     pub reconnect_count: Arc<AtomicUsize>,
     pub last_audit_note: Arc<Mutex<String>>,
     pub audit_guid: Arc<Mutex<String>>,
+    pub transport_stats: Arc<Mutex<TransportStats>>,
 }
 
 #[derive(Clone)]
@@ -541,6 +544,10 @@ impl<T: InvokeUiSession> Session<T> {
         (vp8, av1, h264, h265)
     }
 
+    pub fn screen_content_supported(&self) -> bool {
+        self.lc.read().unwrap().screen_content_supported()
+    }
+
     pub fn update_supported_decodings(&self) {
         let msg = self.lc.write().unwrap().update_supported_decodings();
         self.send(Data::Message(msg));
@@ -559,6 +566,83 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Message(msg));
     }
 
+    /// Ask this peer to wake `id`, which should be on one of its subnets,
+    /// see `crate::lan::find_wol_relays`.
+    #[cfg(not(target_os = "ios"))]
+    pub fn wake_peer(&self, id: String) {
+        if !self
+            .lc
+            .read()
+            .unwrap()
+            .is_peer_support(crate::lan::PEER_SUPPORT_WOL_RELAY)
+        {
+            self.msgbox(
+                "custom-nocancel",
+                "Wake-on-LAN",
+                "The peer does not support relaying Wake-on-LAN",
+                "",
+            );
+            return;
+        }
+        let Some(request) = crate::lan::wol_relay_request(&id) else {
+            self.msgbox(
+                "custom-nocancel",
+                "Wake-on-LAN",
+                &format!("No MAC address of {} is known", id),
+                "",
+            );
+            return;
+        };
+        let mut misc = Misc::new();
+        misc.set_plugin_request(PluginRequest {
+            id: crate::lan::WOL_RELAY_REQUEST_ID.to_owned(),
+            content: serde_json::to_vec(&request).unwrap_or_default().into(),
+            ..Default::default()
+        });
+        let mut msg_out = Message::new();
+        msg_out.set_misc(misc);
+        self.send(Data::Message(msg_out));
+    }
+
+    /// Discovered peers which this peer may wake, see `wake_peer`.
+    #[cfg(not(target_os = "ios"))]
+    pub fn get_wol_targets(&self) -> Vec<String> {
+        crate::lan::find_wol_targets(&self.get_id())
+    }
+
+    #[cfg(not(target_os = "ios"))]
+    pub fn handle_wol_relay_response(&self, content: &[u8]) {
+        let res = match serde_json::from_slice::<crate::lan::WolRelayResponse>(content) {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("Invalid wake-on-lan relay response: {}", err);
+                return;
+            }
+        };
+        if !res.error.is_empty() {
+            self.msgbox("custom-nocancel", "Wake-on-LAN", &res.error, "");
+            return;
+        }
+        self.msgbox(
+            "custom-nook-nocancel-hasclose",
+            "Wake-on-LAN",
+            &format!("Magic packet sent, waiting for {} to come online", res.id),
+            "",
+        );
+        let session = self.clone();
+        std::thread::spawn(move || {
+            let text = if crate::lan::wait_online(
+                res.id.clone(),
+                std::time::Duration::from_secs(WOL_RELAY_WAIT_TIMEOUT_SECS),
+            ) {
+                format!("{} is online", res.id)
+            } else {
+                format!("{} did not come online", res.id)
+            };
+            session.msgbox("custom-nocancel", "Wake-on-LAN", &text, "");
+        });
+    }
+
     #[cfg(all(feature = "flutter", feature = "plugin_framework"))]
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     pub fn send_plugin_request(&self, request: PluginRequest) {
@@ -569,6 +653,22 @@ impl<T: InvokeUiSession> Session<T> {
         self.send(Data::Message(msg_out));
     }
 
+    pub fn get_transport_stats(&self) -> String {
+        let mut stats = self.transport_stats.lock().unwrap().clone();
+        // The first connection is also started by `reconnect()` in sciter.
+        let reconnect_count_thr = if cfg!(feature = "flutter") { 0 } else { 1 };
+        stats.reconnects = self
+            .reconnect_count
+            .load(Ordering::SeqCst)
+            .saturating_sub(reconnect_count_thr);
+        serde_json::to_string(&stats).unwrap_or_default()
+    }
+
+    pub fn update_transport_stats(&self) {
+        self.ui_handler
+            .update_transport_stats(self.get_transport_stats());
+    }
+
     pub fn get_audit_server(&self, typ: String) -> String {
         if LocalConfig::get_option("access_token").is_empty() {
             return "".to_owned();
@@ -1672,6 +1772,7 @@ pub trait InvokeUiSession: Send + Sync + Clone + 'static + Sized + Default {
     fn set_permission(&self, name: &str, value: bool);
     fn close_success(&self);
     fn update_quality_status(&self, qs: QualityStatus);
+    fn update_transport_stats(&self, stats: String);
     fn set_connection_type(&self, is_secured: bool, direct: bool, stream_type: &str);
     fn set_fingerprint(&self, fingerprint: String);
     fn job_error(&self, id: i32, err: String, file_num: i32);
@@ -1879,6 +1980,10 @@ impl<T: InvokeUiSession> Interface for Session<T> {
 
     async fn handle_test_delay(&self, t: TestDelay, peer: &mut Stream) {
         if !t.from_client {
+            self.transport_stats
+                .lock()
+                .unwrap()
+                .add_rtt_sample(t.last_delay);
             self.update_quality_status(QualityStatus {
                 delay: Some(t.last_delay as _),
                 target_bitrate: Some(t.target_bitrate as _),
@@ -1954,7 +2059,10 @@ pub async fn io_loop<T: InvokeUiSession>(handler: Session<T>, round: u32) {
             loop {
                 match receiver.recv().await {
                     Some(Data::AddPortForward((port, remote_host, remote_port))) => {
-                        if port <= 0 || remote_port <= 0 {
+                        if port <= 0
+                            || (remote_port <= 0
+                                && !crate::port_forward::is_dynamic_forward(&remote_host))
+                        {
                             continue;
                         }
                         let (sender, receiver) = mpsc::unbounded_channel::<Data>();
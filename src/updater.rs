@@ -1,6 +1,15 @@
 use crate::{common::do_check_software_update, hbbs_http::create_http_client_with_url};
-use hbb_common::{bail, config, log, ResultType};
+use hbb_common::{
+    bail, config,
+    config::Config,
+    get_version_number, log,
+    sha2::{Digest, Sha256},
+    sodiumoxide::crypto::sign,
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     io::Write,
     path::PathBuf,
     sync::{
@@ -24,6 +33,166 @@ static CONTROLLING_SESSION_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 const DUR_ONE_DAY: Duration = Duration::from_secs(60 * 60 * 24);
 
+/// Url of a signed update manifest, replaces the check against the official releases.
+pub const OPTION_UPDATE_MANIFEST_URL: &str = "update-manifest-url";
+/// "stable" (default), "beta", or a version to stay on.
+pub const OPTION_UPDATE_CHANNEL: &str = "update-channel";
+// Base64 ed25519 public key the manifest artifacts are signed with.
+const UPDATE_PK: Option<&str> = option_env!("RUSTDESK_UPDATE_PK");
+// The new version must start its server within this time, or the old one is restored.
+// Only for the exe installations, an msi one has no previous package to restore from.
+const ROLLBACK_TIMEOUT: Duration = Duration::from_secs(180);
+const PENDING_UPDATE_FILE: &str = "update-pending.json";
+
+/// Update manifest, e.g.
+/// ```json
+/// {
+///   "channels": { "stable": { "version": "1.4.2", "rollout": 20 } },
+///   "releases": [{
+///     "version": "1.4.2",
+///     "artifacts": [{
+///       "platform": "windows-x86_64", "url": "rustdesk-1.4.2-x86_64.exe",
+///       "sha256": "<hex>", "signature": "<base64 of the ed25519 signature of artifact_message()>"
+///     }]
+///   }]
+/// }
+/// ```
+/// Relative urls are resolved against the manifest url, so a static mirror works.
+#[derive(Debug, Default, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    channels: HashMap<String, Channel>,
+    #[serde(default)]
+    releases: Vec<Release>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Channel {
+    version: String,
+    /// Percentage of the devices getting it.
+    #[serde(default = "default_rollout")]
+    rollout: u32,
+}
+
+fn default_rollout() -> u32 {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    version: String,
+    #[serde(default)]
+    artifacts: Vec<Artifact>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Artifact {
+    platform: String,
+    url: String,
+    sha256: String,
+    signature: String,
+}
+
+// Binds the hash to the version and platform, so that an artifact can't be served for another one.
+fn artifact_message(version: &str, artifact: &Artifact) -> String {
+    format!(
+        "{}\n{}\n{}",
+        version,
+        artifact.platform,
+        artifact.sha256.to_lowercase()
+    )
+}
+
+fn verify_artifact(
+    version: &str,
+    artifact: &Artifact,
+    data: &[u8],
+    pk: &sign::PublicKey,
+) -> ResultType<()> {
+    let sha256 = hex::encode(Sha256::digest(data));
+    if !sha256.eq_ignore_ascii_case(&artifact.sha256) {
+        bail!("SHA-256 mismatch of {}", artifact.url);
+    }
+    let sig: [u8; sign::SIGNATUREBYTES] = match crate::decode64(&artifact.signature)?.try_into() {
+        Ok(sig) => sig,
+        Err(_) => bail!("Invalid signature of {}", artifact.url),
+    };
+    let msg = artifact_message(version, artifact);
+    if !sign::verify_detached(&sign::Signature::new(sig), msg.as_bytes(), pk) {
+        bail!("Signature mismatch of {}", artifact.url);
+    }
+    Ok(())
+}
+
+// Stable for a device and a version, so that a device stays in or out during a rollout.
+fn rollout_bucket(id: &str, version: &str) -> u32 {
+    let hash = Sha256::digest(format!("{}:{}", id, version).as_bytes());
+    u16::from_le_bytes([hash[0], hash[1]]) as u32 % 100
+}
+
+fn select_release<'a>(
+    manifest: &'a Manifest,
+    channel: &str,
+    id: &str,
+    current: &str,
+) -> Option<&'a Release> {
+    let version = match channel {
+        "" | "stable" | "beta" => {
+            let channel = manifest.channels.get(if channel.is_empty() {
+                "stable"
+            } else {
+                channel
+            })?;
+            if get_version_number(&channel.version) <= get_version_number(current) {
+                return None;
+            }
+            if rollout_bucket(id, &channel.version) >= channel.rollout {
+                log::info!(
+                    "Update {} is not rolled out to this device yet",
+                    channel.version
+                );
+                return None;
+            }
+            &channel.version
+        }
+        // pinned, can also be a downgrade
+        pinned => {
+            if pinned == current {
+                return None;
+            }
+            pinned
+        }
+    };
+    manifest.releases.iter().find(|r| r.version == *version)
+}
+
+fn resolve_url(manifest_url: &str, url: &str) -> String {
+    if url.contains("://") {
+        return url.to_owned();
+    }
+    let base = manifest_url
+        .rsplit_once('/')
+        .map(|(base, _)| base)
+        .unwrap_or(manifest_url);
+    format!("{}/{}", base, url.trim_start_matches('/'))
+}
+
+#[cfg(target_os = "windows")]
+fn artifact_platform(update_msi: bool) -> &'static str {
+    if !cfg!(feature = "flutter") {
+        "windows-x86-sciter"
+    } else if update_msi {
+        "windows-x86_64-msi"
+    } else {
+        "windows-x86_64"
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn artifact_platform(_update_msi: bool) -> &'static str {
+    ""
+}
+
 pub fn update_controlling_session_count(count: usize) {
     CONTROLLING_SESSION_COUNT.store(count, Ordering::SeqCst);
 }
@@ -120,9 +289,15 @@ fn start_auto_update_check_(rx_msg: Receiver<UpdateMsg>) {
 fn check_update(manually: bool) -> ResultType<()> {
     #[cfg(target_os = "windows")]
     let update_msi = crate::platform::is_msi_installed()? && !crate::is_custom_client();
+    #[cfg(not(target_os = "windows"))]
+    let update_msi = false;
     if !(manually || config::Config::get_bool_option(config::keys::OPTION_ALLOW_AUTO_UPDATE)) {
         return Ok(());
     }
+    let manifest_url = Config::get_option(OPTION_UPDATE_MANIFEST_URL);
+    if !manifest_url.is_empty() {
+        return check_update_from_manifest(&manifest_url, update_msi);
+    }
     if do_check_software_update().is_err() {
         // ignore
         return Ok(());
@@ -196,6 +371,209 @@ fn check_update(manually: bool) -> ResultType<()> {
     Ok(())
 }
 
+fn check_update_from_manifest(manifest_url: &str, update_msi: bool) -> ResultType<()> {
+    let Some(pk) = UPDATE_PK
+        .and_then(|pk| crate::decode64(pk).ok())
+        .and_then(|pk| sign::PublicKey::from_slice(&pk))
+    else {
+        bail!("No update public key built in, refusing the update manifest");
+    };
+    let client = create_http_client_with_url(manifest_url);
+    let response = client.get(manifest_url).send()?;
+    if !response.status().is_success() {
+        bail!("Failed to get the update manifest: {}", response.status());
+    }
+    let manifest: Manifest = serde_json::from_slice(&response.bytes()?)?;
+    let channel = Config::get_option(OPTION_UPDATE_CHANNEL);
+    let Some(release) = select_release(&manifest, &channel, &Config::get_id(), crate::VERSION)
+    else {
+        log::debug!("No update available.");
+        return Ok(());
+    };
+    let platform = artifact_platform(update_msi);
+    let Some(artifact) = release.artifacts.iter().find(|a| a.platform == platform) else {
+        bail!("No {} artifact of version {}", platform, release.version);
+    };
+    let version = release.version.clone();
+    let download_url = resolve_url(manifest_url, &artifact.url);
+    log::debug!("New version available: {}", &version);
+    let Some(file_path) = get_download_file_from_url(&download_url) else {
+        bail!("Failed to get the file path from the URL: {}", download_url);
+    };
+    // A previous download is reused only if it is still the signed one.
+    let is_file_verified = std::fs::read(&file_path)
+        .map(|data| verify_artifact(&version, artifact, &data, &pk).is_ok())
+        .unwrap_or(false);
+    if !is_file_verified {
+        std::fs::remove_file(&file_path).ok();
+        // Resumed if a previous run was interrupted.
+        crate::hbbs_http::downloader::download_file_sync(
+            download_url,
+            file_path.clone(),
+            crate::hbbs_http::downloader::DownloadOptions {
+                sha256: Some(artifact.sha256.clone()),
+                segments: 4,
+            },
+        )?;
+        if let Err(e) = verify_artifact(&version, artifact, &std::fs::read(&file_path)?, &pk) {
+            std::fs::remove_file(&file_path).ok();
+            return Err(e);
+        }
+    }
+    if has_no_active_conns() {
+        #[cfg(target_os = "windows")]
+        update_new_version(update_msi, &version, &file_path);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingUpdate {
+    from_version: String,
+    to_version: String,
+    app_name: String,
+    install_dir: PathBuf,
+    backup_dir: PathBuf,
+    deadline: i64,
+}
+
+fn pending_update_file() -> PathBuf {
+    Config::path(PENDING_UPDATE_FILE)
+}
+
+/// Called when the server has started, the update is then kept.
+pub fn confirm_update() {
+    let path = pending_update_file();
+    let Ok(data) = std::fs::read(&path) else {
+        return;
+    };
+    match serde_json::from_slice::<PendingUpdate>(&data) {
+        Ok(pending) if pending.to_version != crate::VERSION => {
+            log::warn!(
+                "Pending update to {}, but {} is running",
+                pending.to_version,
+                crate::VERSION
+            );
+        }
+        _ => {
+            log::info!("Update to {} confirmed", crate::VERSION);
+            std::fs::remove_file(&path).ok();
+        }
+    }
+}
+
+// Copy the installation and a watchdog out of it, before the update replaces it.
+#[cfg(target_os = "windows")]
+fn prepare_rollback(version: &str) -> ResultType<PendingUpdate> {
+    let app_name = crate::get_app_name();
+    let install_dir = PathBuf::from(crate::platform::get_install_info().1);
+    let root = std::env::temp_dir().join(format!("{}-update-backup", app_name));
+    std::fs::remove_dir_all(&root).ok();
+    let backup_dir = root.join("install");
+    copy_dir(&install_dir, &backup_dir)?;
+    std::fs::copy(
+        backup_dir.join(format!("{}.exe", app_name)),
+        root.join(format!("{}-rollback.exe", app_name)),
+    )?;
+    let pending = PendingUpdate {
+        from_version: crate::VERSION.to_owned(),
+        to_version: version.to_owned(),
+        app_name,
+        install_dir,
+        backup_dir,
+        deadline: hbb_common::get_time() + ROLLBACK_TIMEOUT.as_millis() as i64,
+    };
+    std::fs::write(pending_update_file(), serde_json::to_vec(&pending)?)?;
+    Ok(pending)
+}
+
+#[cfg(target_os = "windows")]
+fn start_rollback_watchdog(pending: &PendingUpdate) -> ResultType<()> {
+    let Some(root) = pending.backup_dir.parent() else {
+        bail!("Invalid backup dir {:?}", pending.backup_dir);
+    };
+    // Not named as the app, so that the update doesn't kill it.
+    let exe = root.join(format!("{}-rollback.exe", pending.app_name));
+    let marker = pending_update_file();
+    crate::platform::run_exe_direct(
+        &exe.to_string_lossy(),
+        vec!["--update-rollback", &marker.to_string_lossy()],
+        false,
+    )?;
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn discard_rollback(pending: &PendingUpdate) {
+    std::fs::remove_file(pending_update_file()).ok();
+    if let Some(root) = pending.backup_dir.parent() {
+        std::fs::remove_dir_all(root).ok();
+    }
+}
+
+/// `--update-rollback <marker>`, restores the backup if the update is not confirmed in time.
+#[cfg(target_os = "windows")]
+pub fn run_rollback_watchdog(marker: &str) {
+    let Ok(pending) = std::fs::read(marker)
+        .map_err(|e| e.to_string())
+        .and_then(|x| serde_json::from_slice::<PendingUpdate>(&x).map_err(|e| e.to_string()))
+    else {
+        log::error!("Failed to read pending update {}", marker);
+        return;
+    };
+    let confirmed = || {
+        if std::path::Path::new(marker).exists() {
+            return false;
+        }
+        log::info!("Update to {} confirmed, backup removed", pending.to_version);
+        // Not the parent, this exe is running from it.
+        std::fs::remove_dir_all(&pending.backup_dir).ok();
+        true
+    };
+    while hbb_common::get_time() < pending.deadline {
+        std::thread::sleep(Duration::from_secs(5));
+        if confirmed() {
+            return;
+        }
+    }
+    // A confirmation may have landed since the last check.
+    if confirmed() {
+        return;
+    }
+    log::error!(
+        "Version {} did not start within {:?}, restoring {}",
+        pending.to_version,
+        ROLLBACK_TIMEOUT,
+        pending.from_version
+    );
+    match crate::platform::restore_install_dir(
+        &pending.app_name,
+        &pending.backup_dir,
+        &pending.install_dir,
+    ) {
+        Ok(_) => {
+            std::fs::remove_file(marker).ok();
+            log::info!("Version {} restored", pending.from_version);
+        }
+        Err(e) => log::error!("Failed to restore {}: {}", pending.from_version, e),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn copy_dir(src: &std::path::Path, dst: &std::path::Path) -> ResultType<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let to = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to)?;
+        } else {
+            std::fs::copy(entry.path(), to)?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(target_os = "windows")]
 fn update_new_version(update_msi: bool, version: &str, file_path: &PathBuf) {
     log::debug!(
@@ -205,6 +583,8 @@ fn update_new_version(update_msi: bool, version: &str, file_path: &PathBuf) {
     if let Some(p) = file_path.to_str() {
         if let Some(session_id) = crate::platform::get_current_process_session_id() {
             if update_msi {
+                // msi has its own rollback on failed installations, but nothing restores
+                // the old version if the new one is installed and then fails to start.
                 match crate::platform::update_me_msi(p, true) {
                     Ok(_) => {
                         log::debug!("New version \"{}\" updated.", version);
@@ -241,6 +621,13 @@ fn update_new_version(update_msi: bool, version: &str, file_path: &PathBuf) {
                     ));
                     None
                 };
+                let rollback = match prepare_rollback(version) {
+                    Ok(pending) => Some(pending),
+                    Err(e) => {
+                        log::error!("Failed to back up for rollback: {}", e);
+                        None
+                    }
+                };
                 let update_launched = match crate::platform::launch_privileged_process(
                     session_id,
                     &format!("{} --update", p),
@@ -259,6 +646,13 @@ fn update_new_version(update_msi: bool, version: &str, file_path: &PathBuf) {
                         false
                     }
                 };
+                if let Some(pending) = rollback.as_ref() {
+                    if !update_launched {
+                        discard_rollback(pending);
+                    } else if let Err(e) = start_rollback_watchdog(pending) {
+                        log::error!("Failed to start the rollback watchdog: {}", e);
+                    }
+                }
                 if !update_launched {
                     if let Some(dir) = custom_client_staging_dir {
                         hbb_common::allow_err!(crate::platform::remove_custom_client_staging_dir(
@@ -288,3 +682,50 @@ pub fn get_download_file_from_url(url: &str) -> Option<PathBuf> {
     let filename = url.split('/').last()?;
     Some(std::env::temp_dir().join(filename))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let (pk, sk) = sign::gen_keypair();
+        let data = b"new version";
+        let mut artifact = Artifact {
+            platform: "windows-x86_64".to_owned(),
+            url: "rustdesk-1.9.0-x86_64.exe".to_owned(),
+            sha256: hex::encode(Sha256::digest(data)),
+            signature: "".to_owned(),
+        };
+        let sig = sign::sign_detached(artifact_message("1.9.0", &artifact).as_bytes(), &sk);
+        artifact.signature = crate::encode64(sig.to_bytes());
+        assert!(verify_artifact("1.9.0", &artifact, data, &pk).is_ok());
+        assert!(verify_artifact("1.9.1", &artifact, data, &pk).is_err());
+        assert!(verify_artifact("1.9.0", &artifact, b"tampered", &pk).is_err());
+
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+                "channels": {"stable": {"version": "1.9.0"}, "beta": {"version": "1.9.1", "rollout": 0}},
+                "releases": [{"version": "1.9.0"}, {"version": "1.9.1"}, {"version": "1.0.0"}]
+            }"#,
+        )
+        .unwrap();
+        let version = |channel, current| {
+            select_release(&manifest, channel, "123456789", current).map(|r| r.version.as_str())
+        };
+        assert_eq!(version("", "1.8.0"), Some("1.9.0"));
+        assert_eq!(version("stable", "1.9.0"), None);
+        assert_eq!(version("beta", "1.8.0"), None);
+        assert_eq!(version("1.0.0", "1.8.0"), Some("1.0.0"));
+
+        assert_eq!(
+            resolve_url("http://10.0.0.1/rustdesk/manifest.json", "a.exe"),
+            "http://10.0.0.1/rustdesk/a.exe"
+        );
+        assert_eq!(
+            resolve_url("http://10.0.0.1/manifest.json", "https://x/a.exe"),
+            "https://x/a.exe"
+        );
+        assert!(rollout_bucket("123456789", "1.9.0") < 100);
+    }
+}